@@ -34,10 +34,6 @@ relocate:
     ret
 
 .end_relocate:
-    call check_extended_disk
-    cmp al, 1
-    jne no_edd
-
     mov si, msg_starting
     call puts
 
@@ -46,12 +42,23 @@ relocate:
     mov word [disk_address_packet.dap_dest_offset], 0x0000
     mov word [disk_address_packet.dap_num_sectors_read], 64
     mov dword [disk_address_packet.dap_lba_lo], 34
+
+    call check_extended_disk
+    cmp al, 1
+    jne .chs
     call read_sectors
+    jmp .read_done
+.chs:
+    ; No EDD, the sectors overwrite this copy's data but the code runs from 0x7a00
+    call read_sectors_chs
+.read_done:
+    jc disk_error
 
     jmp 0x0000:0x7c00
 
-no_edd:
-    mov si, msg_no_edd
+disk_error:
+    ; Through the relocated copy, the read may have overwritten this one
+    mov si, msg_disk_error - 512
     call puts
 
 end:
@@ -62,7 +69,7 @@ end:
 %include "./src/boot/diskutils.asm"
 %include "./src/boot/print.asm"
 
-msg_no_edd: db "No EDD", CR, ENDL, 0
+msg_disk_error: db "Disk error", CR, ENDL, 0
 msg_starting: db "Loading stage 1", CR, ENDL, 0
 
 ; Signature
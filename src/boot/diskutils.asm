@@ -13,21 +13,19 @@ check_extended_disk:
     mov bx, 0x55AA
     int 0x13
 
-    jc .no_edd
+    mov al, 0
+    jc .done
     cmp bx, 0xAA55
-    pop bx
-    jne .no_edd
+    jne .done
 
     and cx, 0b111
     cmp cx, 0b111
-    pop cx
-    jne .no_edd
+    jne .done
 
     mov al, 1
-    ret
-
-.no_edd:
-    mov al, 0
+.done:
+    pop cx
+    pop bx
     ret
 
 ; Reads sectors from disk
@@ -45,6 +43,77 @@ read_sectors:
     int 0x13
     ret
 
+; Reads sectors one at a time with INT 13h AH=02h, for BIOSes without the extensions
+; The geometry comes from INT 13h AH=08h, sectors are 512 bytes
+; The packet is only read before the first sector lands, it may be overwritten by the read
+; Parameters:
+;    dl:    drive number
+;    disk_address_packet
+; Returns:
+;    carry flag set on failure
+; Modifies:
+;    ax, bx, cx, si, di
+read_sectors_chs:
+    push bp
+    mov bp, sp
+    push es
+    push dx                         ; [bp - 4]: drive number
+
+    xor di, di
+    mov es, di
+    mov ah, 0x08
+    int 0x13
+    jc .done
+    and cx, 0x3F
+    jz .no_geometry
+    push cx                         ; [bp - 6]: sectors per track
+    mov al, dh
+    xor ah, ah
+    inc ax
+    push ax                         ; [bp - 8]: heads
+
+    mov es, [disk_address_packet.dap_dest_segment]
+    mov bx, [disk_address_packet.dap_dest_offset]
+    mov di, [disk_address_packet.dap_num_sectors_read]
+    mov si, [disk_address_packet.dap_lba_lo]
+.next:
+    test di, di
+    jz .done
+
+    ; LBA to CHS: sector = LBA % spt + 1, head = LBA / spt % heads, cylinder = LBA / spt / heads
+    mov ax, si
+    xor dx, dx
+    div word [bp - 6]
+    mov cl, dl
+    inc cl
+    xor dx, dx
+    div word [bp - 8]
+    mov ch, al
+    shl ah, 6
+    or cl, ah
+    mov dh, dl
+    mov dl, [bp - 4]
+
+    mov ax, 0x0201
+    int 0x13
+    jc .done
+
+    mov ax, es
+    add ax, 512 / 16
+    mov es, ax
+    inc si
+    dec di
+    jmp .next
+
+.no_geometry:
+    stc
+.done:
+    mov dx, [bp - 4]
+    mov es, [bp - 2]
+    mov sp, bp
+    pop bp
+    ret
+
 disk_address_packet:
     .dap_size:              db 0x10
     .dap_null:              db 0
@@ -14,14 +14,21 @@ stage1_entry:
     mov sp, 0x7c00
     sti
 
+    ; Without EDD, sectors are read through CHS and are 512 bytes
+    mov bx, 512 * 64 / 16
+    call check_extended_disk
+    mov [edd_present], al
+    cmp al, 1
+    jne .read
+
     call read_drive_parameters
     jc .err
     
     ; ebx = BYTES_PER_SECTOR * 64 / 16 (reading 64 sectors of BYTES_PER_SECTOR bytes, 16 bytes offset = 1 segment offset)
     mov bx, word [disk_parameters_struct.dps_bytes_per_sector]
     shl bx, 2
-
-    mov ecx, 5
+.read:
+    mov ecx, STAGE2_READS
     mov word [disk_address_packet.dap_dest_segment], 0x07c0
     mov word [disk_address_packet.dap_dest_offset], 0x0000
     mov word [disk_address_packet.dap_num_sectors_read], 64
@@ -31,7 +38,13 @@ stage1_entry:
     add word [disk_address_packet.dap_dest_segment], bx
 
     pusha
+    cmp byte [edd_present], 1
+    jne .read_chs
     call read_sectors
+    jmp .read_done
+.read_chs:
+    call read_sectors_chs
+.read_done:
     jc .err
     popa
     dec ecx
@@ -68,6 +81,8 @@ stage1_fail:
 
 msg_load_gdt: db "Loading GDT", CR, ENDL, 0
 msg_disk_error: db "Disk error", CR, ENDL, 0
+; 1 when the BIOS has the INT 13h extensions, stage2 is then read with AH=42h
+edd_present: db 0
 
 %include "./src/stage1/gdt_def.asm"
 %include "./src/stage1/print.asm"
@@ -75,6 +90,9 @@ msg_disk_error: db "Disk error", CR, ENDL, 0
 
 ENDL EQU 10
 CR EQU 13
+; Reads of 64 sectors after the 64 boot.asm loaded at 0x7c00, stage1 included
+; 15 * 64 sectors end at 0x7fc00, below the EBDA. linker.ld checks stage2 fits in the 15 * 64 - 1 sectors after stage1
+STAGE2_READS EQU 14
 
 bits 32
 stage1_pmode:
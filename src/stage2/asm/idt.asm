@@ -0,0 +1,33 @@
+; Exception entry points for the stage2 diagnostic IDT (see src/idt.rs)
+; Every stub leaves the same frame on the stack:
+;   CR2, EDI, ESI, EBP, ESP, EBX, EDX, ECX, EAX (pushad), vector, error code, EIP, CS, EFLAGS
+; and calls exception_handler with a pointer to it. The handler never returns.
+EXTERN exception_handler
+
+; Arguments: vector, 1 if the CPU pushes an error code
+%macro EXCEPTION_STUB 2
+GLOBAL exception_stub_%1
+exception_stub_%1:
+%if %2 == 0
+    push dword 0                ; No error code, keep the frame layout
+%endif
+    push dword %1
+    jmp exception_common
+%endmacro
+
+EXCEPTION_STUB 0, 0             ; #DE
+EXCEPTION_STUB 6, 0             ; #UD
+EXCEPTION_STUB 8, 1             ; #DF
+EXCEPTION_STUB 13, 1            ; #GP
+EXCEPTION_STUB 14, 1            ; #PF
+
+exception_common:
+    pushad
+    mov eax, cr2
+    push eax
+    cld
+    push esp                    ; Pointer to the frame
+    call exception_handler
+    cli
+    hlt
+    jmp $
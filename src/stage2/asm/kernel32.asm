@@ -0,0 +1,63 @@
+EXTERN GDTR
+
+GLOBAL jump32
+jump32:
+    [bits 32]
+    cli
+    lgdt [GDTR]
+
+    mov eax, [esp + 4]  ; 32-bit data selector
+    mov esi, [esp + 8]  ; 32-bit code selector
+    mov ebx, [esp + 12] ; entry point
+    mov ecx, [esp + 16] ; stack pointer
+    mov edx, [esp + 20] ; obsiboot pointer
+
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+    mov ss, ax
+    mov esp, ecx
+    mov ebp, esp
+
+    ; Reload CS with the selector from our own GDT
+    push esi
+    push dword .pmode32
+    retf
+.pmode32:
+    ; Argument, both cdecl on the stack and in eax
+    push edx
+    mov eax, edx
+
+    ; Call 32-bit kernel entry
+    call ebx
+
+    cli
+    hlt
+    jmp $
+
+GLOBAL jump_multiboot2
+jump_multiboot2:
+    [bits 32]
+    cli
+    lgdt [GDTR]
+
+    mov eax, [esp + 4]  ; 32-bit data selector
+    mov esi, [esp + 8]  ; 32-bit code selector
+    mov ecx, [esp + 12] ; entry point
+    mov ebx, [esp + 16] ; boot information pointer
+
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+    mov ss, ax
+
+    ; Reload CS with the selector from our own GDT
+    push esi
+    push dword .pmode32
+    retf
+.pmode32:
+    ; The kernel sets up its own stack, the spec leaves esp undefined
+    mov eax, 0x36D76289
+    jmp ecx
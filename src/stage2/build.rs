@@ -28,17 +28,120 @@ fn find_asm_recursive() {
     }).unwrap();
 }
 
+/// Collects every file the stage2 image is built from, relative to the crate root
+fn collect_build_inputs(dir: &Path, root: &Path, files: &mut Vec<std::path::PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            if path.file_name() != Some("target".as_ref()) {
+                collect_build_inputs(&path, root, files);
+            }
+        } else if matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("rs" | "asm" | "ld" | "toml")
+        ) {
+            files.push(path.strip_prefix(root).unwrap().to_path_buf());
+        }
+    }
+}
+
+/// Names of the features declared in Cargo.toml, enabled or not
+fn declared_features(manifest: &str) -> Vec<String> {
+    manifest
+        .lines()
+        .skip_while(|line| line.trim() != "[features]")
+        .skip(1)
+        .take_while(|line| !line.trim_start().starts_with('['))
+        .filter_map(|line| line.split_once('=').map(|(name, _)| name.trim()))
+        .filter(|name| !name.is_empty() && !name.starts_with('#') && *name != "default")
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// `CARGO_FEATURE_*` variable Cargo sets for an enabled feature
+fn feature_variable(feature: &str) -> String {
+    format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"))
+}
+
+/// Version line of the compiler Cargo builds with
+fn rustc_version() -> String {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let output = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .expect("Failed to run rustc --version");
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+/// 128-bit FNV-1a over the path and contents of every build input sorted by path, then the profile, the enabled features and the rustc version.
+/// Depends only on those, so two builds of the same tree with the same settings get the same id.
+fn compute_build_id(features: &[String]) -> u128 {
+    const FNV_OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const FNV_PRIME: u128 = 0x0000000001000000000000000000013B;
+
+    let root = std::env::current_dir().unwrap();
+    let mut files = Vec::new();
+    collect_build_inputs(&root, &root, &mut files);
+    files.sort();
+
+    let mut hash = FNV_OFFSET;
+    let mut fold = |bytes: &[u8]| {
+        for &byte in bytes.iter().chain(&[0]) {
+            hash ^= byte as u128;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    };
+    for file in files {
+        let name = file.to_string_lossy().replace('\\', "/");
+        fold(name.as_bytes());
+        fold(&fs::read(root.join(&file)).unwrap());
+    }
+    fold(std::env::var("PROFILE").unwrap_or_default().as_bytes());
+    let mut enabled: Vec<&String> = features
+        .iter()
+        .filter(|feature| std::env::var_os(feature_variable(feature)).is_some())
+        .collect();
+    enabled.sort();
+    for feature in enabled {
+        fold(feature.as_bytes());
+    }
+    fold(rustc_version().as_bytes());
+    hash
+}
+
 fn main() {
-    // Assemble the assembly file
-    Command::new("nasm")
-        .args(["-f", "elf32", "-o", "main.o", "main.asm"])
-        .status()
-        .expect("Failed to assemble main.asm");
-
-    // Link the object file with Rust's output
-    println!("cargo:rustc-link-arg=main.o");
+    // Host builds only run the unit tests, the asm is for the bare-metal target
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("none") {
+        // Assemble the assembly file
+        Command::new("nasm")
+            .args(["-f", "elf32", "-o", "main.o", "main.asm"])
+            .status()
+            .expect("Failed to assemble main.asm");
+
+        // Link the object file with Rust's output
+        println!("cargo:rustc-link-arg=main.o");
+    }
     println!("cargo:rerun-if-changed=main.asm");
     println!("cargo:rerun-if-changed=build.rs");
 
     find_asm_recursive();
+
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=linker.ld");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    // Embedded by reload::CONFIG_OVERRIDE, so test setups can boot an alternate config file
+    println!("cargo:rerun-if-env-changed=OBSIBOOT_CONFIG");
+    // The build id also covers the profile, the features and the compiler
+    let features = declared_features(&fs::read_to_string("Cargo.toml").unwrap());
+    for variable in ["PROFILE", "RUSTC"]
+        .into_iter()
+        .map(String::from)
+        .chain(features.iter().map(|feature| feature_variable(feature)))
+    {
+        println!("cargo:rerun-if-env-changed={variable}");
+    }
+    println!(
+        "cargo:rustc-env=OBSIBOOT_BUILD_ID={:032x}",
+        compute_build_id(&features)
+    );
 }
@@ -1,7 +1,8 @@
 BITS 32
 EXTERN rust_entry
 
-SECTION .text
+; Linked first by linker.ld, stage1 jumps to 0x7e00
+SECTION .text.stage3_entry progbits alloc exec nowrite
 
 GLOBAL stage3_entry
 stage3_entry:
@@ -10,7 +11,11 @@ stage3_entry:
     hlt
     jmp $
 
+SECTION .text
+
 %include "asm/io.asm"
 %include "asm/bios.asm"
 %include "asm/cpuid.asm"
-%include "asm/paging.asm"
\ No newline at end of file
+%include "asm/idt.asm"
+%include "asm/paging.asm"
+%include "asm/kernel32.asm"
\ No newline at end of file
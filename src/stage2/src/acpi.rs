@@ -0,0 +1,80 @@
+use crate::{
+    bios::EBDA_SEGMENT_PTR,
+    log_warn, printf,
+    warnings::{warning, WarningId},
+};
+
+const RSDP_SIGNATURE: [u8; 8] = *b"RSD PTR ";
+/// Size covered by the ACPI 1.0 checksum
+const RSDP_V1_SIZE: usize = 20;
+/// Size covered by the extended checksum of ACPI 2.0 and later
+const RSDP_V2_SIZE: usize = 36;
+/// The RSDP is always on a 16 byte boundary
+const RSDP_ALIGN: usize = 16;
+
+/// Only the first KiB of the EBDA is searched
+const EBDA_SEARCH_SIZE: usize = 0x400;
+const BIOS_AREA_START: usize = 0xE0000;
+const BIOS_AREA_END: usize = 0x100000;
+
+#[derive(Clone, Copy)]
+pub struct Rsdp {
+    /// Physical address of the RSDP structure, below 1MiB
+    pub address: u32,
+    /// 0 for ACPI 1.0, 2 and up for the extended structure with the XSDT
+    pub revision: u8,
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// The RSDP at `address` if its signature and checksums are valid
+fn rsdp_at(address: usize) -> Option<Rsdp> {
+    let bytes = unsafe { core::slice::from_raw_parts(address as *const u8, RSDP_V2_SIZE) };
+    if bytes[..RSDP_SIGNATURE.len()] != RSDP_SIGNATURE || checksum(&bytes[..RSDP_V1_SIZE]) != 0 {
+        return None;
+    }
+    let revision = bytes[15];
+    if revision >= 2 && checksum(bytes) != 0 {
+        return None;
+    }
+    Some(Rsdp {
+        address: address as u32,
+        revision,
+    })
+}
+
+fn scan(start: usize, end: usize) -> Option<Rsdp> {
+    (start..end)
+        .step_by(RSDP_ALIGN)
+        .take_while(|address| address + RSDP_V2_SIZE <= end)
+        .find_map(rsdp_at)
+}
+
+/// Looks for the ACPI RSDP in the first KiB of the EBDA, then in the 0xE0000-0xFFFFF BIOS area. <br>
+/// Returns None with a warning on machines without ACPI. <br>
+pub fn find_rsdp() -> Option<Rsdp> {
+    let ebda = unsafe { (EBDA_SEGMENT_PTR as *const u16).read_volatile() } as usize * 16;
+    let found = if ebda != 0 {
+        scan(ebda, ebda + EBDA_SEARCH_SIZE)
+    } else {
+        None
+    }
+    .or_else(|| scan(BIOS_AREA_START, BIOS_AREA_END));
+
+    match found {
+        Some(rsdp) => {
+            printf!(
+                b"ACPI: RSDP at 0x%x, revision 0x%x\r\n",
+                rsdp.address as usize,
+                rsdp.revision as usize
+            );
+        }
+        None => {
+            log_warn!(b"Warning: ACPI RSDP not found\r\n");
+            warning(WarningId::AcpiMissing);
+        }
+    }
+    found
+}
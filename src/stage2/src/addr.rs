@@ -0,0 +1,164 @@
+use core::cell::SyncUnsafeCell;
+
+use crate::{
+    e9::{write_hex_u32, write_string},
+    mem::{heap_region, SortedMap},
+    printf,
+};
+
+/// Addresses annotated per boot, later ones are printed plain so a flood of messages doesn't spend its time in lookups
+pub const MAX_ANNOTATED_ADDRESSES: usize = 4096;
+/// Registered regions walked down from the nearest start, enough for the arena inside the heap inside RAM
+const MAX_NESTING: usize = 4;
+/// Fixed ranges of the PC memory map, known before anything is registered
+const HARDWARE_REGIONS: [(u64, u64, &[u8]); 5] = [
+    (0x0, 0x400, b"IVT"),
+    (0x400, 0x500, b"BDA"),
+    (0xA0000, 0xC0000, b"VGA"),
+    (0xC0000, 0xC8000, b"video BIOS"),
+    (0xF0000, 0x100000, b"BIOS ROM"),
+];
+
+extern "C" {
+    /// Bounds of the stage2 image, from the linker script
+    static stage2_start: u8;
+    static stage2_end: u8;
+}
+
+#[derive(Clone, Copy)]
+enum RegionName {
+    Named(&'static [u8]),
+    /// LOAD program header of the kernel, by index
+    KernelSegment(usize),
+}
+
+struct AddressRegion {
+    end: u64,
+    name: RegionName,
+}
+
+struct RegionTable {
+    /// Regions by start address, None until [`init_address_regions`] ran on a working heap
+    regions: Option<SortedMap<u64, AddressRegion>>,
+    annotated: usize,
+}
+
+/// Only touched by the single stage2 thread, never from an interrupt handler
+unsafe impl Sync for RegionTable {}
+
+static TABLE: SyncUnsafeCell<RegionTable> = SyncUnsafeCell::new(RegionTable {
+    regions: None,
+    annotated: 0,
+});
+
+fn table() -> &'static mut RegionTable {
+    unsafe { &mut *TABLE.get() }
+}
+
+/// Builds the region table and registers the stage2 image, call it once the heap works. <br>
+/// Until then addresses are only annotated with the hardware ranges and the heap. <br>
+pub fn init_address_regions() {
+    table().regions = Some(SortedMap::new(16));
+    let (start, end) = stage2_image();
+    register_region(start, end, b"stage2");
+}
+
+/// `(start, end)` of the stage2 image, its bss included
+pub fn stage2_image() -> (u64, u64) {
+    unsafe {
+        (
+            &stage2_start as *const u8 as u64,
+            &stage2_end as *const u8 as u64,
+        )
+    }
+}
+
+fn insert(start: u64, end: u64, name: RegionName) {
+    if start >= end {
+        return;
+    }
+    if let Some(regions) = table().regions.as_mut() {
+        regions.insert(start, AddressRegion { end, name });
+    }
+}
+
+/// Names `[start, end)` in annotated addresses. A region starting where another one starts replaces it. <br>
+/// Regions may nest, the innermost one is shown. <br>
+pub fn register_region(start: u64, end: u64, name: &'static [u8]) {
+    insert(start, end, RegionName::Named(name));
+}
+
+/// Names the virtual range of the kernel LOAD segment `index`
+pub fn register_kernel_segment(index: usize, start: u64, end: u64) {
+    insert(start, end, RegionName::KernelSegment(index));
+}
+
+/// Region containing `addr` and its start: registered ones first, then the heap, then the hardware ranges
+fn find_region(addr: u64) -> Option<(u64, RegionName)> {
+    if let Some(regions) = table().regions.as_ref() {
+        if let Some((start, region)) = regions
+            .iter_at_or_below(&addr)
+            .take(MAX_NESTING)
+            .find(|(_, region)| addr < region.end)
+        {
+            return Some((*start, region.name));
+        }
+    }
+    if let Some((start, end)) = heap_region() {
+        if start <= addr && addr < end {
+            return Some((start, RegionName::Named(b"heap")));
+        }
+    }
+    HARDWARE_REGIONS
+        .iter()
+        .find(|(start, end, _)| *start <= addr && addr < *end)
+        .map(|(start, _, name)| (*start, RegionName::Named(name)))
+}
+
+/// `0x` and 8 hex digits, or 16 when the address doesn't fit in 32 bits
+pub fn write_addr_plain(addr: u64) {
+    printf!(b"0x");
+    if addr >> 32 != 0 {
+        write_hex_u32((addr >> 32) as u32);
+    }
+    write_hex_u32(addr as u32);
+}
+
+/// Writes `addr` followed by the region containing it, like `0x01F43000 [heap+0x00043000]`. <br>
+/// Falls back to [`write_addr_plain`] once [`MAX_ANNOTATED_ADDRESSES`] addresses were annotated. <br>
+pub fn write_addr(addr: u64) {
+    write_addr_plain(addr);
+    write_annotation(addr);
+}
+
+/// Writes `start-end`, annotated with the region containing `start`
+pub fn write_addr_range(start: u64, end: u64) {
+    write_addr_plain(start);
+    printf!(b"-");
+    write_addr_plain(end);
+    write_annotation(start);
+}
+
+fn write_annotation(addr: u64) {
+    let table = table();
+    if table.annotated >= MAX_ANNOTATED_ADDRESSES {
+        return;
+    }
+    table.annotated += 1;
+    if table.annotated == MAX_ANNOTATED_ADDRESSES {
+        printf!(b" (address annotations stop here)");
+    }
+    let Some((start, name)) = find_region(addr) else {
+        return;
+    };
+    printf!(b" [");
+    match name {
+        RegionName::Named(name) => write_string(name),
+        RegionName::KernelSegment(index) => printf!(b"kernel segment 0x%x", index),
+    }
+    if addr != start {
+        printf!(b"+");
+        write_addr_plain(addr - start);
+    }
+    printf!(b"]");
+}
@@ -0,0 +1,150 @@
+use core::{
+    arch::x86::{__cpuid, _rdtsc},
+    cell::SyncUnsafeCell,
+};
+
+use crate::{
+    bios::{set_preferred_transfer_sectors, ExtendedDisk, MAX_TRANSFER_SECTORS},
+    e9::write_u64_decimal,
+    gpt::DiskRange,
+    iolat, log_warn, printf,
+    video::Video,
+    warnings::{warning, WarningId},
+};
+
+/// Sectors per BIOS call measured by the benchmark
+pub const BENCH_TRANSFER_SIZES: [usize; 5] = [1, 8, 32, 64, MAX_TRANSFER_SECTORS];
+/// Bytes read per transfer size when `bench_bytes=` isn't set
+pub const DEFAULT_BENCH_BYTES: u64 = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy)]
+pub struct BenchResult {
+    pub sectors_per_call: usize,
+    pub bytes: u64,
+    pub us: u64,
+}
+
+impl BenchResult {
+    /// Throughput in KiB/s, 0 when the time couldn't be measured
+    pub fn kib_per_second(&self) -> u64 {
+        match self.us {
+            0 => 0,
+            us => self.bytes * 1_000_000 / 1024 / us,
+        }
+    }
+}
+
+/// Results of the last run, `bytes == 0` for sizes that weren't measured
+static RESULTS: SyncUnsafeCell<[BenchResult; BENCH_TRANSFER_SIZES.len()]> = SyncUnsafeCell::new(
+    [BenchResult {
+        sectors_per_call: 0,
+        bytes: 0,
+        us: 0,
+    }; BENCH_TRANSFER_SIZES.len()],
+);
+
+/// Results of the last benchmark run, empty if it never ran
+pub fn results() -> impl Iterator<Item = BenchResult> {
+    unsafe { *RESULTS.get() }
+        .into_iter()
+        .filter(|r| r.bytes != 0)
+}
+
+/// Times sequential reads of `sectors` sectors from `start` in calls of `sectors_per_call`
+fn measure(
+    disk: &mut ExtendedDisk,
+    start: u64,
+    sectors: u64,
+    sectors_per_call: usize,
+    bps: u64,
+) -> Option<BenchResult> {
+    let begin = unsafe { _rdtsc() };
+    let mut lba = start;
+    while lba < start + sectors {
+        let count = (sectors_per_call as u64).min(start + sectors - lba) as usize;
+        if let Err(e) = disk.read_sectors(lba, count) {
+            printf!(b"    0x%x sectors per call: ", sectors_per_call);
+            e.printf();
+            printf!(b"\r\n");
+            return None;
+        }
+        lba += count as u64;
+    }
+    let cycles = unsafe { _rdtsc() } - begin;
+    Some(BenchResult {
+        sectors_per_call,
+        bytes: sectors * bps,
+        us: iolat::cycles_to_us(cycles),
+    })
+}
+
+/// Measures sequential read throughput of `disk` for every size in [`BENCH_TRANSFER_SIZES`], reading at most `total_bytes` per size inside `range`. <br>
+/// Only reads, each size starts where the previous one stopped when the range is large enough so the disk cache doesn't flatter later sizes. <br>
+/// The fastest size becomes the preferred transfer size of the disk layer for the rest of the boot. <br>
+pub fn run_disk_benchmark(disk: &mut ExtendedDisk, range: DiskRange, total_bytes: u64) {
+    let Ok(params) = disk.get_params() else {
+        printf!(b"Disk benchmark: no disk parameters, skipped\r\n");
+        return;
+    };
+    let bps = (params.bytes_per_sector as u64).max(1);
+    let range_sectors = range.end_lba - range.start_lba + 1;
+    let sectors = (total_bytes / bps).clamp(1, range_sectors);
+
+    if !iolat::enabled() {
+        iolat::enable();
+    }
+    iolat::reset();
+
+    printf!(b"Disk benchmark: ");
+    write_u64_decimal(sectors * bps);
+    printf!(b" bytes per transfer size\r\n");
+    if (__cpuid(1).ecx >> 31) & 1 != 0 {
+        log_warn!(b"Warning: running under a hypervisor, the results measure the emulator, not the disk\r\n");
+        warning(WarningId::BenchHypervisor);
+    }
+
+    let results = unsafe { &mut *RESULTS.get() };
+    let mut best: Option<BenchResult> = None;
+    for (i, sectors_per_call) in BENCH_TRANSFER_SIZES.iter().enumerate() {
+        let offset = if (i as u64 + 1) * sectors <= range_sectors {
+            i as u64 * sectors
+        } else {
+            0
+        };
+        let Some(result) = measure(
+            disk,
+            range.start_lba + offset,
+            sectors,
+            *sectors_per_call,
+            bps,
+        ) else {
+            continue;
+        };
+        results[i] = result;
+        printf!(b"    0x%x sectors per call: ", *sectors_per_call as u32);
+        write_u64_decimal(result.us);
+        printf!(b" us, ");
+        write_u64_decimal(result.kib_per_second());
+        printf!(b" KiB/s\r\n");
+        unsafe {
+            let video = Video::get();
+            video.write_string(b"Bench 0x");
+            video.write_hex_u32(*sectors_per_call as u32);
+            video.write_string(b" sectors/call: 0x");
+            video.write_hex_u32(result.kib_per_second() as u32);
+            video.write_string(b" KiB/s\n");
+        }
+        if best.is_none_or(|best| result.kib_per_second() > best.kib_per_second()) {
+            best = Some(result);
+        }
+    }
+    iolat::printf_histograms();
+
+    if let Some(best) = best {
+        printf!(
+            b"Disk benchmark: using 0x%x sectors per call for the rest of the boot\r\n",
+            best.sectors_per_call
+        );
+        set_preferred_transfer_sectors(best.sectors_per_call);
+    }
+}
@@ -1,6 +1,18 @@
-use core::ptr::addr_of;
+use core::{
+    arch::asm,
+    ptr::addr_of,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use crate::{eflags, kpanic, mem::Buffer, ptr_to_seg_off, seg_off_to_ptr, video::Video};
+use crate::{
+    diskhealth::{record_disk_error, SHORT_READ_CODE},
+    e9, eflags, kpanic, log_warn,
+    media::{read_only, record_write_failure},
+    mem::Buffer,
+    printf, ptr_to_seg_off, seg_off_to_ptr,
+    video::Video,
+    warnings::{warning, WarningId},
+};
 
 #[repr(C, packed)]
 pub struct BiosInterruptResult {
@@ -44,6 +56,35 @@ impl BiosInterruptResult {
     }
 }
 
+/// Snapshot of the EFLAGS a BIOS call must not change, checked against the flags the BIOS returned with. <br>
+/// The trampoline reloads its own EFLAGS, IDTR and GDTR before returning, so only the copy in [`BiosInterruptResult`] shows what the BIOS left. <br>
+/// Take it right before the call, and check it with [`BiosCallGuard::verify`] right after. <br>
+pub struct BiosCallGuard {
+    eflags: usize,
+}
+
+/// Flags a well-behaved BIOS call returns with unchanged
+const GUARDED_EFLAGS: usize = eflags::DF | eflags::IOPL | eflags::NT | eflags::VM;
+
+impl BiosCallGuard {
+    pub fn new() -> Self {
+        let eflags: usize;
+        unsafe { asm!("pushfd", "pop {}", out(reg) eflags) };
+        Self { eflags }
+    }
+
+    /// Returns whether the BIOS returned with the guarded flags as they were before the call
+    pub fn verify(&self, result: &BiosInterruptResult) -> bool {
+        (result.eflags & GUARDED_EFLAGS) == (self.eflags & GUARDED_EFLAGS)
+    }
+}
+
+impl Default for BiosCallGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
 pub struct DiskParamsRaw {
@@ -69,7 +110,8 @@ pub struct DiskAccessPacket {
 }
 
 unsafe extern "cdecl" {
-    pub unsafe fn unsafe_call_bios_interrupt(
+    #[link_name = "unsafe_call_bios_interrupt"]
+    unsafe fn raw_call_bios_interrupt(
         bios_idt: usize,
         interrupt: usize,
         eax: usize,
@@ -85,6 +127,36 @@ unsafe extern "cdecl" {
     ) -> usize;
 }
 
+/// Calls the BIOS interrupt `interrupt` in real mode, returns a pointer to a [`BiosInterruptResult`]. <br>
+/// With the `bios-latency` feature, the call is timed when tracking is enabled (see `iolat`). <br>
+/// # Safety
+/// `bios_idt` must be the real mode IVT, the registers must be valid for the interrupt
+#[allow(clippy::too_many_arguments)]
+#[inline(always)]
+pub unsafe fn unsafe_call_bios_interrupt(
+    bios_idt: usize,
+    interrupt: usize,
+    eax: usize,
+    ebx: usize,
+    ecx: usize,
+    edx: usize,
+    esi: usize,
+    edi: usize,
+    ds: usize,
+    es: usize,
+    fs: usize,
+    gs: usize,
+) -> usize {
+    #[cfg(feature = "bios-latency")]
+    let start = crate::iolat::start();
+    let result = raw_call_bios_interrupt(
+        bios_idt, interrupt, eax, ebx, ecx, edx, esi, edi, ds, es, fs, gs,
+    );
+    #[cfg(feature = "bios-latency")]
+    crate::iolat::record(start, interrupt, [eax, ebx, ecx, edx, esi, edi]);
+    result
+}
+
 static mut DAP: DiskAccessPacket = DiskAccessPacket {
     size: 0x10,
     null: 0,
@@ -104,7 +176,74 @@ static mut PARAMS: DiskParamsRaw = DiskParamsRaw {
     bytes_per_sector: 0,
     ptr: 0,
 };
-static mut BUFF: [u8; 4096] = [0; 4096];
+/// Smallest sector size the GPT and ext2 readers handle
+pub const MIN_SECTOR_SIZE: usize = 512;
+/// Largest sector [`ExtendedDisk::read_sector`] handles, 4Kn drives
+pub const MAX_SECTOR_SIZE: usize = 4096;
+static mut BUFF: [u8; MAX_SECTOR_SIZE] = [0; MAX_SECTOR_SIZE];
+
+/// Most sectors one INT 13h AH=42h call can transfer on every BIOS (Phoenix EDD limit)
+pub const MAX_TRANSFER_SECTORS: usize = 127;
+/// Real mode bounce buffer for multi-sector transfers
+static mut TRANSFER_BUFF: [u8; MAX_TRANSFER_SECTORS * 512] = [0; MAX_TRANSFER_SECTORS * 512];
+
+/// BDA word holding the real mode segment of the EBDA
+pub const EBDA_SEGMENT_PTR: usize = 0x40E;
+/// The EBDA runs up to the end of conventional memory
+const EBDA_END: u64 = 0xA0000;
+
+/// `(start, end)` of the static buffers BIOS calls read and write through, in use until the kernel is entered
+pub fn bios_buffers() -> [(u64, u64); 2] {
+    let buff = addr_of!(BUFF) as u64;
+    let transfer_buff = addr_of!(TRANSFER_BUFF) as u64;
+    [
+        (buff, buff + MAX_SECTOR_SIZE as u64),
+        (
+            transfer_buff,
+            transfer_buff + (MAX_TRANSFER_SECTORS * 512) as u64,
+        ),
+    ]
+}
+
+/// `(start, end)` of the extended BIOS data area, None when the BDA doesn't point to one
+pub fn ebda_range() -> Option<(u64, u64)> {
+    let start = unsafe { (EBDA_SEGMENT_PTR as *const u16).read_volatile() } as u64 * 16;
+    (start != 0 && start < EBDA_END).then_some((start, EBDA_END))
+}
+
+/// Times the missing remainder of a read is requested again after the BIOS reported success but transferred fewer sectors
+const SHORT_READ_RETRIES: usize = 3;
+/// Times a read that failed with CF=1 is attempted again, each time after a drive reset (INT 13h AH=00h)
+pub const READ_ERROR_RETRIES: usize = 3;
+/// INT 13h status of a timed out command, often a drive still spinning up
+const STATUS_TIMEOUT: usize = 0x80;
+/// Wait before retrying after [`STATUS_TIMEOUT`], in microseconds
+const TIMEOUT_RETRY_DELAY_US: u32 = 2_000_000;
+/// Reads where the BIOS reported success but transferred fewer sectors than requested
+static SHORT_READS: AtomicUsize = AtomicUsize::new(0);
+
+/// Sectors transferred by extended reads since boot
+static SECTORS_READ: AtomicUsize = AtomicUsize::new(0);
+
+/// Sectors read from any disk since boot, the difference around an operation is its I/O cost
+pub fn sectors_read() -> usize {
+    SECTORS_READ.load(Ordering::Relaxed)
+}
+
+/// Number of short transfers seen since boot, non zero means the firmware misreports INT 13h reads
+pub fn short_read_count() -> usize {
+    SHORT_READS.load(Ordering::Relaxed)
+}
+/// Sectors per BIOS call multi-sector readers should use, set from the disk benchmark
+static PREFERRED_TRANSFER_SECTORS: AtomicUsize = AtomicUsize::new(1);
+
+pub fn preferred_transfer_sectors() -> usize {
+    PREFERRED_TRANSFER_SECTORS.load(Ordering::Relaxed)
+}
+
+pub fn set_preferred_transfer_sectors(sectors: usize) {
+    PREFERRED_TRANSFER_SECTORS.store(sectors.clamp(1, MAX_TRANSFER_SECTORS), Ordering::Relaxed);
+}
 
 #[derive(Clone, Copy)]
 pub struct DiskParams {
@@ -116,27 +255,162 @@ pub struct DiskParams {
     pub bytes_per_sector: u16,
 }
 
+/// Short description of an INT 13h status code (AH after the call)
+pub fn int13_status_string(code: usize) -> &'static [u8] {
+    match code {
+        0x00 => b"no error",
+        0x01 => b"invalid command",
+        0x02 => b"address mark not found",
+        0x03 => b"write protected",
+        0x04 => b"sector not found",
+        0x05 => b"reset failed",
+        0x06 => b"disk changed",
+        0x07 => b"drive parameter activity failed",
+        0x08 => b"DMA overrun",
+        0x09 => b"DMA across 64K boundary",
+        0x0A => b"bad sector",
+        0x0B => b"bad track",
+        0x0C => b"unsupported track / invalid media",
+        0x0D => b"invalid number of sectors",
+        0x0E => b"control data address mark detected",
+        0x0F => b"DMA arbitration level out of range",
+        0x10 => b"uncorrectable CRC/ECC error",
+        0x11 => b"ECC corrected data error",
+        0x20 => b"controller failure",
+        0x31 => b"no media in drive",
+        0x32 => b"incorrect drive type in CMOS",
+        0x40 => b"seek failure",
+        0x80 => b"timeout / drive not ready",
+        0xAA => b"drive not ready",
+        0xB0 => b"volume not locked in drive",
+        0xB1 => b"volume locked in drive",
+        0xB2 => b"volume not removable",
+        0xB3 => b"volume in use",
+        0xB4 => b"lock count exceeded",
+        0xB5 => b"valid eject request failed",
+        0xBB => b"undefined error",
+        0xCC => b"write fault",
+        0xE0 => b"status register error",
+        0xFF => b"sense operation failed",
+        _ => b"unknown error",
+    }
+}
+
 pub enum DiskError {
     OutputBufferTooSmall,
     InvalidDiskParameters,
     FailedMemAlloc(usize),
-    ReadError(usize),
+    /// INT 13h status code, LBA, number of retries done before giving up
+    ReadError(usize, u64, usize),
     ReadParametersError(usize),
+    /// INT 13h status code, LBA, number of retries done before giving up
+    WriteError(usize, u64, usize),
+    /// The BIOS kept reporting success while transferring only `got` of the `requested` sectors at `lba`
+    ShortRead {
+        requested: usize,
+        got: usize,
+        lba: u64,
+    },
+    /// Write refused without calling the BIOS, the boot medium is read-only for this boot (see `media`)
+    ReadOnlyMedia,
+    /// The LBA is past the 1024 cylinders a CHS read (INT 13h AH=02h) can address
+    LbaBeyondChsReach(u64),
 }
 
 impl DiskError {
+    /// Logs e.g. `read error 0x80 (timeout / drive not ready) at LBA 123456 after 3 retries`, without a trailing newline
+    pub fn printf(&self) {
+        match self {
+            DiskError::ReadError(c, lba, retries) | DiskError::WriteError(c, lba, retries) => {
+                match self {
+                    DiskError::ReadError(..) => printf!(b"read error 0x%b (", *c as u8),
+                    _ => printf!(b"write error 0x%b (", *c as u8),
+                }
+                e9::write_string(int13_status_string(*c));
+                printf!(b") at LBA ");
+                e9::write_u64_decimal(*lba);
+                printf!(b" after ");
+                e9::write_u64_decimal(*retries as u64);
+                printf!(b" retries");
+            }
+            DiskError::ReadParametersError(c) => {
+                printf!(b"read parameters error 0x%b (", *c as u8);
+                e9::write_string(int13_status_string(*c));
+                printf!(b")");
+            }
+            DiskError::ShortRead {
+                requested,
+                got,
+                lba,
+            } => {
+                printf!(b"short read of 0x%x/0x%x sectors at LBA ", *got, *requested);
+                e9::write_u64_decimal(*lba);
+            }
+            DiskError::ReadOnlyMedia => printf!(b"boot medium is read-only"),
+            DiskError::LbaBeyondChsReach(lba) => {
+                printf!(b"LBA ");
+                e9::write_u64_decimal(*lba);
+                printf!(b" beyond CHS reach");
+            }
+            DiskError::OutputBufferTooSmall => printf!(b"output buffer too small"),
+            DiskError::InvalidDiskParameters => printf!(b"invalid disk parameters"),
+            DiskError::FailedMemAlloc(size) => {
+                printf!(b"failed to allocate memory: 0x%x", *size)
+            }
+        }
+    }
+
     pub fn panic(&self) -> ! {
+        printf!(b"Disk error: ");
+        self.printf();
+        printf!(b"\r\n");
         unsafe {
             let video = Video::get();
             video.write_string(b"Disk error: ");
             match self {
-                DiskError::ReadError(c) => {
-                    video.write_string(b"read error 0x");
-                    video.write_hex_u32(*c as u32);
+                DiskError::ReadError(c, lba, retries) | DiskError::WriteError(c, lba, retries) => {
+                    match self {
+                        DiskError::ReadError(..) => video.write_string(b"read error 0x"),
+                        _ => video.write_string(b"write error 0x"),
+                    }
+                    video.write_hex_u8(*c as u8);
+                    video.write_string(b" (");
+                    video.write_string(int13_status_string(*c));
+                    video.write_string(b") at LBA 0x");
+                    video.write_hex_u32((*lba >> 32) as u32);
+                    video.write_hex_u32(*lba as u32);
+                    video.write_string(b" after 0x");
+                    video.write_hex_u32(*retries as u32);
+                    video.write_string(b" retries");
                 }
                 DiskError::ReadParametersError(c) => {
                     video.write_string(b"read parameters error 0x");
                     video.write_hex_u32(*c as u32);
+                    video.write_string(b" (");
+                    video.write_string(int13_status_string(*c));
+                    video.write_char(b')');
+                }
+                DiskError::ShortRead {
+                    requested,
+                    got,
+                    lba,
+                } => {
+                    video.write_string(b"short read of 0x");
+                    video.write_hex_u32(*got as u32);
+                    video.write_string(b"/0x");
+                    video.write_hex_u32(*requested as u32);
+                    video.write_string(b" sectors at LBA 0x");
+                    video.write_hex_u32((*lba >> 32) as u32);
+                    video.write_hex_u32(*lba as u32);
+                }
+                DiskError::ReadOnlyMedia => {
+                    video.write_string(b"boot medium is read-only");
+                }
+                DiskError::LbaBeyondChsReach(lba) => {
+                    video.write_string(b"LBA 0x");
+                    video.write_hex_u32((*lba >> 32) as u32);
+                    video.write_hex_u32(*lba as u32);
+                    video.write_string(b" beyond CHS reach");
                 }
                 DiskError::OutputBufferTooSmall => {
                     video.write_string(b"output buffer too small");
@@ -155,11 +429,20 @@ impl DiskError {
     }
 }
 
+/// Cylinders a CHS address can reach, 10 bits
+const CHS_MAX_CYLINDERS: u32 = 1024;
+/// Sector size of every drive without INT 13h extensions
+const CHS_SECTOR_SIZE: u16 = 512;
+
+/// A BIOS drive, read with the INT 13h extensions or, on BIOSes without them, with CHS reads. <br>
+/// The mechanism is picked by [`ExtendedDisk::check_present`] and hidden from the rest of the API. <br>
 #[derive(Clone)]
 pub struct ExtendedDisk {
     disk: u8,
     bios_idt: usize,
     params: Option<DiskParams>,
+    /// Reads go through INT 13h AH=02h, `params` were synthesized from the AH=08h geometry
+    chs: bool,
 }
 
 impl ExtendedDisk {
@@ -168,10 +451,36 @@ impl ExtendedDisk {
             disk,
             bios_idt,
             params: None,
+            chs: false,
+        }
+    }
+
+    /// Whether the drive can be read: with the INT 13h extensions, or else with CHS reads if the BIOS reports a geometry
+    pub fn check_present(&mut self) -> bool {
+        if self.check_extensions() {
+            return true;
         }
+        let Some(params) = self.chs_geometry() else {
+            return false;
+        };
+        printf!(b"Drive 0x%b: no INT 13h extensions, ", self.disk);
+        printf!(
+            b"CHS reads with 0x%x cylinders 0x%x heads 0x%x sectors\r\n",
+            params.cylinders as usize,
+            params.heads as usize,
+            params.sectors_per_track as usize
+        );
+        self.params = Some(params);
+        self.chs = true;
+        true
+    }
+
+    /// Whether reads use the INT 13h extensions, false when falling back to CHS
+    pub fn has_extensions(&self) -> bool {
+        !self.chs
     }
 
-    pub fn check_present(&self) -> bool {
+    fn check_extensions(&self) -> bool {
         unsafe {
             let result = unsafe_call_bios_interrupt(
                 self.bios_idt,
@@ -194,6 +503,86 @@ impl ExtendedDisk {
         }
     }
 
+    /// Drive geometry from INT 13h AH=08h, capped to what CHS reads can address
+    fn chs_geometry(&self) -> Option<DiskParams> {
+        unsafe {
+            let result = unsafe_call_bios_interrupt(
+                self.bios_idt,
+                0x13,
+                0x0800,
+                0,
+                0,
+                self.disk as usize,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ) as *const BiosInterruptResult;
+
+            if ((*result).eflags & eflags::CF) != 0 {
+                return None;
+            }
+            let ecx = (*result).ecx;
+            let cylinders = ((ecx >> 8) & 0xFF | (ecx & 0xC0) << 2) as u32 + 1;
+            let heads = (((*result).edx >> 8) & 0xFF) as u32 + 1;
+            let sectors_per_track = (ecx & 0x3F) as u32;
+            if sectors_per_track == 0 {
+                return None;
+            }
+            Some(DiskParams {
+                info: 0,
+                cylinders,
+                heads,
+                sectors_per_track,
+                sectors: cylinders as u64 * heads as u64 * sectors_per_track as u64,
+                bytes_per_sector: CHS_SECTOR_SIZE,
+            })
+        }
+    }
+
+    /// Cylinder, head and 1-based sector of `lba`, None past the 1024th cylinder
+    fn lba_to_chs(params: &DiskParams, lba: u64) -> Option<(u32, u32, u32)> {
+        let spt = params.sectors_per_track as u64;
+        let heads = params.heads as u64;
+        let cylinder = lba / (spt * heads);
+        if cylinder >= params.cylinders.min(CHS_MAX_CYLINDERS) as u64 {
+            return None;
+        }
+        Some((
+            cylinder as u32,
+            (lba / spt % heads) as u32,
+            (lba % spt) as u32 + 1,
+        ))
+    }
+
+    /// Disk type from INT 13h AH=15h: 0 no disk, 1 floppy without change line, 2 with change line, 3 hard disk. None if the call failed.
+    pub fn disk_type(&self) -> Option<u8> {
+        unsafe {
+            let result = unsafe_call_bios_interrupt(
+                self.bios_idt,
+                0x13,
+                0x1500,
+                0,
+                0,
+                self.disk as usize,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ) as *const BiosInterruptResult;
+
+            if ((*result).eflags & eflags::CF) != 0 {
+                None
+            } else {
+                Some((((*result).eax & 0xFFFF) >> 8) as u8)
+            }
+        }
+    }
+
     pub fn get_params(&mut self) -> Result<DiskParams, DiskError> {
         if let Some(params) = self.params {
             return Ok(params);
@@ -217,7 +606,9 @@ impl ExtendedDisk {
             ) as *const BiosInterruptResult;
 
             if ((*result).eflags & eflags::CF) != 0 {
-                Err(DiskError::ReadParametersError((*result).eax as usize))
+                Err(DiskError::ReadParametersError(
+                    ((*result).eax & 0xFFFF) >> 8,
+                ))
             } else {
                 let params = DiskParams {
                     info: PARAMS.info,
@@ -233,62 +624,240 @@ impl ExtendedDisk {
         }
     }
 
+    /// Total sectors of the disk, 64-bit so disks over 2TiB aren't truncated
+    pub fn sector_count(&mut self) -> Result<u64, DiskError> {
+        Ok(self.get_params()?.sectors)
+    }
+
     pub fn read_sector(&mut self, lba: u64, buffer: &mut Buffer) -> Result<(), DiskError> {
         let bps = self.get_params()?.bytes_per_sector as usize;
+        if bps == 0 || bps > MAX_SECTOR_SIZE {
+            return Err(DiskError::InvalidDiskParameters);
+        }
         if buffer.len() < bps {
             return Err(DiskError::OutputBufferTooSmall);
         }
 
-        let (segment, offset) = ptr_to_seg_off(addr_of!(BUFF) as usize);
-
         unsafe {
-            let (dap_seg, dap_off) = ptr_to_seg_off(addr_of!(DAP) as usize);
-            DAP = DiskAccessPacket {
-                size: 0x10,
-                null: 0,
-                sector_count: 1,
-                offset,
-                segment,
-                lba,
-            };
+            self.extended_read(lba, 1, addr_of!(BUFF) as usize, bps)?;
 
+            let output_buf = addr_of!(BUFF) as *const u8;
+            for (i, item) in buffer.iter_mut().enumerate().take(bps) {
+                *item = *output_buf.add(i);
+            }
+        }
+        Ok(())
+    }
+
+    /// Resets the drive (INT 13h AH=00h), returns false if the BIOS reported an error
+    pub fn reset(&self) -> bool {
+        unsafe {
             let result = unsafe_call_bios_interrupt(
                 self.bios_idt,
                 0x13,
-                0x4200,
+                0x0000,
                 0,
                 0,
                 self.disk as usize,
-                dap_off as usize,
                 0,
-                dap_seg as usize,
-                dap_seg as usize,
-                dap_seg as usize,
-                dap_seg as usize,
+                0,
+                0,
+                0,
+                0,
+                0,
             ) as *const BiosInterruptResult;
+            ((*result).eflags & eflags::CF) == 0
+        }
+    }
 
-            if ((*result).eflags & eflags::CF) != 0 {
-                return Err(DiskError::ReadError(((*result).eax & 0xFFFF) >> 8));
-            }
+    /// Reads `count` sectors at `lba` into the real mode buffer at `buffer` (INT 13h AH=42h, or AH=02h in CHS mode). <br>
+    /// The BIOS writes the number of sectors actually transferred back into the DAP. When it reports success with fewer, the sectors read are kept and only the remainder is requested again, up to `SHORT_READ_RETRIES` times. <br>
+    /// A failed call is retried up to [`READ_ERROR_RETRIES`] times after resetting the drive, with an extra wait on timeouts. <br>
+    /// # Safety
+    /// `buffer` must be below 1MiB and hold `count * bps` bytes
+    unsafe fn extended_read(
+        &mut self,
+        lba: u64,
+        count: usize,
+        buffer: usize,
+        bps: usize,
+    ) -> Result<(), DiskError> {
+        let params = self.get_params()?;
+        if self.chs && Self::lba_to_chs(&params, lba + count as u64 - 1).is_none() {
+            return Err(DiskError::LbaBeyondChsReach(lba + count as u64 - 1));
+        }
+        let mut done = 0;
+        let mut retries = 0;
+        let mut error_retries = 0;
+        while done < count {
+            // One sector per CHS read, old BIOSes can't cross tracks or 64KiB DMA boundaries
+            let requested = if self.chs { 1 } else { count - done };
+            let result = if self.chs {
+                self.chs_read_one(&params, lba + done as u64, buffer + done * bps)
+            } else {
+                self.bios_extended_read(lba + done as u64, requested, buffer + done * bps)
+            };
 
-            let output_buf = seg_off_to_ptr(segment, offset) as *const u8;
-            for (i, item) in buffer.iter_mut().enumerate().take(bps) {
-                *item = *output_buf.add(i);
+            let got = match result {
+                Ok(got) => got.min(requested),
+                Err(code) => {
+                    record_disk_error(lba + done as u64, code as u16);
+                    if error_retries == READ_ERROR_RETRIES {
+                        return Err(DiskError::ReadError(code, lba + done as u64, error_retries));
+                    }
+                    error_retries += 1;
+                    printf!(b"Disk 0x%b: read error 0x%b (", self.disk, code as u8);
+                    e9::write_string(int13_status_string(code));
+                    printf!(b") at LBA ");
+                    e9::write_u64_decimal(lba + done as u64);
+                    printf!(b", reset and retry 0x%x\r\n", error_retries);
+                    if !self.reset() {
+                        printf!(b"Disk 0x%b: reset failed\r\n", self.disk);
+                    }
+                    if code == STATUS_TIMEOUT {
+                        wait_us(self.bios_idt, TIMEOUT_RETRY_DELAY_US);
+                    }
+                    continue;
+                }
+            };
+            done += got;
+            SECTORS_READ.fetch_add(got, Ordering::Relaxed);
+            if got < requested {
+                SHORT_READS.fetch_add(1, Ordering::Relaxed);
+                record_disk_error(lba + done as u64, SHORT_READ_CODE);
+                warning(WarningId::ShortRead);
+                log_warn!(
+                    b"Warning: BIOS reported success but transferred 0x%x of 0x%x sectors at LBA 0x%x%x\r\n",
+                    got as u32,
+                    requested as u32,
+                    ((lba + done as u64) >> 32) as u32,
+                    (lba + done as u64) as u32
+                );
+                if retries == SHORT_READ_RETRIES {
+                    return Err(DiskError::ShortRead {
+                        requested: count,
+                        got: done,
+                        lba,
+                    });
+                }
+                retries += 1;
             }
         }
         Ok(())
     }
 
-    /// # Safety
-    /// Passed buffer must be at least `bytes_per_sector` long
-    pub unsafe fn unsafe_read_sector_to_buffer(
+    /// One INT 13h AH=42h call, returns the sectors the BIOS reports transferred or its status code
+    unsafe fn bios_extended_read(
         &mut self,
         lba: u64,
-        buffer: *mut u8,
-    ) -> Result<(), DiskError> {
-        let bps = self.get_params()?.bytes_per_sector as usize;
+        count: usize,
+        buffer: usize,
+    ) -> Result<usize, usize> {
+        let (segment, offset) = ptr_to_seg_off(buffer);
+        let (dap_seg, dap_off) = ptr_to_seg_off(addr_of!(DAP) as usize);
+        DAP = DiskAccessPacket {
+            size: 0x10,
+            null: 0,
+            sector_count: count as u16,
+            offset,
+            segment,
+            lba,
+        };
+
+        let result = unsafe_call_bios_interrupt(
+            self.bios_idt,
+            0x13,
+            0x4200,
+            0,
+            0,
+            self.disk as usize,
+            dap_off as usize,
+            0,
+            dap_seg as usize,
+            dap_seg as usize,
+            dap_seg as usize,
+            dap_seg as usize,
+        ) as *const BiosInterruptResult;
+
+        if ((*result).eflags & eflags::CF) != 0 {
+            return Err(((*result).eax & 0xFFFF) >> 8);
+        }
+        Ok(addr_of!(DAP.sector_count).read_volatile() as usize)
+    }
+
+    /// Reads the sector at `lba` with INT 13h AH=02h, returns 1 or the BIOS status code. <br>
+    /// `lba` must be within CHS reach, checked by [`Self::extended_read`]. <br>
+    unsafe fn chs_read_one(
+        &mut self,
+        params: &DiskParams,
+        lba: u64,
+        buffer: usize,
+    ) -> Result<usize, usize> {
+        self.chs_transfer_one(0x0201, params, lba, buffer)
+    }
+
+    /// One CHS call transferring the sector at `lba`, `function` is AX: 0x0201 reads, 0x0301 writes. Returns 1 or the BIOS status code
+    unsafe fn chs_transfer_one(
+        &mut self,
+        function: usize,
+        params: &DiskParams,
+        lba: u64,
+        buffer: usize,
+    ) -> Result<usize, usize> {
+        let (cylinder, head, sector) = Self::lba_to_chs(params, lba).ok_or(0x04usize)?;
+        let (segment, offset) = ptr_to_seg_off(buffer);
+        let result = unsafe_call_bios_interrupt(
+            self.bios_idt,
+            0x13,
+            function,
+            offset as usize,
+            ((cylinder as usize & 0xFF) << 8) | ((cylinder as usize >> 2) & 0xC0) | sector as usize,
+            ((head as usize) << 8) | self.disk as usize,
+            0,
+            0,
+            segment as usize,
+            segment as usize,
+            0,
+            0,
+        ) as *const BiosInterruptResult;
+
+        if ((*result).eflags & eflags::CF) != 0 {
+            return Err(((*result).eax & 0xFFFF) >> 8);
+        }
+        Ok(1)
+    }
+
+    /// Writes the first `bytes_per_sector` bytes of `buffer` to the sector at `lba` (INT 13h AH=43h no verify, or AH=03h in CHS mode)
+    pub fn write_sector(&mut self, lba: u64, buffer: &Buffer) -> Result<(), DiskError> {
+        if read_only() {
+            return Err(DiskError::ReadOnlyMedia);
+        }
+        let params = self.get_params()?;
+        let bps = params.bytes_per_sector as usize;
+        if buffer.len() < bps {
+            return Err(DiskError::OutputBufferTooSmall);
+        }
+        if self.chs && Self::lba_to_chs(&params, lba).is_none() {
+            return Err(DiskError::LbaBeyondChsReach(lba));
+        }
+
         let (segment, offset) = ptr_to_seg_off(addr_of!(BUFF) as usize);
+
         unsafe {
+            let input_buf = seg_off_to_ptr(segment, offset) as *mut u8;
+            for (i, item) in buffer.iter().enumerate().take(bps) {
+                *input_buf.add(i) = item;
+            }
+
+            if self.chs {
+                if let Err(code) = self.chs_transfer_one(0x0301, &params, lba, input_buf as usize) {
+                    record_disk_error(lba, code as u16);
+                    record_write_failure(code);
+                    return Err(DiskError::WriteError(code, lba, 0));
+                }
+                return Ok(());
+            }
+
             let (dap_seg, dap_off) = ptr_to_seg_off(addr_of!(DAP) as usize);
             DAP = DiskAccessPacket {
                 size: 0x10,
@@ -302,7 +871,7 @@ impl ExtendedDisk {
             let result = unsafe_call_bios_interrupt(
                 self.bios_idt,
                 0x13,
-                0x4200,
+                0x4300,
                 0,
                 0,
                 self.disk as usize,
@@ -315,10 +884,27 @@ impl ExtendedDisk {
             ) as *const BiosInterruptResult;
 
             if ((*result).eflags & eflags::CF) != 0 {
-                return Err(DiskError::ReadError(((*result).eax & 0xFFFF) >> 8));
+                let code = ((*result).eax & 0xFFFF) >> 8;
+                record_disk_error(lba, code as u16);
+                record_write_failure(code);
+                return Err(DiskError::WriteError(code, lba, 0));
             }
+        }
+        Ok(())
+    }
 
-            let output_buf = seg_off_to_ptr(segment, offset) as *const u8;
+    /// # Safety
+    /// Passed buffer must be at least `bytes_per_sector` long
+    pub unsafe fn unsafe_read_sector_to_buffer(
+        &mut self,
+        lba: u64,
+        buffer: *mut u8,
+    ) -> Result<(), DiskError> {
+        let bps = self.get_params()?.bytes_per_sector as usize;
+        unsafe {
+            self.extended_read(lba, 1, addr_of!(BUFF) as usize, bps)?;
+
+            let output_buf = addr_of!(BUFF) as *const u8;
             for i in 0..bps {
                 *buffer.add(i) = *output_buf.add(i);
             }
@@ -326,6 +912,26 @@ impl ExtendedDisk {
         Ok(())
     }
 
+    /// Reads `count` sectors at `lba` into the real mode transfer buffer, and returns them. <br>
+    /// Normally a single BIOS call, short transfers are completed by [`Self::extended_read`]. <br>
+    /// The returned slice is overwritten by the next transfer. <br>
+    pub fn read_sectors(&mut self, lba: u64, count: usize) -> Result<&[u8], DiskError> {
+        let bps = self.get_params()?.bytes_per_sector as usize;
+        if bps == 0 {
+            return Err(DiskError::InvalidDiskParameters);
+        }
+        let len = count * bps;
+        if count == 0 || len > MAX_TRANSFER_SECTORS * 512 {
+            return Err(DiskError::OutputBufferTooSmall);
+        }
+
+        unsafe {
+            let buffer = addr_of!(TRANSFER_BUFF) as usize;
+            self.extended_read(lba, count, buffer, bps)?;
+            Ok(core::slice::from_raw_parts(buffer as *const u8, len))
+        }
+    }
+
     pub fn read_to_buffer(&mut self, lba: u64, buffer: &mut Buffer) -> Result<(), DiskError> {
         let bps = self.get_params()?.bytes_per_sector as usize;
         if bps == 0 {
@@ -345,3 +951,91 @@ impl ExtendedDisk {
         Ok(())
     }
 }
+
+/// The BIOS tick counter wraps at midnight
+pub const TICKS_PER_DAY: u32 = 0x1800B0;
+
+/// BIOS timer ticks since midnight (INT 1Ah, AH=00h), 18.2 per second
+pub fn bios_ticks(bios_idt: usize) -> u32 {
+    unsafe {
+        let result = unsafe_call_bios_interrupt(bios_idt, 0x1A, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0)
+            as *const BiosInterruptResult;
+        ((((*result).ecx & 0xFFFF) << 16) | ((*result).edx & 0xFFFF)) as u32
+    }
+}
+
+/// Ticks elapsed since `start`, across midnight
+pub fn ticks_since(bios_idt: usize, start: u32) -> u32 {
+    (bios_ticks(bios_idt) + TICKS_PER_DAY - start) % TICKS_PER_DAY
+}
+
+/// Zero flag, bit 6 of EFLAGS
+const EFLAGS_ZF: usize = 1 << 6;
+
+/// A keystroke read from the BIOS keyboard buffer
+#[derive(Clone, Copy)]
+pub struct Keystroke {
+    pub scan_code: u8,
+    /// 0 for keys without an ASCII code, like the arrows
+    pub ascii: u8,
+}
+
+/// Right and left Shift bits of the BIOS keyboard flags
+pub const KEYBOARD_FLAGS_SHIFT: u8 = 0b11;
+
+/// Returns the pending keystroke, if any, leaving it in the BIOS buffer (INT 16h, AH=01h)
+pub fn peek_keystroke(bios_idt: usize) -> Option<Keystroke> {
+    unsafe {
+        let result = unsafe_call_bios_interrupt(bios_idt, 0x16, 0x0100, 0, 0, 0, 0, 0, 0, 0, 0, 0)
+            as *const BiosInterruptResult;
+        if (*result).eflags & EFLAGS_ZF != 0 {
+            return None;
+        }
+        Some(Keystroke {
+            scan_code: ((*result).eax >> 8) as u8,
+            ascii: (*result).eax as u8,
+        })
+    }
+}
+
+/// Returns the pending keystroke, if any, and removes it from the BIOS buffer (INT 16h)
+pub fn poll_keystroke(bios_idt: usize) -> Option<Keystroke> {
+    peek_keystroke(bios_idt)?;
+    unsafe {
+        let result = unsafe_call_bios_interrupt(bios_idt, 0x16, 0x0000, 0, 0, 0, 0, 0, 0, 0, 0, 0)
+            as *const BiosInterruptResult;
+        Some(Keystroke {
+            scan_code: ((*result).eax >> 8) as u8,
+            ascii: (*result).eax as u8,
+        })
+    }
+}
+
+/// Returns the shift flags of the BIOS keyboard (INT 16h, AH=02h), see [`KEYBOARD_FLAGS_SHIFT`]
+pub fn keyboard_flags(bios_idt: usize) -> u8 {
+    unsafe {
+        let result = unsafe_call_bios_interrupt(bios_idt, 0x16, 0x0200, 0, 0, 0, 0, 0, 0, 0, 0, 0)
+            as *const BiosInterruptResult;
+        (*result).eax as u8
+    }
+}
+
+/// Waits `us` microseconds (INT 15h, AH=86h)
+pub fn wait_us(bios_idt: usize, us: u32) {
+    unsafe {
+        unsafe_call_bios_interrupt(
+            bios_idt,
+            0x15,
+            0x8600,
+            0,
+            (us >> 16) as usize,
+            (us & 0xFFFF) as usize,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        );
+    }
+}
@@ -0,0 +1,1101 @@
+use core::{ptr, slice};
+
+// The containers only need an allocator: the stage2 heap, or the host's in the unit tests
+#[cfg(not(test))]
+use crate::{
+    kpanic,
+    mem::{mem_alloc, mem_free, mem_realloc},
+};
+#[cfg(test)]
+use tests::{kpanic, mem_alloc, mem_free, mem_realloc};
+
+/// [`Vec::sort_by`] insertion sorts runs of this length before merging them
+const INSERTION_SORT_MAX_LEN: usize = 16;
+
+pub struct Vec<T>
+where
+    T: Sized,
+{
+    ptr: *mut T,
+    len: usize,
+    cap: usize,
+}
+
+impl<T> Default for Vec<T>
+where
+    T: Sized,
+{
+    fn default() -> Self {
+        Self::new(16)
+    }
+}
+
+impl<T> Vec<T>
+where
+    T: Sized,
+{
+    #[inline(always)]
+    pub fn get_element_size_bytes() -> usize {
+        let raw_size = size_of::<T>();
+        let alignment = align_of::<T>();
+        if raw_size.is_multiple_of(alignment) {
+            raw_size
+        } else {
+            raw_size + alignment - (raw_size % alignment)
+        }
+    }
+
+    pub fn new(capacity: usize) -> Self {
+        Self::new_tagged(capacity, b"vec")
+    }
+
+    /// Like [`Vec::new`], `tag` names the subsystem in the `heap-poison` log
+    pub fn new_tagged(capacity: usize, tag: &'static [u8]) -> Self {
+        Self::try_new_tagged(capacity, tag).unwrap_or_else(|| kpanic())
+    }
+
+    /// Like [`Vec::new`], but returns None instead of panicking when the allocation fails
+    pub fn try_new(capacity: usize) -> Option<Self> {
+        Self::try_new_tagged(capacity, b"vec")
+    }
+
+    pub fn try_new_tagged(capacity: usize, tag: &'static [u8]) -> Option<Self> {
+        if capacity == 0 {
+            kpanic();
+        }
+        Some(Self {
+            ptr: mem_alloc(capacity * Vec::<T>::get_element_size_bytes(), tag)?,
+            len: 0,
+            cap: capacity,
+        })
+    }
+
+    /// # Safety
+    /// Creates a null pointer
+    pub const unsafe fn unsafe_null() -> Self {
+        Self {
+            ptr: ptr::null_mut(),
+            len: 0,
+            cap: 0,
+        }
+    }
+
+    pub fn ensure_capacity(&mut self, capacity: usize) {
+        if self.cap < capacity {
+            unsafe {
+                self.ptr = mem_realloc(self.ptr, capacity * Vec::<T>::get_element_size_bytes())
+                    .unwrap_or_else(|_| kpanic());
+            }
+        }
+    }
+
+    pub fn grow(&mut self, capacity: usize) {
+        if self.cap >= capacity {
+            return;
+        }
+        while self.cap < capacity {
+            self.cap *= 2;
+        }
+        unsafe {
+            self.ptr = mem_realloc(self.ptr, self.cap * Vec::<T>::get_element_size_bytes())
+                .unwrap_or_else(|_| kpanic());
+        }
+    }
+
+    /// Like [`Vec::grow`], but returns false instead of panicking when the allocation fails
+    pub fn try_grow(&mut self, capacity: usize) -> bool {
+        if self.cap >= capacity {
+            return true;
+        }
+        let mut cap = self.cap.max(1);
+        while cap < capacity {
+            cap *= 2;
+        }
+        match unsafe { mem_realloc(self.ptr, cap * Vec::<T>::get_element_size_bytes()) } {
+            Ok(ptr) => {
+                self.ptr = ptr;
+                self.cap = cap;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    #[inline(always)]
+    fn get_ptr_for_idx(&self, idx: usize) -> *mut T {
+        ((self.ptr as usize) + idx * Vec::<T>::get_element_size_bytes()) as *mut T
+    }
+
+    pub fn push(&mut self, value: T) {
+        self.grow(self.len + 1);
+        unsafe {
+            // The slot is uninitialized, assigning would drop garbage
+            self.get_ptr_for_idx(self.len).write(value);
+        }
+        self.len += 1;
+    }
+
+    /// Like [`Vec::push`], but hands `value` back instead of panicking when growing fails
+    pub fn try_push(&mut self, value: T) -> Result<(), T> {
+        if !self.try_grow(self.len + 1) {
+            return Err(value);
+        }
+        unsafe {
+            self.get_ptr_for_idx(self.len).write(value);
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.len {
+            return None;
+        }
+        unsafe { Some(&*self.get_ptr_for_idx(index)) }
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        if index >= self.len {
+            return None;
+        }
+        unsafe { Some(&mut *self.get_ptr_for_idx(index)) }
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+
+        self.len -= 1;
+
+        unsafe {
+            let ptr = self.get_ptr_for_idx(self.len);
+            let value = ptr.read();
+            Some(value)
+        }
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        if self.is_empty() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    pub fn iter<'a>(&'a self) -> RefIterVec<'a, T> {
+        RefIterVec { vec: self, idx: 0 }
+    }
+
+    pub fn swap(&mut self, a: usize, b: usize) {
+        unsafe {
+            let ptr_a = self.get_ptr_for_idx(a);
+            let ptr_b = self.get_ptr_for_idx(b);
+            ptr::swap(ptr_a, ptr_b);
+        }
+    }
+
+    /// Sorts with `cmp` returning negative, zero or positive, kept for the older callers of [`Vec::sort_by`]
+    pub fn bubble_sort(&mut self, cmp: impl Fn(&T, &T) -> isize) {
+        self.sort_by(|a, b| cmp(a, b).cmp(&0));
+    }
+
+    /// # Safety
+    /// `index` must be below `len`
+    #[inline(always)]
+    unsafe fn at(&self, index: usize) -> &T {
+        &*self.get_ptr_for_idx(index)
+    }
+
+    /// Sorts in place, stable: equal elements keep their order. <br>
+    /// Insertion sort over short runs, then bottom up merges through a scratch buffer on the heap, nothing recurses on the tiny stage2 stack. <br>
+    pub fn sort_by(&mut self, cmp: impl Fn(&T, &T) -> core::cmp::Ordering) {
+        let len = self.len;
+        for start in (0..len).step_by(INSERTION_SORT_MAX_LEN) {
+            self.insertion_sort(start, (start + INSERTION_SORT_MAX_LEN).min(len), &cmp);
+        }
+        if len <= INSERTION_SORT_MAX_LEN {
+            return;
+        }
+        let scratch: *mut T = mem_alloc(len * Vec::<T>::get_element_size_bytes(), b"sort")
+            .unwrap_or_else(|| kpanic());
+        // Every pass moves all the elements from `src` to `dst`, merging neighbouring runs of `width`
+        let (mut src, mut dst) = (self.ptr, scratch);
+        let mut width = INSERTION_SORT_MAX_LEN;
+        while width < len {
+            for start in (0..len).step_by(2 * width) {
+                let middle = (start + width).min(len);
+                let end = (start + 2 * width).min(len);
+                unsafe { merge(src, dst, start, middle, end, &cmp) };
+            }
+            (src, dst) = (dst, src);
+            width *= 2;
+        }
+        if src != self.ptr {
+            unsafe { ptr::copy_nonoverlapping(src, self.ptr, len) };
+        }
+        mem_free(scratch);
+    }
+
+    fn insertion_sort(
+        &mut self,
+        start: usize,
+        end: usize,
+        cmp: &impl Fn(&T, &T) -> core::cmp::Ordering,
+    ) {
+        for i in start + 1..end {
+            let mut j = i;
+            while j > start && unsafe { cmp(self.at(j - 1), self.at(j)) }.is_gt() {
+                self.swap(j - 1, j);
+                j -= 1;
+            }
+        }
+    }
+
+    /// On a vector sorted consistently with `f`, `Ok(index)` of an element `f` returns `Equal` for, or `Err(index)` where one would be inserted
+    pub fn binary_search_by(&self, f: impl Fn(&T) -> core::cmp::Ordering) -> Result<usize, usize> {
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match f(unsafe { self.at(middle) }) {
+                core::cmp::Ordering::Less => low = middle + 1,
+                core::cmp::Ordering::Greater => high = middle,
+                core::cmp::Ordering::Equal => return Ok(middle),
+            }
+        }
+        Err(low)
+    }
+
+    pub fn insert(&mut self, index: usize, value: T) -> bool {
+        if index > self.len {
+            false
+        } else if index == self.len {
+            self.push(value);
+            true
+        } else {
+            self.grow(self.len + 1);
+
+            // Shift elements to the right
+            for i in (index..self.len).rev() {
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        self.get_ptr_for_idx(i),
+                        self.get_ptr_for_idx(i + 1),
+                        1,
+                    );
+                }
+            }
+
+            unsafe {
+                // The old value was moved one slot to the right
+                self.get_ptr_for_idx(index).write(value);
+            }
+            self.len += 1;
+
+            true
+        }
+    }
+
+    /// Removes the element at `index`, shifting the following ones to the left
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        if index >= self.len {
+            return None;
+        }
+        unsafe {
+            let value = self.get_ptr_for_idx(index).read();
+            for i in index + 1..self.len {
+                ptr::copy_nonoverlapping(self.get_ptr_for_idx(i), self.get_ptr_for_idx(i - 1), 1);
+            }
+            self.len -= 1;
+            Some(value)
+        }
+    }
+
+    /// Drops the elements from `len` on, nothing happens if the vector isn't longer
+    pub fn truncate(&mut self, len: usize) {
+        while self.len > len {
+            self.pop();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    /// Drops the elements `pred` rejects, the kept ones are moved down in order
+    pub fn retain(&mut self, pred: impl Fn(&T) -> bool) {
+        let mut kept = 0;
+        for i in 0..self.len {
+            unsafe {
+                let ptr = self.get_ptr_for_idx(i);
+                if !pred(&*ptr) {
+                    ptr::drop_in_place(ptr);
+                    continue;
+                }
+                if i != kept {
+                    ptr::copy_nonoverlapping(ptr, self.get_ptr_for_idx(kept), 1);
+                }
+            }
+            kept += 1;
+        }
+        self.len = kept;
+    }
+}
+
+/// Moves the sorted runs `src[start..middle]` and `src[middle..end]` to `dst[start..end]`, merged. <br>
+/// On ties the left run goes first, which keeps [`Vec::sort_by`] stable. <br>
+///
+/// # Safety
+/// Both buffers must hold at least `end` elements, the ones in `src` are moved out
+unsafe fn merge<T>(
+    src: *const T,
+    dst: *mut T,
+    start: usize,
+    middle: usize,
+    end: usize,
+    cmp: &impl Fn(&T, &T) -> core::cmp::Ordering,
+) {
+    let (mut left, mut right) = (start, middle);
+    for out in start..end {
+        let from_left =
+            right == end || (left < middle && !cmp(&*src.add(right), &*src.add(left)).is_lt());
+        let from = if from_left { &mut left } else { &mut right };
+        ptr::copy_nonoverlapping(src.add(*from), dst.add(out), 1);
+        *from += 1;
+    }
+}
+
+/// What a bounded [`SortedMap`] does when a new key is inserted while it is full
+#[derive(Clone, Copy)]
+pub enum EvictionPolicy<K, V> {
+    /// Nothing is evicted, the insertion fails
+    Reject,
+    /// The entry inserted, replaced or touched the longest ago is evicted
+    Oldest,
+    /// The entry with the lowest rank is evicted, ties go to the oldest
+    Callback(fn(&K, &V) -> u64),
+}
+
+struct SortedMapEntry<K, V> {
+    key: K,
+    value: V,
+    /// Value of the map's sequence counter when the entry was last inserted, replaced or touched
+    sequence: u64,
+}
+
+/// A map kept sorted by key in a [`Vec`], looked up by binary search. <br>
+/// Meant for the small tables of the bootloader: insertion and removal shift the entries after the key. <br>
+/// A bounded map evicts according to its [`EvictionPolicy`] when full, [`SortedMap::touch`] lets caches layer LRU on top of [`EvictionPolicy::Oldest`]. <br>
+pub struct SortedMap<K: Ord, V> {
+    entries: Vec<SortedMapEntry<K, V>>,
+    max_len: Option<usize>,
+    policy: EvictionPolicy<K, V>,
+    sequence: u64,
+}
+
+impl<K: Ord, V> SortedMap<K, V> {
+    /// An unbounded map, with room for `capacity` entries before growing
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Vec::new(capacity.max(1)),
+            max_len: None,
+            policy: EvictionPolicy::Reject,
+            sequence: 0,
+        }
+    }
+
+    /// A map holding at most `max_len` entries, evicting according to `policy` past that
+    pub fn bounded(max_len: usize, policy: EvictionPolicy<K, V>) -> Self {
+        Self {
+            entries: Vec::new(max_len.max(1)),
+            max_len: Some(max_len),
+            policy,
+            sequence: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// `Ok(index)` of `key`, or `Err(index)` where it would be inserted
+    fn search(&self, key: &K) -> Result<usize, usize> {
+        self.entries.binary_search_by(|entry| entry.key.cmp(key))
+    }
+
+    fn next_sequence(&mut self) -> u64 {
+        self.sequence += 1;
+        self.sequence
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let index = self.search(key).ok()?;
+        self.entries.get(index).map(|entry| &entry.value)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let index = self.search(key).ok()?;
+        self.entries.get_mut(index).map(|entry| &mut entry.value)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.search(key).is_ok()
+    }
+
+    /// Marks `key` as just used for [`EvictionPolicy::Oldest`], returns false when it isn't in the map
+    pub fn touch(&mut self, key: &K) -> bool {
+        let Ok(index) = self.search(key) else {
+            return false;
+        };
+        let sequence = self.next_sequence();
+        match self.entries.get_mut(index) {
+            Some(entry) => {
+                entry.sequence = sequence;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let index = self.search(key).ok()?;
+        self.entries.remove(index).map(|entry| entry.value)
+    }
+
+    /// Entries in key order
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|entry| (&entry.key, &entry.value))
+    }
+
+    /// Entries with a key up to `key`, nearest first
+    pub fn iter_at_or_below(&self, key: &K) -> impl Iterator<Item = (&K, &V)> {
+        let end = match self.search(key) {
+            Ok(index) => index + 1,
+            Err(index) => index,
+        };
+        (0..end)
+            .rev()
+            .filter_map(|index| self.entries.get(index))
+            .map(|entry| (&entry.key, &entry.value))
+    }
+
+    /// Index of the entry the policy evicts, `None` for [`EvictionPolicy::Reject`]
+    fn eviction_candidate(&self) -> Option<usize> {
+        let rank = |entry: &SortedMapEntry<K, V>| match self.policy {
+            EvictionPolicy::Reject => None,
+            EvictionPolicy::Oldest => Some((0, entry.sequence)),
+            EvictionPolicy::Callback(rank) => {
+                Some((rank(&entry.key, &entry.value), entry.sequence))
+            }
+        };
+        let mut best: Option<(usize, (u64, u64))> = None;
+        for (index, entry) in self.entries.iter().enumerate() {
+            let rank = rank(entry)?;
+            if best.is_none_or(|(_, best)| rank < best) {
+                best = Some((index, rank));
+            }
+        }
+        best.map(|(index, _)| index)
+    }
+
+    /// Inserts or replaces `key`. Returns the replaced value, or `Err` with the entry when the map is full and the policy rejects it or the allocation failed. <br>
+    /// An evicted entry is dropped, the map is left unchanged when the insertion fails. <br>
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<Option<V>, (K, V)> {
+        let sequence = self.next_sequence();
+        match self.search(&key) {
+            Ok(index) => {
+                let Some(entry) = self.entries.get_mut(index) else {
+                    return Err((key, value));
+                };
+                entry.sequence = sequence;
+                Ok(Some(core::mem::replace(&mut entry.value, value)))
+            }
+            Err(mut index) => {
+                let evicted = if self.max_len.is_some_and(|max| self.entries.len() >= max) {
+                    let Some(evicted) = self.eviction_candidate() else {
+                        return Err((key, value));
+                    };
+                    Some(evicted)
+                } else {
+                    None
+                };
+                // Grown before evicting, so that a failed allocation loses neither entry
+                let len = self.entries.len() + usize::from(evicted.is_none());
+                if !self.entries.try_grow(len) {
+                    return Err((key, value));
+                }
+                if let Some(evicted) = evicted {
+                    self.entries.remove(evicted);
+                    if evicted < index {
+                        index -= 1;
+                    }
+                }
+                self.entries.insert(
+                    index,
+                    SortedMapEntry {
+                        key,
+                        value,
+                        sequence,
+                    },
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Like [`SortedMap::try_insert`], but an insertion that can't be done is silently dropped
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.try_insert(key, value).ok().flatten()
+    }
+}
+
+impl<T> Clone for Vec<T>
+where
+    T: Clone,
+{
+    fn clone(&self) -> Self {
+        let mut other = Vec::new(self.len);
+        for i in 0..self.len {
+            other.push(self.get(i).unwrap_or_else(|| kpanic()).clone());
+        }
+        other
+    }
+}
+
+impl<T> Drop for Vec<T>
+where
+    T: Sized,
+{
+    fn drop(&mut self) {
+        if self.ptr.is_null() {
+            return;
+        }
+        self.clear();
+        mem_free(self.ptr);
+    }
+}
+
+pub struct RefIterVec<'a, T>
+where
+    T: Sized,
+{
+    vec: &'a Vec<T>,
+    idx: usize,
+}
+
+impl<'a, T> Iterator for RefIterVec<'a, T>
+where
+    T: Sized,
+{
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let res = self.vec.get(self.idx)?;
+        self.idx += 1;
+        Some(res)
+    }
+}
+
+/// Consuming iterator, owns the elements from `idx` to `end`. <br>
+/// The inner vector's length is 0 so it only frees the memory, the unconsumed elements are dropped by [`IterVec`] itself. <br>
+pub struct IterVec<T>
+where
+    T: Sized,
+{
+    vec: Vec<T>,
+    idx: usize,
+    end: usize,
+}
+
+impl<T> Iterator for IterVec<T>
+where
+    T: Sized,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.idx >= self.end {
+            None
+        } else {
+            self.idx += 1;
+            Some(unsafe { self.vec.get_ptr_for_idx(self.idx - 1).read() })
+        }
+    }
+}
+
+impl<T> Drop for IterVec<T>
+where
+    T: Sized,
+{
+    fn drop(&mut self) {
+        while self.idx < self.end {
+            self.idx += 1;
+            unsafe { ptr::drop_in_place(self.vec.get_ptr_for_idx(self.idx - 1)) };
+        }
+    }
+}
+
+impl<T> IntoIterator for Vec<T>
+where
+    T: Sized,
+{
+    type Item = T;
+    type IntoIter = IterVec<T>;
+
+    fn into_iter(mut self) -> Self::IntoIter {
+        let end = self.len;
+        self.len = 0;
+        IterVec {
+            vec: self,
+            idx: 0,
+            end,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        alloc::{self, Layout},
+        cell::Cell,
+    };
+
+    /// Host blocks keep their size in front of the data, for `mem_realloc` and `mem_free`
+    const HEADER_SIZE: usize = 16;
+
+    thread_local! {
+        /// Blocks handed out and not freed yet
+        static LIVE_BLOCKS: Cell<usize> = const { Cell::new(0) };
+        /// Allocations that still succeed, `None` for all of them
+        static ALLOCATIONS_LEFT: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    fn block_layout(size: usize) -> Layout {
+        Layout::from_size_align(size + HEADER_SIZE, HEADER_SIZE).unwrap()
+    }
+
+    fn allocation_allowed() -> bool {
+        ALLOCATIONS_LEFT.with(|left| match left.get() {
+            Some(0) => false,
+            Some(n) => {
+                left.set(Some(n - 1));
+                true
+            }
+            None => true,
+        })
+    }
+
+    pub(super) fn mem_alloc<T>(size: usize, _tag: &'static [u8]) -> Option<*mut T> {
+        if !allocation_allowed() {
+            return None;
+        }
+        unsafe {
+            let block = alloc::alloc(block_layout(size));
+            assert!(!block.is_null());
+            (block as *mut usize).write(size);
+            LIVE_BLOCKS.with(|live| live.set(live.get() + 1));
+            Some(block.add(HEADER_SIZE) as *mut T)
+        }
+    }
+
+    pub(super) fn mem_free<T>(ptr: *mut T) {
+        if ptr.is_null() {
+            return;
+        }
+        unsafe {
+            let block = (ptr as *mut u8).sub(HEADER_SIZE);
+            alloc::dealloc(block, block_layout((block as *const usize).read()));
+        }
+        LIVE_BLOCKS.with(|live| live.set(live.get() - 1));
+    }
+
+    pub(super) unsafe fn mem_realloc<T>(ptr: *mut T, size: usize) -> Result<*mut T, *mut T> {
+        if !allocation_allowed() {
+            return Err(ptr);
+        }
+        let block = (ptr as *mut u8).sub(HEADER_SIZE);
+        let block = alloc::realloc(
+            block,
+            block_layout((block as *const usize).read()),
+            size + HEADER_SIZE,
+        );
+        assert!(!block.is_null());
+        (block as *mut usize).write(size);
+        Ok(block.add(HEADER_SIZE) as *mut T)
+    }
+
+    pub(super) fn kpanic() -> ! {
+        panic!("kpanic");
+    }
+
+    fn live_blocks() -> usize {
+        LIVE_BLOCKS.with(Cell::get)
+    }
+
+    /// Counts its drops in a shared cell
+    #[derive(Debug)]
+    struct Counted<'a> {
+        value: u32,
+        drops: &'a Cell<usize>,
+    }
+
+    impl Drop for Counted<'_> {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    fn counted_vec(len: u32, drops: &Cell<usize>) -> Vec<Counted<'_>> {
+        let mut vec = Vec::new(4);
+        for value in 0..len {
+            vec.push(Counted { value, drops });
+        }
+        vec
+    }
+
+    fn values(vec: &Vec<Counted>) -> std::vec::Vec<u32> {
+        vec.iter().map(|counted| counted.value).collect()
+    }
+
+    fn vec_of<T: Clone>(values: &[T]) -> Vec<T> {
+        let mut vec = Vec::new(values.len().max(1));
+        for value in values {
+            vec.push(value.clone());
+        }
+        vec
+    }
+
+    /// Deterministic pseudo random keys, a small LCG
+    fn random_keys(len: usize, modulus: u32) -> std::vec::Vec<u32> {
+        let mut state = 0x1234_5678u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 8) % modulus
+            })
+            .collect()
+    }
+
+    /// Inputs that hurt naive quicksorts or break unstable sorts
+    fn adversarial_patterns(len: usize) -> std::vec::Vec<(&'static str, std::vec::Vec<u32>)> {
+        let len32 = len as u32;
+        std::vec![
+            ("sorted", (0..len32).collect()),
+            ("reversed", (0..len32).rev().collect()),
+            ("all equal", std::vec![7; len]),
+            ("two values", (0..len32).map(|i| i % 2).collect()),
+            (
+                "organ pipe",
+                (0..len32).map(|i| i.min(len32 - 1 - i)).collect(),
+            ),
+            ("sawtooth", (0..len32).map(|i| i % 17).collect()),
+            (
+                "sorted with a tail",
+                (0..len32)
+                    .map(|i| if i + 4 >= len32 { len32 - i } else { i })
+                    .collect(),
+            ),
+            ("few distinct", random_keys(len, 4)),
+            ("random", random_keys(len, u32::MAX)),
+        ]
+    }
+
+    const SORT_LENGTHS: [usize; 9] = [1, 2, 15, 16, 17, 33, 100, 257, 1000];
+
+    #[test]
+    fn sort_by_is_stable_on_adversarial_patterns() {
+        for len in SORT_LENGTHS {
+            for (name, keys) in adversarial_patterns(len) {
+                // The position rides along to check equal keys keep their order
+                let pairs: std::vec::Vec<(u32, usize)> = keys.iter().copied().zip(0..).collect();
+                let mut vec = vec_of(&pairs);
+                vec.sort_by(|a, b| a.0.cmp(&b.0));
+                let mut expected = pairs.clone();
+                expected.sort_by_key(|pair| pair.0);
+                assert_eq!(
+                    vec.as_slice(),
+                    expected.as_slice(),
+                    "{name}, {len} elements"
+                );
+            }
+        }
+        assert_eq!(live_blocks(), 0);
+    }
+
+    #[test]
+    fn sort_by_stays_n_log_n_on_adversarial_patterns() {
+        let len = 4096;
+        // Insertion sort within the runs, then one comparison per element and merge pass
+        let bound =
+            len * (INSERTION_SORT_MAX_LEN + (len / INSERTION_SORT_MAX_LEN).ilog2() as usize);
+        for (name, keys) in adversarial_patterns(len) {
+            let mut vec = vec_of(&keys);
+            let comparisons = Cell::new(0);
+            vec.sort_by(|a, b| {
+                comparisons.set(comparisons.get() + 1);
+                a.cmp(b)
+            });
+            assert!(vec.as_slice().is_sorted(), "{name}");
+            assert!(comparisons.get() <= bound, "{name}: {}", comparisons.get());
+        }
+    }
+
+    #[test]
+    fn sort_by_sorts_empty_vectors() {
+        let mut vec: Vec<u32> = Vec::new(4);
+        vec.sort_by(|a, b| a.cmp(b));
+        assert!(vec.is_empty());
+    }
+
+    #[test]
+    fn truncate_drops_the_tail_once() {
+        let drops = Cell::new(0);
+        let mut vec = counted_vec(10, &drops);
+        vec.truncate(4);
+        assert_eq!(drops.get(), 6);
+        assert_eq!(values(&vec), [0, 1, 2, 3]);
+        vec.truncate(8);
+        assert_eq!(drops.get(), 6);
+        drop(vec);
+        assert_eq!(drops.get(), 10);
+        assert_eq!(live_blocks(), 0);
+    }
+
+    #[test]
+    fn clear_drops_everything_and_keeps_the_vector_usable() {
+        let drops = Cell::new(0);
+        let mut vec = counted_vec(5, &drops);
+        vec.clear();
+        assert_eq!(drops.get(), 5);
+        assert!(vec.is_empty());
+        vec.push(Counted {
+            value: 42,
+            drops: &drops,
+        });
+        assert_eq!(values(&vec), [42]);
+        drop(vec);
+        assert_eq!(drops.get(), 6);
+        assert_eq!(live_blocks(), 0);
+    }
+
+    #[test]
+    fn retain_drops_the_rejected_once_and_keeps_the_order() {
+        let drops = Cell::new(0);
+        let mut vec = counted_vec(10, &drops);
+        vec.retain(|counted| counted.value % 3 == 0);
+        assert_eq!(drops.get(), 6);
+        assert_eq!(values(&vec), [0, 3, 6, 9]);
+        drop(vec);
+        assert_eq!(drops.get(), 10);
+        assert_eq!(live_blocks(), 0);
+    }
+
+    #[test]
+    fn retain_keeping_all_or_nothing() {
+        let drops = Cell::new(0);
+        let mut vec = counted_vec(6, &drops);
+        vec.retain(|_| true);
+        assert_eq!(drops.get(), 0);
+        assert_eq!(values(&vec), [0, 1, 2, 3, 4, 5]);
+        vec.retain(|_| false);
+        assert_eq!(drops.get(), 6);
+        assert!(vec.is_empty());
+        drop(vec);
+        assert_eq!(drops.get(), 6);
+        assert_eq!(live_blocks(), 0);
+    }
+
+    #[test]
+    fn into_iter_moves_every_element_out_once() {
+        let drops = Cell::new(0);
+        let vec = counted_vec(8, &drops);
+        let mut seen = std::vec::Vec::new();
+        for counted in vec {
+            seen.push(counted.value);
+            assert_eq!(drops.get(), seen.len() - 1);
+        }
+        assert_eq!(seen, [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(drops.get(), 8);
+        assert_eq!(live_blocks(), 0);
+    }
+
+    #[test]
+    fn into_iter_drops_the_rest_after_an_early_break() {
+        let drops = Cell::new(0);
+        let vec = counted_vec(8, &drops);
+        for counted in vec {
+            if counted.value == 2 {
+                break;
+            }
+        }
+        // 0 to 2 were dropped by the loop body, 3 to 7 by the iterator
+        assert_eq!(drops.get(), 8);
+        assert_eq!(live_blocks(), 0);
+    }
+
+    #[test]
+    fn into_iter_dropped_unused_drops_everything() {
+        let drops = Cell::new(0);
+        let iter = counted_vec(5, &drops).into_iter();
+        assert_eq!(drops.get(), 0);
+        drop(iter);
+        assert_eq!(drops.get(), 5);
+        assert_eq!(live_blocks(), 0);
+    }
+
+    #[test]
+    fn into_iter_elements_taken_outlive_the_iterator() {
+        let drops = Cell::new(0);
+        let mut iter = counted_vec(4, &drops).into_iter();
+        let first = iter.next().unwrap();
+        let second = iter.next().unwrap();
+        drop(iter);
+        assert_eq!(drops.get(), 2);
+        assert_eq!((first.value, second.value), (0, 1));
+        drop((first, second));
+        assert_eq!(drops.get(), 4);
+        assert_eq!(live_blocks(), 0);
+    }
+
+    /// Runs `f` with only `count` more allocations succeeding
+    fn with_allocations_left<R>(count: usize, f: impl FnOnce() -> R) -> R {
+        ALLOCATIONS_LEFT.with(|left| left.set(Some(count)));
+        let result = f();
+        ALLOCATIONS_LEFT.with(|left| left.set(None));
+        result
+    }
+
+    fn keys<V>(map: &SortedMap<u32, V>) -> std::vec::Vec<u32> {
+        map.iter().map(|(key, _)| *key).collect()
+    }
+
+    #[test]
+    fn sorted_map_keeps_keys_sorted_and_replaces() {
+        let mut map = SortedMap::new(2);
+        for key in random_keys(50, 20) {
+            map.insert(key, key * 10);
+        }
+        let mut expected = random_keys(50, 20);
+        expected.sort();
+        expected.dedup();
+        assert_eq!(keys(&map), expected);
+        assert_eq!(
+            map.try_insert(expected[0], 7).ok(),
+            Some(Some(expected[0] * 10))
+        );
+        assert_eq!(map.get(&expected[0]), Some(&7));
+        assert_eq!(map.len(), expected.len());
+    }
+
+    #[test]
+    fn sorted_map_oldest_evicts_the_least_recently_used() {
+        let mut map = SortedMap::bounded(3, EvictionPolicy::Oldest);
+        for key in [5, 1, 3] {
+            map.insert(key, key);
+        }
+        assert!(map.touch(&5));
+        map.insert(4, 4);
+        assert_eq!(keys(&map), [3, 4, 5]);
+        map.insert(3, 30);
+        map.insert(2, 2);
+        assert_eq!(keys(&map), [2, 3, 4]);
+    }
+
+    #[test]
+    fn sorted_map_reject_hands_the_entry_back() {
+        let mut map = SortedMap::bounded(2, EvictionPolicy::Reject);
+        map.insert(1, 'a');
+        map.insert(2, 'b');
+        assert_eq!(map.try_insert(3, 'c').err(), Some((3, 'c')));
+        assert_eq!(map.try_insert(2, 'B').ok(), Some(Some('b')));
+        assert_eq!(keys(&map), [1, 2]);
+    }
+
+    #[test]
+    fn sorted_map_callback_evicts_the_lowest_rank() {
+        let mut map = SortedMap::bounded(3, EvictionPolicy::Callback(|_, value: &u64| *value));
+        map.insert(1, 30);
+        map.insert(2, 10);
+        map.insert(3, 20);
+        map.insert(4, 40);
+        assert_eq!(keys(&map), [1, 3, 4]);
+    }
+
+    #[test]
+    fn sorted_map_failed_growth_loses_nothing() {
+        let drops = Cell::new(0);
+        let mut map = SortedMap::new(2);
+        for value in 0..2 {
+            map.insert(
+                value,
+                Counted {
+                    value,
+                    drops: &drops,
+                },
+            );
+        }
+        let rejected = with_allocations_left(0, || {
+            map.try_insert(
+                9,
+                Counted {
+                    value: 9,
+                    drops: &drops,
+                },
+            )
+        });
+        let (key, counted) = rejected.err().unwrap();
+        assert_eq!((key, counted.value), (9, 9));
+        drop(counted);
+        assert_eq!(drops.get(), 1);
+        assert_eq!(keys(&map), [0, 1]);
+        drop(map);
+        assert_eq!(drops.get(), 3);
+        assert_eq!(live_blocks(), 0);
+    }
+
+    #[test]
+    fn sorted_map_eviction_needs_no_allocation() {
+        let drops = Cell::new(0);
+        let mut map = SortedMap::bounded(2, EvictionPolicy::Oldest);
+        for value in 0..2 {
+            map.insert(
+                value,
+                Counted {
+                    value,
+                    drops: &drops,
+                },
+            );
+        }
+        let inserted = with_allocations_left(0, || {
+            map.try_insert(
+                5,
+                Counted {
+                    value: 5,
+                    drops: &drops,
+                },
+            )
+        });
+        assert!(matches!(inserted, Ok(None)));
+        assert_eq!(drops.get(), 1);
+        assert_eq!(keys(&map), [1, 5]);
+        drop(map);
+        assert_eq!(drops.get(), 3);
+        assert_eq!(live_blocks(), 0);
+    }
+}
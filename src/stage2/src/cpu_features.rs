@@ -0,0 +1,101 @@
+use core::arch::{asm, x86::__cpuid};
+
+use crate::{
+    e9::write_string,
+    obsiboot::{BOOT_FLAG_GLOBAL_PAGES, BOOT_FLAG_PAT, BOOT_FLAG_WRITE_PROTECT},
+    printf,
+};
+
+/// Ring 0 writes to read-only pages fault
+const CR0_WP: u32 = 1 << 16;
+const CR4_PGE: u32 = 1 << 7;
+/// CPUID leaf 1 EDX bits
+const CPUID_PGE: u32 = 1 << 13;
+const CPUID_PAT: u32 = 1 << 16;
+const IA32_PAT: u32 = 0x277;
+
+/// Memory types of the PAT entries
+pub const PAT_UNCACHEABLE: u8 = 0x00;
+pub const PAT_WRITE_COMBINING: u8 = 0x01;
+pub const PAT_WRITE_THROUGH: u8 = 0x04;
+pub const PAT_WRITE_BACK: u8 = 0x06;
+pub const PAT_UNCACHED: u8 = 0x07;
+
+/// Entries 0 to 3 keep their reset values, so PWT and PCD alone mean what they do without a PAT. <br>
+/// Entry 5, selected by the PAT and PWT bits of an entry, is write-combining. <br>
+pub const PAT_LAYOUT: [u8; 8] = [
+    PAT_WRITE_BACK,
+    PAT_WRITE_THROUGH,
+    PAT_UNCACHED,
+    PAT_UNCACHEABLE,
+    PAT_WRITE_BACK,
+    PAT_WRITE_COMBINING,
+    PAT_UNCACHED,
+    PAT_UNCACHEABLE,
+];
+
+unsafe fn enable_write_protect() -> bool {
+    // Every CPU since the 486 has CR0.WP, there's no CPUID bit for it
+    let cr0: u32;
+    asm!("mov {}, cr0", out(reg) cr0);
+    asm!("mov cr0, {}", in(reg) cr0 | CR0_WP);
+    true
+}
+
+unsafe fn enable_global_pages() -> bool {
+    if __cpuid(1).edx & CPUID_PGE == 0 {
+        return false;
+    }
+    let cr4: u32;
+    asm!("mov {}, cr4", out(reg) cr4);
+    asm!("mov cr4, {}", in(reg) cr4 | CR4_PGE);
+    true
+}
+
+unsafe fn program_pat() -> bool {
+    if __cpuid(1).edx & CPUID_PAT == 0 {
+        return false;
+    }
+    let value = u64::from_le_bytes(PAT_LAYOUT);
+    asm!(
+        "wrmsr",
+        in("ecx") IA32_PAT,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+    );
+    true
+}
+
+/// Enables CR0.WP and CR4.PGE and programs the PAT with [`PAT_LAYOUT`], each one only when the CPU has it. <br>
+/// Runs right before `enable_paging_and_jump64`, which sets CR4.PAE and EFER.LME itself and keeps the other CR0 and CR4 bits. <br>
+/// Returns the `BOOT_FLAG_*` bits of what was enabled. <br>
+pub fn enable_paging_features() -> u32 {
+    let mut flags = 0;
+    unsafe {
+        if enable_write_protect() {
+            flags |= BOOT_FLAG_WRITE_PROTECT;
+        }
+        if enable_global_pages() {
+            flags |= BOOT_FLAG_GLOBAL_PAGES;
+        }
+        if program_pat() {
+            flags |= BOOT_FLAG_PAT;
+        }
+    }
+
+    printf!(b"Paging features:");
+    let features: [(u32, &[u8]); 3] = [
+        (BOOT_FLAG_WRITE_PROTECT, b"WP"),
+        (BOOT_FLAG_GLOBAL_PAGES, b"PGE"),
+        (BOOT_FLAG_PAT, b"PAT"),
+    ];
+    for (flag, name) in features {
+        printf!(b" ");
+        write_string(name);
+        if flags & flag == 0 {
+            printf!(b" (unsupported)");
+        }
+    }
+    printf!(b"\r\n");
+    flags
+}
@@ -0,0 +1,292 @@
+use core::cell::SyncUnsafeCell;
+
+use crate::{
+    bios::int13_status_string,
+    crc32::crc32,
+    e9::{write_string, write_u32_decimal},
+    fs::{Ext2FileSystem, Ext2FileType},
+    lang::{decimal, hex, render, Text},
+    media::writes_allowed,
+    mem::Buffer,
+    printf,
+    video::Video,
+};
+
+/// Sectors per LBA bucket (64MiB of 512-byte sectors), errors are only located to their bucket
+pub const LBA_BUCKET_SECTORS: u64 = 64 * 1024 * 1024 / 512;
+/// Distinct (bucket, error) pairs tracked, the least frequent one is replaced when full
+pub const HEALTH_ENTRIES: usize = 8;
+/// A merged record halves its counts every this many boots, so old errors age out
+pub const DECAY_INTERVAL_BOOTS: u32 = 4;
+/// Default of `disk_health_notice=`: errors of one kind in one bucket needed for the notice
+pub const DEFAULT_NOTICE_THRESHOLD: u32 = 8;
+/// Error code recorded for reads the BIOS reported successful but that transferred fewer sectors, outside the INT 13h status range
+pub const SHORT_READ_CODE: u16 = 0x100;
+/// Default of `disk_health_file=`
+pub const DEFAULT_HEALTH_PATH: &[u8] = b"/obsiboot-health.bin";
+
+const RECORD_MAGIC: [u8; 4] = *b"OBDH";
+/// Bucket, code and count, little endian
+const ENCODED_ENTRY_SIZE: usize = 10;
+/// Magic, boots, the entries and a CRC32 of everything before it
+pub const ENCODED_RECORD_SIZE: usize = 4 + 4 + HEALTH_ENTRIES * ENCODED_ENTRY_SIZE + 4;
+
+#[derive(Clone, Copy)]
+pub struct HealthEntry {
+    pub bucket: u32,
+    /// INT 13h status code, or [`SHORT_READ_CODE`]
+    pub code: u16,
+    pub count: u32,
+}
+
+impl HealthEntry {
+    pub fn description(&self) -> &'static [u8] {
+        match self.code {
+            SHORT_READ_CODE => b"short read",
+            code => int13_status_string(code as usize),
+        }
+    }
+}
+
+/// Disk errors aggregated by LBA bucket and error code, for one boot or merged across boots
+#[derive(Clone, Copy)]
+pub struct DiskHealthRecord {
+    /// Boots merged into this record, 1 for a single boot
+    pub boots: u32,
+    entries: [Option<HealthEntry>; HEALTH_ENTRIES],
+}
+
+impl DiskHealthRecord {
+    pub const fn empty() -> Self {
+        Self {
+            boots: 1,
+            entries: [None; HEALTH_ENTRIES],
+        }
+    }
+
+    /// No boot merged yet, what a record file starts as
+    const fn fresh() -> Self {
+        Self {
+            boots: 0,
+            entries: [None; HEALTH_ENTRIES],
+        }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &HealthEntry> {
+        self.entries.iter().flatten()
+    }
+
+    fn add(&mut self, bucket: u32, code: u16, count: u32) {
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .flatten()
+            .find(|entry| entry.bucket == bucket && entry.code == code)
+        {
+            entry.count = entry.count.saturating_add(count);
+            return;
+        }
+        let entry = HealthEntry {
+            bucket,
+            code,
+            count,
+        };
+        if let Some(slot) = self.entries.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(entry);
+            return;
+        }
+        let least = self
+            .entries
+            .iter_mut()
+            .flatten()
+            .min_by_key(|entry| entry.count);
+        if let Some(least) = least {
+            if least.count < count {
+                *least = entry;
+            }
+        }
+    }
+
+    pub fn record(&mut self, lba: u64, code: u16) {
+        self.add((lba / LBA_BUCKET_SECTORS) as u32, code, 1);
+    }
+
+    /// Adds the errors of one boot, halving every count each [`DECAY_INTERVAL_BOOTS`] boots
+    pub fn merge_boot(&mut self, boot: &DiskHealthRecord) {
+        for entry in boot.entries() {
+            self.add(entry.bucket, entry.code, entry.count);
+        }
+        self.boots = self.boots.saturating_add(1);
+        if self.boots.is_multiple_of(DECAY_INTERVAL_BOOTS) {
+            for slot in self.entries.iter_mut() {
+                if let Some(entry) = slot {
+                    entry.count /= 2;
+                    if entry.count == 0 {
+                        *slot = None;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Layout of the record file, unused entries have a zero count
+    fn encode(&self) -> [u8; ENCODED_RECORD_SIZE] {
+        let mut data = [0; ENCODED_RECORD_SIZE];
+        data[..4].copy_from_slice(&RECORD_MAGIC);
+        data[4..8].copy_from_slice(&self.boots.to_le_bytes());
+        let (slots, _) = data[8..ENCODED_RECORD_SIZE - 4].as_chunks_mut::<ENCODED_ENTRY_SIZE>();
+        for (slot, entry) in slots.iter_mut().zip(self.entries()) {
+            slot[..4].copy_from_slice(&entry.bucket.to_le_bytes());
+            slot[4..6].copy_from_slice(&entry.code.to_le_bytes());
+            slot[6..].copy_from_slice(&entry.count.to_le_bytes());
+        }
+        let crc = crc32(&data[..ENCODED_RECORD_SIZE - 4]);
+        data[ENCODED_RECORD_SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+        data
+    }
+
+    /// Reverse of [`DiskHealthRecord::encode`], `None` when the magic or the CRC doesn't match
+    fn decode(data: &[u8]) -> Option<Self> {
+        let data = data.get(..ENCODED_RECORD_SIZE)?;
+        let u32_at = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        if data[..4] != RECORD_MAGIC
+            || crc32(&data[..ENCODED_RECORD_SIZE - 4]) != u32_at(&data[ENCODED_RECORD_SIZE - 4..])
+        {
+            return None;
+        }
+        let mut record = Self::fresh();
+        record.boots = u32_at(&data[4..]);
+        let (slots, _) = data[8..ENCODED_RECORD_SIZE - 4].as_chunks::<ENCODED_ENTRY_SIZE>();
+        for (entry, slot) in record.entries.iter_mut().zip(slots) {
+            let count = u32_at(&slot[6..]);
+            if count != 0 {
+                *entry = Some(HealthEntry {
+                    bucket: u32_at(slot),
+                    code: u16::from_le_bytes([slot[4], slot[5]]),
+                    count,
+                });
+            }
+        }
+        Some(record)
+    }
+
+    /// The most frequent error, if it reaches `threshold`
+    pub fn over_threshold(&self, threshold: u32) -> Option<HealthEntry> {
+        self.entries()
+            .max_by_key(|entry| entry.count)
+            .filter(|entry| threshold != 0 && entry.count >= threshold)
+            .copied()
+    }
+}
+
+/// Errors seen during this boot
+static SESSION: SyncUnsafeCell<DiskHealthRecord> = SyncUnsafeCell::new(DiskHealthRecord::empty());
+
+/// Records a disk error, call it wherever a BIOS disk call fails or misbehaves
+pub fn record_disk_error(lba: u64, code: u16) {
+    unsafe { (*SESSION.get()).record(lba, code) };
+}
+
+pub fn session_record() -> DiskHealthRecord {
+    unsafe { *SESSION.get() }
+}
+
+/// Record of the previous boots read by [`load_disk_health`], `None` while tracking only this boot
+static PREVIOUS_BOOTS: SyncUnsafeCell<Option<DiskHealthRecord>> = SyncUnsafeCell::new(None);
+
+/// The previous boots with this one merged in, or this boot alone without a record file
+pub fn merged_record() -> DiskHealthRecord {
+    let session = session_record();
+    match unsafe { *PREVIOUS_BOOTS.get() } {
+        Some(mut merged) => {
+            merged.merge_boot(&session);
+            merged
+        }
+        None => session,
+    }
+}
+
+/// Reads the record of the previous boots from the pre-created file at `path`. <br>
+/// A missing file keeps the tracking to this boot, a file not holding a record starts a fresh one. <br>
+pub fn load_disk_health(ext2: &mut Ext2FileSystem, path: &[u8]) {
+    let session_only = |reason: &[u8]| {
+        printf!(b"Disk health: ");
+        write_string(path);
+        printf!(b" ");
+        write_string(reason);
+        printf!(b", tracking this boot only\r\n");
+    };
+    let Ok(Ext2FileType::File(mut file)) = ext2.open_path(path) else {
+        session_only(b"not found or not a file");
+        return;
+    };
+    if file.get_size() < ENCODED_RECORD_SIZE {
+        session_only(b"too small");
+        return;
+    }
+    let Some(mut data) = Buffer::new_tagged(ENCODED_RECORD_SIZE, b"health") else {
+        session_only(b"can't be buffered");
+        return;
+    };
+    if !matches!(
+        file.read(&mut data, ENCODED_RECORD_SIZE),
+        Ok(ENCODED_RECORD_SIZE)
+    ) {
+        session_only(b"unreadable");
+        return;
+    }
+    let record = DiskHealthRecord::decode(&data).unwrap_or_else(|| {
+        printf!(b"Disk health: no valid record in ");
+        write_string(path);
+        printf!(b", starting a new one\r\n");
+        DiskHealthRecord::fresh()
+    });
+    unsafe { *PREVIOUS_BOOTS.get() = Some(record) };
+}
+
+/// Writes the previous boots merged with this one back to `path`, when [`load_disk_health`] read it. <br>
+/// Errors after this call still count for the notice, but aren't persisted. <br>
+pub fn save_disk_health(ext2: &mut Ext2FileSystem, path: &[u8]) {
+    if unsafe { (*PREVIOUS_BOOTS.get()).is_none() } || !writes_allowed(b"Disk health record") {
+        return;
+    }
+    let encoded = merged_record().encode();
+    let written = match ext2.open_path(path) {
+        Ok(Ext2FileType::File(mut file)) => file.write_in_place(&encoded),
+        _ => return,
+    };
+    if !matches!(written, Ok(ENCODED_RECORD_SIZE)) {
+        printf!(b"Disk health: failed to write ");
+        write_string(path);
+        printf!(b"\r\n");
+    }
+}
+
+/// Shows a notice suggesting media replacement when the same error repeated at least `threshold` times near the same LBA. <br>
+/// Counts the errors of the previous boots too when [`load_disk_health`] found a record. <br>
+pub fn check_disk_health(threshold: u32) {
+    let record = merged_record();
+    let Some(worst) = record.over_threshold(threshold) else {
+        return;
+    };
+    let near_mib = worst.bucket as u64 * LBA_BUCKET_SECTORS / 2048;
+    printf!(b"Disk health: 0x%x ", worst.count);
+    write_string(worst.description());
+    printf!(b" errors near MiB 0x%x across the last ", near_mib as u32);
+    write_u32_decimal(record.boots);
+    printf!(b" boots, the boot media may be failing\r\n");
+    let (boots, start) = decimal(record.boots);
+    unsafe {
+        let video = Video::get();
+        video.write_string(&render(
+            Text::DiskHealthNotice,
+            &[
+                &hex(worst.count),
+                worst.description(),
+                &hex(near_mib as u32),
+                &boots[start..],
+            ],
+        ));
+        video.write_char(b'\n');
+    }
+}
@@ -1,30 +1,170 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+
 use crate::{
+    fbcon,
     io::{inb, outb},
+    log,
     mem::Buffer,
+    printf,
     video::get_hex_digit,
 };
 
+const LPT_DATA: u16 = 0x378;
+const LPT_STATUS: u16 = 0x379;
+const LPT_CONTROL: u16 = 0x37A;
+const LPT_STATUS_ACK: u8 = 1 << 6;
+const LPT_STATUS_BUSY: u8 = 1 << 5;
+const LPT_CONTROL_STROBE: u8 = 1;
+/// Status reads before a handshake step is given up, the port is dropped after that
+const HANDSHAKE_SPINS: u32 = 5000;
+
+const PARALLEL_UNPROBED: u8 = 0;
+const PARALLEL_LIVE: u8 = 1;
+/// No port answered the probe
+const PARALLEL_ABSENT: u8 = 2;
+/// The port was there but a handshake timed out, output goes to 0xE9 only since
+const PARALLEL_TIMED_OUT: u8 = 3;
+
+static PARALLEL_STATE: AtomicU8 = AtomicU8::new(PARALLEL_UNPROBED);
+static PARALLEL_TIMEOUTS: AtomicU32 = AtomicU32::new(0);
+
+/// Set in safe mode, every logged byte is also written to the VGA text console
+static VGA_MIRROR: AtomicBool = AtomicBool::new(false);
+
+pub fn set_vga_mirror(enabled: bool) {
+    VGA_MIRROR.store(enabled, Ordering::Relaxed);
+}
+
 pub fn write_string(string: &[u8]) {
     for c in string.iter() {
         write_char(*c);
     }
 }
 
+/// Whether a parallel port latches what is written to its data register, a missing one floats to all ones
+unsafe fn probe_parallel() -> bool {
+    for pattern in [0xA5, 0x5A] {
+        outb(LPT_DATA, pattern);
+        if inb(LPT_DATA) != pattern {
+            return false;
+        }
+    }
+    inb(LPT_STATUS) != 0xFF
+}
+
+/// Spins until the status bits of `mask` are `set`, false after [`HANDSHAKE_SPINS`] reads
+unsafe fn wait_parallel_status(mask: u8, set: bool) -> bool {
+    (0..HANDSHAKE_SPINS).any(|_| (inb(LPT_STATUS) & mask != 0) == set)
+}
+
+/// Writes `character` to the parallel port with the strobe handshake, false if the port didn't follow
+unsafe fn write_parallel(character: u8) -> bool {
+    if !wait_parallel_status(LPT_STATUS_ACK, true) {
+        return false;
+    }
+    outb(LPT_DATA, character);
+    outb(LPT_CONTROL, inb(LPT_CONTROL) | LPT_CONTROL_STROBE);
+    let done = wait_parallel_status(LPT_STATUS_BUSY, false);
+    outb(LPT_CONTROL, inb(LPT_CONTROL) & !LPT_CONTROL_STROBE);
+    done
+}
+
 #[no_mangle]
 pub fn write_char(character: u8) {
+    let (to_e9, to_vga) = log::route(character);
+    if to_e9 {
+        write_debug_ports(character);
+    }
+    if to_vga || (to_e9 && VGA_MIRROR.load(Ordering::Relaxed)) {
+        fbcon::write_screen_char(character);
+    }
+}
+
+fn write_debug_ports(character: u8) {
     unsafe {
         // BOCHS
         outb(0xE9, character);
 
-        // QEMU
-        while inb(0x379) & 0b01000000 == 0 {}
-        outb(0x378, character);
-        outb(0x37A, inb(0x37A) | 1);
-        while inb(0x379) & 0b00100000 != 0 {}
-        outb(0x37A, inb(0x37A) & 0b11111110);
+        // QEMU, probed on the first byte since a missing port would hang the handshake
+        let mut state = PARALLEL_STATE.load(Ordering::Relaxed);
+        if state == PARALLEL_UNPROBED {
+            state = if probe_parallel() {
+                PARALLEL_LIVE
+            } else {
+                PARALLEL_ABSENT
+            };
+            PARALLEL_STATE.store(state, Ordering::Relaxed);
+        }
+        if state == PARALLEL_LIVE && !write_parallel(character) {
+            PARALLEL_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+            PARALLEL_STATE.store(PARALLEL_TIMED_OUT, Ordering::Relaxed);
+        }
     }
 }
 
+/// Bytes shown by one hex dump at most, the rest is only counted
+pub const MAX_HEX_DUMP: usize = 4096;
+const HEX_DUMP_LINE: usize = 16;
+
+/// Dumps `data` 16 bytes per line: offset from `base`, hex bytes, then the printable ASCII with dots for the rest
+fn hex_dump_lines(base: usize, data: &[u8]) {
+    let shown = data.len().min(MAX_HEX_DUMP);
+    for (i, line) in data[..shown].chunks(HEX_DUMP_LINE).enumerate() {
+        printf!(b"  %x: ", base + i * HEX_DUMP_LINE);
+        for column in 0..HEX_DUMP_LINE {
+            match line.get(column) {
+                Some(byte) => printf!(b"%b ", *byte),
+                None => printf!(b"   "),
+            }
+        }
+        write_char(b'|');
+        for byte in line {
+            write_char(if (0x20..0x7F).contains(byte) {
+                *byte
+            } else {
+                b'.'
+            });
+        }
+        printf!(b"|\r\n");
+    }
+    if shown < data.len() {
+        printf!(b"  ... 0x%x more bytes not shown\r\n", data.len() - shown);
+    }
+}
+
+/// Hex and ASCII dump of `data` under `label`, offsets count from the start of `data`. At most [`MAX_HEX_DUMP`] bytes are shown
+pub fn hex_dump(label: &[u8], data: &[u8]) {
+    write_string(label);
+    printf!(b" (0x%x bytes):\r\n", data.len());
+    hex_dump_lines(0, data);
+}
+
+/// Like [`hex_dump`] for `len` bytes of memory at `addr`, offsets are addresses
+/// # Safety
+/// `addr..addr + len` must be readable
+pub unsafe fn hex_dump_phys(addr: usize, len: usize) {
+    printf!(b"Memory at 0x%x (0x%x bytes):\r\n", addr, len);
+    hex_dump_lines(addr, core::slice::from_raw_parts(addr as *const u8, len));
+}
+
+/// Logs which debug output channels are live
+pub fn debug_output_status() {
+    printf!(b"Debug output: port 0xE9, parallel port ");
+    write_string(match PARALLEL_STATE.load(Ordering::Relaxed) {
+        PARALLEL_LIVE => b"live",
+        PARALLEL_ABSENT => b"absent",
+        PARALLEL_TIMED_OUT => b"dropped after a handshake timeout",
+        _ => b"not probed",
+    });
+    if VGA_MIRROR.load(Ordering::Relaxed) {
+        printf!(b", VGA mirror");
+    }
+    printf!(
+        b" (0x%x timeouts)\r\n",
+        PARALLEL_TIMEOUTS.load(Ordering::Relaxed) as usize
+    );
+}
+
 pub fn write_hex_u8(value: u8) {
     write_char(get_hex_digit((value >> 4) & 0xF));
     write_char(get_hex_digit(value & 0xF));
@@ -52,6 +192,19 @@ pub fn write_buffer_as_string(buffer: &Buffer) {
     write_buffer_slice_as_string(buffer, 0, buffer.len());
 }
 
+/// Writes the buffer with every byte that isn't printable ASCII, '/' and '\\' escaped as `\xNN`, so hostile names round-trip unambiguously
+pub fn write_buffer_as_escaped_string(buffer: &Buffer) {
+    for c in buffer.iter() {
+        if (0x20..0x7F).contains(&c) && c != b'/' && c != b'\\' {
+            write_char(c);
+        } else {
+            write_char(b'\\');
+            write_char(b'x');
+            write_hex_u8(c);
+        }
+    }
+}
+
 macro_rules! impl_display_dec {
     ($value: ident, $buffer: ident, $i: ident) => {{
         let mut v = $value;
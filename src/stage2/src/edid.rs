@@ -0,0 +1,102 @@
+use core::cell::SyncUnsafeCell;
+
+use crate::{
+    bios::{unsafe_call_bios_interrupt, BiosCallGuard, BiosInterruptResult},
+    e9::write_u32_decimal,
+    printf, ptr_to_seg_off,
+};
+
+const EDID_SIZE: usize = 128;
+const EDID_HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+/// First detailed timing descriptor, the preferred timing since EDID 1.3
+const PREFERRED_TIMING_OFFSET: usize = 54;
+
+/// Real-mode addressable buffer the BIOS writes the EDID base block into
+static EDID_BUFFER: SyncUnsafeCell<[u8; EDID_SIZE]> = SyncUnsafeCell::new([0; EDID_SIZE]);
+
+pub enum EdidError {
+    /// INT 10h AX=4F15h failed, with the returned AX
+    Unsupported(u16),
+    /// The BIOS call changed the protected mode state
+    CorruptedState,
+    BadHeader,
+    BadChecksum(u8),
+    /// The first descriptor isn't a detailed timing, or has no active area
+    NoPreferredTiming,
+}
+
+impl EdidError {
+    pub fn printf(&self) {
+        match self {
+            EdidError::Unsupported(ax) => {
+                printf!(b"VBE/DDC read failed, ax=0x%x", *ax as u32)
+            }
+            EdidError::CorruptedState => printf!(b"the BIOS call corrupted state"),
+            EdidError::BadHeader => printf!(b"bad EDID header"),
+            EdidError::BadChecksum(sum) => {
+                printf!(b"bad EDID checksum, bytes sum to 0x%b", *sum)
+            }
+            EdidError::NoPreferredTiming => printf!(b"no preferred detailed timing"),
+        }
+    }
+}
+
+/// Native `(width, height)` of the display, from the preferred detailed timing of its EDID. <br>
+/// Reads the EDID base block of the first display with VBE/DDC (INT 10h AX=4F15h BL=01h). <br>
+pub fn read_native_resolution(bios_idt: usize) -> Result<(u16, u16), EdidError> {
+    unsafe {
+        *EDID_BUFFER.get() = [0; EDID_SIZE];
+        let (seg, off) = ptr_to_seg_off(EDID_BUFFER.get() as usize);
+
+        let guard = BiosCallGuard::new();
+        let res = unsafe_call_bios_interrupt(
+            bios_idt,
+            0x10,
+            0x4f15,
+            0x01,
+            0,
+            0,
+            0,
+            off as usize,
+            seg as usize,
+            seg as usize,
+            seg as usize,
+            seg as usize,
+        ) as *const BiosInterruptResult;
+        if !guard.verify(&*res) {
+            return Err(EdidError::CorruptedState);
+        }
+        if ((*res).eax & 0xFFFF) != 0x4F {
+            return Err(EdidError::Unsupported((*res).eax as u16));
+        }
+        parse_native_resolution(&*EDID_BUFFER.get())
+    }
+}
+
+fn parse_native_resolution(edid: &[u8; EDID_SIZE]) -> Result<(u16, u16), EdidError> {
+    if edid[..EDID_HEADER.len()] != EDID_HEADER {
+        return Err(EdidError::BadHeader);
+    }
+    let sum = edid.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    if sum != 0 {
+        return Err(EdidError::BadChecksum(sum));
+    }
+
+    let timing = &edid[PREFERRED_TIMING_OFFSET..PREFERRED_TIMING_OFFSET + 18];
+    // A zero pixel clock marks a display descriptor instead of a timing
+    if timing[0] == 0 && timing[1] == 0 {
+        return Err(EdidError::NoPreferredTiming);
+    }
+    let width = timing[2] as u16 | ((timing[4] >> 4) as u16) << 8;
+    let height = timing[5] as u16 | ((timing[7] >> 4) as u16) << 8;
+    if width == 0 || height == 0 {
+        return Err(EdidError::NoPreferredTiming);
+    }
+
+    printf!(b"EDID: native resolution ");
+    write_u32_decimal(width as u32);
+    printf!(b"x");
+    write_u32_decimal(height as u32);
+    printf!(b"\r\n");
+    Ok((width, height))
+}
@@ -1,10 +1,20 @@
 use crate::{
+    addr::write_addr,
+    e9::{hex_dump, write_string},
     fs::{Ext2Error, Ext2File},
     kpanic,
+    log::{self, LogLevel},
     mem::{Buffer, Vec},
+    pagetable::HIGHER_HALF_START,
+    paging::AddressSource,
+    printf,
     video::Video,
+    warnings::{self, WarningId},
 };
 
+/// Bytes of the file start dumped when the ELF magic is wrong
+const ELF_DUMP_SIZE: usize = 32;
+
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
 pub struct ElfHeader32 {
@@ -100,11 +110,342 @@ pub struct ElfProgramHeader64 {
     pub align: u64,
 }
 
+/// The leading fields of a section header, enough to read the extended counts of section zero
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct ElfSectionHeader32 {
+    pub sh_name: u32,
+    pub sh_type: u32,
+    pub sh_flags: u32,
+    pub sh_addr: u32,
+    pub sh_offset: u32,
+    pub sh_size: u32,
+    pub sh_link: u32,
+    pub sh_info: u32,
+    pub sh_addralign: u32,
+    pub sh_entsize: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct ElfSectionHeader64 {
+    pub sh_name: u32,
+    pub sh_type: u32,
+    pub sh_flags: u64,
+    pub sh_addr: u64,
+    pub sh_offset: u64,
+    pub sh_size: u64,
+    pub sh_link: u32,
+    pub sh_info: u32,
+    pub sh_addralign: u64,
+    pub sh_entsize: u64,
+}
+
+/// `e_phnum` value meaning the real count is in `sh_info` of section zero
+pub const PN_XNUM: u16 = 0xFFFF;
+/// `e_shstrndx` value meaning the real index is in `sh_link` of section zero
+pub const SHN_XINDEX: u16 = 0xFFFF;
+/// Sanity cap on the program header count, real kernels have a handful
+pub const MAX_PROGRAM_HEADERS: usize = 1024;
+
 pub const SEGMENT_TYPE_NULL: u32 = 0;
 pub const SEGMENT_TYPE_LOAD: u32 = 1;
 pub const SEGMENT_TYPE_DYNAMIC: u32 = 2;
 pub const SEGMENT_TYPE_INTERP: u32 = 3;
 pub const SEGMENT_TYPE_NOTE: u32 = 4;
+pub const SEGMENT_TYPE_SHLIB: u32 = 5;
+pub const SEGMENT_TYPE_PHDR: u32 = 6;
+pub const SEGMENT_TYPE_TLS: u32 = 7;
+pub const SEGMENT_TYPE_GNU_EH_FRAME: u32 = 0x6474_E550;
+pub const SEGMENT_TYPE_GNU_STACK: u32 = 0x6474_E551;
+pub const SEGMENT_TYPE_GNU_RELRO: u32 = 0x6474_E552;
+pub const SEGMENT_TYPE_GNU_PROPERTY: u32 = 0x6474_E553;
+
+/// Dynamic section tags whose presence (with a non zero size) means relocations must be applied
+const DT_NULL: u64 = 0;
+const DT_RELASZ: u64 = 8;
+const DT_RELSZ: u64 = 18;
+const DT_RELRSZ: u64 = 35;
+
+/// How the loader treats a program header, shared by the 32-bit and 64-bit paths
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SegmentClass {
+    /// Loaded and mapped
+    Load,
+    /// Ignored, notes carry no loading information
+    Note,
+    /// Ignored, the kernel is expected to set up TLS itself
+    Tls,
+    /// Ignored, relocations are never applied
+    Dynamic,
+    /// Ignored, makes no sense for a kernel
+    Interp,
+    /// Ignored, the loader maps segments with their own permissions
+    Relro,
+    /// Ignored, harmless (PHDR, GNU_STACK, GNU_EH_FRAME, GNU_PROPERTY, NULL)
+    Informational,
+    /// Ignored, unknown type
+    Unknown,
+}
+
+const SEGMENT_CLASSES: [(SegmentClass, &[u8]); 8] = [
+    (SegmentClass::Load, b"LOAD"),
+    (SegmentClass::Note, b"NOTE"),
+    (SegmentClass::Tls, b"TLS"),
+    (SegmentClass::Dynamic, b"DYNAMIC"),
+    (SegmentClass::Interp, b"INTERP"),
+    (SegmentClass::Relro, b"GNU_RELRO"),
+    (SegmentClass::Informational, b"other known"),
+    (SegmentClass::Unknown, b"unknown"),
+];
+
+impl SegmentClass {
+    pub fn of(segment_type: u32) -> Self {
+        match segment_type {
+            SEGMENT_TYPE_LOAD => SegmentClass::Load,
+            SEGMENT_TYPE_NOTE => SegmentClass::Note,
+            SEGMENT_TYPE_TLS => SegmentClass::Tls,
+            SEGMENT_TYPE_DYNAMIC => SegmentClass::Dynamic,
+            SEGMENT_TYPE_INTERP => SegmentClass::Interp,
+            SEGMENT_TYPE_GNU_RELRO => SegmentClass::Relro,
+            SEGMENT_TYPE_NULL
+            | SEGMENT_TYPE_SHLIB
+            | SEGMENT_TYPE_PHDR
+            | SEGMENT_TYPE_GNU_EH_FRAME
+            | SEGMENT_TYPE_GNU_STACK
+            | SEGMENT_TYPE_GNU_PROPERTY => SegmentClass::Informational,
+            _ => SegmentClass::Unknown,
+        }
+    }
+
+    fn index(&self) -> usize {
+        SEGMENT_CLASSES
+            .iter()
+            .position(|(class, _)| class == self)
+            .unwrap_or(SEGMENT_CLASSES.len() - 1)
+    }
+
+    pub fn name(&self) -> &'static [u8] {
+        SEGMENT_CLASSES[self.index()].1
+    }
+}
+
+/// A program header reduced to what the segment classification needs
+struct SegmentSummary {
+    segment_type: u32,
+    offset: u64,
+    filesz: u64,
+}
+
+/// Returns whether the dynamic section at `offset` declares relocations
+fn dynamic_has_relocations(
+    file: &mut Ext2File,
+    offset: u64,
+    filesz: u64,
+    is64: bool,
+) -> Result<bool, ElfError> {
+    let entry_size = if is64 { 16 } else { 8 };
+    let len = filesz as usize;
+    let mut buffer =
+        Buffer::new_tagged(len.max(1), b"elf").ok_or(ElfError::FailedMemAlloc(len.max(1)))?;
+    file.seek(offset as usize).map_err(ElfError::Ext2Error)?;
+    let read = file.read(&mut buffer, len).map_err(ElfError::Ext2Error)?;
+
+    let data: &[u8] = &buffer;
+    for entry in data[..read.min(len)].chunks_exact(entry_size) {
+        let (tag, value) = if is64 {
+            (
+                u64::from_le_bytes(entry[0..8].try_into().unwrap_or([0; 8])),
+                u64::from_le_bytes(entry[8..16].try_into().unwrap_or([0; 8])),
+            )
+        } else {
+            (
+                u32::from_le_bytes(entry[0..4].try_into().unwrap_or([0; 4])) as u64,
+                u32::from_le_bytes(entry[4..8].try_into().unwrap_or([0; 4])) as u64,
+            )
+        };
+        match tag {
+            DT_NULL => break,
+            DT_RELASZ | DT_RELSZ | DT_RELRSZ if value != 0 => return Ok(true),
+            _ => {}
+        }
+    }
+    Ok(false)
+}
+
+/// Prints how many segments of each class the kernel has, and warns about the classes that usually mean a misconfigured kernel build. <br>
+/// With `strict`, those warnings become a load failure. <br>
+fn classify_segments(
+    file: &mut Ext2File,
+    segments: &Vec<SegmentSummary>,
+    is64: bool,
+    strict: bool,
+) -> Result<(), ElfError> {
+    let mut counts = [0usize; SEGMENT_CLASSES.len()];
+    let mut problems = 0;
+    for (i, segment) in segments.iter().enumerate() {
+        let class = SegmentClass::of(segment.segment_type);
+        counts[class.index()] += 1;
+
+        let warning: Option<&[u8]> = match class {
+            SegmentClass::Tls => Some(b"TLS segment, the loader will not initialize it"),
+            SegmentClass::Interp => Some(b"INTERP segment, a kernel has no interpreter"),
+            SegmentClass::Dynamic
+                if dynamic_has_relocations(file, segment.offset, segment.filesz, is64)? =>
+            {
+                Some(b"DYNAMIC segment with relocations, the loader never applies them")
+            }
+            _ => None,
+        };
+        if let Some(warning) = warning {
+            problems += 1;
+            log::with_level(LogLevel::Warn, || {
+                printf!(b"Warning: program header %x: ", i);
+                write_string(warning);
+                printf!(b"\r\n");
+            });
+            warnings::warning(WarningId::ElfIgnoredSegment);
+        }
+    }
+
+    printf!(b"ELF segments:");
+    for (i, (class, name)) in SEGMENT_CLASSES.iter().enumerate() {
+        if counts[i] == 0 {
+            continue;
+        }
+        printf!(b" ");
+        write_string(name);
+        printf!(b"=%x", counts[i]);
+        if *class != SegmentClass::Load {
+            printf!(b" (ignored)");
+        }
+    }
+    printf!(b"\r\n");
+
+    if problems != 0 {
+        unsafe {
+            let video = Video::get();
+            video.write_string(b"Warning: kernel ELF has 0x");
+            video.write_hex_u32(problems as u32);
+            video.write_string(b" segments the loader ignores, see the log\n");
+        }
+        if strict {
+            printf!(b"strict_elf=1: refusing to load the kernel\r\n");
+            return Err(ElfError::IgnoredSegments(problems));
+        }
+    }
+    Ok(())
+}
+
+/// The fields of a LOAD program header the sanity checks need, widened to 64 bits
+#[derive(Clone, Copy)]
+struct LoadSegment {
+    /// Index of the program header
+    index: usize,
+    offset: u64,
+    filesz: u64,
+    /// Virtual address for a 64-bit kernel, physical address for a 32-bit kernel (loaded without paging)
+    address: u64,
+    memsz: u64,
+    align: u64,
+}
+
+/// Checks that the `filesz` file bytes of segment `index` fit in its `memsz` memory bytes and inside the `file_size` byte file. <br>
+/// The loader repeats it right before sizing a buffer from `filesz`. <br>
+pub fn check_segment_file_size(
+    index: usize,
+    offset: u64,
+    filesz: u64,
+    memsz: u64,
+    file_size: u64,
+) -> Result<(), ElfError> {
+    if filesz > memsz {
+        return Err(ElfError::FileSizeExceedsMemSize(index));
+    }
+    match offset.checked_add(filesz) {
+        Some(end) if end <= file_size => Ok(()),
+        end => Err(ElfError::SegmentOutOfFile(index, end.unwrap_or(u64::MAX))),
+    }
+}
+
+/// Checks that file bytes fit in memory bytes and inside the `file_size` byte file, <br>
+/// that alignments are powers of two, and that no two segments overlap. <br>
+fn check_load_segments(segments: &Vec<LoadSegment>, file_size: u64) -> Result<(), ElfError> {
+    for segment in segments.iter() {
+        let i = segment.index;
+        check_segment_file_size(i, segment.offset, segment.filesz, segment.memsz, file_size)?;
+        if !segment.align.is_power_of_two() {
+            return Err(ElfError::BadSegmentAlignment(i, segment.align));
+        }
+    }
+
+    for (k, a) in segments.iter().enumerate() {
+        if a.memsz == 0 {
+            continue;
+        }
+        for b in segments.iter().skip(k + 1) {
+            if b.memsz == 0 {
+                continue;
+            }
+            // Saturating: wrapping ranges are reported by `check_kernel_range`
+            let a_end = a.address.saturating_add(a.memsz);
+            let b_end = b.address.saturating_add(b.memsz);
+            if a.address < b_end && b.address < a_end {
+                return Err(ElfError::OverlappingSegments(a.index, b.index));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the LOAD segments of a 64-bit kernel before anything is allocated for them, stopping at the first problem. <br>
+/// Addresses must be in the higher half, see `check_load_segments` for the other checks. <br>
+pub fn validate_program_headers(
+    phs: &Vec<ElfProgramHeader64>,
+    file_size: u64,
+) -> Result<(), ElfError> {
+    let mut segments = Vec::new_tagged(phs.len().max(1), b"elf");
+    for (i, ph) in phs.iter().enumerate() {
+        if ph.segment_type != SEGMENT_TYPE_LOAD {
+            continue;
+        }
+        if ph.p_vaddr < HIGHER_HALF_START {
+            return Err(ElfError::SegmentBelowHigherHalf(i, ph.p_vaddr));
+        }
+        segments.push(LoadSegment {
+            index: i,
+            offset: ph.p_offset,
+            filesz: ph.p_filesz,
+            address: ph.p_vaddr,
+            memsz: ph.p_memsz,
+            align: ph.align,
+        });
+    }
+    check_load_segments(&segments, file_size)
+}
+
+/// Checks the LOAD segments of a 32-bit kernel, by their physical addresses since it runs without paging. <br>
+/// Whether those addresses are usable memory is checked by the loader, which knows the memory layout. <br>
+pub fn validate_program_headers32(
+    phs: &Vec<ElfProgramHeader32>,
+    file_size: u64,
+) -> Result<(), ElfError> {
+    let mut segments = Vec::new_tagged(phs.len().max(1), b"elf");
+    for (i, ph) in phs.iter().enumerate() {
+        if ph.segment_type != SEGMENT_TYPE_LOAD {
+            continue;
+        }
+        segments.push(LoadSegment {
+            index: i,
+            offset: ph.p_offset as u64,
+            filesz: ph.p_filesz as u64,
+            address: ph.p_paddr as u64,
+            memsz: ph.p_memsz as u64,
+            align: ph.align as u64,
+        });
+    }
+    check_load_segments(&segments, file_size)
+}
 
 pub const FLAG_EXECUTABLE: u32 = 1;
 pub const FLAG_WRITABLE: u32 = 2;
@@ -115,6 +456,41 @@ pub enum ElfError {
     Ext2Error(Ext2Error),
     FailedMemAlloc(usize),
     InvalidMagic,
+    VirtualRangeConflicts(usize),
+    /// The entry point isn't inside any LOAD segment
+    EntryOutsideSegments(u64),
+    /// The entry point is inside the given segment, which isn't executable
+    EntryNotExecutable(u64, usize),
+    /// The entry point isn't mapped by the built page tables
+    EntryNotMapped(u64),
+    /// This many TLS, INTERP or relocated DYNAMIC segments were found with `strict_elf=1`
+    IgnoredSegments(usize),
+    /// `e_phnum` is PN_XNUM (or `e_shnum` is 0 with a section table offset) but section zero can't be read
+    ExtendedCountWithoutSections,
+    /// More program headers than [`MAX_PROGRAM_HEADERS`]
+    TooManyProgramHeaders(usize),
+    /// `e_phentsize` is smaller than a program header
+    InvalidProgramHeaderSize(u16),
+    /// The program header table ends past the end of the file
+    ProgramHeadersOutOfFile(u64),
+    /// Bits 63:48 of the address aren't a sign extension of bit 47
+    NonCanonicalAddress(AddressSource, u64),
+    /// A kernel-space address below the higher half
+    LowerHalfAddress(AddressSource, u64),
+    /// The range starting at the address with this size runs past the end of the address space
+    CrossesCanonicalGap(AddressSource, u64, u64),
+    /// The LOAD segment has more bytes in the file than in memory
+    FileSizeExceedsMemSize(usize),
+    /// The LOAD segment's file bytes end at this offset, past the end of the file
+    SegmentOutOfFile(usize, u64),
+    /// The byte ranges of these two LOAD segments overlap
+    OverlappingSegments(usize, usize),
+    /// The physical range of this LOAD segment of a 32-bit kernel, starting at this address, isn't free usable memory
+    SegmentNotInUsableMemory(usize, u64),
+    /// The LOAD segment starts below [`HIGHER_HALF_START`]
+    SegmentBelowHigherHalf(usize, u64),
+    /// The LOAD segment's alignment is zero or not a power of two
+    BadSegmentAlignment(usize, u64),
 }
 
 impl ElfError {
@@ -133,6 +509,127 @@ impl ElfError {
                 ElfError::InvalidMagic => {
                     video.write_string(b"Invalid ELF magic\n");
                 }
+                ElfError::VirtualRangeConflicts(count) => {
+                    video.write_string(b"Kernel virtual ranges conflict: 0x");
+                    video.write_hex_u32(*count as u32);
+                    video.write_string(b" conflicts\n");
+                }
+                ElfError::EntryOutsideSegments(entry) => {
+                    video.write_string(b"Kernel entry point 0x");
+                    video.write_hex_u32((*entry >> 32) as u32);
+                    video.write_hex_u32(*entry as u32);
+                    video.write_string(b" is not inside any LOAD segment\n");
+                }
+                ElfError::EntryNotExecutable(entry, segment) => {
+                    video.write_string(b"Kernel entry point 0x");
+                    video.write_hex_u32((*entry >> 32) as u32);
+                    video.write_hex_u32(*entry as u32);
+                    video.write_string(b" is in non executable segment 0x");
+                    video.write_hex_u32(*segment as u32);
+                    video.write_char(b'\n');
+                }
+                ElfError::EntryNotMapped(entry) => {
+                    video.write_string(b"Kernel entry point 0x");
+                    video.write_hex_u32((*entry >> 32) as u32);
+                    video.write_hex_u32(*entry as u32);
+                    video.write_string(b" is not mapped\n");
+                }
+                ElfError::IgnoredSegments(count) => {
+                    video.write_string(b"Kernel has 0x");
+                    video.write_hex_u32(*count as u32);
+                    video.write_string(b" segments the loader would ignore (strict_elf=1)\n");
+                }
+                ElfError::ExtendedCountWithoutSections => {
+                    video.write_string(
+                        b"ELF header uses an extended header count but has no section headers\n",
+                    );
+                }
+                ElfError::TooManyProgramHeaders(count) => {
+                    video.write_string(b"Too many program headers: 0x");
+                    video.write_hex_u32(*count as u32);
+                    video.write_char(b'\n');
+                }
+                ElfError::InvalidProgramHeaderSize(size) => {
+                    video.write_string(b"Invalid program header entry size: 0x");
+                    video.write_hex_u32(*size as u32);
+                    video.write_char(b'\n');
+                }
+                ElfError::ProgramHeadersOutOfFile(end) => {
+                    video.write_string(b"Program header table ends past the end of the file: 0x");
+                    video.write_hex_u32((*end >> 32) as u32);
+                    video.write_hex_u32(*end as u32);
+                    video.write_char(b'\n');
+                }
+                ElfError::NonCanonicalAddress(source, address) => {
+                    printf_address_error(b"non canonical address", *source, *address);
+                    video.write_string(b"Kernel has a non canonical address: 0x");
+                    video.write_hex_u32((*address >> 32) as u32);
+                    video.write_hex_u32(*address as u32);
+                    video.write_string(b", see the log\n");
+                }
+                ElfError::LowerHalfAddress(source, address) => {
+                    printf_address_error(b"address below 0xFFFF800000000000", *source, *address);
+                    video.write_string(b"Kernel has a lower half address: 0x");
+                    video.write_hex_u32((*address >> 32) as u32);
+                    video.write_hex_u32(*address as u32);
+                    video.write_string(b", see the log\n");
+                }
+                ElfError::CrossesCanonicalGap(source, address, size) => {
+                    printf_address_error(
+                        b"range wrapping around the address space",
+                        *source,
+                        *address,
+                    );
+                    printf!(b"    size 0x%x%x\r\n", (*size >> 32) as u32, *size as u32);
+                    video.write_string(b"Kernel range at 0x");
+                    video.write_hex_u32((*address >> 32) as u32);
+                    video.write_hex_u32(*address as u32);
+                    video.write_string(b" wraps around the address space, see the log\n");
+                }
+                ElfError::FileSizeExceedsMemSize(segment) => {
+                    video.write_string(b"Program header 0x");
+                    video.write_hex_u32(*segment as u32);
+                    video.write_string(b": p_filesz is larger than p_memsz\n");
+                }
+                ElfError::SegmentOutOfFile(segment, end) => {
+                    video.write_string(b"Program header 0x");
+                    video.write_hex_u32(*segment as u32);
+                    video.write_string(b": data ends past the end of the file, at 0x");
+                    video.write_hex_u32((*end >> 32) as u32);
+                    video.write_hex_u32(*end as u32);
+                    video.write_char(b'\n');
+                }
+                ElfError::OverlappingSegments(a, b) => {
+                    video.write_string(b"Program headers 0x");
+                    video.write_hex_u32(*a as u32);
+                    video.write_string(b" and 0x");
+                    video.write_hex_u32(*b as u32);
+                    video.write_string(b" load overlapping address ranges\n");
+                }
+                ElfError::SegmentBelowHigherHalf(segment, address) => {
+                    video.write_string(b"Program header 0x");
+                    video.write_hex_u32(*segment as u32);
+                    video.write_string(b": p_vaddr 0x");
+                    video.write_hex_u32((*address >> 32) as u32);
+                    video.write_hex_u32(*address as u32);
+                    video.write_string(b" is below the higher half\n");
+                }
+                ElfError::SegmentNotInUsableMemory(segment, address) => {
+                    video.write_string(b"Program header 0x");
+                    video.write_hex_u32(*segment as u32);
+                    video.write_string(b": physical range at 0x");
+                    video.write_hex_u32((*address >> 32) as u32);
+                    video.write_hex_u32(*address as u32);
+                    video.write_string(b" is not free usable memory\n");
+                }
+                ElfError::BadSegmentAlignment(segment, align) => {
+                    video.write_string(b"Program header 0x");
+                    video.write_hex_u32(*segment as u32);
+                    video.write_string(b": p_align 0x");
+                    video.write_hex_u32((*align >> 32) as u32);
+                    video.write_hex_u32(*align as u32);
+                    video.write_string(b" is not a power of two\n");
+                }
                 ElfError::Ext2Error(e) => e.panic(),
             }
             kpanic()
@@ -140,18 +637,31 @@ impl ElfError {
     }
 }
 
+fn printf_address_error(message: &[u8], source: AddressSource, address: u64) {
+    printf!(b"Kernel ");
+    source.printf();
+    printf!(b": ");
+    write_string(message);
+    printf!(b" ");
+    write_addr(address);
+    printf!(b"\r\n");
+}
+
 fn parse_elf_header(file: &mut Ext2File) -> Result<ElfHeaderFlavour, ElfError> {
-    let mut elf_header = Buffer::new(size_of::<ElfHeader>())
+    let mut elf_header = Buffer::new_tagged(size_of::<ElfHeader>(), b"elf")
         .ok_or(ElfError::FailedMemAlloc(size_of::<ElfHeader>()))?;
     file.seek(0).map_err(ElfError::Ext2Error)?;
     file.read(&mut elf_header, size_of::<ElfHeader>())
         .map_err(ElfError::Ext2Error)?;
 
+    if elf_header[..4] != *b"\x7fELF" {
+        log::with_level(LogLevel::Debug, || {
+            hex_dump(b"Kernel file start", &elf_header[..ELF_DUMP_SIZE]);
+        });
+        return Err(ElfError::InvalidMagic);
+    }
     let elf_header: ElfHeader = elf_header.boxed::<ElfHeader>().unbox();
     unsafe {
-        if &elf_header.elf32.magic != b"\x7fELF" {
-            return Err(ElfError::InvalidMagic);
-        }
         if elf_header.elf32.bits == 0x01 {
             let elf_header = elf_header.elf32;
             if elf_header.endianness != ENDIANNESS_LITTLE {
@@ -175,7 +685,84 @@ pub struct ElfFile32<'a> {
 }
 
 macro_rules! impl_load_ph {
-    ($elfph: ident, $utype: ident) => {
+    ($elfph: ident, $elfsh: ident, $utype: ident) => {
+        /// Reads section header zero, which holds the real header counts when they overflow the ELF header fields
+        fn load_section_zero(&mut self) -> Result<Option<$elfsh>, ElfError> {
+            if self.header.section_header_table_offset == 0
+                || (self.header.section_header_entry_size as usize) < size_of::<$elfsh>()
+            {
+                return Ok(None);
+            }
+            self.file
+                .seek(self.header.section_header_table_offset as usize)
+                .map_err(ElfError::Ext2Error)?;
+            let mut buf = Buffer::new_tagged(size_of::<$elfsh>(), b"elf")
+                .ok_or(ElfError::FailedMemAlloc(size_of::<$elfsh>()))?;
+            let read = self
+                .file
+                .read(&mut buf, size_of::<$elfsh>())
+                .map_err(ElfError::Ext2Error)?;
+            if read != size_of::<$elfsh>() {
+                return Ok(None);
+            }
+            Ok(Some(buf.boxed::<$elfsh>().unbox()))
+        }
+
+        /// Returns the real program header count, following the PN_XNUM escape into section zero. <br>
+        /// The count is capped by [`MAX_PROGRAM_HEADERS`] and the whole table must be inside the file. <br>
+        pub fn program_header_count(&mut self) -> Result<usize, ElfError> {
+            let count = if self.header.program_header_entry_count == PN_XNUM {
+                let section_zero = self
+                    .load_section_zero()?
+                    .ok_or(ElfError::ExtendedCountWithoutSections)?;
+                printf!(
+                    b"ELF: e_phnum is PN_XNUM, real count 0x%x\r\n",
+                    section_zero.sh_info
+                );
+                section_zero.sh_info as usize
+            } else {
+                self.header.program_header_entry_count as usize
+            };
+            if count > MAX_PROGRAM_HEADERS {
+                return Err(ElfError::TooManyProgramHeaders(count));
+            }
+            let entry_size = self.header.program_header_entry_size;
+            if count != 0 && (entry_size as usize) < size_of::<$elfph>() {
+                return Err(ElfError::InvalidProgramHeaderSize(entry_size));
+            }
+            let end =
+                self.header.program_header_table_offset as u64 + count as u64 * entry_size as u64;
+            if count != 0 && end > self.file.get_size() as u64 {
+                return Err(ElfError::ProgramHeadersOutOfFile(end));
+            }
+            Ok(count)
+        }
+
+        /// Returns the real section header count, `e_shnum` is 0 and the count is in `sh_size` of section zero when it overflows
+        pub fn section_header_count(&mut self) -> Result<usize, ElfError> {
+            if self.header.section_header_table_offset == 0 {
+                return Ok(0);
+            }
+            match self.header.section_header_entry_count {
+                0 => Ok(self
+                    .load_section_zero()?
+                    .ok_or(ElfError::ExtendedCountWithoutSections)?
+                    .sh_size as usize),
+                count => Ok(count as usize),
+            }
+        }
+
+        /// Returns the index of the section name string table, following the SHN_XINDEX escape into section zero
+        pub fn section_name_table_index(&mut self) -> Result<usize, ElfError> {
+            match self.header.index_of_section_header_string_table {
+                SHN_XINDEX => Ok(self
+                    .load_section_zero()?
+                    .ok_or(ElfError::ExtendedCountWithoutSections)?
+                    .sh_link as usize),
+                index => Ok(index as usize),
+            }
+        }
+
         fn load_ph(&mut self, i: $utype) -> Result<(), ElfError> {
             let offset = self.header.program_header_table_offset
                 + (i * self.header.program_header_entry_size as $utype);
@@ -184,7 +771,7 @@ macro_rules! impl_load_ph {
                 .seek(offset as usize)
                 .map_err(ElfError::Ext2Error)?;
 
-            let mut buf = Buffer::new(core::mem::size_of::<$elfph>())
+            let mut buf = Buffer::new_tagged(core::mem::size_of::<$elfph>(), b"elf")
                 .ok_or(ElfError::FailedMemAlloc(core::mem::size_of::<$elfph>()))?;
 
             self.file
@@ -202,15 +789,34 @@ macro_rules! impl_load_ph {
             if !self.ph.is_empty() {
                 return Ok(&self.ph);
             }
-            self.ph
-                .ensure_capacity(self.header.program_header_entry_count as usize);
+            let count = self.program_header_count()?;
+            self.ph.ensure_capacity(count);
 
-            for i in 0..self.header.program_header_entry_count {
+            for i in 0..count {
                 self.load_ph(i as $utype)?;
             }
 
             Ok(&self.ph)
         }
+
+        /// Summarizes the program headers by [`SegmentClass`], see `classify_segments`
+        pub fn classify_segments(&mut self, strict: bool) -> Result<(), ElfError> {
+            self.load_program_headers()?;
+            let mut segments = Vec::new_tagged(self.ph.len().max(1), b"elf");
+            for ph in self.ph.iter() {
+                segments.push(SegmentSummary {
+                    segment_type: ph.segment_type,
+                    offset: ph.p_offset as u64,
+                    filesz: ph.p_filesz as u64,
+                });
+            }
+            classify_segments(
+                &mut self.file,
+                &segments,
+                size_of::<$elfph>() == size_of::<ElfProgramHeader64>(),
+                strict,
+            )
+        }
     };
 }
 
@@ -223,7 +829,13 @@ impl<'a> ElfFile32<'a> {
         })
     }
 
-    impl_load_ph!(ElfProgramHeader32, u32);
+    impl_load_ph!(ElfProgramHeader32, ElfSectionHeader32, u32);
+
+    /// Loads the program headers and checks them with [`validate_program_headers32`]
+    pub fn validate_program_headers(&mut self) -> Result<(), ElfError> {
+        self.load_program_headers()?;
+        validate_program_headers32(&self.ph, self.file.get_size64())
+    }
 
     pub fn entry_point(&self) -> u32 {
         self.header.entry_offset
@@ -233,7 +845,7 @@ impl<'a> ElfFile32<'a> {
         &self.file
     }
 
-    pub fn get_file_mut(&mut self) -> &'a mut Ext2File {
+    pub fn get_file_mut(&mut self) -> &mut Ext2File<'a> {
         &mut self.file
     }
 }
@@ -253,7 +865,13 @@ impl<'a> ElfFile64<'a> {
         })
     }
 
-    impl_load_ph!(ElfProgramHeader64, u64);
+    impl_load_ph!(ElfProgramHeader64, ElfSectionHeader64, u64);
+
+    /// Loads the program headers and checks them with [`validate_program_headers`]
+    pub fn validate_program_headers(&mut self) -> Result<(), ElfError> {
+        self.load_program_headers()?;
+        validate_program_headers(&self.ph, self.file.get_size64())
+    }
 
     pub fn entry_point(&self) -> u64 {
         self.header.entry_offset
@@ -263,7 +881,7 @@ impl<'a> ElfFile64<'a> {
         &self.file
     }
 
-    pub fn get_file_mut(&mut self) -> &'a mut Ext2File {
+    pub fn get_file_mut(&mut self) -> &mut Ext2File<'a> {
         &mut self.file
     }
 }
@@ -0,0 +1,383 @@
+use core::{
+    cell::SyncUnsafeCell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    printf,
+    video::{
+        vga_read, vga_write, CellDisplay, Character, Video, VGA_CRTC_INDEX, VGA_GC_INDEX,
+        VGA_SEQ_INDEX,
+    },
+};
+
+/// Glyph height of the fonts the console can render, all glyphs are 8 pixels wide
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FbFont {
+    Font8x8,
+    Font8x16,
+}
+
+impl FbFont {
+    pub const fn glyph_height(&self) -> usize {
+        match self {
+            FbFont::Font8x8 => 8,
+            FbFont::Font8x16 => 16,
+        }
+    }
+}
+
+/// Font and integer scaling factor (1 to 4) of the framebuffer console
+#[derive(Clone, Copy)]
+pub struct FbFontConfig {
+    pub font: FbFont,
+    pub scale: usize,
+}
+
+pub const MAX_FONT_SCALE: usize = 4;
+/// The automatic font selection targets this many text rows, within [`AUTO_MIN_ROWS`, `AUTO_MAX_ROWS`] when possible
+const AUTO_TARGET_ROWS: usize = 40;
+const AUTO_MIN_ROWS: usize = 30;
+const AUTO_MAX_ROWS: usize = 50;
+
+/// Parses `8x8`, `8x16`, `8x8x2`, `8x16x3`, ... Returns None for `auto` and invalid values, `valid` tells them apart
+pub fn parse_fb_font(value: &[u8]) -> (Option<FbFontConfig>, bool) {
+    if value == b"auto" {
+        return (None, true);
+    }
+    let (font, rest) = if let Some(rest) = value.strip_prefix(b"8x16") {
+        (FbFont::Font8x16, rest)
+    } else if let Some(rest) = value.strip_prefix(b"8x8") {
+        (FbFont::Font8x8, rest)
+    } else {
+        return (None, false);
+    };
+    let scale = match rest {
+        b"" => 1,
+        [b'x', digit] if (b'1'..=b'4').contains(digit) => (digit - b'0') as usize,
+        _ => return (None, false),
+    };
+    (Some(FbFontConfig { font, scale }), true)
+}
+
+/// Picks the font and scale giving the row count closest to [`AUTO_TARGET_ROWS`] for a mode `height` pixels high
+pub fn auto_font(height: usize) -> FbFontConfig {
+    let mut best = FbFontConfig {
+        font: FbFont::Font8x16,
+        scale: 1,
+    };
+    let mut best_distance = usize::MAX;
+    for font in [FbFont::Font8x16, FbFont::Font8x8] {
+        for scale in 1..=MAX_FONT_SCALE {
+            let rows = height / (font.glyph_height() * scale);
+            let mut distance = rows.abs_diff(AUTO_TARGET_ROWS);
+            if !(AUTO_MIN_ROWS..=AUTO_MAX_ROWS).contains(&rows) {
+                distance += AUTO_MAX_ROWS;
+            }
+            if rows != 0 && distance < best_distance {
+                best_distance = distance;
+                best = FbFontConfig { font, scale };
+            }
+        }
+    }
+    best
+}
+
+/// 8x16 glyphs copied out of the VGA font memory, and the 8x8 glyphs derived from them
+static FONT_8X16: SyncUnsafeCell<[u8; 256 * 16]> = SyncUnsafeCell::new([0; 256 * 16]);
+static FONT_8X8: SyncUnsafeCell<[u8; 256 * 8]> = SyncUnsafeCell::new([0; 256 * 8]);
+static FONT_CAPTURED: AtomicBool = AtomicBool::new(false);
+/// The console text output goes to once the display left VGA text mode, see [`set_active_console`]
+static ACTIVE_CONSOLE: SyncUnsafeCell<Option<FramebufferConsole>> = SyncUnsafeCell::new(None);
+
+/// Makes `console` the one [`with_active_console`] hands out, called once after the VBE mode switch
+pub fn set_active_console(console: FramebufferConsole) {
+    unsafe { *ACTIVE_CONSOLE.get() = Some(console) };
+}
+
+/// Runs `f` on the framebuffer console, None while the display is still in VGA text mode
+pub fn with_active_console<R>(f: impl FnOnce(&mut FramebufferConsole) -> R) -> Option<R> {
+    unsafe { (*ACTIVE_CONSOLE.get()).as_mut().map(f) }
+}
+
+/// Set once a VBE mode replaced the VGA text mode, 0xB8000 isn't on screen anymore
+static TEXT_MODE_LEFT: AtomicBool = AtomicBool::new(false);
+
+/// Called right after a VBE mode is set, screen output is dropped until [`set_active_console`]
+pub fn leave_text_mode() {
+    TEXT_MODE_LEFT.store(true, Ordering::Relaxed);
+}
+
+/// Writes `character` on the screen: on the framebuffer console once there is one, in the VGA text buffer while in text mode
+pub fn write_screen_char(character: u8) {
+    if with_active_console(|console| console.write_char(character)).is_some()
+        || TEXT_MODE_LEFT.load(Ordering::Relaxed)
+    {
+        return;
+    }
+    unsafe { Video::get().write_char(character) };
+}
+
+/// Copies the font the VGA card uses in text mode (plane 2) into the console fonts. <br>
+/// Must be called in the BIOS 80x25 text mode, before `text_mode=` loads the 8x8 font and before switching to a VBE mode. The 8x8 font keeps every row pair of the 8x16 one OR-ed together. <br>
+pub fn capture_vga_font() {
+    unsafe {
+        let map_mask = vga_read(VGA_SEQ_INDEX, 0x02);
+        let memory_mode = vga_read(VGA_SEQ_INDEX, 0x04);
+        let read_map = vga_read(VGA_GC_INDEX, 0x04);
+        let gc_mode = vga_read(VGA_GC_INDEX, 0x05);
+        let gc_misc = vga_read(VGA_GC_INDEX, 0x06);
+        let char_height = ((vga_read(VGA_CRTC_INDEX, 0x09) & 0x1F) as usize + 1).min(16);
+
+        // Sequential access to plane 2 at 0xA0000
+        vga_write(VGA_SEQ_INDEX, 0x02, 0x04);
+        vga_write(VGA_SEQ_INDEX, 0x04, 0x07);
+        vga_write(VGA_GC_INDEX, 0x04, 0x02);
+        vga_write(VGA_GC_INDEX, 0x05, 0x00);
+        vga_write(VGA_GC_INDEX, 0x06, 0x04);
+
+        let font16 = &mut *FONT_8X16.get();
+        for c in 0..256 {
+            for row in 0..16 {
+                font16[c * 16 + row] = if row < char_height {
+                    *((0xA0000 + c * 32 + row) as *const u8)
+                } else {
+                    0
+                };
+            }
+        }
+
+        vga_write(VGA_SEQ_INDEX, 0x02, map_mask);
+        vga_write(VGA_SEQ_INDEX, 0x04, memory_mode);
+        vga_write(VGA_GC_INDEX, 0x04, read_map);
+        vga_write(VGA_GC_INDEX, 0x05, gc_mode);
+        vga_write(VGA_GC_INDEX, 0x06, gc_misc);
+
+        let font8 = &mut *FONT_8X8.get();
+        for c in 0..256 {
+            for row in 0..8 {
+                font8[c * 8 + row] = font16[c * 16 + row * 2] | font16[c * 16 + row * 2 + 1];
+            }
+        }
+
+        printf!(b"Captured VGA font, character height %x\r\n", char_height);
+    }
+    FONT_CAPTURED.store(true, Ordering::Relaxed);
+}
+
+/// Text console drawn on a linear framebuffer. <br>
+/// Rows and columns are derived from the effective glyph size (font size times scale), query them with [`FramebufferConsole::geometry`]. <br>
+pub struct FramebufferConsole {
+    framebuffer: usize,
+    pitch: usize,
+    bytes_per_pixel: usize,
+    font: FbFont,
+    scale: usize,
+    columns: usize,
+    rows: usize,
+    x: usize,
+    y: usize,
+    foreground: u32,
+    background: u32,
+}
+
+impl FramebufferConsole {
+    /// Returns None if the font wasn't captured, the bpp isn't 16, 24 or 32, or not even one glyph fits
+    pub fn new(
+        framebuffer: usize,
+        width: usize,
+        height: usize,
+        pitch: usize,
+        bpp: u8,
+        font: Option<FbFontConfig>,
+    ) -> Option<Self> {
+        if !FONT_CAPTURED.load(Ordering::Relaxed) || !matches!(bpp, 16 | 24 | 32) {
+            return None;
+        }
+        let font = font.unwrap_or_else(|| auto_font(height));
+        let scale = font.scale.clamp(1, MAX_FONT_SCALE);
+        let columns = width / (8 * scale);
+        let rows = height / (font.font.glyph_height() * scale);
+        if columns == 0 || rows == 0 {
+            return None;
+        }
+        Some(Self {
+            framebuffer,
+            pitch,
+            bytes_per_pixel: bpp as usize / 8,
+            font: font.font,
+            scale,
+            columns,
+            rows,
+            x: 0,
+            y: 0,
+            foreground: 0xFFFF_FFFF,
+            background: 0,
+        })
+    }
+
+    /// Returns `(columns, rows)`
+    pub fn geometry(&self) -> (usize, usize) {
+        (self.columns, self.rows)
+    }
+
+    /// Returns the writing position, `(column, row)`
+    pub fn position(&self) -> (usize, usize) {
+        (self.x, self.y)
+    }
+
+    /// Returns the effective glyph size in pixels, `(width, height)`
+    pub fn glyph_size(&self) -> (usize, usize) {
+        (8 * self.scale, self.font.glyph_height() * self.scale)
+    }
+
+    fn glyph(&self, c: u8) -> &'static [u8] {
+        let h = self.font.glyph_height();
+        let start = c as usize * h;
+        let font: &'static [u8] = unsafe {
+            match self.font {
+                FbFont::Font8x8 => &*FONT_8X8.get(),
+                FbFont::Font8x16 => &*FONT_8X16.get(),
+            }
+        };
+        &font[start..start + h]
+    }
+
+    /// # Safety
+    /// `dst` must point to a pixel inside the framebuffer
+    #[inline(always)]
+    unsafe fn put_pixel(&self, dst: usize, color: u32) {
+        match self.bytes_per_pixel {
+            4 => (dst as *mut u32).write_volatile(color),
+            3 => {
+                (dst as *mut u16).write_volatile(color as u16);
+                ((dst + 2) as *mut u8).write_volatile((color >> 16) as u8);
+            }
+            _ => (dst as *mut u16).write_volatile(color as u16),
+        }
+    }
+
+    fn draw_glyph(&mut self, column: usize, row: usize, c: u8) {
+        self.draw_glyph_colored(column, row, c, self.foreground, self.background);
+    }
+
+    /// Packs a 0xRRGGBB color for the mode's bpp (8:8:8 for 24 and 32 bpp, 5:6:5 for 16 bpp)
+    fn pack_rgb(&self, rgb: u32) -> u32 {
+        match self.bytes_per_pixel {
+            2 => ((rgb >> 8) & 0xF800) | ((rgb >> 5) & 0x07E0) | ((rgb >> 3) & 0x001F),
+            _ => rgb,
+        }
+    }
+
+    fn draw_glyph_colored(
+        &mut self,
+        column: usize,
+        row: usize,
+        c: u8,
+        foreground: u32,
+        background: u32,
+    ) {
+        let (glyph_w, glyph_h) = self.glyph_size();
+        let base =
+            self.framebuffer + row * glyph_h * self.pitch + column * glyph_w * self.bytes_per_pixel;
+        for (gy, bits) in self.glyph(c).iter().enumerate() {
+            for sy in 0..self.scale {
+                let line = base + (gy * self.scale + sy) * self.pitch;
+                for gx in 0..8 {
+                    let color = if bits & (0x80 >> gx) != 0 {
+                        foreground
+                    } else {
+                        background
+                    };
+                    let pixel = line + gx * self.scale * self.bytes_per_pixel;
+                    for sx in 0..self.scale {
+                        unsafe { self.put_pixel(pixel + sx * self.bytes_per_pixel, color) };
+                    }
+                }
+            }
+        }
+    }
+
+    fn clear_rows(&mut self, first: usize, count: usize) {
+        let row_bytes = self.glyph_size().1 * self.pitch;
+        unsafe {
+            ((self.framebuffer + first * row_bytes) as *mut u8).write_bytes(0, count * row_bytes);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.clear_rows(0, self.rows);
+        self.x = 0;
+        self.y = 0;
+    }
+
+    pub fn scroll(&mut self) {
+        let row_bytes = self.glyph_size().1 * self.pitch;
+        unsafe {
+            core::ptr::copy(
+                (self.framebuffer + row_bytes) as *const u8,
+                self.framebuffer as *mut u8,
+                (self.rows - 1) * row_bytes,
+            );
+        }
+        self.clear_rows(self.rows - 1, 1);
+    }
+
+    fn line_feed(&mut self) {
+        self.x = 0;
+        if self.y + 1 >= self.rows {
+            self.scroll();
+        } else {
+            self.y += 1;
+        }
+    }
+
+    pub fn write_char(&mut self, c: u8) {
+        match c {
+            b'\n' => self.line_feed(),
+            b'\r' => self.x = 0,
+            _ => {
+                self.draw_glyph(self.x, self.y, c);
+                self.x += 1;
+                if self.x >= self.columns {
+                    self.line_feed();
+                }
+            }
+        }
+    }
+
+    pub fn write_string(&mut self, string: &[u8]) {
+        for c in string {
+            self.write_char(*c);
+        }
+    }
+}
+
+/// The 16 VGA text colors, as 0xRRGGBB
+const VGA_PALETTE: [u32; 16] = [
+    0x000000, 0x0000AA, 0x00AA00, 0x00AAAA, 0xAA0000, 0xAA00AA, 0xAA5500, 0xAAAAAA, 0x555555,
+    0x5555FF, 0x55FF55, 0x55FFFF, 0xFF5555, 0xFF55FF, 0xFFFF55, 0xFFFFFF,
+];
+
+/// Cells are drawn as glyphs, with the VGA attribute mapped to the VGA palette. The framebuffer can't be read back as cells.
+impl CellDisplay for FramebufferConsole {
+    fn size(&self) -> (usize, usize) {
+        self.geometry()
+    }
+
+    fn write_run(&mut self, x: usize, y: usize, cells: &[Character]) {
+        for (i, cell) in cells
+            .iter()
+            .enumerate()
+            .take(self.columns.saturating_sub(x))
+        {
+            let foreground = self.pack_rgb(VGA_PALETTE[(cell.color & 0x0F) as usize]);
+            let background = self.pack_rgb(VGA_PALETTE[(cell.color >> 4) as usize]);
+            self.draw_glyph_colored(x + i, y, cell.character, foreground, background);
+        }
+    }
+
+    fn read_cell(&self, _x: usize, _y: usize) -> Option<Character> {
+        None
+    }
+}
@@ -1,12 +1,17 @@
-use core::ptr;
+use core::{ptr, slice};
 
 use crate::{
-    bios::{DiskError, ExtendedDisk},
+    bios::{DiskError, ExtendedDisk, MAX_SECTOR_SIZE, MIN_SECTOR_SIZE},
+    e9::{hex_dump, write_buffer_as_escaped_string},
     gpt::DiskRange,
     kpanic,
-    mem::{Box, Buffer, RefIterVec, Vec},
+    log::{self, LogLevel},
+    log_warn,
+    mem::{Box, Buffer, EvictionPolicy, RefIterVec, SortedMap, Vec},
     printf,
+    stream::{StreamError, StreamSink},
     video::Video,
+    warnings::{warning, WarningId},
 };
 
 #[repr(C, packed)]
@@ -55,6 +60,11 @@ pub struct Ext2SuperBlock {
     pub journal_inode: u32,
     pub journal_device: u32,
     pub head_of_orphan_inode_list: u32,
+    pub hash_seed: [u32; 4],
+    pub default_hash_version: u8,
+    pub journal_backup_type: u8,
+    /// Size of a block group descriptor when `REQUIRED_FEATURE_64BIT` is set
+    pub group_descriptor_size: u16,
 }
 
 pub const EXT2_SUPERBLOCK_SIGNATURE: u16 = 0xEF53;
@@ -83,12 +93,29 @@ pub const REQUIRED_FEATURE_COMPRESSION: u32 = 0x1;
 pub const REQUIRED_FEATURE_DIRECTORY_ENTRIES_HAVE_TYPE_FIELD: u32 = 0x2;
 pub const REQUIRED_FEATURE_FS_NEEDS_TO_REPLAY_JOURNAL: u32 = 0x4;
 pub const REQUIRED_FEATURE_FS_USES_JOURNAL_DEVICE: u32 = 0x8;
+pub const REQUIRED_FEATURE_META_BLOCK_GROUPS: u32 = 0x10;
+pub const REQUIRED_FEATURE_EXTENTS: u32 = 0x40;
+pub const REQUIRED_FEATURE_64BIT: u32 = 0x80;
+pub const REQUIRED_FEATURE_MULTIPLE_MOUNT_PROTECTION: u32 = 0x100;
+pub const REQUIRED_FEATURE_FLEXIBLE_BLOCK_GROUPS: u32 = 0x200;
+pub const REQUIRED_FEATURE_INLINE_DATA: u32 = 0x8000;
+pub const REQUIRED_FEATURE_ENCRYPTION: u32 = 0x10000;
+/// Required features the reader can't handle, mounting fails when any is set
+const UNSUPPORTED_REQUIRED_FEATURES: u32 = REQUIRED_FEATURE_COMPRESSION
+    | REQUIRED_FEATURE_FS_USES_JOURNAL_DEVICE
+    | REQUIRED_FEATURE_META_BLOCK_GROUPS
+    | REQUIRED_FEATURE_INLINE_DATA
+    | REQUIRED_FEATURE_ENCRYPTION;
 
 pub const RO_FEATURE_SPARSE_DESCRIPTOR_TABLES: u32 = 0x1;
 pub const RO_FEATURE_64BIT_FILE_SIZE: u32 = 0x2;
 pub const RO_FEATURE_DIRECTORY_CONTENT_IN_BINARY_TREE: u32 = 0x4;
 
 const BLOCK_GROUP_DESCRIPTOR_SIZE: usize = 32;
+/// Bytes of a superblock without the ext2 signature that get dumped
+const SUPERBLOCK_DUMP_SIZE: usize = 128;
+/// Smallest valid `group_descriptor_size` with `REQUIRED_FEATURE_64BIT`
+const BLOCK_GROUP_DESCRIPTOR_SIZE_64BIT: usize = 64;
 
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
@@ -134,6 +161,8 @@ pub const INODE_TYPE_BLOCK_DEVICE: u16 = 0x6000;
 pub const INODE_TYPE_REGULAR_FILE: u16 = 0x8000;
 pub const INODE_TYPE_SYMLINK: u16 = 0xA000;
 pub const INODE_TYPE_UNIX_SOCKET: u16 = 0xC000;
+/// Bits of `type_and_permissions` holding one of the `INODE_TYPE_*` values
+pub const INODE_TYPE_MASK: u16 = 0xF000;
 
 pub const INODE_PERMISSION_OTHER_EXECUTE: u16 = 0x1;
 pub const INODE_PERMISSION_OTHER_WRITE: u16 = 0x2;
@@ -159,6 +188,65 @@ pub const INODE_FLAG_NO_UPDATE_ATIME: u32 = 0x80;
 pub const INODE_FLAG_HASH_INDEXED_DIRECTORY: u32 = 0x10000;
 pub const INODE_FLAG_AFS_DIRECTORY: u32 = 0x20000;
 pub const INODE_FLAG_JOURNAL_FILE_DATA: u32 = 0x40000;
+/// The block pointers hold an ext4 extent tree instead of a block map
+pub const INODE_FLAG_EXTENTS: u32 = 0x80000;
+
+const EXTENT_HEADER_MAGIC: u16 = 0xF30A;
+/// Header, extents and indices are all 12 bytes
+const EXTENT_ENTRY_SIZE: usize = 12;
+/// Linux never builds deeper trees
+const EXTENT_MAX_DEPTH: u16 = 5;
+/// Extents longer than this are uninitialized (read as zeros), their length is `len - EXTENT_UNINIT_LEN`
+const EXTENT_UNINIT_LEN: u16 = 32768;
+
+/// The extent containing the last mapped block: `len` blocks from `logical` map to `physical`, or are a zero filled hole when `physical` is 0
+#[derive(Clone, Copy)]
+struct CachedExtent {
+    logical: u32,
+    len: u32,
+    physical: u64,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// The 60 bytes of `i_block`, where ext4 stores the root of the extent tree
+fn inode_block_area(inode: &Ext2Inode) -> [u8; 60] {
+    let mut area = [0u8; 60];
+    let pointers = inode.direct_block_pointers;
+    let indirect = [
+        inode.single_indirect_block_pointer,
+        inode.double_indirect_block_pointer,
+        inode.triple_indirect_block_pointer,
+    ];
+    for (i, pointer) in pointers.iter().chain(indirect.iter()).enumerate() {
+        area[i * 4..i * 4 + 4].copy_from_slice(&pointer.to_le_bytes());
+    }
+    area
+}
+
+/// Returns `(entry count, depth)` of the extent node in `node`, checking its magic and that its entries fit
+fn extent_node_header(node: &[u8]) -> Result<(usize, u16), Ext2Error> {
+    if node.len() < EXTENT_ENTRY_SIZE || read_u16(node, 0) != EXTENT_HEADER_MAGIC {
+        return Err(Ext2Error::BadExtentTree);
+    }
+    let entries = read_u16(node, 2) as usize;
+    let depth = read_u16(node, 6);
+    if (entries + 1) * EXTENT_ENTRY_SIZE > node.len() || depth > EXTENT_MAX_DEPTH {
+        return Err(Ext2Error::BadExtentTree);
+    }
+    Ok((entries, depth))
+}
 
 pub enum Ext2Error {
     BadBlockGroupDescriptorTableEntrySize(usize, usize),
@@ -176,8 +264,31 @@ pub enum Ext2Error {
     BadSuperblock,
     NullPointer,
     NotFound,
+    /// An extent node has a bad magic, too many entries or is too deep
+    BadExtentTree,
+    /// The filesystem needs these required (incompat) features, which the reader doesn't support
+    UnsupportedFeatures(u32),
+    /// A path has more components than [`MAX_PATH_DEPTH`]
+    PathTooDeep(usize),
+    /// Inode reached again by the path component at this index, the directory tree loops
+    DirectoryLoop(u32, usize),
+    /// The path component at this index doesn't exist, or a directory was expected there and a file was found
+    FileNotFound(usize),
+    /// More than [`MAX_SYMLINK_HOPS`] symlinks were followed while opening a path, they probably form a cycle
+    TooManySymlinks,
+    /// The symlink at this inode has an empty target or one longer than a block
+    BadSymlink(usize),
+    /// Block, its first LBA, and the partition's first and last LBA: the block isn't inside the partition
+    BlockOutOfRange(u64, u64, u64, u64),
 }
 
+/// Most components a path may have, bounds the work of a single resolution
+pub const MAX_PATH_DEPTH: usize = 64;
+/// Most symlinks followed while opening a single path
+pub const MAX_SYMLINK_HOPS: usize = 8;
+/// Symlink targets shorter than this are stored in the inode's block pointers
+const FAST_SYMLINK_MAX_LEN: usize = 60;
+
 impl Ext2Error {
     pub fn panic(&self) -> ! {
         unsafe {
@@ -249,6 +360,54 @@ impl Ext2Error {
                 Ext2Error::NotFound => {
                     video.write_string(b"Not found\n");
                 }
+                Ext2Error::FileNotFound(index) => {
+                    video.write_string(b"File not found (path component 0x");
+                    video.write_hex_u32(*index as u32);
+                    video.write_string(b")\n");
+                }
+                Ext2Error::TooManySymlinks => {
+                    video.write_string(b"Too many levels of symbolic links\n");
+                }
+                Ext2Error::BadSymlink(inode) => {
+                    video.write_string(b"Bad symbolic link at inode 0x");
+                    video.write_hex_u32(*inode as u32);
+                    video.write_char(b'\n');
+                }
+                Ext2Error::BadExtentTree => {
+                    video.write_string(b"Corrupted ext4 extent tree\n");
+                }
+                Ext2Error::UnsupportedFeatures(features) => {
+                    video.write_string(b"Unsupported filesystem features: 0x");
+                    video.write_hex_u32(*features);
+                    video.write_char(b'\n');
+                }
+                Ext2Error::PathTooDeep(depth) => {
+                    video.write_string(b"Path too deep: 0x");
+                    video.write_hex_u32(*depth as u32);
+                    video.write_string(b" components\n");
+                }
+                Ext2Error::DirectoryLoop(inode, index) => {
+                    video.write_string(b"Directory loop: inode 0x");
+                    video.write_hex_u32(*inode);
+                    video.write_string(b" reached again by path component 0x");
+                    video.write_hex_u32(*index as u32);
+                    video.write_char(b'\n');
+                }
+                Ext2Error::BlockOutOfRange(block, lba, start, end) => {
+                    video.write_string(b"Block 0x");
+                    video.write_hex_u32((*block >> 32) as u32);
+                    video.write_hex_u32(*block as u32);
+                    video.write_string(b" at LBA 0x");
+                    video.write_hex_u32((*lba >> 32) as u32);
+                    video.write_hex_u32(*lba as u32);
+                    video.write_string(b" is outside the partition (LBA 0x");
+                    video.write_hex_u32((*start >> 32) as u32);
+                    video.write_hex_u32(*start as u32);
+                    video.write_string(b" to 0x");
+                    video.write_hex_u32((*end >> 32) as u32);
+                    video.write_hex_u32(*end as u32);
+                    video.write_string(b"), corrupt filesystem\n");
+                }
             }
         }
         kpanic();
@@ -381,7 +540,10 @@ impl InodeReadingLocation {
 pub struct CachedInodeReadingLocation {
     location: InodeReadingLocation,
     inode: Ext2Inode,
-    max_block: usize,
+    /// Size in bytes, 64-bit for regular files when the filesystem has large files
+    size: u64,
+    /// Blocks holding data, 0 for an empty file
+    block_count: usize,
 
     table1: Buffer,
     table1_addr: usize,
@@ -391,6 +553,14 @@ pub struct CachedInodeReadingLocation {
 
     table3: Buffer,
     table3_addr: usize,
+
+    /// Whether the inode uses an extent tree, the tables then cache extent nodes: table1 the leaf, table2 and table3 the index nodes
+    extents: bool,
+    extent: Option<CachedExtent>,
+    /// Physical block of the current location, resolved on seek and advance when using extents
+    mapped_block: usize,
+    /// Holes read as zeros so far
+    sparse_blocks: usize,
 }
 
 impl CachedInodeReadingLocation {
@@ -401,26 +571,150 @@ impl CachedInodeReadingLocation {
         }
         let location =
             InodeReadingLocation::new(ext2.block_size() / 4, 0).ok_or(Ext2Error::NullBlockSize)?;
-        let table1 = Buffer::new(size).ok_or(Ext2Error::FailedMemAlloc(size))?;
-        let table2 = Buffer::new(size).ok_or(Ext2Error::FailedMemAlloc(size))?;
-        let table3 = Buffer::new(size).ok_or(Ext2Error::FailedMemAlloc(size))?;
-
-        let max_block = (inode.size_lo as usize) / size;
+        let table1 = Buffer::new_tagged(size, b"ext2").ok_or(Ext2Error::FailedMemAlloc(size))?;
+        let table2 = Buffer::new_tagged(size, b"ext2").ok_or(Ext2Error::FailedMemAlloc(size))?;
+        let table3 = Buffer::new_tagged(size, b"ext2").ok_or(Ext2Error::FailedMemAlloc(size))?;
+
+        let large_file = ext2.superblock.readonly_or_support_features & RO_FEATURE_64BIT_FILE_SIZE
+            != 0
+            && inode.type_and_permissions & INODE_TYPE_MASK == INODE_TYPE_REGULAR_FILE;
+        let file_size = if large_file {
+            ((inode.size_hi_or_dir_acl as u64) << 32) | inode.size_lo as u64
+        } else {
+            inode.size_lo as u64
+        };
+        let block_count = usize::try_from(file_size.div_ceil(size as u64))
+            .map_err(|_| Ext2Error::InvalidArgument)?;
 
-        Ok(Self {
+        let fd = Self {
             location,
             inode,
-            max_block,
+            size: file_size,
+            block_count,
             table1_addr: 0,
             table2_addr: 0,
             table3_addr: 0,
             table1,
             table2,
             table3,
-        })
+            extents: inode.flags & INODE_FLAG_EXTENTS != 0,
+            extent: None,
+            mapped_block: 0,
+            sparse_blocks: 0,
+        };
+        if fd.extents {
+            extent_node_header(&inode_block_area(&inode))?;
+        }
+        Ok(fd)
+    }
+
+    /// Reads extent node `block` into the given table (1 to 3), unless it is already there
+    fn read_extent_node(
+        &mut self,
+        ext2: &mut Ext2FileSystem,
+        table: usize,
+        block: usize,
+    ) -> Result<(), Ext2Error> {
+        let (buffer, addr) = match table {
+            1 => (&mut self.table1, &mut self.table1_addr),
+            2 => (&mut self.table2, &mut self.table2_addr),
+            _ => (&mut self.table3, &mut self.table3_addr),
+        };
+        if *addr != block {
+            *addr = 0;
+            ext2.read_block(block as u64, buffer)?;
+            *addr = block;
+        }
+        Ok(())
+    }
+
+    /// Maps logical block `logical` through the extent tree, 0 for holes and uninitialized extents
+    fn map_extent(&mut self, ext2: &mut Ext2FileSystem, logical: u32) -> Result<usize, Ext2Error> {
+        let extent = match self.extent {
+            Some(extent) if logical >= extent.logical && logical - extent.logical < extent.len => {
+                extent
+            }
+            _ => {
+                let root = inode_block_area(&self.inode);
+                // 0 is the root in the inode, 1 to 3 are the tables
+                let mut table = 0;
+                let mut level = 0;
+                loop {
+                    let node: &[u8] = match table {
+                        0 => &root,
+                        1 => &self.table1,
+                        2 => &self.table2,
+                        _ => &self.table3,
+                    };
+                    let (entries, depth) = extent_node_header(node)?;
+                    // Entries are sorted by their first logical block, the last one starting at or before `logical` may contain it
+                    let found = (0..entries)
+                        .map(|i| (i + 1) * EXTENT_ENTRY_SIZE)
+                        .take_while(|offset| read_u32(node, *offset) <= logical)
+                        .last();
+                    if depth == 0 {
+                        let extent = found.map(|offset| {
+                            let len = read_u16(node, offset + 4);
+                            let physical = ((read_u16(node, offset + 6) as u64) << 32)
+                                | read_u32(node, offset + 8) as u64;
+                            CachedExtent {
+                                logical: read_u32(node, offset),
+                                len: if len > EXTENT_UNINIT_LEN {
+                                    (len - EXTENT_UNINIT_LEN) as u32
+                                } else {
+                                    len as u32
+                                },
+                                physical: if len > EXTENT_UNINIT_LEN { 0 } else { physical },
+                            }
+                        });
+                        match extent {
+                            Some(extent) if logical - extent.logical < extent.len => break extent,
+                            // Hole in the file
+                            _ => return Ok(0),
+                        }
+                    }
+                    let Some(offset) = found else {
+                        return Ok(0);
+                    };
+                    let child = ((read_u16(node, offset + 8) as u64) << 32)
+                        | read_u32(node, offset + 4) as u64;
+                    let child = usize::try_from(child).map_err(|_| Ext2Error::BadExtentTree)?;
+                    level += 1;
+                    if level > EXTENT_MAX_DEPTH {
+                        return Err(Ext2Error::BadExtentTree);
+                    }
+                    table = if depth == 1 {
+                        1
+                    } else if level == 1 {
+                        2
+                    } else {
+                        3
+                    };
+                    self.read_extent_node(ext2, table, child)?;
+                }
+            }
+        };
+        self.extent = Some(extent);
+        if extent.physical == 0 {
+            return Ok(0);
+        }
+        usize::try_from(extent.physical + (logical - extent.logical) as u64)
+            .map_err(|_| Ext2Error::BadExtentTree)
+    }
+
+    /// Resolves the physical block of the current location when using extents
+    fn update_mapping(&mut self, ext2: &mut Ext2FileSystem) -> Result<(), Ext2Error> {
+        if self.extents {
+            let logical = self.location.current_idx() as u32;
+            self.mapped_block = self.map_extent(ext2, logical)?;
+        }
+        Ok(())
     }
 
     fn check_table1(&mut self, ext2: &mut Ext2FileSystem) -> Result<(), Ext2Error> {
+        if self.extents {
+            return Ok(());
+        }
         let addr = match self.location.location {
             InodeReadingLocationInfo::Direct(_) => 0,
             InodeReadingLocationInfo::Single(_) => self.inode.single_indirect_block_pointer,
@@ -447,6 +741,10 @@ impl CachedInodeReadingLocation {
     }
 
     fn follow1(&self, idx: usize) -> Result<usize, Ext2Error> {
+        // No table, the whole range it would map is a hole
+        if self.table1_addr == 0 {
+            return Ok(0);
+        }
         if idx * 4 < self.table1.len() {
             let entry = unsafe { *(self.table1.get_ptr().add(idx * 4) as *const u32) };
             Ok(entry as usize)
@@ -456,6 +754,9 @@ impl CachedInodeReadingLocation {
     }
 
     fn check_table2(&mut self, ext2: &mut Ext2FileSystem) -> Result<(), Ext2Error> {
+        if self.extents {
+            return Ok(());
+        }
         let addr = match self.location.location {
             InodeReadingLocationInfo::Direct(_) => 0,
             InodeReadingLocationInfo::Single(_) => 0,
@@ -482,6 +783,10 @@ impl CachedInodeReadingLocation {
     }
 
     fn follow2(&self, idx: usize) -> Result<usize, Ext2Error> {
+        // No table, the whole range it would map is a hole
+        if self.table2_addr == 0 {
+            return Ok(0);
+        }
         if idx * 4 < self.table2.len() {
             let entry = unsafe { *(self.table2.get_ptr().add(idx * 4) as *const u32) };
             Ok(entry as usize)
@@ -491,6 +796,9 @@ impl CachedInodeReadingLocation {
     }
 
     fn check_table3(&mut self, ext2: &mut Ext2FileSystem) -> Result<(), Ext2Error> {
+        if self.extents {
+            return Ok(());
+        }
         let addr = match self.location.location {
             InodeReadingLocationInfo::Direct(_) => 0,
             InodeReadingLocationInfo::Single(_) => 0,
@@ -517,6 +825,10 @@ impl CachedInodeReadingLocation {
     }
 
     fn follow3(&self, idx: usize) -> Result<usize, Ext2Error> {
+        // No table, the whole range it would map is a hole
+        if self.table3_addr == 0 {
+            return Ok(0);
+        }
         if idx * 4 < self.table3.len() {
             let entry = unsafe { *(self.table3.get_ptr().add(idx * 4) as *const u32) };
             Ok(entry as usize)
@@ -531,10 +843,15 @@ impl CachedInodeReadingLocation {
         self.check_table1(ext2)?;
         self.check_table2(ext2)?;
         self.check_table3(ext2)?;
+        self.update_mapping(ext2)?;
         Ok(())
     }
 
+    /// Physical block of the current location, 0 for a hole
     pub fn get_next_block(&self) -> Result<usize, Ext2Error> {
+        if self.extents {
+            return Ok(self.mapped_block);
+        }
         Ok(match self.location.location {
             InodeReadingLocationInfo::Direct(direct) => {
                 if direct >= 12 {
@@ -560,25 +877,36 @@ impl CachedInodeReadingLocation {
         if buffer.len() < bs {
             return Err(Ext2Error::BufferTooSmall(buffer.len(), bs));
         }
-        let block = self.get_next_block()?;
         let block_idx = self.location.current_idx();
-        ext2.read_block(block as u64, buffer)?;
-        if block_idx < self.max_block {
+        // Past the end, and the only case of an empty file: nothing to read, not even block pointer 0
+        if block_idx >= self.block_count {
+            return Ok(0);
+        }
+        let block = self.get_next_block()?;
+        if block == 0 {
+            // Hole, block 0 is the boot block and superblock area, never file data
+            buffer[..bs].fill(0);
+            self.sparse_blocks += 1;
+        } else {
+            ext2.read_block(block as u64, buffer)?;
+        }
+        if block_idx + 1 < self.block_count {
             Ok(bs)
         } else {
-            let read = (self.inode.size_lo as usize) % bs;
-            Ok(if read == 0 { bs } else { read })
+            Ok((self.size - (block_idx as u64) * (bs as u64)) as usize)
         }
     }
 
+    /// Moves to the next block, false at the last block of the file or when it has none
     pub fn advance(&mut self, ext2: &mut Ext2FileSystem) -> Result<bool, Ext2Error> {
         let block = self.location.current_idx();
-        if block >= self.max_block || !self.location.advance() {
+        if block + 1 >= self.block_count || !self.location.advance() {
             return Ok(false);
         }
         self.check_table1(ext2)?;
         self.check_table2(ext2)?;
         self.check_table3(ext2)?;
+        self.update_mapping(ext2)?;
         Ok(true)
     }
 }
@@ -590,6 +918,8 @@ pub struct Ext2File<'a> {
     cached_buffer_block: usize,
     cached_buffer_size: usize,
     curr_offset: usize,
+    /// Contents served instead of the inode data, see [`Ext2File::into_memory`]
+    memory: Option<Buffer>,
 }
 
 impl<'a> Ext2File<'a> {
@@ -601,10 +931,11 @@ impl<'a> Ext2File<'a> {
         let mut value = Self {
             fd,
             ext2,
-            block_buffer: Buffer::new(bs).ok_or(Ext2Error::FailedMemAlloc(bs))?,
+            block_buffer: Buffer::new_tagged(bs, b"ext2").ok_or(Ext2Error::FailedMemAlloc(bs))?,
             cached_buffer_block: 0,
             cached_buffer_size: 0,
             curr_offset: 0,
+            memory: None,
         };
         value.internal_update_buffer()?;
         Ok(value)
@@ -616,15 +947,27 @@ impl<'a> Ext2File<'a> {
         Ok(())
     }
 
+    /// Replaces the file contents with `contents`, like the decompressed image of a compressed file. <br>
+    /// Reads, seeks and streams then come from memory, the filesystem stays borrowed. <br>
+    pub fn into_memory(mut self, contents: Buffer) -> Self {
+        self.memory = Some(contents);
+        self.curr_offset = 0;
+        self
+    }
+
     pub fn seek(&mut self, offset: usize) -> Result<(), Ext2Error> {
-        if offset >= self.fd.inode.size_lo as usize {
+        if offset >= self.get_size() {
             printf!(
                 b"Invalid offset: %x (max size: %x)\n",
                 offset,
-                self.fd.inode.size_lo as usize
+                self.get_size()
             );
             return Err(Ext2Error::InvalidArgument);
         }
+        if self.memory.is_some() {
+            self.curr_offset = offset;
+            return Ok(());
+        }
         let bs = self.ext2.block_size();
         if bs == 0 {
             return Err(Ext2Error::NullBlockSize);
@@ -636,8 +979,29 @@ impl<'a> Ext2File<'a> {
     }
 
     pub fn read(&mut self, buffer: &mut Buffer, max_count: usize) -> Result<usize, Ext2Error> {
-        if max_count > buffer.len() {
-            return Err(Ext2Error::BufferTooSmall(max_count, buffer.len()));
+        self.read_at(buffer, 0, max_count)
+    }
+
+    /// Like [`Ext2File::read`], but stores the data at `buffer_offset` in `buffer`
+    pub fn read_at(
+        &mut self,
+        buffer: &mut Buffer,
+        buffer_offset: usize,
+        max_count: usize,
+    ) -> Result<usize, Ext2Error> {
+        if buffer_offset + max_count > buffer.len() {
+            return Err(Ext2Error::BufferTooSmall(
+                buffer_offset + max_count,
+                buffer.len(),
+            ));
+        }
+        if let Some(memory) = &self.memory {
+            let count = max_count.min(memory.len().saturating_sub(self.curr_offset));
+            if !memory.copy_to(self.curr_offset, buffer, buffer_offset, count) {
+                return Err(Ext2Error::BufferCopyError);
+            }
+            self.curr_offset += count;
+            return Ok(count);
         }
         let bs = self.ext2.block_size();
         if bs == 0 {
@@ -647,9 +1011,13 @@ impl<'a> Ext2File<'a> {
         let mut read = 0;
         if current_block == self.cached_buffer_block {
             let curr_off = self.curr_offset % bs;
-            let block_rem = bs - curr_off;
+            // The last block of the file is only partly data
+            let block_rem = self.cached_buffer_size.saturating_sub(curr_off);
             let to_copy = max_count.min(block_rem);
-            if !self.block_buffer.copy_to(curr_off, buffer, 0, to_copy) {
+            if !self
+                .block_buffer
+                .copy_to(curr_off, buffer, buffer_offset, to_copy)
+            {
                 return Err(Ext2Error::BufferCopyError);
             }
             read = to_copy;
@@ -663,7 +1031,10 @@ impl<'a> Ext2File<'a> {
             self.internal_update_buffer()?;
 
             let rem_copy = (max_count - read).min(self.cached_buffer_size);
-            if !self.block_buffer.copy_to(0, buffer, read, rem_copy) {
+            if !self
+                .block_buffer
+                .copy_to(0, buffer, buffer_offset + read, rem_copy)
+            {
                 return Err(Ext2Error::BufferCopyError);
             }
             read += rem_copy;
@@ -673,15 +1044,118 @@ impl<'a> Ext2File<'a> {
         Ok(read)
     }
 
+    /// Feeds up to `max_count` bytes from the current offset to `sink`, straight from the block buffer. <br>
+    /// Stops at the first sink error. Returns how many bytes were streamed, fewer at the end of the file. <br>
+    pub fn stream_to<S: StreamSink>(
+        &mut self,
+        sink: &mut S,
+        max_count: usize,
+    ) -> Result<usize, StreamError> {
+        let bs = self.ext2.block_size();
+        if bs == 0 {
+            return Err(StreamError::Ext2Error(Ext2Error::NullBlockSize));
+        }
+        if let Some(memory) = &self.memory {
+            // Block sized chunks, like a file read from the disk
+            let end = memory.len().min(self.curr_offset.saturating_add(max_count));
+            let start = self.curr_offset.min(end);
+            for chunk in memory[start..end].chunks(bs) {
+                sink.update(chunk)?;
+                self.curr_offset += chunk.len();
+            }
+            return Ok(end - start);
+        }
+        let mut streamed = 0;
+        if self.curr_offset / bs == self.cached_buffer_block {
+            let curr_off = self.curr_offset % bs;
+            let len = max_count.min(self.cached_buffer_size.saturating_sub(curr_off));
+            sink.update(&self.block_buffer[curr_off..curr_off + len])?;
+            streamed = len;
+            self.curr_offset += len;
+        }
+
+        while streamed < max_count {
+            if !self.fd.advance(self.ext2).map_err(StreamError::Ext2Error)? {
+                break;
+            }
+            self.internal_update_buffer()
+                .map_err(StreamError::Ext2Error)?;
+
+            let len = (max_count - streamed).min(self.cached_buffer_size);
+            sink.update(&self.block_buffer[..len])?;
+            streamed += len;
+            self.curr_offset += len;
+        }
+
+        Ok(streamed)
+    }
+
     pub fn read_all(&mut self) -> Result<Buffer, Ext2Error> {
-        let len = self.fd.inode.size_lo as usize;
-        let mut buffer = Buffer::new(len).ok_or(Ext2Error::FailedMemAlloc(len))?;
+        let len = self.get_size();
+        let mut buffer = Buffer::new_tagged(len, b"ext2").ok_or(Ext2Error::FailedMemAlloc(len))?;
         self.read(&mut buffer, len)?;
         Ok(buffer)
     }
 
     pub fn get_size(&self) -> usize {
-        self.fd.inode.size_lo as usize
+        match &self.memory {
+            Some(memory) => memory.len(),
+            // Capped, a file larger than the address space can't be read whole anyway
+            None => usize::try_from(self.fd.size).unwrap_or(usize::MAX),
+        }
+    }
+
+    /// Full 64-bit size of a regular file, `size_hi` holds the upper half when the filesystem has large files
+    pub fn get_size64(&self) -> u64 {
+        if let Some(memory) = &self.memory {
+            return memory.len() as u64;
+        }
+        self.fd.size
+    }
+
+    /// Holes of the file read as zeros so far, by reads and streams
+    pub fn sparse_blocks(&self) -> usize {
+        self.fd.sparse_blocks
+    }
+
+    /// Last modification time, in seconds since the UNIX epoch
+    pub fn get_mtime(&self) -> u32 {
+        self.fd.inode.mtime
+    }
+
+    /// Overwrites the file from its beginning, without allocating blocks nor changing its size. <br>
+    /// Writes at most `get_size()` bytes and returns how many bytes were written. <br>
+    pub fn write_in_place(&mut self, data: &[u8]) -> Result<usize, Ext2Error> {
+        if self.memory.is_some() {
+            return Err(Ext2Error::InvalidArgument);
+        }
+        let bs = self.ext2.block_size();
+        if bs == 0 {
+            return Err(Ext2Error::NullBlockSize);
+        }
+        let len = data.len().min(self.get_size());
+
+        self.fd.seek(self.ext2, 0)?;
+        let mut written = 0;
+        while written < len {
+            let block = self.fd.get_next_block()?;
+            if block == 0 {
+                // Sparse hole, writing would need a block allocation
+                return Err(Ext2Error::NullPointer);
+            }
+            self.ext2.read_block(block as u64, &mut self.block_buffer)?;
+            let count = (len - written).min(bs);
+            self.block_buffer[..count].copy_from_slice(&data[written..(written + count)]);
+            self.ext2.write_block(block as u64, &self.block_buffer)?;
+            written += count;
+
+            if written < len && !self.fd.advance(self.ext2)? {
+                break;
+            }
+        }
+
+        self.seek(0)?;
+        Ok(written)
     }
 }
 
@@ -721,6 +1195,17 @@ impl Ext2DirectoryEntry {
         &self.name
     }
 
+    /// Whether this entry can be matched by path resolution. <br>
+    /// Names that are empty or contain '/' or NUL can't be expressed as a path component. <br>
+    pub fn is_path_component(&self) -> bool {
+        !self.name.is_empty() && !self.name.iter().any(|c| c == b'/' || c == 0)
+    }
+
+    /// Whether this entry is the literal "." or ".." entry, which path resolution never matches by name
+    pub fn is_dot_entry(&self) -> bool {
+        self.has_name(b".") || self.has_name(b"..")
+    }
+
     pub fn get_inode(&self) -> u32 {
         self.inode
     }
@@ -730,10 +1215,14 @@ pub struct Ext2Directory<'a> {
     ext2: &'a mut Ext2FileSystem,
     fd: CachedInodeReadingLocation,
     entries: Vec<Ext2DirectoryEntry>,
-    self_entry: usize,
-    parent_entry: usize,
+    /// Index of the live "." and ".." entries, a directory missing either fails to parse
+    self_entry: Option<usize>,
+    parent_entry: Option<usize>,
 }
 
+/// Entries an [`Ext2Directory`] has room for before its vector grows
+const DIRECTORY_ENTRIES_CAPACITY: usize = 16;
+
 impl<'a> Ext2Directory<'a> {
     fn new(
         fd: CachedInodeReadingLocation,
@@ -742,14 +1231,16 @@ impl<'a> Ext2Directory<'a> {
         let mut dir = Ext2Directory {
             ext2,
             fd,
-            entries: Vec::default(),
-            self_entry: 0,
-            parent_entry: 0,
+            entries: Vec::try_new(DIRECTORY_ENTRIES_CAPACITY).ok_or(Ext2Error::FailedMemAlloc(
+                DIRECTORY_ENTRIES_CAPACITY * size_of::<Ext2DirectoryEntry>(),
+            ))?,
+            self_entry: None,
+            parent_entry: None,
         };
         // Allocate buffers
-        let mut buffer = Buffer::new(dir.fd.inode.size_lo as usize)
+        let mut buffer = Buffer::new_tagged(dir.fd.inode.size_lo as usize, b"ext2")
             .ok_or(Ext2Error::FailedMemAlloc(dir.fd.inode.size_lo as usize))?;
-        let mut block_buffer = Buffer::new(dir.ext2.block_size())
+        let mut block_buffer = Buffer::new_tagged(dir.ext2.block_size(), b"ext2")
             .ok_or(Ext2Error::FailedMemAlloc(dir.ext2.block_size()))?;
 
         // Read content
@@ -780,7 +1271,7 @@ impl<'a> Ext2Directory<'a> {
 
             let mut entry = Ext2DirectoryEntry {
                 inode: entry_raw.inode,
-                name: Buffer::new(name_entry_len)
+                name: Buffer::new_tagged(name_entry_len, b"ext2")
                     .ok_or(Ext2Error::FailedMemAlloc(name_entry_len))?,
             };
 
@@ -793,33 +1284,46 @@ impl<'a> Ext2Directory<'a> {
                 return Err(Ext2Error::DirectoryParseFailed);
             }
 
-            if entry.has_name(b".") {
-                dir.self_entry = dir.entries.len();
-            }
-            if entry.has_name(b"..") {
-                dir.parent_entry = dir.entries.len();
-            }
-
             idx += entry_raw.entry_size as usize;
             if entry.inode != 0 {
-                dir.entries.push(entry);
+                if entry.name.is_empty() {
+                    printf!(
+                        b"Skipping empty-name directory entry (inode 0x%x)\r\n",
+                        entry.inode
+                    );
+                    continue;
+                }
+                if entry.has_name(b".") {
+                    dir.self_entry = Some(dir.entries.len());
+                }
+                if entry.has_name(b"..") {
+                    dir.parent_entry = Some(dir.entries.len());
+                }
+                if dir.entries.try_push(entry).is_err() {
+                    return Err(Ext2Error::FailedMemAlloc(
+                        (dir.entries.len() + 1) * size_of::<Ext2DirectoryEntry>(),
+                    ));
+                }
                 continue;
             }
         }
 
+        if dir.self_entry.is_none() || dir.parent_entry.is_none() {
+            return Err(Ext2Error::DirectoryParseFailed);
+        }
         Ok(dir)
     }
 
     pub fn get_inode(&self) -> u32 {
-        self.entries
-            .get(self.self_entry)
+        self.self_entry
+            .and_then(|i| self.entries.get(i))
             .unwrap_or_else(|| kpanic())
             .inode
     }
 
     pub fn get_parent_inode(&self) -> u32 {
-        self.entries
-            .get(self.parent_entry)
+        self.parent_entry
+            .and_then(|i| self.entries.get(i))
             .unwrap_or_else(|| kpanic())
             .inode
     }
@@ -829,11 +1333,184 @@ impl<'a> Ext2Directory<'a> {
     }
 }
 
+/// One traversed path component: the directory searched, the exact name bytes matched and the resulting inode
+pub struct Ext2PathStep {
+    pub directory_inode: u32,
+    pub name: Buffer,
+    pub child_inode: u32,
+}
+
+/// The result of resolving a path, along with the exact chain of entries that led to it
+pub struct Ext2PathResolution {
+    pub inode: usize,
+    pub steps: Vec<Ext2PathStep>,
+}
+
+impl Ext2PathResolution {
+    pub fn printf(&self) {
+        printf!(b"/");
+        for step in self.steps.iter() {
+            printf!(b" -> [dir 0x%x] \"", step.directory_inode);
+            write_buffer_as_escaped_string(&step.name);
+            printf!(b"\" -> inode 0x%x", step.child_inode);
+        }
+        printf!(b"\r\n");
+    }
+}
+
 pub enum Ext2FileType<'a> {
     File(Ext2File<'a>),
     Directory(Ext2Directory<'a>),
 }
 
+/// Longest name kept in the lookup cache, longer names always go through the directory reader
+const LOOKUP_CACHE_NAME_LEN: usize = 32;
+const LOOKUP_CACHE_ENTRIES: usize = 64;
+const INODE_CACHE_ENTRIES: usize = 16;
+/// Blocks kept by the block cache, 32KiB to 128KiB of heap depending on the block size
+const BLOCK_CACHE_ENTRIES: usize = 32;
+
+/// (parent inode, name hash, name length, zero padded name)
+type LookupCacheKey = (u32, u32, u8, [u8; LOOKUP_CACHE_NAME_LEN]);
+
+/// Remembers resolved (parent inode, name) -> child inode pairs, recently read inodes and recently read blocks. <br>
+/// The mount is read-only (file writes never touch metadata), so lookups and inodes never need invalidation. Written blocks are updated in place, or dropped when the write fails. <br>
+/// All tables evict the least recently used entry. <br>
+struct Ext2LookupCache {
+    lookups: SortedMap<LookupCacheKey, u32>,
+    inodes: SortedMap<u32, Ext2Inode>,
+    blocks: SortedMap<u64, Buffer>,
+    hits: usize,
+    misses: usize,
+    inode_hits: usize,
+    inode_misses: usize,
+    block_hits: usize,
+    block_misses: usize,
+}
+
+/// FNV-1a
+fn hash_name(name: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811C_9DC5;
+    for c in name {
+        hash ^= *c as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+/// `None` for names too long to be cached
+fn lookup_cache_key(parent: u32, name: &[u8]) -> Option<LookupCacheKey> {
+    if name.len() > LOOKUP_CACHE_NAME_LEN {
+        return None;
+    }
+    let mut padded = [0; LOOKUP_CACHE_NAME_LEN];
+    padded[..name.len()].copy_from_slice(name);
+    Some((parent, hash_name(name), name.len() as u8, padded))
+}
+
+impl Ext2LookupCache {
+    fn new() -> Self {
+        Self {
+            lookups: SortedMap::bounded(LOOKUP_CACHE_ENTRIES, EvictionPolicy::Oldest),
+            inodes: SortedMap::bounded(INODE_CACHE_ENTRIES, EvictionPolicy::Oldest),
+            blocks: SortedMap::bounded(BLOCK_CACHE_ENTRIES, EvictionPolicy::Oldest),
+            hits: 0,
+            misses: 0,
+            inode_hits: 0,
+            inode_misses: 0,
+            block_hits: 0,
+            block_misses: 0,
+        }
+    }
+
+    fn lookup(&mut self, parent: u32, name: &[u8]) -> Option<u32> {
+        let key = lookup_cache_key(parent, name)?;
+        match self.lookups.get(&key).copied() {
+            Some(child) => {
+                self.hits += 1;
+                self.lookups.touch(&key);
+                Some(child)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert_lookup(&mut self, parent: u32, name: &[u8], child: u32) {
+        if let Some(key) = lookup_cache_key(parent, name) {
+            self.lookups.insert(key, child);
+        }
+    }
+
+    fn inode(&mut self, inode: u32) -> Option<Ext2Inode> {
+        match self.inodes.get(&inode).copied() {
+            Some(data) => {
+                self.inode_hits += 1;
+                self.inodes.touch(&inode);
+                Some(data)
+            }
+            None => {
+                self.inode_misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert_inode(&mut self, inode: u32, data: Ext2Inode) {
+        self.inodes.insert(inode, data);
+    }
+
+    /// Copies a cached block into `buffer`, false on a miss
+    fn read_block(&mut self, block: u64, buffer: &mut [u8]) -> bool {
+        match self.blocks.get(&block) {
+            Some(cached) => {
+                buffer.copy_from_slice(&cached[..buffer.len()]);
+                self.blocks.touch(&block);
+                self.block_hits += 1;
+                true
+            }
+            None => {
+                self.block_misses += 1;
+                false
+            }
+        }
+    }
+
+    /// Caches a copy of `data`, or refreshes the cached one. Skipped when the heap is full.
+    fn insert_block(&mut self, block: u64, data: &[u8]) {
+        if let Some(cached) = self.blocks.get_mut(&block) {
+            cached[..data.len()].copy_from_slice(data);
+            return;
+        }
+        if let Some(mut copy) = Buffer::new_tagged(data.len(), b"ext2") {
+            copy.copy_from_slice(data);
+            self.blocks.insert(block, copy);
+        }
+    }
+
+    /// Forgets the cached copy of `block`, whose content on the disk is unknown
+    fn invalidate_block(&mut self, block: u64) {
+        self.blocks.remove(&block);
+    }
+}
+
+/// What a superblock-only [`Ext2FileSystem::probe`] learns about a partition
+pub struct Ext2Probe {
+    pub volume_name: [u8; 16],
+    pub fs_id: [u8; 16],
+    pub last_mount_path: [u8; 64],
+    /// Disk sectors the probe read
+    pub sectors_read: usize,
+}
+
+/// Trims the zero padding of a superblock string field
+pub fn superblock_string(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|c| *c == 0).unwrap_or(field.len());
+    &field[..len]
+}
+
 pub struct Ext2FileSystem {
     disk: ExtendedDisk,
     partition: DiskRange,
@@ -841,6 +1518,7 @@ pub struct Ext2FileSystem {
     block_groups: Vec<Ext2BlockGroupDescriptor>,
     sectors_per_block: usize,
     sector_size: usize,
+    cache: Ext2LookupCache,
 }
 
 impl Ext2FileSystem {
@@ -852,60 +1530,113 @@ impl Ext2FileSystem {
             block_groups: Vec::default(),
             sectors_per_block: 0,
             sector_size: 0,
+            cache: Ext2LookupCache::new(),
         };
         ext2.read_superblock()?;
+        ext2.check_features()?;
         ext2.read_block_group_descriptor_table()?;
         Ok(ext2)
     }
 
-    fn read_superblock(&mut self) -> Result<(), Ext2Error> {
-        let params = self.disk.get_params().map_err(Ext2Error::DiskError)?;
+    /// Sector size of `disk` if the ext2 layer handles it: a power of two from 512 bytes to 4KiB. <br>
+    /// Blocks may be smaller than a sector, e.g. 1KiB blocks on 2KiB optical sectors. <br>
+    fn sector_size(disk: &mut ExtendedDisk) -> Result<usize, Ext2Error> {
+        let params = disk.get_params().map_err(Ext2Error::DiskError)?;
         let bps = params.bytes_per_sector as usize;
-        if bps != 512 && bps != 4096 {
+        if !(MIN_SECTOR_SIZE..=MAX_SECTOR_SIZE).contains(&bps) || !bps.is_power_of_two() {
             return Err(Ext2Error::BadDiskSectorSize(params.bytes_per_sector));
         }
-        self.sector_size = bps;
-
-        let mut superblock_buffer = Buffer::new(1024).ok_or(Ext2Error::FailedMemAlloc(1024))?;
-        let mut buffer = Buffer::new(4096).ok_or(Ext2Error::FailedMemAlloc(4096))?;
+        Ok(bps)
+    }
 
-        // For dev profile, low optimization doesn't recognize that bps is not 0 from the first !=512 && !=4096 check
+    /// Reads the 1KiB superblock at byte 1024 of `partition`, which starts mid-sector on 2KiB and 4KiB sectors. <br>
+    /// Returns it with the number of sectors read. <br>
+    fn read_superblock_bytes(
+        disk: &mut ExtendedDisk,
+        partition: &DiskRange,
+        bps: usize,
+    ) -> Result<(Buffer, usize), Ext2Error> {
+        // For dev profile, low optimization doesn't recognize that bps is not 0 from the sector size check
         // Gets optimized out on release profile, and removes undefined panick symbols related to division by 0 on dev profile
-        // Weak compiler bruh
         if bps == 0 {
-            return Err(Ext2Error::BadDiskSectorSize(params.bytes_per_sector));
+            return Err(Ext2Error::BadDiskSectorSize(0));
+        }
+        let mut sector = Buffer::new_tagged(bps, b"ext2").ok_or(Ext2Error::FailedMemAlloc(bps))?;
+        let mut raw = Buffer::new_tagged(1024, b"ext2").ok_or(Ext2Error::FailedMemAlloc(1024))?;
+        let mut lba = partition.start_lba + (1024 / bps) as u64;
+        let mut start = 1024 % bps;
+        let mut copied = 0;
+        let mut sectors = 0;
+        while copied < 1024 {
+            disk.read_sector(lba, &mut sector)
+                .map_err(Ext2Error::DiskError)?;
+            let len = (bps - start).min(1024 - copied);
+            if !sector.copy_to(start, &mut raw, copied, len) {
+                return Err(Ext2Error::BufferCopyError);
+            }
+            copied += len;
+            start = 0;
+            lba += 1;
+            sectors += 1;
         }
+        Ok((raw, sectors))
+    }
 
-        let start_lba = 1024 / bps;
-        let buf_idx = 1024 % bps;
+    /// Reads only the superblock of `partition` (1KiB at offset 1024), without mounting it. <br>
+    /// Enough to tell an ext2 filesystem apart and to read its label, UUID and last mount path. <br>
+    pub fn probe(disk: &mut ExtendedDisk, partition: &DiskRange) -> Result<Ext2Probe, Ext2Error> {
+        let bps = Self::sector_size(disk)?;
+        let (raw, sectors) = Self::read_superblock_bytes(disk, partition, bps)?;
+        let superblock = raw.boxed::<Ext2SuperBlock>();
+        if superblock.signature != EXT2_SUPERBLOCK_SIGNATURE {
+            return Err(Ext2Error::BadSuperblock);
+        }
+        Ok(Ext2Probe {
+            volume_name: superblock.volume_name,
+            fs_id: superblock.fs_id,
+            last_mount_path: superblock.last_mount_path,
+            sectors_read: sectors,
+        })
+    }
 
-        self.disk
-            .read_to_buffer(start_lba as u64 + self.partition.start_lba, &mut buffer)
-            .map_err(Ext2Error::DiskError)?;
-        buffer.copy_to(buf_idx, &mut superblock_buffer, 0, 1024);
-        self.superblock = superblock_buffer.boxed::<Ext2SuperBlock>();
+    fn read_superblock(&mut self) -> Result<(), Ext2Error> {
+        let bps = Self::sector_size(&mut self.disk)?;
+        self.sector_size = bps;
 
-        if (self.block_size() % bps) != 0 {
-            // A block isn't a whole amount of logical sectors
-            return Err(Ext2Error::BadBlockSize(
-                self.block_size(),
-                params.bytes_per_sector,
-            ));
+        let (raw, _) = Self::read_superblock_bytes(&mut self.disk, &self.partition, bps)?;
+        let superblock = raw.boxed::<Ext2SuperBlock>();
+        if superblock.signature != EXT2_SUPERBLOCK_SIGNATURE {
+            log::with_level(LogLevel::Debug, || {
+                let bytes = unsafe {
+                    slice::from_raw_parts(
+                        &*superblock as *const Ext2SuperBlock as *const u8,
+                        SUPERBLOCK_DUMP_SIZE,
+                    )
+                };
+                hex_dump(b"ext2 superblock", bytes);
+            });
+            return Err(Ext2Error::BadSuperblock);
         }
-        self.sectors_per_block = self.block_size() / bps;
+        self.superblock = superblock;
+
+        // Both are powers of two, a block is a whole amount of sectors or a part of one
+        self.sectors_per_block = self.block_size().div_ceil(bps);
 
         Ok(())
     }
 
     fn read_block_group_descriptor_table(&mut self) -> Result<(), Ext2Error> {
         let entry_count = self.count_block_groups()?;
-        let table_size = entry_count * BLOCK_GROUP_DESCRIPTOR_SIZE;
+        let descriptor_size = self.block_group_descriptor_size()?;
+        let table_size = entry_count * descriptor_size;
         let bs = self.block_size();
         if bs == 0 {
             return Err(Ext2Error::NullBlockSize);
         }
-        let mut buffer = Buffer::new(table_size).ok_or(Ext2Error::FailedMemAlloc(table_size))?;
-        let mut block_buffer = Buffer::new(bs).ok_or(Ext2Error::FailedMemAlloc(bs))?;
+        let mut buffer =
+            Buffer::new_tagged(table_size, b"ext2").ok_or(Ext2Error::FailedMemAlloc(table_size))?;
+        let mut block_buffer =
+            Buffer::new_tagged(bs, b"ext2").ok_or(Ext2Error::FailedMemAlloc(bs))?;
 
         let mut read = 0;
         let mut disk_byte = if bs == 1024 { 2048 } else { bs };
@@ -926,7 +1657,7 @@ impl Ext2FileSystem {
 
         self.block_groups.ensure_capacity(entry_count);
         for i in 0..entry_count {
-            let offset = i * BLOCK_GROUP_DESCRIPTOR_SIZE;
+            let offset = i * descriptor_size;
             let block_group =
                 unsafe { &*(buffer.get_ptr().add(offset) as *const Ext2BlockGroupDescriptor) };
             self.block_groups.push(*block_group);
@@ -935,8 +1666,40 @@ impl Ext2FileSystem {
         Ok(())
     }
 
+    /// First LBA of `block` and the byte offset of the block in it, checking that the whole block is inside the partition. <br>
+    /// The offset is only non zero for blocks smaller than a sector. <br>
+    /// Every block read and write goes through it, so a garbage block pointer can't reach another partition's sectors. <br>
+    fn block_lba(&self, block: u64) -> Result<(u64, usize), Ext2Error> {
+        let bs = self.block_size() as u64;
+        let bps = self.sector_size as u64;
+        let begin_lba = block
+            .checked_mul(bs)
+            .and_then(|byte| (byte / bps).checked_add(self.partition.start_lba));
+        match begin_lba {
+            Some(lba) if self.partition.contains(lba, self.sectors_per_block as u64) => {
+                Ok((lba, (block * bs % bps) as usize))
+            }
+            lba => Err(Ext2Error::BlockOutOfRange(
+                block,
+                lba.unwrap_or(u64::MAX),
+                self.partition.start_lba,
+                self.partition.end_lba,
+            )),
+        }
+    }
+
     unsafe fn unsafe_read_block(&mut self, block: u64, buffer: *mut u8) -> Result<(), Ext2Error> {
-        let begin_lba: u64 = block * self.sectors_per_block as u64 + self.partition.start_lba;
+        let (begin_lba, offset) = self.block_lba(block)?;
+        let bs = self.block_size();
+        if bs < self.sector_size {
+            let mut sector = Buffer::new_tagged(self.sector_size, b"ext2")
+                .ok_or(Ext2Error::FailedMemAlloc(self.sector_size))?;
+            self.disk
+                .read_sector(begin_lba, &mut sector)
+                .map_err(Ext2Error::DiskError)?;
+            ptr::copy_nonoverlapping(sector[offset..offset + bs].as_ptr(), buffer, bs);
+            return Ok(());
+        }
         for i in 0..self.sectors_per_block {
             let lba = begin_lba + i as u64;
             let output_addr = buffer.add(i * self.sector_size);
@@ -948,11 +1711,103 @@ impl Ext2FileSystem {
         Ok(())
     }
 
+    /// Reads a block through the block cache, only going to the disk on a miss
     fn read_block(&mut self, block: u64, buffer: &mut Buffer) -> Result<(), Ext2Error> {
-        if buffer.len() < self.block_size() {
-            return Err(Ext2Error::BufferTooSmall(buffer.len(), self.block_size()));
+        let bs = self.block_size();
+        if buffer.len() < bs {
+            return Err(Ext2Error::BufferTooSmall(buffer.len(), bs));
+        }
+        if self.cache.read_block(block, &mut buffer[..bs]) {
+            return Ok(());
         }
-        unsafe { self.unsafe_read_block(block, buffer.get_ptr()) }
+        unsafe { self.unsafe_read_block(block, buffer.get_ptr())? };
+        self.cache.insert_block(block, &buffer[..bs]);
+        Ok(())
+    }
+
+    /// Writes a block to the disk and the block cache. <br>
+    /// A failed write may have reached some of the sectors, so the cached copy is dropped and the next read goes to the disk. <br>
+    fn write_block(&mut self, block: u64, buffer: &Buffer) -> Result<(), Ext2Error> {
+        let bs = self.block_size();
+        if buffer.len() < bs {
+            return Err(Ext2Error::BufferTooSmall(buffer.len(), bs));
+        }
+        match self.write_block_to_disk(block, buffer) {
+            Ok(()) => {
+                self.cache.insert_block(block, &buffer[..bs]);
+                Ok(())
+            }
+            Err(e) => {
+                self.cache.invalidate_block(block);
+                Err(e)
+            }
+        }
+    }
+
+    fn write_block_to_disk(&mut self, block: u64, buffer: &Buffer) -> Result<(), Ext2Error> {
+        let bs = self.block_size();
+        let mut sector = Buffer::new_tagged(self.sector_size, b"ext2")
+            .ok_or(Ext2Error::FailedMemAlloc(self.sector_size))?;
+        let (begin_lba, offset) = self.block_lba(block)?;
+        if bs < self.sector_size {
+            // Read-modify-write of the sector holding the block
+            self.disk
+                .read_sector(begin_lba, &mut sector)
+                .map_err(Ext2Error::DiskError)?;
+            sector[offset..offset + bs].copy_from_slice(&buffer[..bs]);
+            return self
+                .disk
+                .write_sector(begin_lba, &sector)
+                .map_err(Ext2Error::DiskError);
+        }
+        for i in 0..self.sectors_per_block {
+            if !buffer.copy_to(i * self.sector_size, &mut sector, 0, self.sector_size) {
+                return Err(Ext2Error::BufferCopyError);
+            }
+            self.disk
+                .write_sector(begin_lba + i as u64, &sector)
+                .map_err(Ext2Error::DiskError)?;
+        }
+        Ok(())
+    }
+
+    /// Descriptors are 32 bytes, or `group_descriptor_size` with the ext4 64-bit feature. Only their first 32 bytes are used.
+    fn block_group_descriptor_size(&self) -> Result<usize, Ext2Error> {
+        if self.superblock.required_features & REQUIRED_FEATURE_64BIT == 0 {
+            return Ok(BLOCK_GROUP_DESCRIPTOR_SIZE);
+        }
+        let size = self.superblock.group_descriptor_size as usize;
+        if size < BLOCK_GROUP_DESCRIPTOR_SIZE_64BIT || !size.is_power_of_two() {
+            return Err(Ext2Error::BadBlockGroupDescriptorTableEntrySize(
+                size,
+                BLOCK_GROUP_DESCRIPTOR_SIZE_64BIT,
+            ));
+        }
+        Ok(size)
+    }
+
+    /// Rejects filesystems needing features the reader can't handle, and logs the ext4 ones it can
+    fn check_features(&self) -> Result<(), Ext2Error> {
+        let required = self.superblock.required_features;
+        let unsupported = required & UNSUPPORTED_REQUIRED_FEATURES;
+        if unsupported != 0 {
+            printf!(
+                b"Unsupported required filesystem features: 0x%x\r\n",
+                unsupported
+            );
+            return Err(Ext2Error::UnsupportedFeatures(unsupported));
+        }
+        if required & REQUIRED_FEATURE_EXTENTS != 0 {
+            printf!(
+                b"ext4 filesystem (required features 0x%x), using extent trees where inodes have them\r\n",
+                required
+            );
+        }
+        if required & REQUIRED_FEATURE_FS_NEEDS_TO_REPLAY_JOURNAL != 0 {
+            log_warn!(b"Warning: the filesystem journal needs a replay, recent changes may be missing\r\n");
+            warning(WarningId::JournalReplay);
+        }
+        Ok(())
     }
 
     fn count_block_groups(&self) -> Result<usize, Ext2Error> {
@@ -970,6 +1825,29 @@ impl Ext2FileSystem {
         }
     }
 
+    /// Sectors of the disk holding the filesystem
+    pub fn partition(&self) -> DiskRange {
+        self.partition
+    }
+
+    /// Hit and miss counts of the block cache
+    pub fn cache_stats(&self) -> (usize, usize) {
+        (self.cache.block_hits, self.cache.block_misses)
+    }
+
+    /// Prints the hit/miss counts of the lookup, inode and block caches
+    pub fn printf_cache_stats(&self) {
+        printf!(
+            b"ext2 cache: lookups %x hits / %x misses, inodes %x hits / %x misses\r\n",
+            self.cache.hits,
+            self.cache.misses,
+            self.cache.inode_hits,
+            self.cache.inode_misses
+        );
+        let (hits, misses) = self.cache_stats();
+        printf!(b"ext2 cache: blocks %x hits / %x misses\r\n", hits, misses);
+    }
+
     pub fn block_size(&self) -> usize {
         1024 << (self.superblock.log_block_size as usize)
     }
@@ -1000,6 +1878,9 @@ impl Ext2FileSystem {
         if inode == 0 || inode > self.superblock.inodes_count as usize {
             return Err(Ext2Error::BadInodeIndex(inode));
         }
+        if let Some(data) = self.cache.inode(inode as u32) {
+            return Ok(data);
+        }
 
         let group = self.get_inode_group(inode);
         let index = self.get_inode_index_in_group(inode);
@@ -1027,8 +1908,9 @@ impl Ext2FileSystem {
 
         let offset = (index % inodes_per_block) * inode_size;
         let mut block_buffer =
-            Buffer::new(block_size).ok_or(Ext2Error::FailedMemAlloc(block_size))?;
-        let mut buffer = Buffer::new(inode_size).ok_or(Ext2Error::FailedMemAlloc(inode_size))?;
+            Buffer::new_tagged(block_size, b"ext2").ok_or(Ext2Error::FailedMemAlloc(block_size))?;
+        let mut buffer =
+            Buffer::new_tagged(inode_size, b"ext2").ok_or(Ext2Error::FailedMemAlloc(inode_size))?;
 
         unsafe {
             self.read_block(block + block_offset, &mut block_buffer)?;
@@ -1036,39 +1918,221 @@ impl Ext2FileSystem {
                 kpanic();
             }
 
-            let inode = (buffer.get_ptr() as *mut Ext2Inode).read_unaligned();
-            Ok(inode)
+            let data = (buffer.get_ptr() as *mut Ext2Inode).read_unaligned();
+            self.cache.insert_inode(inode as u32, data);
+            Ok(data)
         }
     }
 
     fn open_inode(&mut self, inode: usize) -> Result<CachedInodeReadingLocation, Ext2Error> {
         let inode = self.get_inode(inode)?;
-        CachedInodeReadingLocation::new(self, inode)
+        let mut fd = CachedInodeReadingLocation::new(self, inode)?;
+        fd.update_mapping(self)?;
+        Ok(fd)
     }
 
     pub fn open<'a>(&'a mut self, inode: usize) -> Result<Ext2FileType<'a>, Ext2Error> {
         let fd = self.open_inode(inode)?;
-        if (fd.inode.type_and_permissions & INODE_TYPE_DIRECTORY) == INODE_TYPE_DIRECTORY {
-            Ok(Ext2FileType::Directory(Ext2Directory::new(fd, self)?))
-        } else if (fd.inode.type_and_permissions & INODE_TYPE_REGULAR_FILE)
-            == INODE_TYPE_REGULAR_FILE
+        match fd.inode.type_and_permissions & INODE_TYPE_MASK {
+            INODE_TYPE_DIRECTORY => Ok(Ext2FileType::Directory(Ext2Directory::new(fd, self)?)),
+            INODE_TYPE_REGULAR_FILE => Ok(Ext2FileType::File(Ext2File::new(fd, self)?)),
+            _ => Err(Ext2Error::UnsupportedInodeType(
+                fd.inode.type_and_permissions,
+            )),
+        }
+    }
+
+    /// Reads the target of a symlink, from the block pointers for short targets or from its first data block otherwise
+    fn read_symlink(&mut self, inode: usize) -> Result<Buffer, Ext2Error> {
+        let data = self.get_inode(inode)?;
+        let size = data.size_lo as usize;
+        let bs = self.block_size();
+        if size == 0 || size > bs {
+            return Err(Ext2Error::BadSymlink(inode));
+        }
+        let mut target =
+            Buffer::new_tagged(size, b"ext2").ok_or(Ext2Error::FailedMemAlloc(size))?;
+
+        // An extended attribute block is counted in the sectors but holds no target
+        let attribute_sectors = if data.extended_attribute_block != 0 {
+            bs / 512
+        } else {
+            0
+        };
+        if size < FAST_SYMLINK_MAX_LEN
+            && data.flags & INODE_FLAG_EXTENTS == 0
+            && data.sectors_count as usize == attribute_sectors
         {
-            Ok(Ext2FileType::File(Ext2File::new(fd, self)?))
+            target.copy_from_slice(&inode_block_area(&data)[..size]);
+            return Ok(target);
+        }
+
+        let mut fd = self.open_inode(inode)?;
+        let mut block = Buffer::new_tagged(bs, b"ext2").ok_or(Ext2Error::FailedMemAlloc(bs))?;
+        fd.read_block(self, &mut block)?;
+        target.copy_from_slice(&block[..size]);
+        Ok(target)
+    }
+
+    /// Queues the components of `path` on `pending` so that the first one is popped first. <br>
+    /// They are tagged with `origin` when they come from a symlink target, with their own index otherwise. <br>
+    fn push_path_components(
+        pending: &mut Vec<(Buffer, usize)>,
+        path: &[u8],
+        origin: Option<usize>,
+    ) -> Result<(), Ext2Error> {
+        let parts = path.split(|c| *c == b'/').filter(|part| !part.is_empty());
+        let count = parts.clone().count();
+        if pending.len() + count > MAX_PATH_DEPTH {
+            return Err(Ext2Error::PathTooDeep(pending.len() + count));
+        }
+        let mut buffers: Vec<(Buffer, usize)> = Vec::new_tagged(count.max(1), b"ext2");
+        for (index, part) in parts.enumerate() {
+            let mut buffer = Buffer::new_tagged(part.len(), b"ext2")
+                .ok_or(Ext2Error::FailedMemAlloc(part.len()))?;
+            buffer.copy_from_slice(part);
+            buffers.push((buffer, origin.unwrap_or(index)));
+        }
+        while let Some(part) = buffers.pop() {
+            pending.push(part);
+        }
+        Ok(())
+    }
+
+    /// Looks `part` up in the directory `inode`, the lookup step shared by [`Self::resolve_path`] and [`Self::lookup_path`]. <br>
+    /// "." and ".." are answered by the directory's parsed self and parent inodes and never cached, other names go through the lookup cache. Entries whose name isn't a valid path component (see [`Ext2DirectoryEntry::is_path_component`]) are never matched. <br>
+    /// Returns None when the directory has no such entry, [`Ext2Error::NotFound`] when `inode` isn't a directory. <br>
+    fn lookup_component(&mut self, inode: usize, part: &[u8]) -> Result<Option<u32>, Ext2Error> {
+        let cacheable = part != b"." && part != b"..";
+        if cacheable {
+            if let Some(child) = self.cache.lookup(inode as u32, part) {
+                return Ok(Some(child));
+            }
+        }
+        let Ext2FileType::Directory(dir) = self.open(inode)? else {
+            return Err(Ext2Error::NotFound);
+        };
+        let child = if part == b"." {
+            Some(dir.get_inode())
+        } else if part == b".." {
+            Some(dir.get_parent_inode())
         } else {
-            Err(Ext2Error::UnsupportedInodeType(
-                fd.inode.type_and_permissions,
-            ))
+            dir.listdir()
+                .find(|entry| {
+                    entry.is_path_component() && !entry.is_dot_entry() && &entry.name == part
+                })
+                .map(|entry| entry.inode)
+        };
+        if let (true, Some(child)) = (cacheable, child) {
+            self.cache.insert_lookup(inode as u32, part, child);
+        }
+        Ok(child)
+    }
+
+    /// Records the step to `child` in `chain`, the directories from the root to the current one. <br>
+    /// "." and ".." legitimately go back up the chain, any other component leading into it is a [`Ext2Error::DirectoryLoop`]. <br>
+    fn enter_directory(
+        chain: &mut Vec<u32>,
+        child: u32,
+        part: &[u8],
+        index: usize,
+    ) -> Result<(), Ext2Error> {
+        match chain.iter().position(|visited| *visited == child) {
+            Some(depth) if part == b"." || part == b".." => {
+                while chain.len() > depth + 1 {
+                    chain.pop();
+                }
+            }
+            Some(_) => return Err(Ext2Error::DirectoryLoop(child, index)),
+            None => chain.push(child),
+        }
+        Ok(())
+    }
+
+    /// Resolves `path` to an inode for [`Self::open_path`], following symlinks in every component. <br>
+    /// Relative symlink targets start from the directory holding the link, absolute ones from the root. <br>
+    /// Components are looked up like in [`Self::resolve_path`], with the same directory loop check. <br>
+    fn lookup_path(&mut self, path: &[u8]) -> Result<usize, Ext2Error> {
+        if path.contains(&0) {
+            return Err(Ext2Error::InvalidArgument);
+        }
+        // Components still to resolve, the next one last
+        let mut pending: Vec<(Buffer, usize)> = Vec::new_tagged(16, b"ext2");
+        Self::push_path_components(&mut pending, path, None)?;
+        let count = pending.len();
+
+        let mut hops = 0;
+        let mut inode = 2;
+        let mut chain: Vec<u32> = Vec::new_tagged(count + 1, b"ext2");
+        chain.push(2);
+        // Index of the component that led to `inode`, blamed when it turns out not to be a directory
+        let mut inode_index = 0;
+        while let Some((part, index)) = pending.pop() {
+            let child = match self.lookup_component(inode, &part) {
+                Ok(Some(child)) => child,
+                Ok(None) => return Err(Ext2Error::FileNotFound(index)),
+                Err(Ext2Error::NotFound) => return Err(Ext2Error::FileNotFound(inode_index)),
+                Err(e) => return Err(e),
+            };
+
+            let data = self.get_inode(child as usize)?;
+            if data.type_and_permissions & INODE_TYPE_MASK == INODE_TYPE_SYMLINK {
+                hops += 1;
+                if hops > MAX_SYMLINK_HOPS {
+                    return Err(Ext2Error::TooManySymlinks);
+                }
+                let target = self.read_symlink(child as usize)?;
+                if target[0] == b'/' {
+                    inode = 2;
+                    chain.clear();
+                    chain.push(2);
+                }
+                Self::push_path_components(&mut pending, &target, Some(index))?;
+                continue;
+            }
+            Self::enter_directory(&mut chain, child, &part, index)?;
+            inode = child as usize;
+            inode_index = index;
+        }
+
+        if path.ends_with(b"/") && count > 0 {
+            let data = self.get_inode(inode)?;
+            if data.type_and_permissions & INODE_TYPE_MASK != INODE_TYPE_DIRECTORY {
+                return Err(Ext2Error::FileNotFound(count - 1));
+            }
         }
+        Ok(inode)
+    }
+
+    /// Opens the file or directory at `path`, walking the directories from the root. <br>
+    /// Repeated slashes are ignored and a trailing slash is accepted when the path names a directory. <br>
+    /// Symlinks are followed in every component, at most [`MAX_SYMLINK_HOPS`] of them, failing with [`Ext2Error::TooManySymlinks`] past that. <br>
+    /// Fails with [`Ext2Error::FileNotFound`] and the index of the offending component when it is missing or when it isn't a directory but more components follow. Components coming from a symlink target are reported with the index of the symlink. <br>
+    pub fn open_path<'a>(&'a mut self, path: &[u8]) -> Result<Ext2FileType<'a>, Ext2Error> {
+        let inode = self.lookup_path(path)?;
+        self.open(inode)
     }
 
     pub fn find_inode(&mut self, path: &[u8]) -> Result<Option<usize>, Ext2Error> {
+        Ok(self.resolve_path(path)?.map(|resolution| resolution.inode))
+    }
+
+    /// Resolves an absolute path, recording every traversed directory entry. <br>
+    /// "." and ".." components use the directory's parsed self and parent inodes. Entries whose name isn't a valid path component (see [`Ext2DirectoryEntry::is_path_component`]) are never matched. <br>
+    pub fn resolve_path(&mut self, path: &[u8]) -> Result<Option<Ext2PathResolution>, Ext2Error> {
         if path.len() == 1 && path[0] == b'/' {
-            return Ok(Some(2));
+            return Ok(Some(Ext2PathResolution {
+                inode: 2,
+                steps: Vec::new_tagged(1, b"ext2"),
+            }));
         }
         if path.is_empty() || path[0] != b'/' || path[path.len() - 1] == b'/' {
             return Err(Ext2Error::InvalidArgument);
         }
-        let mut parts: Vec<&[u8]> = Vec::new(16);
+        if path.contains(&0) {
+            return Err(Ext2Error::InvalidArgument);
+        }
+        let mut parts: Vec<&[u8]> = Vec::new_tagged(16, b"ext2");
         let mut last_slash = 1;
         for (i, &c) in path.iter().enumerate().skip(1) {
             if c == b'/' && last_slash < path.len() && i < path.len() && last_slash <= i {
@@ -1084,25 +2148,32 @@ impl Ext2FileSystem {
             parts.push(&path[last_slash..]);
         }
 
+        if parts.len() > MAX_PATH_DEPTH {
+            return Err(Ext2Error::PathTooDeep(parts.len()));
+        }
+
+        let mut steps: Vec<Ext2PathStep> = Vec::new_tagged(parts.len().max(1), b"ext2");
+        // Directories from the root to the current one, a component leading back into it is a loop
+        let mut chain: Vec<u32> = Vec::new_tagged(parts.len() + 1, b"ext2");
+        chain.push(2);
         let mut inode = 2;
-        'outer: for part in parts {
-            let file = self.open(inode)?;
-            match file {
-                Ext2FileType::Directory(dir) => {
-                    for entry in dir.listdir() {
-                        if &entry.name == part {
-                            inode = entry.inode as usize;
-                            continue 'outer;
-                        }
-                    }
-                    return Ok(None);
-                }
-                _ => {
-                    return Err(Ext2Error::NotFound);
-                }
-            }
+        for (index, part) in parts.iter().copied().enumerate() {
+            let Some(child) = self.lookup_component(inode, part)? else {
+                return Ok(None);
+            };
+            Self::enter_directory(&mut chain, child, part, index)?;
+
+            let mut name = Buffer::new_tagged(part.len(), b"ext2")
+                .ok_or(Ext2Error::FailedMemAlloc(part.len()))?;
+            name.copy_from_slice(part);
+            steps.push(Ext2PathStep {
+                directory_inode: inode as u32,
+                name,
+                child_inode: child,
+            });
+            inode = child as usize;
         }
 
-        Ok(Some(inode))
+        Ok(Some(Ext2PathResolution { inode, steps }))
     }
 }
@@ -1,10 +1,34 @@
 use crate::{
-    bios::{DiskError, ExtendedDisk},
+    bios::{DiskError, ExtendedDisk, MAX_SECTOR_SIZE, MIN_SECTOR_SIZE},
+    crc32::crc32,
+    e9::hex_dump,
     kpanic,
+    log::{self, LogLevel},
+    log_error, log_warn,
     mem::{Buffer, Vec},
+    printf,
     video::Video,
+    warnings::{warning, WarningId},
 };
 
+/// Bytes of the header covered by `header_crc32`
+const GPT_HEADER_SIZE: usize = 0x5C;
+/// Bytes of LBA 0 and LBA 1 dumped at the trace level when no GPT header is found
+const GPT_DUMP_SIZE: usize = 64;
+/// Offset of `header_crc32`, zeroed while computing it
+const GPT_HEADER_CRC_OFFSET: usize = 16;
+/// Smallest entry size allowed by the spec, entries hold the name past the fixed fields
+const MIN_PARTITION_ENTRY_SIZE: usize = 128;
+/// Largest partition array read into memory
+const MAX_PARTITION_ARRAY_SIZE: usize = 1024 * 1024;
+/// Entry count most tools write, larger tables are accepted with a warning
+const DEFAULT_PARTITION_ENTRY_COUNT: usize = 128;
+/// Tables declaring more entries are rejected as corrupt
+const MAX_PARTITION_ENTRY_COUNT: usize = 1024;
+/// Offset of the UTF-16LE name in a partition entry, it runs to the end of the entry
+const PARTITION_NAME_OFFSET: usize = 0x38;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
 #[repr(C, packed)]
 struct MBRPartition {
     pub bootable: u8,
@@ -12,7 +36,7 @@ struct MBRPartition {
     pub os_type: u8,
     pub end_chs: [u8; 3],
     pub start_lba: u32,
-    pub end_lba: u32,
+    pub sector_count: u32,
 }
 
 impl MBRPartition {
@@ -22,7 +46,14 @@ impl MBRPartition {
             && self.os_type == 0
             && self.end_chs == [0, 0, 0]
             && self.start_lba == 0
-            && self.end_lba == 0
+            && self.sector_count == 0
+    }
+
+    /// A GPT protective partition (type 0xEE) covering the GPT header at LBA 1
+    pub fn protects_gpt_header(&self) -> bool {
+        self.os_type == MBR_TYPE_GPT_PROTECTIVE
+            && self.start_lba <= 1
+            && self.start_lba as u64 + self.sector_count as u64 > 1
     }
 }
 
@@ -33,6 +64,7 @@ struct MasterBootRecord {
     pub signature: [u8; 2],
 }
 
+#[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct GPTHeader {
     pub signature: [u8; 8],
@@ -81,13 +113,26 @@ impl GUIDPartitionTableEntry {
 pub struct GUIDPartitionTable {
     header: GPTHeader,
     partitions: Vec<GUIDPartitionTableEntry>,
+    used_backup: bool,
 }
 
 impl GUIDPartitionTable {
+    /// Whether the primary header or array was damaged and the backup copy at the end of the disk was read instead
+    pub fn used_backup(&self) -> bool {
+        self.used_backup
+    }
+
     pub fn get_partitions(&self) -> &Vec<GUIDPartitionTableEntry> {
         &self.partitions
     }
 
+    /// First partition whose name is exactly `name`, see [`decode_partition_name`] for how names are stored
+    pub fn find_partition_by_name(&self, name: &[u8]) -> Option<&GUIDPartitionTableEntry> {
+        self.partitions
+            .iter()
+            .find(|partition| !partition.name.is_empty() && &partition.name[..] == name)
+    }
+
     pub fn get_header(&self) -> &GPTHeader {
         &self.header
     }
@@ -100,21 +145,77 @@ impl GUIDPartitionTable {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct DiskRange {
     pub start_lba: u64,
+    /// Last sector of the range, inclusive
     pub end_lba: u64,
 }
 
+impl DiskRange {
+    /// Whether the `count` sectors starting at `lba` are all inside the range
+    pub fn contains(&self, lba: u64, count: u64) -> bool {
+        lba >= self.start_lba
+            && lba <= self.end_lba
+            && count.saturating_sub(1) <= self.end_lba - lba
+    }
+}
+
 pub enum GPTError {
     FailedMemAlloc(usize),
     BadSectorSize,
     BadMasterBootRecord,
     NotGPT,
+    /// No MBR partition of type 0xEE covers LBA 1
+    NoProtectivePartition,
     UnsupportedTableLBA,
+    BadHeaderChecksum,
+    BadPartitionArrayChecksum,
+    /// Below 128 bytes or not a multiple of 8
+    BadPartitionEntrySize(usize),
+    TooManyPartitionEntries(usize),
+    /// The partition array runs past the last sector of the disk
+    PartitionArrayOutOfBounds,
+    /// The partition at this index in the array ends past the last sector of the disk, or before it starts
+    PartitionOutOfRange(usize),
     DiskError(DiskError),
 }
 
 impl GPTError {
+    /// Logs the error to e9, without a trailing newline
+    pub fn printf(&self) {
+        match self {
+            GPTError::DiskError(e) => {
+                printf!(b"disk error: ");
+                e.printf();
+            }
+            GPTError::FailedMemAlloc(size) => printf!(b"failed to allocate memory: 0x%x", *size),
+            GPTError::BadSectorSize => printf!(b"bad disk sector size"),
+            GPTError::BadMasterBootRecord => printf!(b"bad master boot record"),
+            GPTError::NotGPT => printf!(b"disk is not GPT formatted"),
+            GPTError::NoProtectivePartition => {
+                printf!(b"no protective MBR partition (type 0xEE) covers LBA 1")
+            }
+            GPTError::UnsupportedTableLBA => printf!(b"unsupported partition table LBA"),
+            GPTError::BadHeaderChecksum => printf!(b"GPT header checksum mismatch"),
+            GPTError::BadPartitionArrayChecksum => {
+                printf!(b"GPT partition array checksum mismatch")
+            }
+            GPTError::BadPartitionEntrySize(size) => {
+                printf!(b"bad GPT partition entry size: 0x%x", *size)
+            }
+            GPTError::TooManyPartitionEntries(count) => {
+                printf!(b"too many GPT partition entries: 0x%x", *count)
+            }
+            GPTError::PartitionArrayOutOfBounds => {
+                printf!(b"GPT partition array extends past the end of the disk")
+            }
+            GPTError::PartitionOutOfRange(index) => {
+                printf!(b"GPT partition 0x%x is outside of the disk", *index)
+            }
+        }
+    }
+
     pub fn panic(&self) -> ! {
         unsafe {
             let video = Video::get();
@@ -137,9 +238,36 @@ impl GPTError {
                 GPTError::NotGPT => {
                     video.write_string(b"Disk is not GPT formatted\n");
                 }
+                GPTError::NoProtectivePartition => {
+                    video.write_string(b"Disk is not GPT formatted (no 0xEE MBR partition)\n");
+                }
                 GPTError::UnsupportedTableLBA => {
                     video.write_string(b"Unsupported parition table LBA\n");
                 }
+                GPTError::BadHeaderChecksum => {
+                    video.write_string(b"GPT header checksum mismatch\n");
+                }
+                GPTError::BadPartitionArrayChecksum => {
+                    video.write_string(b"GPT partition array checksum mismatch\n");
+                }
+                GPTError::BadPartitionEntrySize(size) => {
+                    video.write_string(b"Bad GPT partition entry size: 0x");
+                    video.write_hex_u32(*size as u32);
+                    video.write_char(b'\n');
+                }
+                GPTError::TooManyPartitionEntries(count) => {
+                    video.write_string(b"Too many GPT partition entries: 0x");
+                    video.write_hex_u32(*count as u32);
+                    video.write_char(b'\n');
+                }
+                GPTError::PartitionArrayOutOfBounds => {
+                    video.write_string(b"GPT partition array extends past the end of the disk\n");
+                }
+                GPTError::PartitionOutOfRange(index) => {
+                    video.write_string(b"GPT partition 0x");
+                    video.write_hex_u32(*index as u32);
+                    video.write_string(b" is outside of the disk\n");
+                }
             }
         }
         kpanic();
@@ -147,90 +275,292 @@ impl GPTError {
 }
 
 impl GUIDPartitionTable {
+    /// Reads the partition table, falling back to the backup header and array at the end of the disk when the primary ones are missing or fail their checksums. <br>
+    /// A bad protective MBR is then only a warning, a valid GPT header is what counts. <br>
     pub fn read(disk: &mut ExtendedDisk) -> Result<GUIDPartitionTable, GPTError> {
+        Self::read_with_backup(disk, true)
+    }
+
+    /// Like [`Self::read`], but a corrupted primary table is returned as an error instead of using the backup
+    pub fn read_primary_only(disk: &mut ExtendedDisk) -> Result<GUIDPartitionTable, GPTError> {
+        Self::read_with_backup(disk, false)
+    }
+
+    fn read_with_backup(
+        disk: &mut ExtendedDisk,
+        use_backup: bool,
+    ) -> Result<GUIDPartitionTable, GPTError> {
         let disk_params = disk.get_params().map_err(GPTError::DiskError)?;
 
         let sector_size = disk_params.bytes_per_sector as usize;
-        if sector_size != 512 {
+        if !(MIN_SECTOR_SIZE..=MAX_SECTOR_SIZE).contains(&sector_size)
+            || !sector_size.is_power_of_two()
+        {
             return Err(GPTError::BadSectorSize);
         }
 
-        let max_lba = disk_params.sectors - 1;
-
-        let mut buffer = Buffer::new(34 * 512).ok_or(GPTError::FailedMemAlloc(34 * 512))?; // 34 logical 512-byte sectors
-        let mut sector_buffer =
-            Buffer::new(sector_size).ok_or(GPTError::FailedMemAlloc(sector_size))?; // 1 physical sector
+        let sector_count = disk.sector_count().map_err(GPTError::DiskError)?;
+        if sector_count == 0 {
+            return Err(GPTError::NotGPT);
+        }
+        let max_lba = sector_count - 1;
 
-        let mut read = 0;
-        let mut lba = 0;
-        while read < 34 * 512 {
-            disk.read_sector(lba, &mut sector_buffer)
-                .map_err(GPTError::DiskError)?;
+        // A wiped LBA 0 is exactly when the backup matters, so with it a bad protective MBR is only a warning
+        let mbr_error = match Self::check_protective_mbr(disk, sector_size, max_lba) {
+            Ok(()) => None,
+            Err(e) if !use_backup => return Err(e),
+            Err(e) => Some(e),
+        };
+        // Only once a GPT header shows up, a plain MBR disk has no protective MBR either
+        let mbr_missing = mbr_error.is_some();
+        let warn_mbr_missing = || {
+            if mbr_missing {
+                log_warn!(b"Warning: no valid protective MBR, using the GPT header anyway\r\n");
+            }
+        };
 
-            let to_copy = (34 * 512 - read).min(sector_size);
-            sector_buffer.copy_to(0, &mut buffer, read, to_copy);
+        let (error, backup_lba, primary_header) = match Self::read_header(disk, 1, sector_size) {
+            Ok(header) => {
+                warn_mbr_missing();
+                match Self::read_entries(disk, &header, sector_size, max_lba) {
+                    Ok(partitions) => {
+                        return Ok(GUIDPartitionTable {
+                            header,
+                            partitions,
+                            used_backup: false,
+                        })
+                    }
+                    Err(e) => (e, header.backup_lba, true),
+                }
+            }
+            // A header that fails its checks can't be trusted for the backup location, the spec puts it in the last sector
+            Err(e) => (e, max_lba, false),
+        };
+        let recoverable = matches!(
+            error,
+            GPTError::NotGPT | GPTError::BadHeaderChecksum | GPTError::BadPartitionArrayChecksum
+        );
+        // Without any valid GPT header the protective MBR error is the one worth reporting
+        let error = mbr_error.unwrap_or(error);
+        if !use_backup || !recoverable || backup_lba <= 1 || backup_lba > max_lba {
+            return Err(error);
+        }
 
-            read += sector_size;
-            lba += 1;
+        match error {
+            GPTError::BadHeaderChecksum => printf!(b"Primary GPT header checksum mismatch"),
+            GPTError::BadPartitionArrayChecksum => {
+                printf!(b"Primary GPT partition array checksum mismatch")
+            }
+            _ => printf!(b"Primary GPT header missing or invalid"),
         }
+        printf!(
+            b", trying the backup header at LBA 0x%x\r\n",
+            backup_lba as u32
+        );
+        let backup = Self::read_header(disk, backup_lba, sector_size).and_then(|header| {
+            if !primary_header {
+                warn_mbr_missing();
+            }
+            let partitions = Self::read_entries(disk, &header, sector_size, max_lba)?;
+            Ok(GUIDPartitionTable {
+                header,
+                partitions,
+                used_backup: true,
+            })
+        });
+        match backup {
+            Ok(table) => {
+                log_warn!(b"Warning: primary GPT corrupted, using the backup\r\n");
+                warning(WarningId::GptBackup);
+                Ok(table)
+            }
+            Err(_) => {
+                log_error!(b"The backup GPT is unusable too\r\n");
+                Err(error)
+            }
+        }
+    }
 
-        let mbr = unsafe { (buffer.get_ptr() as *const MasterBootRecord).read_unaligned() };
+    /// Checks that LBA 0 holds a protective MBR covering the whole disk
+    fn check_protective_mbr(
+        disk: &mut ExtendedDisk,
+        sector_size: usize,
+        max_lba: u64,
+    ) -> Result<(), GPTError> {
+        let mut sector_buffer =
+            Buffer::new_tagged(sector_size, b"gpt").ok_or(GPTError::FailedMemAlloc(sector_size))?;
+        disk.read_sector(0, &mut sector_buffer)
+            .map_err(GPTError::DiskError)?;
+
+        let mbr = unsafe { (sector_buffer.get_ptr() as *const MasterBootRecord).read_unaligned() };
         if mbr.signature[0] != 0x55 || mbr.signature[1] != 0xAA {
             return Err(GPTError::BadMasterBootRecord);
         }
 
-        if mbr.mbr_partitions[0].bootable != 0
-            || mbr.mbr_partitions[0].os_type != 0xEE
-            || mbr.mbr_partitions[0].start_chs[0] != 0
-            || mbr.mbr_partitions[0].start_chs[1] != 2
-            || mbr.mbr_partitions[0].start_chs[2] != 0
-            || mbr.mbr_partitions[0].start_lba != 1
-            || (if max_lba > u32::MAX as u64 {
-                mbr.mbr_partitions[0].end_lba != u32::MAX
-            } else {
-                mbr.mbr_partitions[0].end_lba != max_lba as u32
-            })
+        let Some(protective) = mbr
+            .mbr_partitions
+            .iter()
+            .position(MBRPartition::protects_gpt_header)
+        else {
+            return Err(GPTError::NoProtectivePartition);
+        };
+
+        // Hybrid MBRs and some partitioning tools don't match the spec exactly, the GPT header is what gets validated
+        let partition = &mbr.mbr_partitions[protective];
+        let expected_count = max_lba.min(u32::MAX as u64) as u32;
+        let sector_count = partition.sector_count;
+        if protective != 0
+            || partition.bootable != 0
+            || partition.start_chs != [0, 2, 0]
+            || partition.start_lba != 1
+            || sector_count != expected_count
         {
-            return Err(GPTError::NotGPT);
+            printf!(
+                b"Note: protective MBR partition 0x%x doesn't follow the spec exactly (bootable 0x%x, start LBA 0x%x, 0x%x sectors)\r\n",
+                protective as u32,
+                partition.bootable as u32,
+                partition.start_lba,
+                sector_count
+            );
         }
-
-        for i in 1..4 {
-            if !mbr.mbr_partitions[i].is_null() {
-                return Err(GPTError::NotGPT);
+        for (i, other) in mbr.mbr_partitions.iter().enumerate() {
+            if i != protective && !other.is_null() {
+                let start_lba = other.start_lba;
+                printf!(
+                    b"Note: hybrid MBR, partition 0x%x has type 0x%x at LBA 0x%x, ignored\r\n",
+                    i as u32,
+                    other.os_type as u32,
+                    start_lba
+                );
             }
         }
+        Ok(())
+    }
+
+    /// Reads and checks the header at `lba`, including its checksum. The header is at the start of the sector
+    fn read_header(
+        disk: &mut ExtendedDisk,
+        lba: u64,
+        sector_size: usize,
+    ) -> Result<GPTHeader, GPTError> {
+        let mut sector_buffer =
+            Buffer::new_tagged(sector_size, b"gpt").ok_or(GPTError::FailedMemAlloc(sector_size))?;
+        disk.read_sector(lba, &mut sector_buffer)
+            .map_err(GPTError::DiskError)?;
 
-        let header = unsafe { (buffer.get_ptr().add(512) as *const GPTHeader).read_unaligned() };
+        let header = unsafe { (sector_buffer.get_ptr() as *const GPTHeader).read_unaligned() };
 
-        if &header.signature != b"EFI PART" || header.header_size != 0x5C {
+        if &header.signature != b"EFI PART" || header.header_size as usize != GPT_HEADER_SIZE {
+            log::with_level(LogLevel::Trace, || {
+                hex_dump(b"GPT header", &sector_buffer[..GPT_DUMP_SIZE]);
+                if disk.read_sector(0, &mut sector_buffer).is_ok() {
+                    hex_dump(b"MBR", &sector_buffer[..GPT_DUMP_SIZE]);
+                }
+            });
             return Err(GPTError::NotGPT);
         }
 
-        if header.partition_table_lba != 2 {
-            return Err(GPTError::UnsupportedTableLBA);
+        let mut bytes = [0u8; GPT_HEADER_SIZE];
+        bytes.copy_from_slice(&sector_buffer[..GPT_HEADER_SIZE]);
+        bytes[GPT_HEADER_CRC_OFFSET..GPT_HEADER_CRC_OFFSET + 4].fill(0);
+        if crc32(&bytes) != header.header_crc32 {
+            return Err(GPTError::BadHeaderChecksum);
+        }
+
+        if header.current_lba != lba {
+            return Err(GPTError::NotGPT);
         }
 
+        Ok(header)
+    }
+
+    /// Reads the partition array described by `header` and checks it against `partition_entries_crc32`. <br>
+    /// Exactly `partition_entry_count * partition_entry_size` bytes are used, starting at `partition_table_lba`. <br>
+    fn read_entries(
+        disk: &mut ExtendedDisk,
+        header: &GPTHeader,
+        sector_size: usize,
+        max_lba: u64,
+    ) -> Result<Vec<GUIDPartitionTableEntry>, GPTError> {
         let entry_size = header.partition_entry_size as usize;
         let part_count = header.partition_entry_count as usize;
-        let name_size = header.partition_entry_size as usize - 0x38;
+        if entry_size < MIN_PARTITION_ENTRY_SIZE || !entry_size.is_multiple_of(8) {
+            return Err(GPTError::BadPartitionEntrySize(entry_size));
+        }
+        if part_count > MAX_PARTITION_ENTRY_COUNT {
+            return Err(GPTError::TooManyPartitionEntries(part_count));
+        }
+        // Only a huge entry size overflows, the count is bounded above
+        let array_size = entry_size
+            .checked_mul(part_count)
+            .ok_or(GPTError::BadPartitionEntrySize(entry_size))?;
+        if array_size > MAX_PARTITION_ARRAY_SIZE {
+            return Err(GPTError::FailedMemAlloc(array_size));
+        }
+        if part_count > DEFAULT_PARTITION_ENTRY_COUNT {
+            log_warn!(
+                b"Warning: GPT declares 0x%x partition entries, more than the usual 128\r\n",
+                part_count
+            );
+            warning(WarningId::GptEntryCount);
+        }
 
-        let mut table = GUIDPartitionTable {
-            header,
-            partitions: Vec::new(part_count),
-        };
+        let table_lba = header.partition_table_lba;
+        let array_sectors = array_size.div_ceil(sector_size).max(1);
+        if table_lba < 2 {
+            return Err(GPTError::UnsupportedTableLBA);
+        }
+        if table_lba
+            .checked_add(array_sectors as u64 - 1)
+            .is_none_or(|end| end > max_lba)
+        {
+            return Err(GPTError::PartitionArrayOutOfBounds);
+        }
 
-        for i in 0..part_count {
-            let (entry, name) = unsafe {
-                let addr = buffer.get_ptr().add(1024 + entry_size * i);
-                let entry = (addr as *const GUIDPartitionTableEntryRaw).read_unaligned();
+        let array_len = array_sectors * sector_size;
+        let mut array =
+            Buffer::new_tagged(array_len, b"gpt").ok_or(GPTError::FailedMemAlloc(array_len))?;
+        let mut sector_buffer =
+            Buffer::new_tagged(sector_size, b"gpt").ok_or(GPTError::FailedMemAlloc(sector_size))?;
+        for i in 0..array_sectors {
+            disk.read_sector(table_lba + i as u64, &mut sector_buffer)
+                .map_err(GPTError::DiskError)?;
+            sector_buffer.copy_to(0, &mut array, i * sector_size, sector_size);
+        }
 
-                if entry.type_guid == [0; 16] {
-                    continue;
-                }
+        if crc32(&array[..array_size]) != header.partition_entries_crc32 {
+            return Err(GPTError::BadPartitionArrayChecksum);
+        }
 
-                let name = Buffer::new(name_size).ok_or(GPTError::FailedMemAlloc(name_size))?;
-                (entry, name)
+        let mut partitions = Vec::new_tagged(part_count.max(1), b"gpt");
+
+        for i in 0..part_count {
+            let Some(raw) = array[..].get(entry_size * i..entry_size * (i + 1)) else {
+                break;
             };
+            // entry_size >= MIN_PARTITION_ENTRY_SIZE, so the raw entry is inside `raw`
+            let entry =
+                unsafe { (raw.as_ptr() as *const GUIDPartitionTableEntryRaw).read_unaligned() };
+
+            if entry.type_guid == [0; 16] {
+                continue;
+            }
+            let (first_lba, last_lba) = (entry.first_lba, entry.last_lba);
+            if first_lba > last_lba || last_lba > max_lba {
+                printf!(
+                    b"GPT partition 0x%x spans LBA 0x%x%x-0x%x%x, the disk ends at 0x%x%x\r\n",
+                    i as u32,
+                    (first_lba >> 32) as u32,
+                    first_lba as u32,
+                    (last_lba >> 32) as u32,
+                    last_lba as u32,
+                    (max_lba >> 32) as u32,
+                    max_lba as u32
+                );
+                return Err(GPTError::PartitionOutOfRange(i));
+            }
+
+            let name = decode_partition_name(&raw[PARTITION_NAME_OFFSET..])?;
 
             let part = GUIDPartitionTableEntry {
                 type_guid: entry.type_guid,
@@ -241,11 +571,50 @@ impl GUIDPartitionTable {
                 name,
             };
 
-            table.partitions.push(part);
+            partitions.push(part);
+        }
+
+        Ok(partitions)
+    }
+}
+
+/// Parses a GUID in its textual form (`xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`) into the on-disk byte order printed by `write_guid`
+pub fn parse_guid(text: &[u8]) -> Option<[u8; 16]> {
+    // Text position of each byte's two digits, the first three groups are little-endian
+    const DIGITS: [usize; 16] = [6, 4, 2, 0, 11, 9, 16, 14, 19, 21, 24, 26, 28, 30, 32, 34];
+    if text.len() != 36 || [8, 13, 18, 23].iter().any(|i| text[*i] != b'-') {
+        return None;
+    }
+    let mut guid = [0u8; 16];
+    for (byte, i) in guid.iter_mut().zip(DIGITS) {
+        let digits = &text[i..i + 2];
+        if !digits.iter().all(u8::is_ascii_hexdigit) {
+            return None;
         }
+        *byte = u8::from_str_radix(core::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(guid)
+}
 
-        Ok(table)
+/// Converts a UTF-16LE partition name to ASCII, up to its NUL terminator. <br>
+/// Code units outside ASCII become `?`, an empty name is a null buffer. <br>
+fn decode_partition_name(raw: &[u8]) -> Result<Buffer, GPTError> {
+    let units = || {
+        raw.as_chunks::<2>()
+            .0
+            .iter()
+            .map(|unit| u16::from_le_bytes(*unit))
+            .take_while(|unit| *unit != 0)
+    };
+    let len = units().count();
+    if len == 0 {
+        return Ok(Buffer::null());
+    }
+    let mut name = Buffer::new_tagged(len, b"gpt").ok_or(GPTError::FailedMemAlloc(len))?;
+    for (c, unit) in name.iter_mut().zip(units()) {
+        *c = if unit < 0x80 { unit as u8 } else { b'?' };
     }
+    Ok(name)
 }
 
 pub const PARTITION_GUID_TYPE_LINUX_FS: [u8; 16] = [
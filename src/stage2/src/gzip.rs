@@ -0,0 +1,483 @@
+use crate::{
+    crc32::crc32,
+    fs::{Ext2Error, Ext2File},
+    kpanic,
+    mem::Buffer,
+    printf,
+    video::Video,
+};
+
+pub const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const METHOD_DEFLATE: u8 = 8;
+const HEADER_SIZE: usize = 10;
+const TRAILER_SIZE: usize = 8;
+
+const FLAG_HCRC: u8 = 1 << 1;
+const FLAG_EXTRA: u8 = 1 << 2;
+const FLAG_NAME: u8 = 1 << 3;
+const FLAG_COMMENT: u8 = 1 << 4;
+const FLAG_RESERVED: u8 = 0xE0;
+
+/// Longest Huffman code allowed by DEFLATE
+const MAX_BITS: usize = 15;
+const MAX_LITERAL_CODES: usize = 286;
+const MAX_DISTANCE_CODES: usize = 30;
+const FIXED_LITERAL_CODES: usize = 288;
+const CODE_LENGTH_CODES: usize = 19;
+const END_OF_BLOCK: u16 = 256;
+
+/// Base lengths and extra bits of the length symbols 257..=285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// Base distances and extra bits of the distance symbols 0..=29
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order the code length code lengths are stored in by dynamic blocks
+const CODE_LENGTH_ORDER: [usize; CODE_LENGTH_CODES] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+pub enum GzipError {
+    Ext2Error(Ext2Error),
+    FailedMemAlloc(usize),
+    /// Compression method other than DEFLATE
+    UnsupportedMethod(u8),
+    /// Header flags with reserved bits set
+    BadFlags(u8),
+    /// The data ends before the stream does
+    Truncated,
+    /// Block type 3
+    BadBlockType,
+    /// Stored block whose length doesn't match its complement
+    BadStoredLength,
+    /// Code lengths that don't form a usable Huffman code
+    BadHuffmanTable,
+    /// Length or distance symbol outside of the tables
+    BadSymbol(u16),
+    /// Back-reference before the start of the output, and the output position
+    BadDistance(usize, usize),
+    /// The trailer declares an empty file
+    Empty,
+    /// Trailer ISIZE and the size actually decompressed
+    SizeMismatch(u32, u32),
+    /// Trailer CRC32 and the one of the decompressed data
+    CrcMismatch(u32, u32),
+}
+
+impl GzipError {
+    pub fn printf(&self) {
+        match self {
+            GzipError::Ext2Error(_) => printf!(b"read error"),
+            GzipError::FailedMemAlloc(size) => {
+                printf!(b"failed to allocate 0x%x bytes", *size)
+            }
+            GzipError::UnsupportedMethod(method) => {
+                printf!(b"unsupported compression method 0x%b", *method)
+            }
+            GzipError::BadFlags(flags) => printf!(b"reserved header flags set: 0x%b", *flags),
+            GzipError::Truncated => printf!(b"truncated stream"),
+            GzipError::BadBlockType => printf!(b"bad DEFLATE block type"),
+            GzipError::BadStoredLength => printf!(b"bad stored block length"),
+            GzipError::BadHuffmanTable => printf!(b"bad Huffman code lengths"),
+            GzipError::BadSymbol(symbol) => printf!(b"bad symbol 0x%x", *symbol as u32),
+            GzipError::BadDistance(distance, position) => printf!(
+                b"distance 0x%x reaches before the start of the output at 0x%x",
+                *distance,
+                *position
+            ),
+            GzipError::Empty => printf!(b"empty file"),
+            GzipError::SizeMismatch(expected, actual) => printf!(
+                b"ISIZE mismatch, trailer says 0x%x bytes, decompressed 0x%x",
+                *expected,
+                *actual
+            ),
+            GzipError::CrcMismatch(expected, actual) => printf!(
+                b"CRC32 mismatch, trailer says 0x%x, data has 0x%x",
+                *expected,
+                *actual
+            ),
+        }
+    }
+
+    pub fn panic(&self) -> ! {
+        if let GzipError::Ext2Error(e) = self {
+            e.panic();
+        }
+        printf!(b"Failed to decompress the gzip image: ");
+        self.printf();
+        printf!(b"\r\n");
+        unsafe {
+            let video = Video::get();
+            video.write_string(b"Failed to decompress the gzip image");
+            match self {
+                GzipError::SizeMismatch(expected, actual)
+                | GzipError::CrcMismatch(expected, actual) => {
+                    video.write_string(b": expected 0x");
+                    video.write_hex_u32(*expected);
+                    video.write_string(b", got 0x");
+                    video.write_hex_u32(*actual);
+                }
+                _ => {}
+            }
+            video.write_char(b'\n');
+        }
+        kpanic();
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    bit_buffer: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8], position: usize) -> Self {
+        Self {
+            data,
+            position,
+            bit_buffer: 0,
+            bit_count: 0,
+        }
+    }
+
+    /// Next `count` bits, least significant first. `count` is at most 16
+    fn bits(&mut self, count: u32) -> Result<u32, GzipError> {
+        while self.bit_count < count {
+            let byte = *self.data.get(self.position).ok_or(GzipError::Truncated)?;
+            self.position += 1;
+            self.bit_buffer |= (byte as u32) << self.bit_count;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buffer & ((1 << count) - 1);
+        self.bit_buffer >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    /// Drops the bits left in the current byte
+    fn align(&mut self) {
+        self.bit_buffer = 0;
+        self.bit_count = 0;
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], GzipError> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + count)
+            .ok_or(GzipError::Truncated)?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, GzipError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, GzipError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Skips a null terminated string
+    fn skip_string(&mut self) -> Result<(), GzipError> {
+        while self.bytes(1)?[0] != 0 {}
+        Ok(())
+    }
+}
+
+/// Canonical Huffman code, decoded one bit at a time
+struct Huffman {
+    /// Number of codes of each length
+    counts: [u16; MAX_BITS + 1],
+    /// Symbols ordered by code
+    symbols: [u16; FIXED_LITERAL_CODES],
+}
+
+impl Huffman {
+    /// Builds the code for symbols of `lengths`, 0 meaning unused. Incomplete codes are accepted
+    fn new(lengths: &[u8]) -> Result<Self, GzipError> {
+        let mut huffman = Self {
+            counts: [0; MAX_BITS + 1],
+            symbols: [0; FIXED_LITERAL_CODES],
+        };
+        for length in lengths {
+            huffman.counts[*length as usize] += 1;
+        }
+        let mut left: i32 = 1;
+        for count in &huffman.counts[1..] {
+            left = (left << 1) - *count as i32;
+            if left < 0 {
+                return Err(GzipError::BadHuffmanTable);
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for length in 1..MAX_BITS {
+            offsets[length + 1] = offsets[length] + huffman.counts[length];
+        }
+        for (symbol, length) in lengths.iter().enumerate() {
+            if *length != 0 {
+                huffman.symbols[offsets[*length as usize] as usize] = symbol as u16;
+                offsets[*length as usize] += 1;
+            }
+        }
+        Ok(huffman)
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, GzipError> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for count in &self.counts[1..] {
+            code |= reader.bits(1)? as i32;
+            let count = *count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(GzipError::BadHuffmanTable)
+    }
+}
+
+/// Decompressed data, sized from the trailer's ISIZE. <br>
+/// Bytes past the end are only counted, so a wrong ISIZE is reported with the real size. <br>
+struct Output {
+    buffer: Buffer,
+    len: usize,
+}
+
+impl Output {
+    fn push(&mut self, byte: u8) {
+        if self.len < self.buffer.len() {
+            self.buffer[self.len] = byte;
+        }
+        self.len += 1;
+    }
+
+    fn copy_back(&mut self, distance: usize, length: usize) -> Result<(), GzipError> {
+        if distance > self.len {
+            return Err(GzipError::BadDistance(distance, self.len));
+        }
+        for _ in 0..length {
+            let from = self.len - distance;
+            let byte = if from < self.buffer.len() {
+                self.buffer[from]
+            } else {
+                0
+            };
+            self.push(byte);
+        }
+        Ok(())
+    }
+}
+
+fn inflate_stored(reader: &mut BitReader, output: &mut Output) -> Result<(), GzipError> {
+    reader.align();
+    let len = reader.u16()?;
+    let complement = reader.u16()?;
+    if len != !complement {
+        return Err(GzipError::BadStoredLength);
+    }
+    for byte in reader.bytes(len as usize)? {
+        output.push(*byte);
+    }
+    Ok(())
+}
+
+fn inflate_codes(
+    reader: &mut BitReader,
+    output: &mut Output,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), GzipError> {
+    loop {
+        let symbol = literals.decode(reader)?;
+        if symbol < END_OF_BLOCK {
+            output.push(symbol as u8);
+            continue;
+        }
+        if symbol == END_OF_BLOCK {
+            return Ok(());
+        }
+
+        let index = (symbol - END_OF_BLOCK - 1) as usize;
+        if index >= LENGTH_BASE.len() {
+            return Err(GzipError::BadSymbol(symbol));
+        }
+        let length =
+            LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index] as u32)? as usize;
+
+        let index = distances.decode(reader)? as usize;
+        if index >= DISTANCE_BASE.len() {
+            return Err(GzipError::BadSymbol(index as u16));
+        }
+        let distance =
+            DISTANCE_BASE[index] as usize + reader.bits(DISTANCE_EXTRA[index] as u32)? as usize;
+        output.copy_back(distance, length)?;
+    }
+}
+
+fn inflate_fixed(reader: &mut BitReader, output: &mut Output) -> Result<(), GzipError> {
+    let mut lengths = [0u8; FIXED_LITERAL_CODES];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    let literals = Huffman::new(&lengths)?;
+    let distances = Huffman::new(&[5; MAX_DISTANCE_CODES])?;
+    inflate_codes(reader, output, &literals, &distances)
+}
+
+fn inflate_dynamic(reader: &mut BitReader, output: &mut Output) -> Result<(), GzipError> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    if literal_count > MAX_LITERAL_CODES || distance_count > MAX_DISTANCE_CODES {
+        return Err(GzipError::BadHuffmanTable);
+    }
+
+    let mut code_lengths = [0u8; CODE_LENGTH_CODES];
+    for index in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[*index] = reader.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths)?;
+
+    let mut lengths = [0u8; MAX_LITERAL_CODES + MAX_DISTANCE_CODES];
+    let total = literal_count + distance_count;
+    let mut filled = 0;
+    while filled < total {
+        let symbol = code_length_code.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                if filled == 0 {
+                    return Err(GzipError::BadHuffmanTable);
+                }
+                (lengths[filled - 1], 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if filled + repeat > total {
+            return Err(GzipError::BadHuffmanTable);
+        }
+        lengths[filled..filled + repeat].fill(value);
+        filled += repeat;
+    }
+    if lengths[END_OF_BLOCK as usize] == 0 {
+        return Err(GzipError::BadHuffmanTable);
+    }
+
+    let literals = Huffman::new(&lengths[..literal_count])?;
+    let distances = Huffman::new(&lengths[literal_count..total])?;
+    inflate_codes(reader, output, &literals, &distances)
+}
+
+/// Decompresses a gzip member: stored, fixed and dynamic Huffman blocks. <br>
+/// The output is sized from the trailer's ISIZE, then checked against it and the trailer's CRC32. <br>
+pub fn gunzip(data: &[u8]) -> Result<Buffer, GzipError> {
+    if data.len() < HEADER_SIZE + TRAILER_SIZE {
+        return Err(GzipError::Truncated);
+    }
+    let mut reader = BitReader::new(data, 0);
+    let header = reader.bytes(HEADER_SIZE)?;
+    if header[2] != METHOD_DEFLATE {
+        return Err(GzipError::UnsupportedMethod(header[2]));
+    }
+    let flags = header[3];
+    if flags & FLAG_RESERVED != 0 {
+        return Err(GzipError::BadFlags(flags));
+    }
+    if flags & FLAG_EXTRA != 0 {
+        let extra_len = reader.u16()? as usize;
+        reader.bytes(extra_len)?;
+    }
+    if flags & FLAG_NAME != 0 {
+        reader.skip_string()?;
+    }
+    if flags & FLAG_COMMENT != 0 {
+        reader.skip_string()?;
+    }
+    if flags & FLAG_HCRC != 0 {
+        reader.u16()?;
+    }
+
+    let tail = &data[data.len() - 4..];
+    let declared_size = u32::from_le_bytes([tail[0], tail[1], tail[2], tail[3]]) as usize;
+    if declared_size == 0 {
+        return Err(GzipError::Empty);
+    }
+    let mut output = Output {
+        buffer: Buffer::new_tagged(declared_size, b"gzip")
+            .ok_or(GzipError::FailedMemAlloc(declared_size))?,
+        len: 0,
+    };
+
+    loop {
+        let last = reader.bits(1)? != 0;
+        match reader.bits(2)? {
+            0 => inflate_stored(&mut reader, &mut output)?,
+            1 => inflate_fixed(&mut reader, &mut output)?,
+            2 => inflate_dynamic(&mut reader, &mut output)?,
+            _ => return Err(GzipError::BadBlockType),
+        }
+        if last {
+            break;
+        }
+    }
+
+    reader.align();
+    let expected_crc = reader.u32()?;
+    let expected_size = reader.u32()?;
+    if output.len as u32 != expected_size || output.len != output.buffer.len() {
+        return Err(GzipError::SizeMismatch(expected_size, output.len as u32));
+    }
+    let actual_crc = crc32(&output.buffer);
+    if actual_crc != expected_crc {
+        return Err(GzipError::CrcMismatch(expected_crc, actual_crc));
+    }
+    Ok(output.buffer)
+}
+
+/// Decompresses `file` in memory when it starts with the gzip magic, returns it unchanged otherwise. <br>
+/// The returned file reads from the decompressed image, see [`Ext2File::into_memory`]. <br>
+pub fn open_maybe_compressed<'f>(mut file: Ext2File<'f>) -> Result<Ext2File<'f>, GzipError> {
+    if file.get_size() < GZIP_MAGIC.len() {
+        return Ok(file);
+    }
+    let mut magic = Buffer::new_tagged(GZIP_MAGIC.len(), b"gzip")
+        .ok_or(GzipError::FailedMemAlloc(GZIP_MAGIC.len()))?;
+    file.seek(0).map_err(GzipError::Ext2Error)?;
+    file.read(&mut magic, GZIP_MAGIC.len())
+        .map_err(GzipError::Ext2Error)?;
+    file.seek(0).map_err(GzipError::Ext2Error)?;
+    if magic[..] != GZIP_MAGIC {
+        return Ok(file);
+    }
+
+    let compressed = file.read_all().map_err(GzipError::Ext2Error)?;
+    let contents = gunzip(&compressed)?;
+    printf!(
+        b"Decompressed gzip image: 0x%x bytes into 0x%x\r\n",
+        compressed.len(),
+        contents.len()
+    );
+    Ok(file.into_memory(contents))
+}
@@ -0,0 +1,195 @@
+use crate::{
+    e9::write_hex_u8,
+    fs::Ext2File,
+    kpanic,
+    lang::{render, Text},
+    printf,
+    stream::{Sha256Sink, StreamSink},
+    video::Video,
+};
+
+pub const SHA256_DIGEST_SIZE: usize = 32;
+const BLOCK_SIZE: usize = 64;
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Incremental SHA-256 (FIPS 180-4), feed it with [`Sha256::update`] in chunks of any size
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; BLOCK_SIZE],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self {
+            state: INITIAL_STATE,
+            block: [0; BLOCK_SIZE],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (i, word) in self.block.as_chunks::<4>().0.iter().enumerate() {
+            w[i] = u32::from_be_bytes(*word);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(ROUND_CONSTANTS[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        while !data.is_empty() {
+            let count = (BLOCK_SIZE - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + count].copy_from_slice(&data[..count]);
+            self.block_len += count;
+            data = &data[count..];
+            if self.block_len == BLOCK_SIZE {
+                self.compress();
+                self.block_len = 0;
+            }
+        }
+    }
+
+    pub fn finalize(mut self) -> [u8; SHA256_DIGEST_SIZE] {
+        let bit_len = self.total_len.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.block_len != BLOCK_SIZE - 8 {
+            self.update(&[0]);
+        }
+        self.update(&bit_len.to_be_bytes());
+
+        let mut digest = [0; SHA256_DIGEST_SIZE];
+        for (bytes, word) in digest.as_chunks_mut::<4>().0.iter_mut().zip(self.state) {
+            *bytes = word.to_be_bytes();
+        }
+        digest
+    }
+}
+
+pub fn sha256(data: &[u8]) -> [u8; SHA256_DIGEST_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Parses 64 hex digits, either case
+pub fn parse_sha256(value: &[u8]) -> Option<[u8; SHA256_DIGEST_SIZE]> {
+    if value.len() != SHA256_DIGEST_SIZE * 2 {
+        return None;
+    }
+    let mut digest = [0; SHA256_DIGEST_SIZE];
+    for (byte, digits) in digest.iter_mut().zip(value.as_chunks::<2>().0) {
+        for c in digits {
+            let digit = match c {
+                b'0'..=b'9' => c - b'0',
+                b'a'..=b'f' => c - b'a' + 10,
+                b'A'..=b'F' => c - b'A' + 10,
+                _ => return None,
+            };
+            *byte = (*byte << 4) | digit;
+        }
+    }
+    Some(digest)
+}
+
+fn printf_digest(digest: &[u8; SHA256_DIGEST_SIZE]) {
+    for byte in digest {
+        write_hex_u8(*byte);
+    }
+}
+
+fn video_digest(video: &mut Video, digest: &[u8; SHA256_DIGEST_SIZE]) {
+    for byte in digest {
+        video.write_hex_u8(*byte);
+    }
+}
+
+/// Hashes the whole kernel file, streamed block by block, and halts unless it matches `expected`. <br>
+/// Both digests are shown on e9 and on the screen on a mismatch. The file is left at offset 0. <br>
+pub fn verify_kernel_sha256(file: &mut Ext2File, expected: &[u8; SHA256_DIGEST_SIZE]) {
+    let size = file.get_size();
+    let mut sink = Sha256Sink::default();
+    if size != 0 {
+        file.seek(0).unwrap_or_else(|e| e.panic());
+        file.stream_to(&mut sink, size)
+            .unwrap_or_else(|e| e.panic());
+        file.seek(0).unwrap_or_else(|e| e.panic());
+    }
+    let actual = sink.finalize();
+    if actual == *expected {
+        printf!(b"Kernel SHA-256 verified: ");
+        printf_digest(&actual);
+        printf!(b"\r\n");
+        return;
+    }
+
+    printf!(b"Kernel SHA-256 mismatch !\r\n  expected ");
+    printf_digest(expected);
+    printf!(b"\r\n  actual   ");
+    printf_digest(&actual);
+    printf!(b"\r\n");
+    unsafe {
+        let video = Video::get();
+        video.write_string(&render(Text::KernelHashMismatch, &[]));
+        video.write_string(b"\nExpected ");
+        video_digest(video, expected);
+        video.write_string(b"\nActual   ");
+        video_digest(video, &actual);
+        video.write_char(b'\n');
+    }
+    kpanic();
+}
@@ -0,0 +1,169 @@
+use core::{arch::asm, cell::SyncUnsafeCell, mem::size_of};
+
+use crate::{
+    e9::write_string,
+    gdt::CODE32_SELECTOR,
+    kpanic, printf,
+    video::{Color, Video},
+};
+
+/// Only the CPU exceptions are covered, stage2 runs with interrupts off
+const IDT_ENTRIES: usize = 32;
+/// Present, ring 0, 32-bit interrupt gate
+const INTERRUPT_GATE: u8 = 0x8E;
+
+pub const VECTOR_DIVIDE_ERROR: u32 = 0;
+pub const VECTOR_INVALID_OPCODE: u32 = 6;
+pub const VECTOR_DOUBLE_FAULT: u32 = 8;
+pub const VECTOR_GENERAL_PROTECTION: u32 = 13;
+pub const VECTOR_PAGE_FAULT: u32 = 14;
+
+extern "cdecl" {
+    fn exception_stub_0();
+    fn exception_stub_6();
+    fn exception_stub_8();
+    fn exception_stub_13();
+    fn exception_stub_14();
+}
+
+/// What the stubs of asm/idt.asm push, lowest address first
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct ExceptionFrame {
+    pub cr2: u32,
+    pub edi: u32,
+    pub esi: u32,
+    pub ebp: u32,
+    /// ESP before `pushad`, not the faulting ESP
+    pub esp: u32,
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
+    pub vector: u32,
+    /// 0 for the exceptions without one
+    pub error_code: u32,
+    pub eip: u32,
+    pub cs: u32,
+    pub eflags: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct IdtEntry {
+    offset_low: u16,
+    selector: u16,
+    zero: u8,
+    type_attributes: u8,
+    offset_high: u16,
+}
+
+impl IdtEntry {
+    const MISSING: IdtEntry = IdtEntry {
+        offset_low: 0,
+        selector: 0,
+        zero: 0,
+        type_attributes: 0,
+        offset_high: 0,
+    };
+
+    fn gate(handler: usize) -> IdtEntry {
+        IdtEntry {
+            offset_low: handler as u16,
+            selector: CODE32_SELECTOR as u16,
+            zero: 0,
+            type_attributes: INTERRUPT_GATE,
+            offset_high: (handler >> 16) as u16,
+        }
+    }
+}
+
+#[repr(C, packed)]
+struct IdtDescriptor {
+    limit: u16,
+    base: u32,
+}
+
+static IDT: SyncUnsafeCell<[IdtEntry; IDT_ENTRIES]> =
+    SyncUnsafeCell::new([IdtEntry::MISSING; IDT_ENTRIES]);
+
+/// Loads an IDT that reports #DE, #UD, #DF, #GP and #PF instead of triple faulting. <br>
+/// BIOS calls keep working: `unsafe_call_bios_interrupt` saves the protected mode IDT and reloads it on return. <br>
+pub fn install_idt() {
+    let idt = unsafe { &mut *IDT.get() };
+    let stubs: [(u32, unsafe extern "cdecl" fn()); 5] = [
+        (VECTOR_DIVIDE_ERROR, exception_stub_0),
+        (VECTOR_INVALID_OPCODE, exception_stub_6),
+        (VECTOR_DOUBLE_FAULT, exception_stub_8),
+        (VECTOR_GENERAL_PROTECTION, exception_stub_13),
+        (VECTOR_PAGE_FAULT, exception_stub_14),
+    ];
+    for (vector, stub) in stubs {
+        idt[vector as usize] = IdtEntry::gate(stub as usize);
+    }
+    let descriptor = IdtDescriptor {
+        limit: (size_of::<[IdtEntry; IDT_ENTRIES]>() - 1) as u16,
+        base: idt.as_ptr() as u32,
+    };
+    unsafe { asm!("lidt [{}]", in(reg) &descriptor as *const IdtDescriptor) };
+}
+
+fn exception_name(vector: u32) -> &'static [u8] {
+    match vector {
+        VECTOR_DIVIDE_ERROR => b"#DE divide error",
+        VECTOR_INVALID_OPCODE => b"#UD invalid opcode",
+        VECTOR_DOUBLE_FAULT => b"#DF double fault",
+        VECTOR_GENERAL_PROTECTION => b"#GP general protection fault",
+        VECTOR_PAGE_FAULT => b"#PF page fault",
+        _ => b"unknown exception",
+    }
+}
+
+/// Common handler of the asm stubs: dumps the frame on e9 and on the screen, then panics
+#[no_mangle]
+extern "cdecl" fn exception_handler(frame: *const ExceptionFrame) -> ! {
+    let frame = unsafe { *frame };
+    let name = exception_name(frame.vector);
+
+    printf!(b"CPU exception 0x%x: ", frame.vector as usize);
+    write_string(name);
+    printf!(
+        b"\r\n  error code 0x%x, EIP 0x%x, CS 0x%x, EFLAGS 0x%x\r\n",
+        frame.error_code as usize,
+        frame.eip as usize,
+        frame.cs as usize,
+        frame.eflags as usize
+    );
+    if frame.vector == VECTOR_PAGE_FAULT {
+        printf!(b"  CR2 0x%x\r\n", frame.cr2 as usize);
+    }
+    printf!(
+        b"  EAX 0x%x EBX 0x%x ECX 0x%x EDX 0x%x\r\n  ESI 0x%x EDI 0x%x EBP 0x%x ESP 0x%x\r\n",
+        frame.eax as usize,
+        frame.ebx as usize,
+        frame.ecx as usize,
+        frame.edx as usize,
+        frame.esi as usize,
+        frame.edi as usize,
+        frame.ebp as usize,
+        frame.esp as usize
+    );
+
+    unsafe {
+        let video = Video::get();
+        video.set_color(Color::White, Color::Red);
+        video.write_string(b"\nCPU exception ");
+        video.write_string(name);
+        video.write_string(b"\nError code 0x");
+        video.write_hex_u32(frame.error_code);
+        video.write_string(b" EIP 0x");
+        video.write_hex_u32(frame.eip);
+        if frame.vector == VECTOR_PAGE_FAULT {
+            video.write_string(b" CR2 0x");
+            video.write_hex_u32(frame.cr2);
+        }
+        video.write_char(b'\n');
+        video.set_color(Color::White, Color::Black);
+    }
+    kpanic();
+}
@@ -0,0 +1,150 @@
+use crate::{
+    e9::write_string,
+    fs::{Ext2FileSystem, Ext2FileType},
+    kpanic,
+    lang::{hex, render, Text},
+    mem::{get_mem_free, Buffer, Vec},
+    printf,
+    stream::{tee, BufferSink, Crc32Sink, StreamSink},
+    video::Video,
+};
+
+/// Uncompressed cpio archives ("newc" and "crc" formats) start with this, and must start on a 4 byte boundary
+const CPIO_MAGIC: &[u8] = b"07070";
+const CPIO_ALIGNMENT: usize = 4;
+
+/// Where one `initrd=` file landed in the combined image
+struct InitrdComponent {
+    inode: usize,
+    offset: usize,
+    size: usize,
+}
+
+/// The `initrd=` files, concatenated in config order into one contiguous buffer
+pub struct InitrdImage {
+    buffer: Buffer,
+    len: usize,
+}
+
+impl InitrdImage {
+    /// Returns `(physical address, size)`, the buffer stays allocated forever
+    pub fn leak(self) -> (u64, u64) {
+        let address = unsafe { self.buffer.get_ptr() as u64 };
+        unsafe { self.buffer.leak() };
+        (address, self.len as u64)
+    }
+}
+
+fn initrd_failed(path: &[u8], message: &[u8]) -> ! {
+    printf!(b"initrd ");
+    write_string(path);
+    printf!(b": ");
+    write_string(message);
+    printf!(b"\r\n");
+    unsafe {
+        let video = Video::get();
+        video.write_string(&render(Text::InitrdFailed, &[path, message]));
+        video.write_char(b'\n');
+    }
+    kpanic();
+}
+
+fn initrd_too_large(size: usize, free: usize) -> ! {
+    unsafe {
+        let video = Video::get();
+        video.write_string(&render(
+            Text::InitrdTooLarge,
+            &[&hex(size as u32), &hex(free as u32)],
+        ));
+        video.write_char(b'\n');
+    }
+    kpanic();
+}
+
+/// Loads every `initrd=` file back to back into one buffer. <br>
+/// Uncompressed cpio members are padded to a 4 byte boundary as the Linux initramfs unpacker requires, compressed members are not. <br>
+/// Every file is found and sized before anything is allocated, so a missing file fails the boot without a partial image. <br>
+pub fn load_initrds<'p>(
+    ext2: &mut Ext2FileSystem,
+    paths: impl Iterator<Item = &'p [u8]> + Clone,
+) -> Option<InitrdImage> {
+    let mut components: Vec<InitrdComponent> = Vec::new_tagged(4, b"initrd");
+    let mut total: usize = 0;
+    for path in paths.clone() {
+        let Some(inode) = ext2.find_inode(path).unwrap_or_else(|e| e.panic()) else {
+            initrd_failed(path, b"not found");
+        };
+        let Ext2FileType::File(mut file) = ext2.open(inode).unwrap_or_else(|e| e.panic()) else {
+            initrd_failed(path, b"not a regular file");
+        };
+        let size = file.get_size();
+        let mut magic = Buffer::new_tagged(CPIO_MAGIC.len(), b"initrd").unwrap_or_else(|| kpanic());
+        let read = file
+            .read(&mut magic, CPIO_MAGIC.len().min(size))
+            .unwrap_or_else(|e| e.panic());
+        if read == CPIO_MAGIC.len() && &magic[..] == CPIO_MAGIC {
+            total = total.next_multiple_of(CPIO_ALIGNMENT);
+        }
+        components.push(InitrdComponent {
+            inode,
+            offset: total,
+            size,
+        });
+        total += size;
+    }
+    if components.is_empty() {
+        return None;
+    }
+
+    let free = get_mem_free();
+    if total > free {
+        printf!(
+            b"initrd: combined size 0x%x is larger than the 0x%x free heap bytes\r\n",
+            total,
+            free
+        );
+        initrd_too_large(total, free);
+    }
+
+    let mut buffer = Buffer::new_tagged(total.max(1), b"initrd").unwrap_or_else(|| {
+        // Enough free bytes overall, but no contiguous block holds the image
+        printf!(
+            b"initrd: failed to allocate 0x%x contiguous bytes\r\n",
+            total
+        );
+        initrd_too_large(total, get_mem_free());
+    });
+    // Alignment padding between members must be zeros
+    unsafe { buffer.get_ptr().write_bytes(0, total) };
+
+    printf!(b"initrd: 0x%x bytes at 0x%x\r\n", total, unsafe {
+        buffer.get_ptr() as usize
+    });
+    for (component, path) in components.iter().zip(paths) {
+        let Ext2FileType::File(mut file) = ext2.open(component.inode).unwrap_or_else(|e| e.panic())
+        else {
+            initrd_failed(path, b"not a regular file");
+        };
+        let mut sink = tee(
+            BufferSink::new(&mut buffer, component.offset),
+            Crc32Sink::default(),
+        );
+        let read = file
+            .stream_to(&mut sink, component.size)
+            .unwrap_or_else(|e| e.panic());
+        if read != component.size {
+            initrd_failed(path, b"short read");
+        }
+        let (_, crc) = sink.finalize();
+        printf!(
+            b"    +0x%x: 0x%x bytes, crc32 0x%x, ",
+            component.offset,
+            component.size,
+            crc as usize
+        );
+        write_string(path);
+        printf!(b"\r\n");
+    }
+
+    Some(InitrdImage { buffer, len: total })
+}
@@ -0,0 +1,200 @@
+use crate::{
+    bios::ExtendedDisk,
+    e9::{write_string, write_u32_decimal, write_u64_decimal},
+    kpanic,
+    mem::{Buffer, Vec},
+    printf,
+    video::Video,
+    warnings::{warning, WarningId},
+};
+
+/// The bootloader version, as [major, minor, patch, build]
+pub const BOOTLOADER_VERSION: [u8; 4] = [1, 0, 0, 0];
+
+/// Hash of the source tree, profile, features and compiler this stage2 was built with (see `build.rs`), as 32 lowercase hex digits
+pub const BUILD_ID_HEX: &str = env!("OBSIBOOT_BUILD_ID");
+/// [`BUILD_ID_HEX`] as bytes, most significant first
+pub const BUILD_ID: [u8; 16] = parse_build_id(BUILD_ID_HEX.as_bytes());
+
+const fn parse_build_id(hex: &[u8]) -> [u8; 16] {
+    const fn digit(c: u8) -> u8 {
+        match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            _ => panic!("OBSIBOOT_BUILD_ID must be lowercase hex"),
+        }
+    }
+    assert!(hex.len() == 32, "OBSIBOOT_BUILD_ID must be 32 hex digits");
+    let mut id = [0u8; 16];
+    let mut i = 0;
+    while i < 16 {
+        id[i] = (digit(hex[2 * i]) << 4) | digit(hex[2 * i + 1]);
+        i += 1;
+    }
+    id
+}
+
+/// Logs the version and build id, the line to correlate logs and reports with a binary
+pub fn printf_version_banner() {
+    printf!(b"ObsidianBootloader ");
+    for (i, part) in BOOTLOADER_VERSION.iter().enumerate() {
+        if i != 0 {
+            printf!(b".");
+        }
+        write_u32_decimal(*part as u32);
+    }
+    printf!(b" build ");
+    write_string(BUILD_ID_HEX.as_bytes());
+    printf!(b"\r\n");
+}
+
+const INSTALL_BANNER_MAGIC: [u8; 16] = *b"OBSIBOOT_INSTALL";
+
+/// Byte offset of stage2 on disk (see the `Sconstruct` disk layout), the banner sits in its first sector
+const STAGE2_DISK_OFFSET: u64 = 35 * 512;
+/// Text only present in the boot sector of an ObsidianBootloader install (see `boot/boot.asm`)
+const BOOT_SECTOR_MARKER: &[u8] = b"Loading stage 1";
+/// Maximum number of BIOS hard disks probed
+const MAX_PROBED_DISKS: u8 = 8;
+
+#[repr(C)]
+pub struct InstallBanner {
+    magic: [u8; 16],
+    version: [u8; 4],
+}
+
+/// Placed right after the stage2 entry stub by the linker script, so that other installs can be found by reading one sector
+#[used]
+#[link_section = ".text.obsiboot_banner"]
+static INSTALL_BANNER: InstallBanner = InstallBanner {
+    magic: INSTALL_BANNER_MAGIC,
+    version: BOOTLOADER_VERSION,
+};
+
+pub enum MultipleInstallsPolicy {
+    Warn,
+    Abort,
+}
+
+struct Installation {
+    drive: u8,
+    sectors: u64,
+    disk_signature: u32,
+    version: [u8; 4],
+}
+
+impl Installation {
+    fn printf(&self, boot_drive: u8, config_mtime: Option<u32>) {
+        printf!(b"    drive 0x%b, ", self.drive);
+        printf!(b"disk signature 0x%x, ", self.disk_signature);
+        write_u64_decimal(self.sectors);
+        printf!(b" sectors, version ");
+        for (i, part) in self.version.iter().enumerate() {
+            if i != 0 {
+                printf!(b".");
+            }
+            write_u32_decimal(*part as u32);
+        }
+        if self.drive == boot_drive {
+            match config_mtime {
+                Some(mtime) => {
+                    printf!(b", config mtime ");
+                    write_u32_decimal(mtime);
+                }
+                None => printf!(b", no config file"),
+            }
+            printf!(b" <-- running");
+        } else {
+            printf!(b", config not probed");
+        }
+        printf!(b"\r\n");
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Reads the boot sector and the first stage2 sector of `drive`, returns the installation found there if any. <br>
+/// Strictly read-only, any disk error means no installation. <br>
+fn probe_disk(drive: u8, bios_idt: usize) -> Option<Installation> {
+    let mut disk = ExtendedDisk::new(drive, bios_idt);
+    if !disk.check_present() {
+        return None;
+    }
+    let params = disk.get_params().ok()?;
+    let bps = params.bytes_per_sector as usize;
+    if bps < 512 {
+        return None;
+    }
+    let mut buffer = Buffer::new(bps)?;
+
+    disk.read_sector(0, &mut buffer).ok()?;
+    let boot_sector: &[u8] = &buffer;
+    if boot_sector.get(510..512)? != [0x55, 0xAA] {
+        return None;
+    }
+    contains(boot_sector.get(0..446)?, BOOT_SECTOR_MARKER)?;
+    let disk_signature = u32::from_le_bytes(boot_sector.get(440..444)?.try_into().ok()?);
+
+    disk.read_sector(STAGE2_DISK_OFFSET / bps as u64, &mut buffer)
+        .ok()?;
+    let sector = buffer[..].get((STAGE2_DISK_OFFSET as usize % bps)..)?;
+    let at = contains(sector, &INSTALL_BANNER_MAGIC)?;
+    let version = sector
+        .get((at + INSTALL_BANNER_MAGIC.len())..(at + INSTALL_BANNER_MAGIC.len() + 4))?
+        .try_into()
+        .ok()?;
+
+    Some(Installation {
+        drive,
+        sectors: params.sectors,
+        disk_signature,
+        version,
+    })
+}
+
+/// Looks for ObsidianBootloader installs on every BIOS hard disk, and lists them if there is more than one. <br>
+/// Reads at most two sectors per disk. Returns the number of installs found. <br>
+pub fn scan_installations(
+    bios_idt: usize,
+    boot_drive: u8,
+    config_mtime: Option<u32>,
+    policy: &MultipleInstallsPolicy,
+) -> usize {
+    // BIOS data area: number of hard disks
+    let disk_count = unsafe { *(0x475 as *const u8) }.min(MAX_PROBED_DISKS);
+
+    let mut installs: Vec<Installation> = Vec::new((disk_count as usize).max(1));
+    for i in 0..disk_count {
+        if let Some(install) = probe_disk(0x80 + i, bios_idt) {
+            installs.push(install);
+        }
+    }
+    if installs.len() <= 1 {
+        return installs.len();
+    }
+
+    printf!(
+        b"Notice: found %x ObsidianBootloader installations:\r\n",
+        installs.len() as u32
+    );
+    for install in installs.iter() {
+        install.printf(boot_drive, config_mtime);
+    }
+    warning(WarningId::MultipleInstalls);
+    unsafe {
+        Video::get().write_string(
+            b"Notice: multiple ObsidianBootloader installations found, check which disk you edit !\n",
+        );
+    }
+
+    if let MultipleInstallsPolicy::Abort = policy {
+        printf!(b"Aborting boot: warn_on_multiple_installs=abort\r\n");
+        unsafe {
+            Video::get().write_string(b"Failed to boot: multiple installations found !\n");
+        }
+        kpanic();
+    }
+    installs.len()
+}
@@ -1,3 +1,5 @@
+use crate::post::{last_post_code, post_codes_enabled};
+
 extern "cdecl" {
     pub fn outb(port: u16, value: u8);
     pub fn outw(port: u16, value: u16);
@@ -7,7 +9,14 @@ extern "cdecl" {
     pub fn inl(port: u16) -> u32;
 }
 
-const UNUSED_PORT: u16 = 0x80;
+pub(crate) const UNUSED_PORT: u16 = 0x80;
+/// Delay port used instead of 0x80 with `post_codes=off`, on boards where 0x80 is repurposed
+const ALTERNATE_DELAY_PORT: u16 = 0xED;
 pub fn iowait() {
-    unsafe { outb(UNUSED_PORT, 0) };
+    if !post_codes_enabled() {
+        unsafe { outb(ALTERNATE_DELAY_PORT, 0) };
+        return;
+    }
+    // Re-emit the current POST code so the delay write doesn't stomp progress markers
+    unsafe { outb(UNUSED_PORT, last_post_code()) };
 }
@@ -0,0 +1,213 @@
+use core::{
+    arch::x86::_rdtsc,
+    cell::SyncUnsafeCell,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use crate::{
+    e9::write_u32_decimal,
+    io::{inb, outb},
+    printf,
+};
+
+/// Upper bounds of the histogram buckets in milliseconds, the last bucket holds everything above
+pub const BUCKET_LIMITS_MS: [u32; 4] = [1, 4, 16, 64];
+pub const BUCKET_NAMES: [&[u8]; 5] = [b"<1ms", b"1-4ms", b"4-16ms", b"16-64ms", b">64ms"];
+
+/// Interrupts with their own histogram, every other one is counted under the last entry
+const TRACKED_INTERRUPTS: [u8; 3] = [0x10, 0x13, 0x15];
+
+#[derive(Clone, Copy)]
+pub struct LatencyHistogram {
+    pub buckets: [u32; 5],
+    pub calls: u32,
+    pub max_cycles: u64,
+}
+
+/// The slowest call seen, with the registers it was made with
+#[derive(Clone, Copy)]
+pub struct WorstCall {
+    pub interrupt: u8,
+    /// eax, ebx, ecx, edx, esi, edi
+    pub registers: [u32; 6],
+    pub cycles: u64,
+}
+
+struct LatencyState {
+    histograms: [LatencyHistogram; TRACKED_INTERRUPTS.len() + 1],
+    worst: Option<WorstCall>,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// TSC frequency measured against the PIT, 0 if the calibration failed
+static CYCLES_PER_MS: AtomicU32 = AtomicU32::new(0);
+/// Single threaded, only touched around BIOS calls
+static STATE: SyncUnsafeCell<LatencyState> = SyncUnsafeCell::new(LatencyState {
+    histograms: [LatencyHistogram {
+        buckets: [0; 5],
+        calls: 0,
+        max_cycles: 0,
+    }; TRACKED_INTERRUPTS.len() + 1],
+    worst: None,
+});
+
+/// PIT input clock, in Hz
+const PIT_FREQUENCY: u32 = 1_193_182;
+const CALIBRATION_MS: u32 = 10;
+
+/// Measures the TSC frequency by timing a one-shot countdown of PIT channel 2
+fn calibrate_tsc() -> u32 {
+    unsafe {
+        let port61 = inb(0x61);
+        // Channel 2 gate on, speaker off
+        outb(0x61, (port61 & !0x02) | 0x01);
+        // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        outb(0x43, 0b1011_0000);
+        let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+        outb(0x42, count as u8);
+        outb(0x42, (count >> 8) as u8);
+
+        let start = _rdtsc();
+        let mut spins: u32 = 0;
+        // Bit 5 is the channel 2 output, it goes high at terminal count
+        while inb(0x61) & 0x20 == 0 {
+            spins += 1;
+            if spins == 0x0100_0000 {
+                outb(0x61, port61);
+                return 0;
+            }
+        }
+        let end = _rdtsc();
+        outb(0x61, port61);
+        ((end - start) / CALIBRATION_MS as u64) as u32
+    }
+}
+
+/// Calibrates the TSC and starts recording BIOS call latencies
+pub fn enable() {
+    let cycles_per_ms = calibrate_tsc();
+    CYCLES_PER_MS.store(cycles_per_ms, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+    printf!(b"BIOS call latency tracking enabled, TSC: ");
+    write_u32_decimal(cycles_per_ms);
+    printf!(b" cycles/ms\r\n");
+}
+
+/// Clears the histograms and the slowest call, to measure one phase of the boot on its own
+pub fn reset() {
+    let state = unsafe { &mut *STATE.get() };
+    for histogram in state.histograms.iter_mut() {
+        *histogram = LatencyHistogram {
+            buckets: [0; 5],
+            calls: 0,
+            max_cycles: 0,
+        };
+    }
+    state.worst = None;
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns the TSC timestamp to pass to [`record`], or None when tracking is disabled
+#[inline(always)]
+pub fn start() -> Option<u64> {
+    if ENABLED.load(Ordering::Relaxed) {
+        Some(unsafe { _rdtsc() })
+    } else {
+        None
+    }
+}
+
+fn bucket(cycles: u64) -> usize {
+    let cycles_per_ms = CYCLES_PER_MS.load(Ordering::Relaxed) as u64;
+    BUCKET_LIMITS_MS
+        .iter()
+        .position(|limit| cycles < *limit as u64 * cycles_per_ms)
+        .unwrap_or(BUCKET_LIMITS_MS.len())
+}
+
+/// Accounts a BIOS call started at `start` (see [`start`])
+#[inline(always)]
+pub fn record(start: Option<u64>, interrupt: usize, registers: [usize; 6]) {
+    let Some(start) = start else {
+        return;
+    };
+    let cycles = unsafe { _rdtsc() }.wrapping_sub(start);
+    let state = unsafe { &mut *STATE.get() };
+
+    let index = TRACKED_INTERRUPTS
+        .iter()
+        .position(|i| *i as usize == interrupt)
+        .unwrap_or(TRACKED_INTERRUPTS.len());
+    let histogram = &mut state.histograms[index];
+    histogram.buckets[bucket(cycles)] += 1;
+    histogram.calls += 1;
+    histogram.max_cycles = histogram.max_cycles.max(cycles);
+
+    if state.worst.is_none_or(|worst| cycles > worst.cycles) {
+        state.worst = Some(WorstCall {
+            interrupt: interrupt as u8,
+            registers: registers.map(|r| r as u32),
+            cycles,
+        });
+    }
+}
+
+/// Returns the interrupt number of histogram `i` (None for the catch-all one) and the histogram
+pub fn histograms() -> impl Iterator<Item = (Option<u8>, LatencyHistogram)> {
+    let state = unsafe { &*STATE.get() };
+    (0..state.histograms.len()).map(|i| (TRACKED_INTERRUPTS.get(i).copied(), state.histograms[i]))
+}
+
+pub fn worst_call() -> Option<WorstCall> {
+    unsafe { (*STATE.get()).worst }
+}
+
+/// Converts TSC cycles to microseconds, 0 if the TSC wasn't calibrated
+pub fn cycles_to_us(cycles: u64) -> u64 {
+    match CYCLES_PER_MS.load(Ordering::Relaxed) as u64 {
+        0 => 0,
+        cycles_per_ms => cycles * 1000 / cycles_per_ms,
+    }
+}
+
+/// Logs one histogram line per interrupt that was called, and the slowest call
+pub fn printf_histograms() {
+    if !enabled() {
+        return;
+    }
+    printf!(b"BIOS call latencies:\r\n");
+    for (interrupt, histogram) in histograms() {
+        if histogram.calls == 0 {
+            continue;
+        }
+        match interrupt {
+            Some(interrupt) => printf!(b"    INT %b:", interrupt),
+            None => printf!(b"    other: "),
+        }
+        for (name, count) in BUCKET_NAMES.iter().zip(histogram.buckets) {
+            printf!(b" ");
+            crate::e9::write_string(name);
+            printf!(b"=");
+            write_u32_decimal(count);
+        }
+        printf!(b", max ");
+        write_u32_decimal(cycles_to_us(histogram.max_cycles) as u32);
+        printf!(b"us\r\n");
+    }
+    if let Some(worst) = worst_call() {
+        printf!(b"    slowest: INT %b, ", worst.interrupt);
+        write_u32_decimal(cycles_to_us(worst.cycles) as u32);
+        printf!(
+            b"us, eax=%x ebx=%x ecx=%x edx=%x esi=%x edi=%x\r\n",
+            worst.registers[0],
+            worst.registers[1],
+            worst.registers[2],
+            worst.registers[3],
+            worst.registers[4],
+            worst.registers[5]
+        );
+    }
+}
@@ -0,0 +1,221 @@
+use crate::{
+    bios::{DiskError, DiskParams, ExtendedDisk},
+    e9::write_string,
+    gpt::DiskRange,
+    mem::Buffer,
+    printf,
+};
+
+/// Sector size of CD and DVD drives, also reported for El Torito no-emulation boots
+pub const OPTICAL_SECTOR_SIZE: u16 = 2048;
+/// ext2 image searched on ISO9660 media, the kernel and config are inside it
+pub const BOOT_IMAGE_PATH: &[u8] = b"/boot/obsiboot.img";
+
+/// The volume descriptor set starts at byte 32KiB, after the system area
+const VOLUME_DESCRIPTORS_OFFSET: u64 = 0x8000;
+const VOLUME_DESCRIPTOR_SIZE: usize = 2048;
+/// Descriptors read before giving up on finding the terminator
+const MAX_VOLUME_DESCRIPTORS: u64 = 32;
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+const STANDARD_IDENTIFIER: &[u8] = b"CD001";
+/// Offsets in the primary volume descriptor
+const PVD_LOGICAL_BLOCK_SIZE: usize = 128;
+const PVD_ROOT_DIRECTORY_RECORD: usize = 156;
+/// Fixed part of a directory record, the name follows
+const DIRECTORY_RECORD_SIZE: usize = 33;
+const RECORD_FLAG_DIRECTORY: u8 = 1 << 1;
+/// Larger directories are taken as corrupt
+const MAX_DIRECTORY_SIZE: usize = 0x40000;
+
+pub enum IsoError {
+    DiskError(DiskError),
+    FailedMemAlloc(usize),
+    BufferCopyError,
+    NoPrimaryVolumeDescriptor,
+    /// Logical block size that isn't a whole amount of disk sectors
+    BadLogicalBlockSize(u16),
+    /// A directory record runs past its directory, at this byte offset of the directory
+    BadDirectoryRecord(usize),
+    BadDirectorySize(usize),
+    NotFound,
+    /// A component before the last one of the path isn't a directory
+    NotADirectory,
+}
+
+impl IsoError {
+    pub fn printf(&self) {
+        match self {
+            IsoError::DiskError(e) => e.printf(),
+            IsoError::FailedMemAlloc(size) => {
+                printf!(b"failed to allocate memory: 0x%x", *size)
+            }
+            IsoError::BufferCopyError => printf!(b"buffer copy error"),
+            IsoError::NoPrimaryVolumeDescriptor => printf!(b"no ISO9660 primary volume descriptor"),
+            IsoError::BadLogicalBlockSize(size) => {
+                printf!(b"bad ISO9660 logical block size 0x%x", *size as usize)
+            }
+            IsoError::BadDirectoryRecord(offset) => {
+                printf!(b"bad ISO9660 directory record at offset 0x%x", *offset)
+            }
+            IsoError::BadDirectorySize(size) => {
+                printf!(b"bad ISO9660 directory size 0x%x", *size)
+            }
+            IsoError::NotFound => printf!(b"file not found"),
+            IsoError::NotADirectory => printf!(b"path component is not a directory"),
+        }
+    }
+}
+
+/// Whether `params` describe a CD/DVD-style drive, booted with El Torito
+pub fn is_optical(params: &DiskParams) -> bool {
+    params.bytes_per_sector == OPTICAL_SECTOR_SIZE
+}
+
+/// Extent of a file or directory, in logical blocks
+#[derive(Clone, Copy)]
+struct Extent {
+    block: u32,
+    size: u32,
+    directory: bool,
+}
+
+impl Extent {
+    /// Parses the directory record at the start of `record`
+    fn parse(record: &[u8]) -> Extent {
+        Extent {
+            block: u32::from_le_bytes([record[2], record[3], record[4], record[5]]),
+            size: u32::from_le_bytes([record[10], record[11], record[12], record[13]]),
+            directory: record[25] & RECORD_FLAG_DIRECTORY != 0,
+        }
+    }
+}
+
+/// Reads `len` bytes at byte `offset` of the disk, sector by sector
+fn read_bytes(
+    disk: &mut ExtendedDisk,
+    bps: usize,
+    offset: u64,
+    len: usize,
+) -> Result<Buffer, IsoError> {
+    let mut bytes = Buffer::new_tagged(len, b"iso9660").ok_or(IsoError::FailedMemAlloc(len))?;
+    let mut sector = Buffer::new_tagged(bps, b"iso9660").ok_or(IsoError::FailedMemAlloc(bps))?;
+    let mut done = 0;
+    while done < len {
+        let position = offset + done as u64;
+        disk.read_sector(position / bps as u64, &mut sector)
+            .map_err(IsoError::DiskError)?;
+        let start = (position % bps as u64) as usize;
+        let count = (bps - start).min(len - done);
+        if !sector.copy_to(start, &mut bytes, done, count) {
+            return Err(IsoError::BufferCopyError);
+        }
+        done += count;
+    }
+    Ok(bytes)
+}
+
+/// Whether the ISO9660 name `name` (e.g. `OBSIBOOT.IMG;1`) is `component`, ignoring case, version and a trailing dot
+fn name_matches(name: &[u8], component: &[u8]) -> bool {
+    let name = match name.iter().position(|c| *c == b';') {
+        Some(end) => &name[..end],
+        None => name,
+    };
+    let name = name.strip_suffix(b".").unwrap_or(name);
+    name.eq_ignore_ascii_case(component)
+}
+
+/// Looks up `component` among the records of `directory`
+fn find_in_directory(
+    disk: &mut ExtendedDisk,
+    bps: usize,
+    block_size: usize,
+    directory: Extent,
+    component: &[u8],
+) -> Result<Extent, IsoError> {
+    let size = directory.size as usize;
+    if size == 0 || size > MAX_DIRECTORY_SIZE {
+        return Err(IsoError::BadDirectorySize(size));
+    }
+    let records = read_bytes(disk, bps, directory.block as u64 * block_size as u64, size)?;
+    let records: &[u8] = &records;
+    let mut offset = 0;
+    while offset < size {
+        let len = records[offset] as usize;
+        if len == 0 {
+            // Records don't cross block boundaries, the rest of the block is padding
+            offset = (offset / block_size + 1) * block_size;
+            continue;
+        }
+        let record = records
+            .get(offset..offset + len)
+            .filter(|record| record.len() > DIRECTORY_RECORD_SIZE)
+            .ok_or(IsoError::BadDirectoryRecord(offset))?;
+        let name_len = record[32] as usize;
+        let name = record
+            .get(DIRECTORY_RECORD_SIZE..DIRECTORY_RECORD_SIZE + name_len)
+            .ok_or(IsoError::BadDirectoryRecord(offset))?;
+        if name_matches(name, component) {
+            return Ok(Extent::parse(record));
+        }
+        offset += len;
+    }
+    Err(IsoError::NotFound)
+}
+
+/// Finds `path` on the ISO9660 filesystem of `disk`, returns the disk sectors holding the file. <br>
+/// Plain ISO9660 names only, Rock Ridge and Joliet extensions are ignored. Files must start on a sector boundary, which holds for 2KiB logical blocks. <br>
+pub fn find_iso_file(disk: &mut ExtendedDisk, path: &[u8]) -> Result<DiskRange, IsoError> {
+    let params = disk.get_params().map_err(IsoError::DiskError)?;
+    let bps = params.bytes_per_sector as usize;
+    if bps == 0 {
+        return Err(IsoError::DiskError(DiskError::InvalidDiskParameters));
+    }
+
+    let mut primary = None;
+    for i in 0..MAX_VOLUME_DESCRIPTORS {
+        let offset = VOLUME_DESCRIPTORS_OFFSET + i * VOLUME_DESCRIPTOR_SIZE as u64;
+        let descriptor = read_bytes(disk, bps, offset, VOLUME_DESCRIPTOR_SIZE)?;
+        if &descriptor[1..6] != STANDARD_IDENTIFIER || descriptor[0] == DESCRIPTOR_TERMINATOR {
+            break;
+        }
+        if descriptor[0] == DESCRIPTOR_PRIMARY {
+            primary = Some(descriptor);
+            break;
+        }
+    }
+    let primary = primary.ok_or(IsoError::NoPrimaryVolumeDescriptor)?;
+    let block_size = u16::from_le_bytes([
+        primary[PVD_LOGICAL_BLOCK_SIZE],
+        primary[PVD_LOGICAL_BLOCK_SIZE + 1],
+    ]);
+    if block_size == 0 || !(block_size as usize).is_multiple_of(bps) {
+        return Err(IsoError::BadLogicalBlockSize(block_size));
+    }
+    let block_size = block_size as usize;
+
+    let mut extent = Extent::parse(&primary[PVD_ROOT_DIRECTORY_RECORD..]);
+    for component in path.split(|c| *c == b'/').filter(|c| !c.is_empty()) {
+        if !extent.directory {
+            return Err(IsoError::NotADirectory);
+        }
+        extent = find_in_directory(disk, bps, block_size, extent, component)?;
+    }
+    if extent.directory || extent.size == 0 {
+        return Err(IsoError::NotFound);
+    }
+
+    let start_lba = extent.block as u64 * (block_size / bps) as u64;
+    let range = DiskRange {
+        start_lba,
+        end_lba: start_lba + (extent.size as u64).div_ceil(bps as u64) - 1,
+    };
+    printf!(b"ISO9660: ");
+    write_string(path);
+    printf!(
+        b" at block 0x%x, 0x%x bytes\r\n",
+        extent.block as usize,
+        extent.size as usize
+    );
+    Ok(range)
+}
@@ -0,0 +1,386 @@
+use core::{cell::SyncUnsafeCell, ops::Deref};
+
+use crate::{
+    e9::write_string,
+    fs::{Ext2FileSystem, Ext2FileType},
+    printf,
+};
+
+/// User-facing screen texts that `lang_file=` can translate. The debug log stays in English. <br>
+/// `{0}`, `{1}`... in a text are replaced by the arguments given to [`render`], in any order. <br>
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Text {
+    CpuidUnsupported,
+    LongModeUnsupported,
+    FpuUnsupported,
+    SseUnsupported,
+    NoExt2Partition,
+    RootNotDirectory,
+    KernelNotFound,
+    KernelNotFile,
+    KernelReadFailed,
+    KernelHashMismatch,
+    InitrdFailed,
+    InitrdTooLarge,
+    VbeModeUnavailable,
+    VbeModeUnsupported,
+    StrictBootFailed,
+    DiskHealthNotice,
+    BootPartitionSwitched,
+    StartingKernel,
+    PauseBeforeJump,
+    PauseSeconds,
+    MenuTitle,
+    MenuHelp,
+    MenuTimeout,
+}
+
+/// Stable key of every text in `lang_file=` and its English default, in [`Text`] order
+const DEFAULT_TEXTS: [(&[u8], &[u8]); 23] = [
+    (b"error.cpuid", b"Failed to boot: CPUID not supported !"),
+    (
+        b"error.long_mode",
+        b"Failed to boot: Long mode not supported !",
+    ),
+    (b"error.fpu", b"Failed to boot: FPU not supported !"),
+    (b"error.sse", b"Failed to boot: SSE not supported !"),
+    (b"error.no_ext2_partition", b"No ext2 partition !"),
+    (b"error.root_not_directory", b"Root is not a directory !"),
+    (
+        b"error.kernel_not_found",
+        b"Failed to boot: {0} not found !",
+    ),
+    (
+        b"error.kernel_not_file",
+        b"Failed to boot: Could not find kernel !",
+    ),
+    (
+        b"error.kernel_read",
+        b"Failed to boot: Could not read kernel !",
+    ),
+    (
+        b"error.kernel_sha256",
+        b"Failed to boot: kernel SHA-256 mismatch !",
+    ),
+    (b"error.initrd", b"Failed to boot: initrd {0}: {1}"),
+    (
+        b"error.initrd_too_large",
+        b"Failed to boot: initrd needs 0x{0} bytes, 0x{1} free !",
+    ),
+    (
+        b"error.vbe_unavailable",
+        b"Failed to boot: requested VBE mode unavailable !",
+    ),
+    (
+        b"notice.vbe_unsupported",
+        b"Configured VBE mode unsupported, not direct color",
+    ),
+    (
+        b"error.strict_boot",
+        b"Failed to boot: strict_boot=1 and warnings were raised:",
+    ),
+    (
+        b"notice.disk_health",
+        b"Notice: 0x{0} {1} errors near MiB 0x{2} across the last {3} boots, boot media may be failing",
+    ),
+    (
+        b"notice.boot_partition",
+        b"Switched to boot_partition= partition 0x{0}.",
+    ),
+    (
+        b"status.starting_kernel",
+        b"ObsidianBootloader: starting {0}",
+    ),
+    (
+        b"status.pause",
+        b"Ready to jump to the kernel: any key to continue, r to reboot",
+    ),
+    (b"status.pause_seconds", b" (0x{0} s)"),
+    (b"menu.title", b"ObsidianBootloader"),
+    (
+        b"menu.help",
+        b"Up/Down to select, Enter to boot the selected entry, R to reload the config",
+    ),
+    (b"menu.timeout", b"The highlighted entry boots in 0x{0} s"),
+];
+
+/// Texts shown before the config and `lang_file=` are read, they always stay in English and `lang_file=` keys for them are ignored
+const EARLY_TEXTS: [Text; 5] = [
+    Text::CpuidUnsupported,
+    Text::FpuUnsupported,
+    Text::SseUnsupported,
+    Text::NoExt2Partition,
+    Text::RootNotDirectory,
+];
+
+/// Larger `lang_file=` files are ignored
+pub const MAX_LANG_FILE_SIZE: usize = 16 * 1024;
+/// Longest rendered text, longer ones are cut
+pub const MAX_RENDERED_LEN: usize = 160;
+
+struct LangTable {
+    /// The loaded `lang_file=`, leaked so the overrides can point into it
+    source: &'static [u8],
+    /// Byte range of each overridden text in `source`
+    overrides: [Option<(usize, usize)>; DEFAULT_TEXTS.len()],
+}
+
+/// Written once by [`load_lang_file`] before any translated text is shown
+static TABLE: SyncUnsafeCell<LangTable> = SyncUnsafeCell::new(LangTable {
+    source: &[],
+    overrides: [None; DEFAULT_TEXTS.len()],
+});
+
+impl Text {
+    pub fn key(self) -> &'static [u8] {
+        DEFAULT_TEXTS[self as usize].0
+    }
+
+    pub fn english(self) -> &'static [u8] {
+        DEFAULT_TEXTS[self as usize].1
+    }
+
+    /// The translation from `lang_file=`, or the English text
+    pub fn get(self) -> &'static [u8] {
+        let table = unsafe { &*TABLE.get() };
+        match table.overrides[self as usize] {
+            Some((start, end)) => &table.source[start..end],
+            None => self.english(),
+        }
+    }
+}
+
+/// Index of the text with `key`
+fn find_key(key: &[u8]) -> Option<usize> {
+    DEFAULT_TEXTS.iter().position(|(k, _)| *k == key)
+}
+
+/// A text with its placeholders replaced, in the VGA code page (CP437) used by both the text mode and the framebuffer fonts
+pub struct Rendered {
+    bytes: [u8; MAX_RENDERED_LEN],
+    len: usize,
+}
+
+impl Rendered {
+    fn push(&mut self, c: u8) {
+        if self.len < MAX_RENDERED_LEN {
+            self.bytes[self.len] = c;
+            self.len += 1;
+        }
+    }
+
+    /// Transliterates UTF-8 to CP437, characters it lacks become `?`
+    fn push_utf8(&mut self, text: &[u8]) {
+        let mut i = 0;
+        while i < text.len() {
+            let (codepoint, len) = decode_utf8(&text[i..]);
+            self.push(codepoint.map_or(b'?', to_cp437));
+            i += len;
+        }
+    }
+}
+
+impl Deref for Rendered {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Decodes the first character of `text`, None when it isn't valid UTF-8. Also returns the bytes consumed (at least 1).
+fn decode_utf8(text: &[u8]) -> (Option<u32>, usize) {
+    let first = text[0];
+    let (len, initial) = match first {
+        0x00..=0x7F => return (Some(first as u32), 1),
+        0xC2..=0xDF => (2, (first & 0x1F) as u32),
+        0xE0..=0xEF => (3, (first & 0x0F) as u32),
+        0xF0..=0xF4 => (4, (first & 0x07) as u32),
+        _ => return (None, 1),
+    };
+    let Some(continuation) = text.get(1..len) else {
+        return (None, 1);
+    };
+    if continuation.iter().any(|c| c & 0xC0 != 0x80) {
+        return (None, 1);
+    }
+    let codepoint = continuation
+        .iter()
+        .fold(initial, |acc, c| (acc << 6) | (c & 0x3F) as u32);
+    (Some(codepoint), len)
+}
+
+/// CP437 glyph of a character, `?` for those without one. Covers ASCII and the Latin-1 letters CP437 has.
+fn to_cp437(codepoint: u32) -> u8 {
+    match codepoint {
+        0x20..=0x7E => codepoint as u8,
+        0xC7 => 0x80,
+        0xFC => 0x81,
+        0xE9 => 0x82,
+        0xE2 => 0x83,
+        0xE4 => 0x84,
+        0xE0 => 0x85,
+        0xE5 => 0x86,
+        0xE7 => 0x87,
+        0xEA => 0x88,
+        0xEB => 0x89,
+        0xE8 => 0x8A,
+        0xEF => 0x8B,
+        0xEE => 0x8C,
+        0xEC => 0x8D,
+        0xC4 => 0x8E,
+        0xC5 => 0x8F,
+        0xC9 => 0x90,
+        0xE6 => 0x91,
+        0xC6 => 0x92,
+        0xF4 => 0x93,
+        0xF6 => 0x94,
+        0xF2 => 0x95,
+        0xFB => 0x96,
+        0xF9 => 0x97,
+        0xFF => 0x98,
+        0xD6 => 0x99,
+        0xDC => 0x9A,
+        0xA2 => 0x9B,
+        0xA3 => 0x9C,
+        0xA5 => 0x9D,
+        0xE1 => 0xA0,
+        0xED => 0xA1,
+        0xF3 => 0xA2,
+        0xFA => 0xA3,
+        0xF1 => 0xA4,
+        0xD1 => 0xA5,
+        0xAA => 0xA6,
+        0xBA => 0xA7,
+        0xBF => 0xA8,
+        0xAC => 0xAA,
+        0xBD => 0xAB,
+        0xBC => 0xAC,
+        0xA1 => 0xAD,
+        0xAB => 0xAE,
+        0xBB => 0xAF,
+        0xDF => 0xE1,
+        0xB5 => 0xE6,
+        0xB1 => 0xF1,
+        0xF7 => 0xF6,
+        0xB0 => 0xF8,
+        0xB7 => 0xFA,
+        0xB2 => 0xFD,
+        0xA0 => 0xFF,
+        _ => b'?',
+    }
+}
+
+/// Renders `text` in the active language, replacing `{n}` with `args[n]`. <br>
+/// Placeholders without a matching argument are dropped, other braces are kept as is. <br>
+pub fn render(text: Text, args: &[&[u8]]) -> Rendered {
+    let mut rendered = Rendered {
+        bytes: [0; MAX_RENDERED_LEN],
+        len: 0,
+    };
+    let template = text.get();
+    let mut start = 0;
+    let mut i = 0;
+    while i < template.len() {
+        let placeholder = match template.get(i..i + 3) {
+            Some([b'{', digit @ b'0'..=b'9', b'}']) => Some((digit - b'0') as usize),
+            _ => None,
+        };
+        let Some(index) = placeholder else {
+            i += 1;
+            continue;
+        };
+        rendered.push_utf8(&template[start..i]);
+        if let Some(arg) = args.get(index) {
+            rendered.push_utf8(arg);
+        }
+        i += 3;
+        start = i;
+    }
+    rendered.push_utf8(&template[start..]);
+    rendered
+}
+
+/// Uppercase hexadecimal digits of `value`, for texts showing numbers the way the screen does elsewhere
+pub fn hex(value: u32) -> [u8; 8] {
+    let mut digits = [0; 8];
+    for (i, digit) in digits.iter_mut().enumerate() {
+        *digit = crate::video::get_hex_digit(((value >> ((7 - i) * 4)) & 0xF) as u8);
+    }
+    digits
+}
+
+/// Decimal digits of `value`, they start at the returned index
+pub fn decimal(value: u32) -> ([u8; 10], usize) {
+    let mut digits = [b'0'; 10];
+    let (mut value, mut start) = (value, 10);
+    while value != 0 || start == 10 {
+        start -= 1;
+        digits[start] = b'0' + (value % 10) as u8;
+        value /= 10;
+    }
+    (digits, start)
+}
+
+/// Loads the `key=value` translations of `path`, keys not in the file keep their English text. <br>
+/// Unknown keys and the keys of [`EARLY_TEXTS`] are logged and ignored. A missing, oversized or unreadable file leaves every text in English. <br>
+pub fn load_lang_file(ext2: &mut Ext2FileSystem, path: &[u8]) {
+    let failed = |reason: &[u8]| {
+        printf!(b"lang_file=");
+        write_string(path);
+        printf!(b": ");
+        write_string(reason);
+        printf!(b", using English\r\n");
+    };
+    let Ok(Ext2FileType::File(mut file)) = ext2.open_path(path) else {
+        failed(b"not found or not a file");
+        return;
+    };
+    if file.get_size() > MAX_LANG_FILE_SIZE {
+        failed(b"larger than 16KiB");
+        return;
+    }
+    let Ok(source) = file.read_all() else {
+        failed(b"read failed");
+        return;
+    };
+
+    let table = unsafe { &mut *TABLE.get() };
+    table.overrides = [None; DEFAULT_TEXTS.len()];
+    let mut overridden = 0;
+    let mut start = 0;
+    for line in source.split(|c| *c == b'\n') {
+        let line_start = start;
+        start += line.len() + 1;
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+        let Some(equals) = line.iter().position(|c| *c == b'=') else {
+            continue;
+        };
+        let Some(index) = find_key(&line[..equals]) else {
+            printf!(b"lang_file: unknown key ");
+            write_string(&line[..equals]);
+            printf!(b"\r\n");
+            continue;
+        };
+        if EARLY_TEXTS.iter().any(|text| *text as usize == index) {
+            printf!(b"lang_file: ");
+            write_string(&line[..equals]);
+            printf!(b" is shown before lang_file= is read, ignored\r\n");
+            continue;
+        }
+        table.overrides[index] = Some((line_start + equals + 1, line_start + line.len()));
+        overridden += 1;
+    }
+    // Never freed: the texts are shown up to the jump to the kernel
+    table.source = unsafe {
+        let source = source.leak();
+        core::slice::from_raw_parts(source.get_ptr(), source.len())
+    };
+    printf!(
+        b"lang_file: 0x%x of 0x%x texts translated\r\n",
+        overridden,
+        DEFAULT_TEXTS.len()
+    );
+}
@@ -1,50 +1,132 @@
-#![no_std]
-#![no_main]
-#![feature(sync_unsafe_cell)]
-#![feature(optimize_attribute)]
-#![feature(int_from_ascii)]
-
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), feature(sync_unsafe_cell))]
+#![cfg_attr(not(test), feature(optimize_attribute))]
+#![cfg_attr(not(test), feature(int_from_ascii))]
+
+// Host `cargo test` only builds the modules free of the BIOS, the asm and the bare-metal target
+#[cfg(not(test))]
+pub mod acpi;
+#[cfg(not(test))]
+pub mod addr;
+#[cfg(not(test))]
 pub mod arith;
+#[cfg(not(test))]
+pub mod bench;
+#[cfg(not(test))]
 pub mod bios;
+pub mod collections;
+#[cfg(not(test))]
 pub mod cpu_extensions;
+#[cfg(not(test))]
+pub mod cpu_features;
+#[cfg(not(test))]
+pub mod crc32;
+#[cfg(not(test))]
+pub mod diskhealth;
+#[cfg(not(test))]
 pub mod e9;
+#[cfg(not(test))]
+pub mod edid;
+#[cfg(not(test))]
 pub mod elf;
+#[cfg(not(test))]
+pub mod fbcon;
+#[cfg(not(test))]
 pub mod fs;
+#[cfg(not(test))]
 pub mod gdt;
+#[cfg(not(test))]
 pub mod gpt;
+#[cfg(not(test))]
+pub mod gzip;
+#[cfg(not(test))]
+pub mod hash;
+#[cfg(not(test))]
+pub mod idt;
+#[cfg(not(test))]
+pub mod initrd;
+#[cfg(not(test))]
+pub mod install;
+#[cfg(not(test))]
 pub mod io;
+#[cfg(not(test))]
+pub mod iolat;
+#[cfg(not(test))]
+pub mod iso9660;
+#[cfg(not(test))]
+pub mod lang;
+#[cfg(not(test))]
+pub mod log;
+#[cfg(not(test))]
+pub mod media;
+#[cfg(not(test))]
 pub mod mem;
+pub mod memlayout;
+#[cfg(not(test))]
+pub mod menu;
+#[cfg(not(test))]
+pub mod multiboot2;
+#[cfg(not(test))]
 pub mod obsiboot;
+pub mod pagetable;
+#[cfg(not(test))]
 pub mod paging;
+#[cfg(not(test))]
+pub mod panicmsg;
+#[cfg(not(test))]
+pub mod pause;
+#[cfg(not(test))]
+pub mod pci;
+#[cfg(not(test))]
+pub mod post;
+#[cfg(not(test))]
+pub mod probe;
+#[cfg(not(test))]
+pub mod reload;
+#[cfg(not(test))]
+pub mod safemode;
+#[cfg(not(test))]
+pub mod scan;
+#[cfg(not(test))]
+pub mod scrollback;
+#[cfg(not(test))]
+pub mod stream;
+#[cfg(not(test))]
+pub mod timing;
+#[cfg(not(test))]
 pub mod vesa;
+#[cfg(not(test))]
 pub mod video;
+#[cfg(not(test))]
+pub mod warnings;
 
 pub mod eflags {
     /// Carry Flag
     pub const CF: usize = 0b00000000000000000000000000000001;
     /// Parity Flag
-    pub const PF: usize = 0b00000000000000000000000000000010;
+    pub const PF: usize = 0b00000000000000000000000000000100;
     /// Auxiliary Carry Flag
-    pub const AF: usize = 0b00000000000000000000000000000100;
+    pub const AF: usize = 0b00000000000000000000000000010000;
     /// Zero Flag
-    pub const ZF: usize = 0b00000000000000000000000000001000;
+    pub const ZF: usize = 0b00000000000000000000000001000000;
     /// Sign Flag
-    pub const SF: usize = 0b00000000000000000000000000010000;
+    pub const SF: usize = 0b00000000000000000000000010000000;
     /// Trap Flag
-    pub const TF: usize = 0b00000000000000000000000000100000;
+    pub const TF: usize = 0b00000000000000000000000100000000;
     /// Interrupt Enable Flag
-    pub const IF: usize = 0b00000000000000000000000001000000;
+    pub const IF: usize = 0b00000000000000000000001000000000;
     /// Direction Flag
-    pub const DF: usize = 0b00000000000000000000000010000000;
+    pub const DF: usize = 0b00000000000000000000010000000000;
     /// Overflow Flag
-    pub const OF: usize = 0b00000000000000000000000100000000;
+    pub const OF: usize = 0b00000000000000000000100000000000;
 
     /// I/O Privilege Level (IOPL)
-    pub const IOPL: usize = 0b00000000000000000001100000000000;
+    pub const IOPL: usize = 0b00000000000000000011000000000000;
     /// Nested Task Flag
     pub const NT: usize = 0b00000000000000000100000000000000;
     /// Resume Flag
-    pub const RF: usize = 0b00000000000000001000000000000000;
+    pub const RF: usize = 0b00000000000000010000000000000000;
     /// Virtual 8086 Mode Flag
     pub const VM: usize = 0b00000000000000100000000000000000;
     /// Alignment Check Flag
@@ -55,19 +137,95 @@ pub mod eflags {
     pub const VIP: usize = 0b00000000000100000000000000000000;
 }
 
-use bios::ExtendedDisk;
+#[cfg(not(test))]
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(not(test))]
+use acpi::{find_rsdp, Rsdp};
+#[cfg(not(test))]
+use addr::init_address_regions;
+#[cfg(not(test))]
+use bench::run_disk_benchmark;
+#[cfg(not(test))]
+use bios::{short_read_count, ExtendedDisk};
+#[cfg(not(test))]
 use cpu_extensions::check_and_enable_cpu_extensions;
-use e9::{write_buffer_as_string, write_guid, write_u64_decimal};
+#[cfg(not(test))]
+use diskhealth::{check_disk_health, load_disk_health, save_disk_health};
+#[cfg(not(test))]
+use e9::{
+    debug_output_status, write_buffer_as_escaped_string, write_buffer_as_string, write_guid,
+    write_string, write_u64_decimal,
+};
+#[cfg(not(test))]
 use elf::{load_elf, ElfFileFlavour};
-use fs::{Ext2FileSystem, Ext2FileType};
+#[cfg(not(test))]
+use fs::{Ext2Error, Ext2FileType};
+#[cfg(not(test))]
 use gdt::{is_cpuid_supported, is_long_mode_supported};
-use gpt::{GUIDPartitionTable, PARTITION_GUID_TYPE_LINUX_FS};
-use mem::{detect_system_memory, get_mem_free, get_mem_total, get_mem_used};
-use obsiboot::ObsiBootConfig;
-use paging::enable_paging_and_run_kernel;
-use vesa::switch_to_graphics;
-
-use crate::video::{Color, Video};
+#[cfg(not(test))]
+use gpt::GUIDPartitionTable;
+#[cfg(not(test))]
+use gzip::open_maybe_compressed;
+#[cfg(not(test))]
+use hash::verify_kernel_sha256;
+#[cfg(not(test))]
+use idt::install_idt;
+#[cfg(not(test))]
+use initrd::load_initrds;
+#[cfg(not(test))]
+use install::{printf_version_banner, scan_installations};
+#[cfg(not(test))]
+use io::outb;
+#[cfg(not(test))]
+use iso9660::is_optical;
+#[cfg(not(test))]
+use lang::{hex, load_lang_file, render, Text};
+#[cfg(not(test))]
+use log::{configure_logging, LogLevel};
+#[cfg(not(test))]
+use media::detect_boot_media;
+#[cfg(not(test))]
+use mem::{
+    detect_system_memory, get_mem_free, get_mem_total, get_mem_used, heap_check, heap_region,
+    limit_heap, SystemMemory, Vec,
+};
+#[cfg(not(test))]
+use menu::select_boot_entry;
+#[cfg(not(test))]
+use multiboot2::Multiboot2Kernel;
+#[cfg(not(test))]
+use obsiboot::{BootProtocol, MemoryReservation, DEFAULT_KERNEL_PATH, MAX_RESERVATIONS};
+#[cfg(not(test))]
+use paging::{enable_paging_and_run_kernel, memory_limit_end, run_kernel32, run_multiboot2};
+#[cfg(not(test))]
+use pause::PauseBeforeJump;
+#[cfg(not(test))]
+use pci::{enumerate_pci, PciDeviceInfo};
+#[cfg(not(test))]
+use post::{codes, post_code, set_post_codes_enabled};
+#[cfg(not(test))]
+use probe::{run_probe_mode, BootMode, ProbeInputs};
+#[cfg(not(test))]
+use reload::read_config;
+#[cfg(not(test))]
+use safemode::check_safe_mode_key;
+#[cfg(not(test))]
+use scan::{
+    mount_selected_partition, open_boot_volume, open_iso_volume, probe_other_drives,
+    scan_boot_partitions, BootVolume,
+};
+#[cfg(not(test))]
+use scrollback::init_scrollback;
+#[cfg(not(test))]
+use timing::{checkpoint, init_timing};
+#[cfg(not(test))]
+use vesa::{safe_mode_info, switch_to_graphics, VbeBootInfo};
+#[cfg(not(test))]
+use warnings::{warning, WarningId};
+
+#[cfg(not(test))]
+use crate::video::{Color, PanicWriter, TextMode, Video};
 
 #[macro_export]
 macro_rules! integer_enum_impl {
@@ -101,10 +259,53 @@ macro_rules! integer_enum_impl {
     };
 }
 
+#[cfg(not(test))]
 extern "cdecl" {
     pub fn stage3_entry();
 }
 
+/// The opened kernel, by `protocol=`
+#[cfg(not(test))]
+enum KernelImage<'f> {
+    ObsiBoot(ElfFileFlavour<'f>),
+    Multiboot2(Multiboot2Kernel<'f>),
+}
+
+/// State gathered while booting, owned by `rust_entry` and passed down by reference
+#[cfg(not(test))]
+pub struct BootState {
+    pub bios_idt: usize,
+    pub boot_drive: usize,
+    pub memory: SystemMemory,
+    pub vbe: VbeBootInfo,
+    /// The `mem_limit=` cap, if any
+    pub mem_limit: Option<u64>,
+    /// The `scrub_handoff_memory=` policy
+    pub scrub_handoff_memory: bool,
+    /// The `stream_kernel=` setting
+    pub stream_kernel: bool,
+    /// The `map_null_page=` setting
+    pub map_null_page: bool,
+    /// The `pause_before_jump=` setting
+    pub pause_before_jump: PauseBeforeJump,
+    /// The `strict_boot=` setting and the `strict_allow=` mask (see `warnings`)
+    pub strict_boot: bool,
+    pub strict_allow: u32,
+    /// The `reserve=` ranges, only the first `reservation_count` are valid
+    pub reservations: [MemoryReservation; MAX_RESERVATIONS],
+    pub reservation_count: usize,
+    /// `(physical address, size)` of the combined initrd image, zeros without `initrd=`
+    pub initrd: (u64, u64),
+    /// `(physical address, length)` of the booted entry's null terminated `cmdline=`, zeros without one
+    pub cmdline: (u32, u32),
+    /// The PCI functions found at boot, empty without PCI
+    pub pci_devices: Vec<PciDeviceInfo>,
+    /// The ACPI RSDP, None on machines without ACPI
+    pub rsdp: Option<Rsdp>,
+    /// Selected at the keyboard, see [`safemode::check_safe_mode_key`]
+    pub safe_mode: bool,
+}
+
 pub fn ptr_to_seg_off(ptr: usize) -> (u16, u16) {
     ((ptr >> 4) as u16, (ptr & 0xF) as u16)
 }
@@ -113,27 +314,78 @@ pub fn seg_off_to_ptr(seg: u16, off: u16) -> usize {
     ((seg as usize) << 4) + (off as usize)
 }
 
+#[cfg(not(test))]
 #[panic_handler]
-pub fn panic(_info: &core::panic::PanicInfo) -> ! {
-    kpanic();
+pub fn panic(info: &core::panic::PanicInfo) -> ! {
+    let registers = panicmsg::PanicRegisters::capture();
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        double_panic();
+    }
+    report_panic(panicmsg::format_panic(info, &registers));
 }
 
-pub fn kpanic() -> ! {
-    unsafe {
-        let video = Video::get();
-        video.set_color(Color::Black, Color::Red);
-        video.write_string(b"\r\nPANIC\r\n");
+/// Set once the panic path is entered, so that a panic raised while panicking is detected
+#[cfg(not(test))]
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the panic path was entered, nothing may allocate past this point
+#[cfg(not(test))]
+pub fn in_panic() -> bool {
+    PANICKING.load(Ordering::SeqCst)
+}
+
+/// Panic within panic: the panic writer itself may be the culprit, only do fixed position writes
+#[cfg(not(test))]
+pub fn double_panic() -> ! {
+    let mut writer = PanicWriter::at_row(0, Color::color(Color::White, Color::Red));
+    writer.write_string(b"DOUBLE PANIC");
+    for c in b"\r\nDOUBLE PANIC\r\n" {
+        unsafe { outb(0xE9, *c) };
     }
 
     #[allow(clippy::empty_loop)]
     loop {}
 }
 
+/// Reports `message` with the allocation-free writers only, the heap may be why we are panicking
+#[cfg(not(test))]
+fn report_panic(message: &[u8]) -> ! {
+    // Messages held before the config was read may explain the panic
+    log::flush_early_log();
+    scrollback::scroll_to_live();
+    let mut writer = PanicWriter::new(Color::color(Color::Black, Color::Red));
+    writer.write_string(b"PANIC\r\n");
+    writer.write_string(message);
+    for c in b"\r\nPANIC\r\n".iter().chain(message) {
+        unsafe { outb(0xE9, *c) };
+    }
+    if !message.is_empty() {
+        for c in b"\r\n" {
+            unsafe { outb(0xE9, *c) };
+        }
+    }
+
+    scrollback::panic_scrollback()
+}
+
+#[cfg(not(test))]
+pub fn kpanic() -> ! {
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        double_panic();
+    }
+    report_panic(b"");
+}
+
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "cdecl" fn rust_entry(bios_idt: usize, boot_drive: usize) -> ! {
+    install_idt();
     unsafe {
         let video = Video::get();
         video.clear();
+        let safe_mode = check_safe_mode_key(bios_idt);
+        printf_version_banner();
+        debug_output_status();
 
         video.write_string(b"Bios IDT: 0x");
         video.write_hex_u8((bios_idt >> 24) as u8);
@@ -142,6 +394,7 @@ pub extern "cdecl" fn rust_entry(bios_idt: usize, boot_drive: usize) -> ! {
         video.write_hex_u8(bios_idt as u8);
         video.write_char(b'\n');
         printf!(b"Bios IDT located at: 0x%x\r\n", bios_idt);
+        init_timing(bios_idt);
 
         video.write_string(b"Booting from drive 0x");
         video.write_hex_u8(boot_drive as u8);
@@ -149,25 +402,29 @@ pub extern "cdecl" fn rust_entry(bios_idt: usize, boot_drive: usize) -> ! {
         printf!(b"Booting from BIOS drive #%bh\r\n", boot_drive);
 
         if !is_cpuid_supported() {
-            video.write_string(b"Failed to boot: CPUID not supported !\n");
+            video.write_string(&render(Text::CpuidUnsupported, &[]));
+            video.write_char(b'\n');
             kpanic();
         }
         printf!(b"CPU supports cpuid\r\n");
 
-        if !is_long_mode_supported() {
-            printf!(b"Long mode not supported\r\n");
-            video.write_string(b"Failed to boot: Long mode not supported !\n");
-            kpanic();
+        // Without long mode only a 32-bit kernel can boot, checked once the kernel is opened
+        let long_mode = is_long_mode_supported();
+        if long_mode {
+            printf!(b"CPU supports long mode\r\n\n");
+        } else {
+            printf!(b"Long mode not supported, only a 32-bit kernel can boot\r\n\n");
         }
-        printf!(b"CPU supports long mode\r\n\n");
 
         let extensions = check_and_enable_cpu_extensions();
         if !extensions.fpu {
-            video.write_string(b"Failed to boot: FPU not supported !\n");
+            video.write_string(&render(Text::FpuUnsupported, &[]));
+            video.write_char(b'\n');
             kpanic();
         }
         if !extensions.sse {
-            video.write_string(b"Failed to boot: SSE not supported !\n");
+            video.write_string(&render(Text::SseUnsupported, &[]));
+            video.write_char(b'\n');
             kpanic();
         }
 
@@ -175,12 +432,20 @@ pub extern "cdecl" fn rust_entry(bios_idt: usize, boot_drive: usize) -> ! {
         if !extended_disk.check_present() {
             kpanic();
         }
-        printf!(b"Extended BIOS disk functions present\r\n");
+        if extended_disk.has_extensions() {
+            printf!(b"Extended BIOS disk functions present\r\n");
+        }
         let disk_params = extended_disk.get_params().unwrap_or_else(|e| e.panic());
+        detect_boot_media(&extended_disk, disk_params.info);
 
-        match detect_system_memory(bios_idt) {
-            Ok(_) => {
+        post_code(codes::MEMORY_DETECT);
+        checkpoint(b"memory detect");
+        let memory = match detect_system_memory(bios_idt) {
+            Ok(memory) => {
                 printf!(b"Successfully detected system memory from BIOS\r\n");
+                init_address_regions();
+                init_scrollback(bios_idt);
+                memory
             }
             Err(e) => {
                 printf!(b"Failed to detect system memory from BIOS: 0x%b\r\n", e);
@@ -189,7 +454,12 @@ pub extern "cdecl" fn rust_entry(bios_idt: usize, boot_drive: usize) -> ! {
                 video.write_char(b'\n');
                 kpanic();
             }
-        }
+        };
+
+        post_code(codes::PCI_SCAN);
+        checkpoint(b"PCI scan");
+        let pci_devices = enumerate_pci();
+        let rsdp = find_rsdp();
 
         macro_rules! show_mem {
             () => {
@@ -203,70 +473,119 @@ pub extern "cdecl" fn rust_entry(bios_idt: usize, boot_drive: usize) -> ! {
             };
         }
 
-        let gpt = GUIDPartitionTable::read(&mut extended_disk).unwrap_or_else(|e| e.panic());
-        printf!(b"\r\nFound GUID Partition Table on boot drive\r\nList partitions:\r\n");
-        for partition in gpt.get_partitions().iter() {
-            if partition.name.is_empty() || !partition.name.iter().any(|c| c != 0) {
-                printf!(b"> NO NAME");
-            } else {
-                printf!(b"> \"");
-                write_buffer_as_string(&partition.name);
-                printf!(b"\"");
+        post_code(codes::GPT_READ);
+        checkpoint(b"GPT read");
+        let boot_gpt = GUIDPartitionTable::read(&mut extended_disk);
+        let partitions = boot_gpt.as_ref().ok().map(|gpt| gpt.get_partitions());
+        if let Ok(gpt) = &boot_gpt {
+            printf!(b"\r\nFound GUID Partition Table on boot drive");
+            if gpt.used_backup() {
+                printf!(b" (backup copy at the end of the disk)");
             }
-            printf!(
-                b"\r\n|--- Begin LBA: HEX %x%x / DEC ",
-                (partition.first_lba >> 32) as u32,
-                partition.first_lba as u32
-            );
-            write_u64_decimal(partition.first_lba);
-            printf!(
-                b"\r\n|--- End LBA: HEX %x%x / DEC ",
-                (partition.last_lba >> 32) as u32,
-                partition.last_lba as u32
-            );
-            write_u64_decimal(partition.last_lba);
-            printf!(b"\r\n|--- Size: ");
-            let size = partition.last_lba - partition.first_lba + 1;
-            write_u64_decimal(size);
-            printf!(b" sectors => ");
-            write_u64_decimal(size * (disk_params.bytes_per_sector as u64));
-            printf!(b" bytes\r\n|--- Type: ");
-            write_guid(partition.type_guid);
-            printf!(b"\r\n|--- Unique id: ");
-            write_guid(partition.unique_guid);
-            printf!(
-                b"\r\n+--- Flags: %x %x\r\n",
-                (partition.flags >> 32) as u32,
-                partition.flags as u32
-            );
+            printf!(b"\r\nList partitions:\r\n");
         }
+        // Every field of every partition, only wanted when debugging the partition scan
+        log::with_level(LogLevel::Debug, || {
+            for partition in partitions
+                .into_iter()
+                .flat_map(|partitions| partitions.iter())
+            {
+                if partition.name.is_empty() || !partition.name.iter().any(|c| c != 0) {
+                    printf!(b"> NO NAME");
+                } else {
+                    printf!(b"> \"");
+                    write_buffer_as_string(&partition.name);
+                    printf!(b"\"");
+                }
+                printf!(
+                    b"\r\n|--- Begin LBA: HEX %x%x / DEC ",
+                    (partition.first_lba >> 32) as u32,
+                    partition.first_lba as u32
+                );
+                write_u64_decimal(partition.first_lba);
+                printf!(
+                    b"\r\n|--- End LBA: HEX %x%x / DEC ",
+                    (partition.last_lba >> 32) as u32,
+                    partition.last_lba as u32
+                );
+                write_u64_decimal(partition.last_lba);
+                printf!(b"\r\n|--- Size: ");
+                let size = partition.last_lba - partition.first_lba + 1;
+                write_u64_decimal(size);
+                printf!(b" sectors => ");
+                write_u64_decimal(size * (disk_params.bytes_per_sector as u64));
+                printf!(b" bytes\r\n|--- Type: ");
+                write_guid(partition.type_guid);
+                printf!(b"\r\n|--- Unique id: ");
+                write_guid(partition.unique_guid);
+                printf!(
+                    b"\r\n+--- Flags: %x %x\r\n",
+                    (partition.flags >> 32) as u32,
+                    partition.flags as u32
+                );
+            }
+        });
         printf!(b"\n");
 
-        let (part_i, mut ext2) = {
-            let mut part = None;
-            for (i, partition) in gpt.get_partitions().iter().enumerate() {
-                if partition.type_guid == PARTITION_GUID_TYPE_LINUX_FS {
-                    match Ext2FileSystem::mount_ro(extended_disk.clone(), partition.as_disk_range())
-                    {
-                        Ok(ext2) => {
-                            part = Some((i, ext2));
-                            break;
-                        }
-                        Err(e) => {
-                            printf!(b"Failed to mount partition 0x%b as ext2: ", i);
-                            e.printf();
-                        }
-                    }
+        post_code(codes::MOUNT);
+        checkpoint(b"mount");
+        let (boot_volume, gpt_error) = match boot_gpt {
+            Ok(gpt) => {
+                let found =
+                    scan_boot_partitions(bios_idt, &extended_disk, &gpt, DEFAULT_KERNEL_PATH);
+                if found.is_none() {
+                    printf!(b"Couldn't find an ext2-formatted linux type filesystem partition on the boot drive.\r\n");
                 }
+                let volume = found.map(|(partition, ext2)| BootVolume {
+                    drive: boot_drive as u8,
+                    disk: extended_disk.clone(),
+                    disk_params,
+                    gpt: Some(gpt),
+                    partition,
+                    ext2,
+                });
+                (volume, None)
             }
-            if let Some(part) = part {
-                part
-            } else {
-                printf!(b"Couldn't find an ext2-formatted linux type filesystem partition.\r\n");
-                video.write_string(b"No ext2 partition !\n");
-                kpanic();
+            Err(e) => {
+                printf!(b"No usable GUID Partition Table on the boot drive: ");
+                e.printf();
+                printf!(b"\r\n");
+                if is_optical(&disk_params) {
+                    printf!(b"Optical boot drive, looking for an ISO9660 boot image\r\n");
+                    let volume = open_iso_volume(boot_drive as u8, &mut extended_disk, disk_params);
+                    (volume, Some(e))
+                } else {
+                    (None, Some(e))
+                }
             }
         };
+        let Some(volume) = boot_volume
+            .or_else(|| probe_other_drives(bios_idt, boot_drive as u8, DEFAULT_KERNEL_PATH))
+        else {
+            if let Some(e) = gpt_error {
+                e.panic();
+            }
+            printf!(b"Couldn't find an ext2-formatted linux type filesystem partition.\r\n");
+            video.write_string(&render(Text::NoExt2Partition, &[]));
+            video.write_char(b'\n');
+            kpanic();
+        };
+        let BootVolume {
+            drive,
+            disk: mut extended_disk,
+            mut disk_params,
+            mut gpt,
+            partition: mut part_i,
+            mut ext2,
+        } = volume;
+        if drive as usize != boot_drive {
+            video.write_string(b"Booting from drive 0x");
+            video.write_hex_u8(drive);
+            video.write_string(b" instead\n");
+            // The media flags and the write checks follow the drive booted from
+            detect_boot_media(&extended_disk, disk_params.info);
+        }
+        let mut boot_drive = drive as usize;
         video.write_string(b"Mounted ext2 partition 0x");
         video.write_hex_u8(part_i as u8);
         video.write_string(b".\n");
@@ -274,77 +593,289 @@ pub extern "cdecl" fn rust_entry(bios_idt: usize, boot_drive: usize) -> ! {
 
         show_mem!();
 
-        let Ext2FileType::Directory(root) = ext2.open(2).unwrap_or_else(|e| e.panic()) else {
+        let Ext2FileType::Directory(root) = ext2.open_path(b"/").unwrap_or_else(|e| e.panic())
+        else {
             printf!(b"Inode 2 is not a directory !\r\n");
-            video.write_string(b"Root is not a directory !\n");
+            video.write_string(&render(Text::RootNotDirectory, &[]));
+            video.write_char(b'\n');
             kpanic();
         };
 
         printf!(b"Listing files of root directory (inode 2):\r\n");
         for entry in root.listdir() {
             printf!(b"    /");
-            write_buffer_as_string(entry.get_name());
+            write_buffer_as_escaped_string(entry.get_name());
             printf!(b"\r\n");
         }
         printf!(b"Done.\r\n\n");
 
-        let config_file = match ext2
-            .find_inode(b"/obsiboot.conf")
-            .unwrap_or_else(|e| e.panic())
-        {
-            None => ObsiBootConfig::empty(),
-            Some(inode) => {
+        post_code(codes::CONFIG);
+        checkpoint(b"config");
+        let mut loaded_config = read_config(&mut ext2);
+        let config_file = &loaded_config.config;
+        configure_logging(config_file.log_level, config_file.vga_log_level);
+        if let Some(path) = &config_file.lang_file {
+            load_lang_file(&mut ext2, path);
+        }
+
+        if let Some(drive) = config_file.boot_drive {
+            if drive as usize == boot_drive {
+                printf!(b"boot_drive=0x%b: already booting from it\r\n", drive);
+            } else if let Some(volume) = open_boot_volume(bios_idt, drive, DEFAULT_KERNEL_PATH) {
+                boot_drive = volume.drive as usize;
+                extended_disk = volume.disk;
+                disk_params = volume.disk_params;
+                gpt = volume.gpt;
+                part_i = volume.partition;
+                ext2 = volume.ext2;
+                detect_boot_media(&extended_disk, disk_params.info);
+                video.write_string(b"Booting from drive 0x");
+                video.write_hex_u8(drive);
+                video.write_string(b" (boot_drive=)\n");
+            } else {
                 printf!(
-                    b"Found obsiboot config at /obsiboot.conf, inode 0x%x\r\n",
-                    inode
+                    b"boot_drive=0x%b: unusable, keeping drive 0x%b\r\n",
+                    drive,
+                    boot_drive as u8
                 );
-                match ext2.open(inode).unwrap_or_else(|e| e.panic()) {
-                    Ext2FileType::File(mut file) => {
-                        let contents = file.read_all().unwrap_or_else(|e| e.panic());
-                        ObsiBootConfig::parse(&contents)
-                    }
-                    _ => {
-                        printf!(b"/obsiboot.conf is not a file !\r\n");
-                        ObsiBootConfig::empty()
-                    }
+            }
+        }
+
+        if let (Some(selector), Some(gpt)) = (&config_file.boot_partition, &gpt) {
+            if let Some((selected, selected_ext2)) =
+                mount_selected_partition(&extended_disk, gpt, selector, part_i)
+            {
+                part_i = selected;
+                ext2 = selected_ext2;
+                video.write_string(&render(
+                    Text::BootPartitionSwitched,
+                    &[&hex(part_i as u32)[6..]],
+                ));
+                video.write_char(b'\n');
+            }
+        }
+
+        // On the partition booted from, the record follows the boot media
+        load_disk_health(&mut ext2, config_file.disk_health_path());
+
+        // While the BIOS 8x16 font is still loaded, `text_mode=` replaces it with the 8x8 one
+        fbcon::capture_vga_font();
+        if config_file.text_mode != TextMode::Mode80x25 {
+            if safe_mode {
+                printf!(b"Safe mode: text_mode= ignored\r\n");
+            } else {
+                video.set_text_mode(bios_idt, config_file.text_mode);
+                let (columns, rows) = video.size();
+                printf!(b"Text mode: %x columns, %x rows\r\n", columns, rows);
+            }
+        }
+
+        set_post_codes_enabled(config_file.post_codes);
+        if config_file.bios_latency {
+            if cfg!(feature = "bios-latency") {
+                iolat::enable();
+            } else {
+                printf!(b"bios_latency=on ignored, stage2 was built without the bios-latency feature\r\n");
+            }
+        }
+        scan_installations(
+            bios_idt,
+            boot_drive as u8,
+            loaded_config.mtime,
+            &config_file.multiple_installs,
+        );
+
+        if let Some(limit) = config_file.mem_limit {
+            let end = memory_limit_end(&memory, config_file.reservations(), limit);
+            if !limit_heap(end) {
+                log_warn!(b"Warning: heap already extends past the mem_limit cap\r\n");
+                warning(WarningId::MemLimitHeap);
+            }
+        }
+
+        for reservation in config_file.reservations() {
+            if reservation.start < 0x10_0000 {
+                log::with_level(LogLevel::Warn, || {
+                    printf!(b"Warning: reserve= range ");
+                    reservation.printf();
+                    printf!(b" covers the low 1MiB (IVT, BIOS data, bootloader image)\r\n");
+                });
+                warning(WarningId::ReserveLowMemory);
+            }
+            // The heap only grows upwards, shrink it below any range reserved inside its region
+            if let Some((heap_start, heap_end)) = heap_region() {
+                if reservation.start < heap_end
+                    && heap_start < reservation.end
+                    && !limit_heap(reservation.start)
+                {
+                    log::with_level(LogLevel::Warn, || {
+                        printf!(b"Warning: reserve= range ");
+                        reservation.printf();
+                        printf!(b" overlaps memory already used by the bootloader heap\r\n");
+                    });
+                    warning(WarningId::ReserveHeapOverlap);
                 }
             }
+        }
+
+        if let BootMode::Bench = config_file.mode {
+            run_disk_benchmark(
+                &mut extended_disk,
+                ext2.partition(),
+                config_file.bench_bytes,
+            );
+        }
+
+        if let BootMode::Probe | BootMode::Bench = config_file.mode {
+            let inputs = ProbeInputs {
+                boot_drive: boot_drive as u8,
+                disk_params: &disk_params,
+                memory: &memory,
+                extensions: &extensions,
+            };
+            run_probe_mode(&mut ext2, config_file, &inputs);
+        }
+
+        checkpoint(b"boot menu");
+        let selected = select_boot_entry(bios_idt, &mut ext2, &mut loaded_config);
+        let config_file = &loaded_config.config;
+        let entry = selected.and_then(|i| config_file.boot_entries().nth(i));
+        let kernel_path = entry.map_or(DEFAULT_KERNEL_PATH, |entry| entry.kernel_path());
+        let cmdline = entry.map_or((0, 0), |entry| entry.leak_cmdline());
+        let kernel_sha256 = match entry {
+            Some(entry) => entry.kernel_sha256.as_ref(),
+            None => config_file.kernel_sha256.as_ref(),
         };
 
-        let mut kernel_file = match ext2
-            .find_inode(b"/kernel64.elf")
-            .unwrap_or_else(|e| e.panic())
-        {
-            Some(inode) => {
-                printf!(b"Found kernel at /kernel64.elf, inode 0x%x\r\n", inode);
-                match ext2.open(inode).unwrap_or_else(|e| e.panic()) {
-                    Ext2FileType::File(file) => {
-                        let elf = load_elf(file).unwrap_or_else(|e| e.panic());
-                        match elf {
-                            ElfFileFlavour::Elf64(elf) => elf,
-                            ElfFileFlavour::Elf32(_) => {
-                                printf!(b"Kernel is an ELF32 file, expected 64-bit kernel (ELF64) !\r\n");
-                                video.write_string(b"Failed to boot: Expected 64-bit kernel !\n");
+        checkpoint(b"initrd load");
+        // Loaded before the kernel file is opened, which keeps the filesystem borrowed until the jump
+        let initrd = load_initrds(&mut ext2, config_file.initrd_paths())
+            .map(|image| image.leak())
+            .unwrap_or((0, 0));
+
+        // The kernel file keeps the filesystem borrowed, errors from here on only reach the notice
+        save_disk_health(&mut ext2, config_file.disk_health_path());
+
+        post_code(codes::KERNEL_HEADERS);
+        checkpoint(b"kernel open");
+        ext2.printf_cache_stats();
+        let kernel = match ext2.open_path(kernel_path) {
+            Ok(Ext2FileType::File(mut file)) => {
+                printf!(b"Found kernel at ");
+                write_string(kernel_path);
+                printf!(b"\r\n");
+                if let Some(expected) = kernel_sha256 {
+                    verify_kernel_sha256(&mut file, expected);
+                }
+                let file = open_maybe_compressed(file).unwrap_or_else(|e| e.panic());
+                if config_file.protocol == BootProtocol::Multiboot2 {
+                    printf!(b"Booting it with the Multiboot2 protocol\r\n");
+                    KernelImage::Multiboot2(
+                        Multiboot2Kernel::open(file).unwrap_or_else(|e| e.panic()),
+                    )
+                } else {
+                    let mut elf = load_elf(file).unwrap_or_else(|e| e.panic());
+                    match &mut elf {
+                        ElfFileFlavour::Elf64(elf) => {
+                            if !long_mode {
+                                printf!(
+                                    b"Kernel is an ELF64 file, but long mode is not supported !\r\n"
+                                );
+                                video.write_string(&render(Text::LongModeUnsupported, &[]));
+                                video.write_char(b'\n');
                                 kpanic();
                             }
+                            elf.validate_program_headers().unwrap_or_else(|e| e.panic());
+                            elf.classify_segments(config_file.strict_elf)
+                                .unwrap_or_else(|e| e.panic());
+                        }
+                        ElfFileFlavour::Elf32(elf) => {
+                            printf!(b"Kernel is an ELF32 file, booting it in protected mode\r\n");
+                            elf.validate_program_headers().unwrap_or_else(|e| e.panic());
+                            elf.classify_segments(config_file.strict_elf)
+                                .unwrap_or_else(|e| e.panic());
                         }
                     }
-                    _ => {
-                        printf!(b"/kernel64.elf is not a file !\r\n");
-                        video.write_string(b"Failed to boot: Could not find kernel !\n");
-                        kpanic();
-                    }
+                    KernelImage::ObsiBoot(elf)
                 }
             }
-            None => {
-                video.write_string(b"Failed to boot: /kernel64.elf not found !\n");
-                printf!(b"/kernel64.elf not found !\r\n");
+            Ok(Ext2FileType::Directory(_)) => {
+                write_string(kernel_path);
+                printf!(b" is not a file !\r\n");
+                video.write_string(&render(Text::KernelNotFile, &[]));
+                video.write_char(b'\n');
+                kpanic();
+            }
+            Err(Ext2Error::FileNotFound(_)) => {
+                video.write_string(&render(Text::KernelNotFound, &[kernel_path]));
+                video.write_char(b'\n');
+                write_string(kernel_path);
+                printf!(b" not found !\r\n");
                 kpanic();
             }
+            Err(e) => e.panic(),
         };
 
-        switch_to_graphics(bios_idt, &config_file);
-        enable_paging_and_run_kernel(&mut kernel_file, bios_idt, boot_drive);
+        // Before the mode switch, so the notice is still on screen
+        check_disk_health(config_file.disk_health_notice);
+        checkpoint(b"video mode");
+        let vbe = if safe_mode {
+            if config_file.vbe_mode.is_some() {
+                printf!(b"Safe mode: vbe_mode= ignored\r\n");
+            }
+            safe_mode_info()
+        } else {
+            switch_to_graphics(bios_idt, config_file)
+        };
+        if let Some(mut console) = vbe.console(config_file.fb_font) {
+            let (columns, rows) = console.geometry();
+            printf!(
+                b"Framebuffer console: %x columns, %x rows\r\n",
+                columns,
+                rows
+            );
+            console.write_string(&render(Text::StartingKernel, &[kernel_path]));
+            console.write_char(b'\n');
+            fbcon::set_active_console(console);
+        }
+        iolat::printf_histograms();
+        if short_read_count() != 0 {
+            printf!(
+                b"Warning: the BIOS reported 0x%x successful reads that transferred fewer sectors than requested, the firmware is misbehaving\r\n",
+                short_read_count()
+            );
+        }
+        // Last chance to catch a corrupted heap before the kernel inherits it
+        heap_check().unwrap_or_else(|e| e.panic());
+        let state = BootState {
+            bios_idt,
+            boot_drive,
+            memory,
+            vbe,
+            mem_limit: config_file.mem_limit,
+            scrub_handoff_memory: config_file.scrub_handoff_memory,
+            stream_kernel: config_file.stream_kernel,
+            map_null_page: config_file.map_null_page,
+            pause_before_jump: config_file.pause_before_jump,
+            strict_boot: config_file.strict_boot,
+            strict_allow: config_file.strict_allow,
+            reservations: config_file.reservations,
+            reservation_count: config_file.reservation_count,
+            initrd,
+            cmdline,
+            pci_devices,
+            rsdp,
+            safe_mode,
+        };
+        match kernel {
+            KernelImage::ObsiBoot(ElfFileFlavour::Elf64(mut kernel_file)) => {
+                enable_paging_and_run_kernel(&mut kernel_file, &state)
+            }
+            KernelImage::ObsiBoot(ElfFileFlavour::Elf32(mut kernel_file)) => {
+                run_kernel32(&mut kernel_file, &state)
+            }
+            KernelImage::Multiboot2(kernel) => run_multiboot2(kernel, &state),
+        }
 
         #[allow(clippy::empty_loop)]
         loop {}
@@ -0,0 +1,193 @@
+use core::cell::SyncUnsafeCell;
+
+use crate::{e9::write_char, printf};
+
+/// Verbosity of a message, a sink shows the messages at or below its level
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+pub const LOG_LEVELS: [LogLevel; 5] = [
+    LogLevel::Error,
+    LogLevel::Warn,
+    LogLevel::Info,
+    LogLevel::Debug,
+    LogLevel::Trace,
+];
+
+/// Level of the e9 sink until `loglevel=` is read
+pub const DEFAULT_E9_LEVEL: LogLevel = LogLevel::Debug;
+/// Level of the VGA sink until `loglevel=` is read, the screen only shows problems
+pub const DEFAULT_VGA_LEVEL: LogLevel = LogLevel::Warn;
+
+/// Bytes of log messages kept until the sinks are configured, older ones are dropped. <br>
+/// Only messages above a sink's default level are kept, the others are written right away. <br>
+const EARLY_LOG_LEN: usize = 4096;
+
+impl LogLevel {
+    /// The name accepted by `loglevel=`
+    pub fn name(self) -> &'static [u8] {
+        match self {
+            LogLevel::Error => b"error",
+            LogLevel::Warn => b"warn",
+            LogLevel::Info => b"info",
+            LogLevel::Debug => b"debug",
+            LogLevel::Trace => b"trace",
+        }
+    }
+
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        LOG_LEVELS.into_iter().find(|level| level.name() == name)
+    }
+}
+
+struct Logger {
+    e9_level: LogLevel,
+    vga_level: LogLevel,
+    /// False until [`configure_logging`] ran, the sinks use their default levels until then
+    configured: bool,
+    /// True while [`flush_early_log`] writes out `early`
+    flushing: bool,
+    /// Level of the message being written, None for plain `printf!` output
+    current: Option<LogLevel>,
+    early: [(LogLevel, u8); EARLY_LOG_LEN],
+    /// Bytes written to `early` since boot, the next one goes to `early_count % EARLY_LOG_LEN`
+    early_count: usize,
+}
+
+/// Single threaded, only touched through the functions below
+static LOGGER: SyncUnsafeCell<Logger> = SyncUnsafeCell::new(Logger {
+    e9_level: DEFAULT_E9_LEVEL,
+    vga_level: DEFAULT_VGA_LEVEL,
+    configured: false,
+    flushing: false,
+    current: None,
+    early: [(LogLevel::Trace, 0); EARLY_LOG_LEN],
+    early_count: 0,
+});
+
+fn logger() -> &'static mut Logger {
+    unsafe { &mut *LOGGER.get() }
+}
+
+/// Whether a message at `level` would reach any sink, always true until the sinks are configured
+pub fn enabled(level: LogLevel) -> bool {
+    let logger = logger();
+    !logger.configured || level <= logger.e9_level || level <= logger.vga_level
+}
+
+/// Runs `f` with everything it prints logged at `level`, for messages built from several writes. <br>
+/// `f` isn't run when no sink would show the message. <br>
+pub fn with_level(level: LogLevel, f: impl FnOnce()) {
+    if !enabled(level) {
+        return;
+    }
+    let previous = logger().current.replace(level);
+    f();
+    logger().current = previous;
+}
+
+/// Where `e9::write_char` sends `character`, as `(e9, vga)`. <br>
+/// Plain `printf!` output only goes to e9. Until the sinks are configured, log messages go out at the default levels, in order with the plain output around them. <br>
+/// Those above a default level are held for the sink, in case its configured level shows them. <br>
+pub fn route(character: u8) -> (bool, bool) {
+    let logger = logger();
+    let Some(level) = logger.current else {
+        return (true, false);
+    };
+    if logger.flushing {
+        // Held bytes, for the sinks that didn't show them at their default level
+        return (
+            level > DEFAULT_E9_LEVEL && level <= logger.e9_level,
+            level > DEFAULT_VGA_LEVEL && level <= logger.vga_level,
+        );
+    }
+    if !logger.configured {
+        if level > DEFAULT_E9_LEVEL || level > DEFAULT_VGA_LEVEL {
+            logger.early[logger.early_count % EARLY_LOG_LEN] = (level, character);
+            logger.early_count += 1;
+        }
+        return (level <= DEFAULT_E9_LEVEL, level <= DEFAULT_VGA_LEVEL);
+    }
+    (level <= logger.e9_level, level <= logger.vga_level)
+}
+
+/// Sets the sink levels and writes the held messages they now show. <br>
+/// Called once the config is read, and again on every reload. <br>
+pub fn configure_logging(e9_level: LogLevel, vga_level: LogLevel) {
+    let logger = logger();
+    logger.e9_level = e9_level;
+    logger.vga_level = vga_level;
+    if logger.configured {
+        return;
+    }
+    logger.configured = true;
+    flush_early_log();
+}
+
+/// Writes out the held messages the current levels show, oldest first. Also used by the panic path before the config was read. <br>
+/// They come after everything written since, they were above the default levels. <br>
+pub fn flush_early_log() {
+    let logger = logger();
+    let count = logger.early_count;
+    logger.early_count = 0;
+    let dropped = count.saturating_sub(EARLY_LOG_LEN);
+    if dropped != 0 {
+        printf!(b"(0x%x bytes of early log dropped)\r\n", dropped);
+    }
+    logger.flushing = true;
+    for i in dropped..count {
+        let (level, character) = logger.early[i % EARLY_LOG_LEN];
+        logger.current = Some(level);
+        write_char(character);
+    }
+    logger.current = None;
+    logger.flushing = false;
+}
+
+#[macro_export]
+macro_rules! log_at {
+    ($level:expr, $($args:tt)*) => {
+        $crate::log::with_level($level, || $crate::printf!($($args)*))
+    };
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($args:tt)*) => {
+        $crate::log_at!($crate::log::LogLevel::Error, $($args)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($args:tt)*) => {
+        $crate::log_at!($crate::log::LogLevel::Warn, $($args)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($args:tt)*) => {
+        $crate::log_at!($crate::log::LogLevel::Info, $($args)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($args:tt)*) => {
+        $crate::log_at!($crate::log::LogLevel::Debug, $($args)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_trace {
+    ($($args:tt)*) => {
+        $crate::log_at!($crate::log::LogLevel::Trace, $($args)*)
+    };
+}
@@ -0,0 +1,169 @@
+use core::{
+    cell::SyncUnsafeCell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    bios::ExtendedDisk,
+    e9::write_string,
+    obsiboot::{MEDIA_READ_ONLY, MEDIA_REMOVABLE, MEDIA_WRITE_PROTECTED},
+    printf,
+};
+
+/// EDD information flag (INT 13h AH=48h): the device uses removable media
+pub const EDD_INFO_REMOVABLE: u16 = 1 << 2;
+/// INT 13h status returned by writes to a write-protected medium
+pub const STATUS_WRITE_PROTECTED: usize = 0x03;
+
+/// Disk type reported by INT 13h AH=15h
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MediaType {
+    NoDisk,
+    /// Floppy or other removable drive, without change-line support
+    Removable,
+    /// Removable drive that reports media changes
+    RemovableChangeLine,
+    Fixed,
+    /// The call failed or returned an unknown type
+    Unknown,
+}
+
+impl MediaType {
+    pub fn from_disk_type(disk_type: Option<u8>) -> Self {
+        match disk_type {
+            Some(0) => MediaType::NoDisk,
+            Some(1) => MediaType::Removable,
+            Some(2) => MediaType::RemovableChangeLine,
+            Some(3) => MediaType::Fixed,
+            _ => MediaType::Unknown,
+        }
+    }
+
+    pub fn name(&self) -> &'static [u8] {
+        match self {
+            MediaType::NoDisk => b"no disk",
+            MediaType::Removable => b"removable",
+            MediaType::RemovableChangeLine => b"removable (change line)",
+            MediaType::Fixed => b"fixed",
+            MediaType::Unknown => b"unknown",
+        }
+    }
+}
+
+/// Characteristics of the boot medium, read once while the disk is enumerated
+#[derive(Clone, Copy)]
+pub struct MediaInfo {
+    pub media_type: MediaType,
+    /// Either INT 13h AH=15h or the EDD information flags say the media is removable
+    pub removable: bool,
+    /// A write failed with [`STATUS_WRITE_PROTECTED`]. BIOSes have no way to ask before writing.
+    pub write_protected: bool,
+}
+
+impl MediaInfo {
+    /// Combines the AH=15h disk type and the EDD information flags
+    pub fn new(disk_type: Option<u8>, edd_info: u16) -> Self {
+        let media_type = MediaType::from_disk_type(disk_type);
+        Self {
+            media_type,
+            removable: matches!(
+                media_type,
+                MediaType::Removable | MediaType::RemovableChangeLine
+            ) || edd_info & EDD_INFO_REMOVABLE != 0,
+            write_protected: false,
+        }
+    }
+
+    /// Conservative decision: writes are refused once the medium is known to be read-only. <br>
+    /// Inconclusive detection allows writes, the first failed write then sets the read-only latch. <br>
+    pub fn writable(&self) -> bool {
+        self.media_type != MediaType::NoDisk && !self.write_protected && !read_only()
+    }
+
+    /// Flags handed to the kernel, see the `MEDIA_*` constants of `obsiboot`
+    pub fn flags(&self) -> u32 {
+        let mut flags = 0;
+        if self.removable {
+            flags |= MEDIA_REMOVABLE;
+        }
+        if self.write_protected {
+            flags |= MEDIA_WRITE_PROTECTED;
+        }
+        if !self.writable() {
+            flags |= MEDIA_READ_ONLY;
+        }
+        flags | ((self.media_type as u32) << 8)
+    }
+
+    pub fn printf(&self) {
+        printf!(b"type ");
+        write_string(self.media_type.name());
+        printf!(b", removable=%b", self.removable as u8);
+        printf!(b", writable=%b", self.writable() as u8);
+        if self.write_protected {
+            printf!(b" (write-protected)");
+        }
+    }
+}
+
+/// Media of the boot disk, written by [`detect_boot_media`] and by the read-only latch
+static BOOT_MEDIA: SyncUnsafeCell<MediaInfo> = SyncUnsafeCell::new(MediaInfo {
+    media_type: MediaType::Unknown,
+    removable: false,
+    write_protected: false,
+});
+/// Session-wide: set by the first failed write, every later write is refused without reaching the BIOS
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Queries the boot disk type and records it with the EDD flags, logs the result
+pub fn detect_boot_media(disk: &ExtendedDisk, edd_info: u16) -> MediaInfo {
+    let info = MediaInfo::new(disk.disk_type(), edd_info);
+    unsafe { *BOOT_MEDIA.get() = info };
+    printf!(b"Boot medium: ");
+    info.printf();
+    printf!(b"\r\n");
+    info
+}
+
+pub fn boot_media() -> MediaInfo {
+    unsafe { *BOOT_MEDIA.get() }
+}
+
+pub fn read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+/// Sets the read-only latch after a failed write, with a single notice for the whole boot
+pub fn record_write_failure(status: usize) {
+    if status == STATUS_WRITE_PROTECTED {
+        unsafe { (*BOOT_MEDIA.get()).write_protected = true };
+    }
+    if READ_ONLY.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    if status == STATUS_WRITE_PROTECTED {
+        printf!(b"Boot medium reports write-protected, disk writes disabled for this boot\r\n");
+    } else {
+        printf!(
+            b"Write to the boot medium failed (0x%b), disk writes disabled for this boot\r\n",
+            status as u8
+        );
+    }
+}
+
+/// Checks up front that `feature` may write to the boot medium, logging why not when it may not
+pub fn writes_allowed(feature: &[u8]) -> bool {
+    let media = boot_media();
+    if media.writable() {
+        return true;
+    }
+    write_string(feature);
+    if media.write_protected {
+        printf!(b" disabled: boot medium reports write-protected\r\n");
+    } else if media.media_type == MediaType::NoDisk {
+        printf!(b" disabled: no medium in the boot drive\r\n");
+    } else {
+        printf!(b" disabled: an earlier write to the boot medium failed\r\n");
+    }
+    false
+}
@@ -1,14 +1,24 @@
 use core::{
+    arch::{asm, x86::_rdtsc},
+    cell::SyncUnsafeCell,
     ops::{Deref, DerefMut},
     ptr, slice,
+    sync::atomic::{AtomicU32, Ordering},
 };
 
 use crate::{
     bios::{unsafe_call_bios_interrupt, BiosInterruptResult},
-    eflags, kpanic, printf, ptr_to_seg_off,
+    double_panic,
+    e9::write_string,
+    eflags, in_panic, kpanic,
+    memlayout::{heap_portion, HeapRegionSelector},
+    post::{codes, post_code_progress},
+    printf, ptr_to_seg_off,
     video::Video,
 };
 
+pub use crate::collections::{EvictionPolicy, IterVec, RefIterVec, SortedMap, Vec};
+
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
 pub struct SystemMemoryMap {
@@ -17,6 +27,8 @@ pub struct SystemMemoryMap {
     len_lo: u32,
     len_hi: u32,
     range_type: u32,
+    /// ACPI 3.0 extended attributes, see [`E820_ATTRIBUTE_ENABLED`]
+    extended_attributes: u32,
 }
 
 impl SystemMemoryMap {
@@ -40,6 +52,33 @@ impl SystemMemoryMap {
     pub fn range_type(&self) -> u32 {
         self.range_type
     }
+
+    pub fn extended_attributes(&self) -> u32 {
+        self.extended_attributes
+    }
+
+    /// False when the BIOS asks for the entry to be ignored
+    pub fn is_enabled(&self) -> bool {
+        self.extended_attributes & E820_ATTRIBUTE_ENABLED != 0
+    }
+
+    fn set_len(&mut self, len: u64) {
+        self.len_lo = len as u32;
+        self.len_hi = (len >> 32) as u32;
+    }
+
+    fn set_base_addr(&mut self, base: u64) {
+        self.base_addr_lo = base as u32;
+        self.base_addr_hi = (base >> 32) as u32;
+    }
+
+    /// `(start, end)` of the part of this entry the heap could use, None unless it's enabled available memory reaching between 1MiB and 4GiB
+    fn heap_portion(&self) -> Option<(u64, u64)> {
+        if !self.is_enabled() || self.range_type != RANGE_TYPE_AVAILABLE {
+            return None;
+        }
+        heap_portion(self.base_addr(), self.len())
+    }
 }
 
 pub const RANGE_TYPE_AVAILABLE: u32 = 0x1;
@@ -47,126 +86,213 @@ pub const RANGE_TYPE_RESERVED: u32 = 0x2;
 pub const RANGE_TYPE_ACPI_RECLAIM: u32 = 0x3;
 pub const RANGE_TYPE_ACPI_NVS: u32 = 0x4;
 
-pub static mut SYSTEM_MEMORY_MAP: [SystemMemoryMap; 64] = [SystemMemoryMap {
+/// Bit 0 of the ACPI 3.0 extended attributes, the entry must be ignored when it's clear
+pub const E820_ATTRIBUTE_ENABLED: u32 = 1;
+
+/// Upper bound on the E820 entries read, so a BIOS that never ends the walk can't hang the boot
+pub const MAX_MEMORY_MAP_ENTRIES: usize = 1024;
+
+const NULL_MEMORY_MAP: SystemMemoryMap = SystemMemoryMap {
     base_addr_lo: 0,
     base_addr_hi: 0,
     len_lo: 0,
     len_hi: 0,
     range_type: 0,
-}; 64];
-pub static mut USED_MAP: usize = 0;
+    extended_attributes: 0,
+};
+
+/// The memory map reported by the BIOS, owned by the boot state
+pub struct SystemMemory {
+    entries: Vec<SystemMemoryMap>,
+    used_map: SystemMemoryMap,
+}
+
+impl SystemMemory {
+    /// The raw E820 entries, in the order the BIOS returned them, ignored ones included
+    pub fn entries(&self) -> &[SystemMemoryMap] {
+        self.entries.as_slice()
+    }
+
+    /// The entries without the ones the BIOS asks to ignore
+    pub fn enabled_entries(&self) -> impl Iterator<Item = &SystemMemoryMap> {
+        self.entries().iter().filter(|map| map.is_enabled())
+    }
+
+    /// The region the heap and page tables live in
+    pub fn used_map(&self) -> &SystemMemoryMap {
+        &self.used_map
+    }
+}
+
+/// Real-mode addressable buffer the BIOS writes each E820 entry into
+static E820_BUFFER: SyncUnsafeCell<SystemMemoryMap> = SyncUnsafeCell::new(NULL_MEMORY_MAP);
+
+struct HeapState {
+    region: Option<SystemMemoryMap>,
+    used: usize,
+}
+
+/// Allocator bookkeeping. <br>
+/// Stage2 is single threaded and never allocates from an interrupt handler, so at most one reference to it is alive at any time. <br>
+static HEAP: SyncUnsafeCell<HeapState> = SyncUnsafeCell::new(HeapState {
+    region: None,
+    used: 0,
+});
+
+fn heap() -> &'static mut HeapState {
+    unsafe { &mut *HEAP.get() }
+}
 
 const SMAP: usize = 0x534D4150;
 
-pub fn detect_system_memory(bios_idt: usize) -> Result<(), u8> {
+/// Runs INT 15h, EAX=E820h over the whole map and calls `f` with every entry. <br>
+/// Asks for 24 byte entries, a BIOS that only writes 20 bytes leaves the entry enabled. <br>
+unsafe fn walk_e820(bios_idt: usize, mut f: impl FnMut(SystemMemoryMap)) -> Result<(), u8> {
+    let mut index = 0;
+    let mut start_addr = 0;
+
+    while index < MAX_MEMORY_MAP_ENTRIES {
+        post_code_progress(codes::E820_ENTRY, index);
+        *E820_BUFFER.get() = SystemMemoryMap {
+            extended_attributes: E820_ATTRIBUTE_ENABLED,
+            ..NULL_MEMORY_MAP
+        };
+        let (seg, off) = ptr_to_seg_off(E820_BUFFER.get() as usize);
+
+        let result = unsafe_call_bios_interrupt(
+            bios_idt,
+            0x15,
+            0xe820,
+            start_addr,
+            size_of::<SystemMemoryMap>(),
+            SMAP,
+            0,
+            off as usize,
+            seg as usize,
+            seg as usize,
+            seg as usize,
+            seg as usize,
+        ) as *const BiosInterruptResult;
+
+        if ((*result).eflags & eflags::CF) != 0 {
+            return Err((((*result).eax & 0xFF00) >> 8) as u8);
+        }
+
+        let mut map = *E820_BUFFER.get();
+        if (*result).ecx < size_of::<SystemMemoryMap>() {
+            map.extended_attributes = E820_ATTRIBUTE_ENABLED;
+        }
+        f(map);
+
+        start_addr = (*result).ebx;
+        if start_addr == 0 {
+            return Ok(());
+        }
+        index += 1;
+    }
+    printf!(
+        b"E820: stopped after 0x%x entries\r\n",
+        MAX_MEMORY_MAP_ENTRIES
+    );
+    Ok(())
+}
+
+/// Picks the heap region on a first walk of the E820 map, then stores every entry on the heap on a second one
+pub fn detect_system_memory(bios_idt: usize) -> Result<SystemMemory, u8> {
     unsafe {
         let video = Video::get();
         video.write_string(b"Detecting system memory...\n");
 
-        let mut index = 0;
-        let mut start_addr = 0;
-
-        loop {
-            if index >= 64 {
-                break;
+        let mut selector = HeapRegionSelector::new();
+        let mut used_map: Option<SystemMemoryMap> = None;
+        let mut count = 0;
+        let mut ignored = 0;
+        walk_e820(bios_idt, |map| {
+            count += 1;
+            if selector.offer(map.heap_portion()) {
+                used_map = Some(map);
             }
-            let map = &mut SYSTEM_MEMORY_MAP[index];
-            let (seg, off) = ptr_to_seg_off(map as *const SystemMemoryMap as usize);
-
-            let result = unsafe_call_bios_interrupt(
-                bios_idt,
-                0x15,
-                0xe820,
-                start_addr,
-                20,
-                SMAP,
-                0,
-                off as usize,
-                seg as usize,
-                seg as usize,
-                seg as usize,
-                seg as usize,
-            ) as *const BiosInterruptResult;
-
-            if ((*result).eflags & eflags::CF) != 0 {
-                return Err((((*result).eax & 0xFF00) >> 8) as u8);
+            if map.heap_portion().is_some() {
+                return;
             }
-
-            if map.base_addr() >= 1024 * 1024
-                && map.base_addr_hi == 0
-                && map.range_type == RANGE_TYPE_AVAILABLE
-            {
-                let max_available = (u32::MAX as u64) - map.len();
-                let available = max_available.min(map.len());
-
-                if USED_MAP < 64 && available > SYSTEM_MEMORY_MAP[USED_MAP].len() {
-                    USED_MAP = index;
-                }
-            } else {
+            if map.is_enabled() {
                 video.write_string(b"Skipped 0x");
-                video.write_hex_u32(map.base_addr_hi);
-                video.write_hex_u32(map.base_addr_lo);
-                video.write_string(b" | Length 0x");
-                video.write_hex_u32(map.len_hi);
-                video.write_hex_u32(map.len_lo);
-                video.write_string(b" | Type 0x");
-                video.write_hex_u32(map.range_type);
-                video.write_char(b'\n');
-            }
-
-            start_addr = (*result).ebx;
-            if start_addr == 0 {
-                break;
+            } else {
+                ignored += 1;
+                video.write_string(b"Ignored 0x");
             }
-
-            index += 1;
-        }
-
-        if USED_MAP < 64 {
-            let map = &mut SYSTEM_MEMORY_MAP[USED_MAP];
-            video.write_string(b"Using 0x");
+            video.write_hex_u32(map.base_addr_hi);
+            video.write_hex_u32(map.base_addr_lo);
+            video.write_string(b" | Length 0x");
             video.write_hex_u32(map.len_hi);
             video.write_hex_u32(map.len_lo);
-            video.write_string(b" bytes of contiguous memory at 0x");
-            video.write_hex_u32(map.base_addr_lo);
+            video.write_string(b" | Type 0x");
+            video.write_hex_u32(map.range_type);
             video.write_char(b'\n');
+        })?;
+        printf!(b"E820: 0x%x entries, 0x%x ignored\r\n", count, ignored);
 
-            let header = get_first_header();
-            // Aligned to 4Kb
-            let max_addr = (u32::MAX as u64).min(map.base_addr() + map.len()) as usize;
-
-            *header = MemoryBlock {
+        let (Some(index), Some(mut map)) = (selector.best(), used_map) else {
+            video.write_string(b"No usable memory above 1MiB\n");
+            kpanic();
+        };
+        // The heap only gets the part between 1MiB and 4GiB
+        let (start, end) = map.heap_portion().unwrap_or_else(|| kpanic());
+        map.set_base_addr(start);
+        map.set_len(end - start);
+        printf!(
+            b"Heap region: E820 entry 0x%x, 0x%x bytes at 0x%x\r\n",
+            index,
+            (end - start) as usize,
+            start as usize
+        );
+        video.write_string(b"Using 0x");
+        video.write_hex_u32(map.len_hi);
+        video.write_hex_u32(map.len_lo);
+        video.write_string(b" bytes of contiguous memory at 0x");
+        video.write_hex_u32(map.base_addr_lo);
+        video.write_char(b'\n');
+
+        heap().region = Some(map);
+        let header = get_first_header();
+        // Aligned to 4Kb
+        let max_addr = (u32::MAX as u64).min(map.base_addr() + map.len()) as usize;
+
+        store_block(
+            header,
+            MemoryBlock {
+                magic: 0,
                 size: max_addr - (header as usize) - size_of::<MemoryBlock>(),
                 free: 1,
                 prev: ptr::null_mut(),
                 next: ptr::null_mut(),
-            };
-
-            printf!(
-                b"Heap allocator: begin=0x%x, end=0x%x\r\n",
-                (header as usize) + size_of::<MemoryBlock>(),
-                max_addr
-            );
-        }
-
-        Ok(())
+                checksum: 0,
+            },
+        );
+
+        printf!(
+            b"Heap allocator: begin=0x%x, end=0x%x\r\n",
+            (header as usize) + size_of::<MemoryBlock>(),
+            max_addr
+        );
+
+        // The heap works now, the second walk stores the entries in it
+        let mut entries = Vec::new(count.max(1));
+        walk_e820(bios_idt, |map| entries.push(map))?;
+
+        Ok(SystemMemory {
+            entries,
+            used_map: map,
+        })
     }
 }
 
 fn get_mem_map() -> SystemMemoryMap {
-    unsafe {
-        if USED_MAP < 64 {
-            SYSTEM_MEMORY_MAP[USED_MAP]
-        } else {
-            kpanic()
-        }
-    }
+    heap().region.unwrap_or_else(|| kpanic())
 }
 
-static mut MEM_USED: usize = 0;
-
 pub fn get_mem_used() -> usize {
-    unsafe { MEM_USED }
+    heap().used
 }
 
 pub fn get_mem_total() -> usize {
@@ -203,6 +329,32 @@ pub unsafe fn memset(dst: usize, value: u8, count: usize) {
     }
 }
 
+static SCRUBBED_BYTES: AtomicU32 = AtomicU32::new(0);
+/// Single threaded, only written by [`scrub`]
+static SCRUB_CYCLES: SyncUnsafeCell<u64> = SyncUnsafeCell::new(0);
+
+/// # Safety
+/// Zeroes `count` bytes at `dst` with `rep stosb`, and accounts them in [`scrub_stats`]
+pub unsafe fn scrub(dst: usize, count: usize) {
+    let start = _rdtsc();
+    asm!(
+        "rep stosb",
+        inout("edi") dst => _,
+        inout("ecx") count => _,
+        in("al") 0u8,
+        options(nostack, preserves_flags)
+    );
+    *SCRUB_CYCLES.get() += _rdtsc().wrapping_sub(start);
+    SCRUBBED_BYTES.fetch_add(count as u32, Ordering::Relaxed);
+}
+
+/// Returns the number of bytes zeroed by [`scrub`] and the TSC cycles it took
+pub fn scrub_stats() -> (u32, u64) {
+    (SCRUBBED_BYTES.load(Ordering::Relaxed), unsafe {
+        *SCRUB_CYCLES.get()
+    })
+}
+
 #[no_mangle]
 #[inline(never)]
 /// # Safety
@@ -277,10 +429,150 @@ where
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
 struct MemoryBlock {
+    /// [`BLOCK_MAGIC`], an overrun from the block before usually clobbers it first
+    magic: u32,
     size: usize,
     free: u8,
     prev: *mut MemoryBlock,
     next: *mut MemoryBlock,
+    /// See [`MemoryBlock::compute_checksum`]
+    checksum: u32,
+}
+
+const BLOCK_MAGIC: u32 = 0x4B4C4250;
+/// Blocks walked by [`heap_check`] and [`heap_dump`] before the list is considered looping
+const MAX_HEAP_BLOCKS: usize = 1 << 20;
+
+impl MemoryBlock {
+    fn compute_checksum(&self) -> u32 {
+        let (size, free, prev, next) = (self.size, self.free, self.prev, self.next);
+        (size as u32).rotate_left(7)
+            ^ (prev as u32).rotate_left(13)
+            ^ (next as u32).rotate_left(21)
+            ^ free as u32
+            ^ BLOCK_MAGIC
+    }
+
+    fn is_valid(&self) -> bool {
+        let (magic, checksum) = (self.magic, self.checksum);
+        magic == BLOCK_MAGIC && checksum == self.compute_checksum()
+    }
+}
+
+/// Writes `block` at `header` with its magic and checksum
+unsafe fn store_block(header: *mut MemoryBlock, mut block: MemoryBlock) {
+    block.magic = BLOCK_MAGIC;
+    block.checksum = block.compute_checksum();
+    header.write_unaligned(block);
+}
+
+/// Reads the block at `header`, checking its magic and checksum with the `heap-check` feature
+unsafe fn load_block(header: *mut MemoryBlock) -> MemoryBlock {
+    let block = header.read_unaligned();
+    if cfg!(feature = "heap-check") && !block.is_valid() {
+        HeapError::BadHeader(header as usize).panic();
+    }
+    block
+}
+
+pub enum HeapError {
+    /// Block header with a bad magic or checksum, overwritten by something else
+    BadHeader(usize),
+    /// Block whose `prev` doesn't point back to the block before it, or whose `next` isn't after it in the heap
+    BrokenLink(usize),
+    /// Block freed while already free
+    DoubleFree(usize),
+    /// Bytes in used blocks (headers included) and the recorded heap usage
+    AccountingMismatch(usize, usize),
+    /// The block list doesn't end, it probably loops
+    TooManyBlocks,
+}
+
+impl HeapError {
+    pub fn printf(&self) {
+        match self {
+            HeapError::BadHeader(header) => {
+                printf!(b"Heap corruption: bad block header at 0x%x\r\n", *header)
+            }
+            HeapError::BrokenLink(header) => {
+                printf!(b"Heap corruption: broken links at block 0x%x\r\n", *header)
+            }
+            HeapError::DoubleFree(ptr) => printf!(b"Double free of 0x%x\r\n", *ptr),
+            HeapError::AccountingMismatch(counted, recorded) => printf!(
+                b"Heap accounting mismatch: 0x%x bytes in used blocks, 0x%x recorded\r\n",
+                *counted,
+                *recorded
+            ),
+            HeapError::TooManyBlocks => printf!(b"Heap corruption: the block list loops\r\n"),
+        }
+    }
+
+    pub fn panic(&self) -> ! {
+        self.printf();
+        heap_dump();
+        heap_dump_recent();
+        unsafe {
+            let video = Video::get();
+            video.write_string(b"Heap corruption detected, see the log\n");
+        }
+        kpanic();
+    }
+}
+
+/// Walks the whole block list: headers intact, `prev`/`next` consistent and in address order, and the used bytes matching the recorded usage
+pub fn heap_check() -> Result<(), HeapError> {
+    let header_size = size_of::<MemoryBlock>();
+    let mut header = get_first_header();
+    let mut prev: *mut MemoryBlock = ptr::null_mut();
+    let mut used = 0;
+    for _ in 0..MAX_HEAP_BLOCKS {
+        let block = unsafe { header.read_unaligned() };
+        if !block.is_valid() {
+            return Err(HeapError::BadHeader(header as usize));
+        }
+        let next = block.next;
+        if block.prev != prev || (!next.is_null() && (next as usize) <= header as usize) {
+            return Err(HeapError::BrokenLink(header as usize));
+        }
+        if block.free == 0 {
+            used += block.size + header_size;
+        }
+        if next.is_null() {
+            return if used == heap().used {
+                Ok(())
+            } else {
+                Err(HeapError::AccountingMismatch(used, heap().used))
+            };
+        }
+        prev = header;
+        header = next;
+    }
+    Err(HeapError::TooManyBlocks)
+}
+
+/// Logs every block's address, size and state, stopping at the first damaged header
+pub fn heap_dump() {
+    let mut header = get_first_header();
+    printf!(b"Heap blocks (header, data size, state):\r\n");
+    for _ in 0..MAX_HEAP_BLOCKS {
+        let block = unsafe { header.read_unaligned() };
+        if !block.is_valid() {
+            printf!(b"    0x%x damaged header\r\n", header as usize);
+            return;
+        }
+        let size = block.size;
+        printf!(b"    0x%x 0x%x ", header as usize, size);
+        printf!(if block.free != 0 {
+            b"free\r\n"
+        } else {
+            b"used\r\n"
+        });
+        if block.next.is_null() {
+            return;
+        }
+        header = block.next;
+    }
+    printf!(b"    ... the block list doesn't end\r\n");
 }
 
 fn get_first_header() -> *mut MemoryBlock {
@@ -294,9 +586,7 @@ fn get_first_header() -> *mut MemoryBlock {
             printf!(b"Not enough memory !\r\n");
             kpanic();
         }
-        // Reserve first 15MiB (in theory, base should be at 1MiB, so we start allocating heap at 16MiB).
-        // Will be used for page tables, etc.
-        base + 15 * 1024 * 1024
+        base
     };
     // Find first 4Kb aligned address
     let aligned_addr = (base_addr & !(0x1000 - 1)) + 0x1000;
@@ -309,10 +599,48 @@ fn get_first_header() -> *mut MemoryBlock {
     first_header as *mut MemoryBlock
 }
 
+/// Shrinks the heap so that it doesn't extend past `max_end`. <br>
+/// Only the free block at the top of the heap can shrink, returns false if allocated memory is already above `max_end` <br>
+pub fn limit_heap(max_end: u64) -> bool {
+    let header_size = size_of::<MemoryBlock>();
+    let last = get_last_header() as *mut MemoryBlock;
+    let mut last_v = unsafe { load_block(last) };
+
+    let data_start = (last as usize + header_size) as u64;
+    if data_start + last_v.size as u64 <= max_end {
+        return true;
+    }
+    if last_v.free == 0 || max_end < data_start {
+        return false;
+    }
+
+    last_v.size = (max_end - data_start) as usize;
+    unsafe { store_block(last, last_v) };
+
+    if let Some(region) = heap().region.as_mut() {
+        region.set_len(max_end - region.base_addr());
+    }
+    printf!(b"Heap allocator: end limited to 0x%x\r\n", max_end as u32);
+    true
+}
+
+/// Returns the `(start, end)` of the memory region the heap lives in
+pub fn heap_region() -> Option<(u64, u64)> {
+    heap()
+        .region
+        .as_ref()
+        .map(|region| (region.base_addr(), region.base_addr() + region.len()))
+}
+
+/// Address of the first heap block, everything the bootloader allocated is between it and [`get_last_header`]
+pub fn get_heap_start() -> u32 {
+    get_first_header() as u32
+}
+
 pub fn get_last_header() -> u32 {
     let mut header = get_first_header();
     loop {
-        let header_v = unsafe { header.read_unaligned() };
+        let header_v = unsafe { load_block(header) };
         if header_v.next.is_null() {
             return header as u32;
         }
@@ -320,63 +648,281 @@ pub fn get_last_header() -> u32 {
     }
 }
 
-fn mem_alloc<T>(size: usize) -> Option<*mut T> {
-    let header_size = size_of::<MemoryBlock>();
+/// Byte freed heap memory is filled with, with the `heap-poison` feature
+const POISON_FREED: u8 = 0xDE;
+/// Byte new allocations are filled with before being handed out, with the `heap-poison` feature
+const POISON_ALLOCATED: u8 = 0xAA;
+/// Heap operations kept by the `heap-poison` log
+const HEAP_LOG_LEN: usize = 64;
+
+#[derive(Clone, Copy)]
+enum HeapOp {
+    Alloc,
+    Free,
+}
+
+#[derive(Clone, Copy)]
+struct HeapLogEntry {
+    op: HeapOp,
+    address: usize,
+    size: usize,
+    /// Subsystem that allocated the block, passed to [`Buffer::new_tagged`] or [`Vec::new_tagged`]
+    tag: &'static [u8],
+}
+
+struct HeapLog {
+    entries: [HeapLogEntry; HEAP_LOG_LEN],
+    /// Operations logged since boot, the next one goes to `count % HEAP_LOG_LEN`
+    count: usize,
+}
+
+/// Single threaded, only touched with the `heap-poison` feature
+static HEAP_LOG: SyncUnsafeCell<HeapLog> = SyncUnsafeCell::new(HeapLog {
+    entries: [HeapLogEntry {
+        op: HeapOp::Alloc,
+        address: 0,
+        size: 0,
+        tag: b"",
+    }; HEAP_LOG_LEN],
+    count: 0,
+});
+
+fn heap_log() -> &'static mut HeapLog {
+    unsafe { &mut *HEAP_LOG.get() }
+}
+
+fn log_heap_op(op: HeapOp, address: usize, size: usize, tag: &'static [u8]) {
+    let log = heap_log();
+    log.entries[log.count % HEAP_LOG_LEN] = HeapLogEntry {
+        op,
+        address,
+        size,
+        tag,
+    };
+    log.count += 1;
+}
+
+/// Tag of the latest logged allocation at `address`, `?` once it left the log
+fn logged_tag(address: usize) -> &'static [u8] {
+    let log = heap_log();
+    (log.count.saturating_sub(HEAP_LOG_LEN)..log.count)
+        .rev()
+        .map(|i| log.entries[i % HEAP_LOG_LEN])
+        .find(|entry| matches!(entry.op, HeapOp::Alloc) && entry.address == address)
+        .map_or(b"?", |entry| entry.tag)
+}
+
+/// Prints the last heap operations, oldest first. Does nothing without the `heap-poison` feature
+pub fn heap_dump_recent() {
+    if !cfg!(feature = "heap-poison") {
+        return;
+    }
+    let log = heap_log();
+    printf!(b"Recent heap operations (address, size, tag):\r\n");
+    for i in log.count.saturating_sub(HEAP_LOG_LEN)..log.count {
+        let entry = log.entries[i % HEAP_LOG_LEN];
+        printf!(match entry.op {
+            HeapOp::Alloc => b"    alloc ",
+            HeapOp::Free => b"    free  ",
+        });
+        printf!(b"0x%x 0x%x ", entry.address, entry.size);
+        write_string(entry.tag);
+        printf!(b"\r\n");
+    }
+}
+
+/// Poisons and logs the block `take_block` just returned, with the `heap-poison` feature
+fn handed_out<T>(ptr: *mut T, size: usize, tag: &'static [u8]) -> *mut T {
+    if cfg!(feature = "heap-poison") {
+        unsafe { (ptr as *mut u8).write_bytes(POISON_ALLOCATED, size) };
+        log_heap_op(HeapOp::Alloc, ptr as usize, size, tag);
+    }
+    ptr
+}
+
+pub(crate) fn mem_alloc<T>(size: usize, tag: &'static [u8]) -> Option<*mut T> {
+    // The panic path must not allocate, the heap may be exhausted or corrupt by then
+    if in_panic() {
+        double_panic();
+    }
     let mut header = get_first_header();
 
     loop {
-        let mut header_v = unsafe { header.read_unaligned() };
+        let header_v = unsafe { load_block(header) };
         if header_v.free != 0 && header_v.size >= size {
-            header_v.free = 0;
-            unsafe {
-                header.write_unaligned(header_v);
-            }
-            // Split the header
-            let header_end = (header as usize) + header_v.size;
-            let desired_end = (header as usize) + size + header_size;
-            let mut next_header = (desired_end & !(0x1000 - 1)) + 0x1000 - header_size;
-            while next_header <= desired_end {
-                next_header += 0x1000;
-            }
-            // Have a valid header address now
-            if next_header + header_size < header_end {
-                // Split
-                header_v.size = next_header - (header as usize) - header_size;
-                let next2_addr = header_v.next;
-                let new_header = MemoryBlock {
-                    free: 1,
-                    prev: header,
-                    next: next2_addr,
-                    size: header_end - next_header - header_size,
-                };
-                unsafe {
-                    (next_header as *mut MemoryBlock).write_unaligned(new_header);
-
-                    if !next2_addr.is_null() {
-                        let mut next2 = next2_addr.read_unaligned();
-                        next2.prev = next_header as *mut MemoryBlock;
-                        next2_addr.write_unaligned(next2);
-                    }
-
-                    header_v.next = next_header as *mut MemoryBlock;
-                    header.write_unaligned(header_v);
-                }
+            return Some(handed_out(take_block(header, size), size, tag));
+        }
+        if header_v.next.is_null() {
+            log_alloc_failure(size);
+            return None;
+        }
+        header = header_v.next;
+    }
+}
+
+/// Logged on every failed allocation, most callers only see a None
+fn log_alloc_failure(size: usize) {
+    printf!(
+        b"Heap: failed to allocate 0x%x bytes, 0x%x bytes free\r\n",
+        size,
+        get_mem_free()
+    );
+}
+
+/// Marks the free block `header` as used, splitting off the pages past `size` as a new free block
+fn take_block<T>(header: *mut MemoryBlock, size: usize) -> *mut T {
+    let header_size = size_of::<MemoryBlock>();
+    let mut header_v = unsafe { load_block(header) };
+    header_v.free = 0;
+    unsafe {
+        store_block(header, header_v);
+    }
+    // Split the header
+    let header_end = (header as usize) + header_v.size;
+    let desired_end = (header as usize) + size + header_size;
+    let mut next_header = (desired_end & !(0x1000 - 1)) + 0x1000 - header_size;
+    while next_header <= desired_end {
+        next_header += 0x1000;
+    }
+    // Have a valid header address now
+    if next_header + header_size < header_end {
+        // Split
+        header_v.size = next_header - (header as usize) - header_size;
+        let next2_addr = header_v.next;
+        let new_header = MemoryBlock {
+            magic: 0,
+            free: 1,
+            prev: header,
+            next: next2_addr,
+            size: header_end - next_header - header_size,
+            checksum: 0,
+        };
+        unsafe {
+            store_block(next_header as *mut MemoryBlock, new_header);
+
+            if !next2_addr.is_null() {
+                let mut next2 = load_block(next2_addr);
+                next2.prev = next_header as *mut MemoryBlock;
+                store_block(next2_addr, next2);
             }
-            // Else no split
-            unsafe {
-                MEM_USED += header_v.size + header_size;
+
+            header_v.next = next_header as *mut MemoryBlock;
+            store_block(header, header_v);
+        }
+    }
+    // Else no split
+    heap().used += header_v.size + header_size;
+    if cfg!(feature = "heap-check") {
+        heap_check().unwrap_or_else(|e| e.panic());
+    }
+    ((header as usize) + header_size) as *mut T
+}
+
+/// Like `mem_alloc`, but the returned address is a multiple of `align`, a power of two. <br>
+/// Every block already starts on a 4KiB boundary. For larger alignments, the free pages before the aligned address stay a free block of their own, so `mem_free` works on the result as usual. <br>
+fn mem_alloc_aligned<T>(size: usize, align: usize, tag: &'static [u8]) -> Option<*mut T> {
+    if !align.is_power_of_two() {
+        return None;
+    }
+    if align <= 0x1000 {
+        return mem_alloc(size, tag);
+    }
+    if in_panic() {
+        double_panic();
+    }
+    let header_size = size_of::<MemoryBlock>();
+    let mut header = get_first_header();
+
+    loop {
+        let header_v = unsafe { load_block(header) };
+        let data = header as usize + header_size;
+        let aligned = data.checked_add(align - 1)? & !(align - 1);
+        let end = data + header_v.size;
+        if header_v.free != 0 && aligned <= end && end - aligned >= size {
+            if aligned == data {
+                return Some(handed_out(take_block(header, size), size, tag));
             }
-            let ptr = ((header as usize) + header_size) as *mut T;
-            return Some(ptr);
+            let aligned_header = unsafe { split_free_block(header, aligned) };
+            return Some(handed_out(take_block(aligned_header, size), size, tag));
         }
         if header_v.next.is_null() {
+            log_alloc_failure(size);
             return None;
         }
         header = header_v.next;
     }
 }
 
-fn mem_free<T>(ptr: *mut T) {
+/// Splits the free block `header` so that a new free block's data starts at `at`, returns the new block's header. <br>
+/// Both addresses are 4KiB aligned, so the leading block keeps at least a page minus its header. <br>
+unsafe fn split_free_block(header: *mut MemoryBlock, at: usize) -> *mut MemoryBlock {
+    let header_size = size_of::<MemoryBlock>();
+    let header_v = load_block(header);
+    let data = header as usize + header_size;
+    let end = data + header_v.size;
+    let new_header = (at - header_size) as *mut MemoryBlock;
+    let next = header_v.next;
+    store_block(
+        new_header,
+        MemoryBlock {
+            magic: 0,
+            size: end - at,
+            free: 1,
+            prev: header,
+            next,
+            checksum: 0,
+        },
+    );
+    if !next.is_null() {
+        let mut next_v = load_block(next);
+        next_v.prev = new_header;
+        store_block(next, next_v);
+    }
+    store_block(
+        header,
+        MemoryBlock {
+            size: new_header as usize - data,
+            next: new_header,
+            ..header_v
+        },
+    );
+    new_header
+}
+
+/// Takes the free heap memory overlapping `[start, end)` out of the allocator, so nothing allocated later lands there. <br>
+/// Allocations already in the range are left alone, the caller moves whatever of them it still needs. Returns the bytes fenced. <br>
+pub fn fence_heap(start: u64, end: u64) -> usize {
+    let header_size = size_of::<MemoryBlock>();
+    let end = end.min(usize::MAX as u64) as usize;
+    let start = start.min(end as u64) as usize;
+    let mut fenced = 0;
+    let mut header = get_first_header();
+
+    loop {
+        let header_v = unsafe { load_block(header) };
+        let data = header as usize + header_size;
+        let block_end = data + header_v.size;
+        if header_v.free != 0 && data < end && start < block_end {
+            let fence_start = data.max(start & !(0x1000 - 1));
+            let fence_header = if fence_start > data {
+                unsafe { split_free_block(header, fence_start) }
+            } else {
+                header
+            };
+            let size = end.min(block_end) - fence_start;
+            handed_out(take_block::<u8>(fence_header, size), size, b"fence");
+            fenced += size;
+            header = fence_header;
+        }
+        let next = unsafe { load_block(header) }.next;
+        if next.is_null() {
+            return fenced;
+        }
+        header = next;
+    }
+}
+
+pub(crate) fn mem_free<T>(ptr: *mut T) {
     if ptr.is_null() {
         return;
     }
@@ -384,61 +930,74 @@ fn mem_free<T>(ptr: *mut T) {
     let header = ((ptr as usize) - header_size) as *mut MemoryBlock;
 
     let mut header_v = unsafe { header.read_unaligned() };
+    if !header_v.is_valid() {
+        HeapError::BadHeader(header as usize).panic();
+    }
+    if header_v.free != 0 {
+        HeapError::DoubleFree(ptr as usize).panic();
+    }
+    if cfg!(feature = "heap-poison") {
+        let size = header_v.size;
+        log_heap_op(HeapOp::Free, ptr as usize, size, logged_tag(ptr as usize));
+        unsafe { (ptr as *mut u8).write_bytes(POISON_FREED, size) };
+    }
     header_v.free = 1;
 
-    unsafe {
-        MEM_USED -= header_v.size + header_size;
-        header.write_unaligned(header_v);
-    };
+    heap().used -= header_v.size + header_size;
+    unsafe { store_block(header, header_v) };
 
     // Merge with next block if free
     if !header_v.next.is_null() {
         let next_header = header_v.next;
-        let next_header_v = unsafe { next_header.read_unaligned() };
+        let next_header_v = unsafe { load_block(next_header) };
         if next_header_v.free != 0 {
             // Update size
             header_v.size += next_header_v.size + header_size;
             header_v.next = next_header_v.next;
             // If there is a block after the one we've merged, make it's prev pointer point to us
             if !header_v.next.is_null() {
-                let mut next_v = unsafe { header_v.next.read_unaligned() };
+                let mut next_v = unsafe { load_block(header_v.next) };
                 next_v.prev = header;
                 // Save the data to the pointer
-                unsafe { header_v.next.write_unaligned(next_v) };
+                unsafe { store_block(header_v.next, next_v) };
             }
             // Save the data to the pointer
-            unsafe { header.write_unaligned(header_v) };
+            unsafe { store_block(header, header_v) };
         }
     }
 
     // Merge with previous block if free
     if !header_v.prev.is_null() {
         let prev_header = header_v.prev;
-        let mut prev_header_v = unsafe { prev_header.read_unaligned() };
+        let mut prev_header_v = unsafe { load_block(prev_header) };
         if prev_header_v.free != 0 {
             // Update prev's size, as we get deleted
             prev_header_v.size += header_v.size + header_size;
             prev_header_v.next = header_v.next;
             // If there's a block after us, make it's prev pointer point to the merged block
             if !header_v.next.is_null() {
-                let mut next_v = unsafe { header_v.next.read_unaligned() };
+                let mut next_v = unsafe { load_block(header_v.next) };
                 next_v.prev = prev_header;
                 // Save the data to the pointer
-                unsafe { header_v.next.write_unaligned(next_v) };
+                unsafe { store_block(header_v.next, next_v) };
             }
             // Save the data to the pointer
-            unsafe { prev_header.write_unaligned(prev_header_v) };
+            unsafe { store_block(prev_header, prev_header_v) };
         }
     }
+
+    if cfg!(feature = "heap-check") {
+        heap_check().unwrap_or_else(|e| e.panic());
+    }
 }
 
 /// # Safety
 /// ptr must be a pointer returned by malloc
-unsafe fn mem_realloc<T>(ptr: *mut T, size: usize) -> Result<*mut T, *mut T> {
+pub(crate) unsafe fn mem_realloc<T>(ptr: *mut T, size: usize) -> Result<*mut T, *mut T> {
     let header_size = size_of::<MemoryBlock>();
     let header = ((ptr as usize) - header_size) as *mut MemoryBlock;
 
-    let mut header_v = unsafe { header.read_unaligned() };
+    let mut header_v = unsafe { load_block(header) };
 
     // Case 1: The current block is already large enough to fit the requested size.
     if header_v.size >= size {
@@ -448,16 +1007,18 @@ unsafe fn mem_realloc<T>(ptr: *mut T, size: usize) -> Result<*mut T, *mut T> {
     // Case 2: Try to merge with the next free block if possible.
     if !header_v.next.is_null() {
         let next_header = header_v.next;
-        let next_header_v = unsafe { next_header.read_unaligned() };
+        let next_header_v = unsafe { load_block(next_header) };
         if next_header_v.free != 0 {
+            // Grown in place, the merged block is now in use
+            heap().used += next_header_v.size + header_size;
             header_v.size += next_header_v.size + header_size;
             header_v.next = next_header_v.next;
             if !header_v.next.is_null() {
-                let mut next_v = unsafe { header_v.next.read_unaligned() };
+                let mut next_v = unsafe { load_block(header_v.next) };
                 next_v.prev = header;
-                unsafe { header_v.next.write_unaligned(next_v) };
+                unsafe { store_block(header_v.next, next_v) };
             }
-            unsafe { header.write_unaligned(header_v) };
+            unsafe { store_block(header, header_v) };
         }
     }
 
@@ -467,7 +1028,12 @@ unsafe fn mem_realloc<T>(ptr: *mut T, size: usize) -> Result<*mut T, *mut T> {
     }
 
     // Case 4: Allocate new memory for the requested size.
-    let new_memory = mem_alloc::<T>(size).ok_or(ptr)?;
+    let tag = if cfg!(feature = "heap-poison") {
+        logged_tag(ptr as usize)
+    } else {
+        b""
+    };
+    let new_memory = mem_alloc::<T>(size, tag).ok_or(ptr)?;
     // Copy data from the old memory to the new memory.
     mem_cpy(new_memory, ptr, header_v.size);
     // Free the old memory.
@@ -489,8 +1055,9 @@ where
 {
     pub fn new(value: T) -> Option<Self> {
         unsafe {
-            let ptr = mem_alloc::<T>(size_of::<T>())?;
-            *ptr = value;
+            let ptr = mem_alloc::<T>(size_of::<T>(), b"box")?;
+            // The memory is uninitialized, assigning would drop garbage
+            ptr.write(value);
             Some(Self { ptr })
         }
     }
@@ -569,275 +1136,34 @@ where
     }
 }
 
-pub struct Vec<T>
-where
-    T: Sized,
-{
-    ptr: *mut T,
+pub struct Buffer {
+    ptr: *mut u8,
     len: usize,
-    cap: usize,
-}
-
-impl<T> Default for Vec<T>
-where
-    T: Sized,
-{
-    fn default() -> Self {
-        Self::new(16)
-    }
-}
-
-impl<T> Vec<T>
-where
-    T: Sized,
-{
-    #[inline(always)]
-    pub fn get_element_size_bytes() -> usize {
-        let raw_size = size_of::<T>();
-        let alignment = align_of::<T>();
-        if raw_size % alignment == 0 {
-            raw_size
-        } else {
-            raw_size + alignment - (raw_size % alignment)
-        }
-    }
-
-    pub fn new(capacity: usize) -> Self {
-        if capacity == 0 {
-            kpanic();
-        }
-        Self {
-            ptr: mem_alloc(capacity * Vec::<T>::get_element_size_bytes())
-                .unwrap_or_else(|| kpanic()),
-            len: 0,
-            cap: capacity,
-        }
-    }
-
-    /// # Safety
-    /// Creates a null pointer
-    pub const unsafe fn unsafe_null() -> Self {
-        Self {
-            ptr: ptr::null_mut(),
-            len: 0,
-            cap: 0,
-        }
-    }
-
-    pub fn ensure_capacity(&mut self, capacity: usize) {
-        if self.cap < capacity {
-            unsafe {
-                self.ptr = mem_realloc(self.ptr, capacity * Vec::<T>::get_element_size_bytes())
-                    .unwrap_or_else(|_| kpanic());
-            }
-        }
-    }
-
-    pub fn grow(&mut self, capacity: usize) {
-        if self.cap >= capacity {
-            return;
-        }
-        while self.cap < capacity {
-            self.cap *= 2;
-        }
-        unsafe {
-            self.ptr = mem_realloc(self.ptr, self.cap * Vec::<T>::get_element_size_bytes())
-                .unwrap_or_else(|_| kpanic());
-        }
-    }
-
-    #[inline(always)]
-    fn get_ptr_for_idx(&self, idx: usize) -> *mut T {
-        ((self.ptr as usize) + idx * Vec::<T>::get_element_size_bytes()) as *mut T
-    }
-
-    pub fn push(&mut self, value: T) {
-        self.grow(self.len + 1);
-        unsafe {
-            *self.get_ptr_for_idx(self.len) = value;
-        }
-        self.len += 1;
-    }
-
-    pub fn len(&self) -> usize {
-        self.len
-    }
-
-    pub fn is_empty(&self) -> bool {
-        self.len == 0
-    }
-
-    pub fn capacity(&self) -> usize {
-        self.cap
-    }
-
-    pub fn get(&self, index: usize) -> Option<&T> {
-        if index >= self.len {
-            return None;
-        }
-        unsafe { Some(&*self.get_ptr_for_idx(index)) }
-    }
-
-    pub fn pop(&mut self) -> Option<T> {
-        if self.is_empty() {
-            return None;
-        }
-
-        self.len -= 1;
-
-        unsafe {
-            let ptr = self.get_ptr_for_idx(self.len);
-            let value = ptr.read();
-            Some(value)
-        }
-    }
-
-    pub fn iter<'a>(&'a self) -> RefIterVec<'a, T> {
-        RefIterVec { vec: self, idx: 0 }
-    }
-
-    pub fn swap(&mut self, a: usize, b: usize) {
-        unsafe {
-            let ptr_a = self.get_ptr_for_idx(a);
-            let ptr_b = self.get_ptr_for_idx(b);
-            ptr::swap(ptr_a, ptr_b);
-        }
-    }
-
-    pub fn bubble_sort(&mut self, cmp: impl Fn(&T, &T) -> isize) {
-        for i in 0..self.len {
-            for j in 0..self.len - i - 1 {
-                let a = self.get(j).unwrap_or_else(|| kpanic());
-                let b = self.get(j + 1).unwrap_or_else(|| kpanic());
-                if cmp(a, b) > 0 {
-                    self.swap(j, j + 1);
-                }
-            }
-        }
-    }
-
-    pub fn insert(&mut self, index: usize, value: T) -> bool {
-        if index > self.len {
-            false
-        } else if index == self.len {
-            self.push(value);
-            true
-        } else {
-            self.grow(self.len + 1);
-
-            // Shift elements to the right
-            for i in (index..self.len).rev() {
-                unsafe {
-                    ptr::copy_nonoverlapping(
-                        self.get_ptr_for_idx(i),
-                        self.get_ptr_for_idx(i + 1),
-                        1,
-                    );
-                }
-            }
-
-            unsafe {
-                *self.get_ptr_for_idx(index) = value;
-            }
-            self.len += 1;
-
-            true
-        }
-    }
-}
-
-impl<T> Clone for Vec<T>
-where
-    T: Clone,
-{
-    fn clone(&self) -> Self {
-        let mut other = Vec::new(self.len);
-        for i in 0..self.len {
-            other.push(self.get(i).unwrap_or_else(|| kpanic()).clone());
-        }
-        other
-    }
-}
-
-impl<T> Drop for Vec<T>
-where
-    T: Sized,
-{
-    fn drop(&mut self) {
-        if self.ptr.is_null() {
-            return;
-        }
-        while self.pop().is_some() {}
-        mem_free(self.ptr);
-    }
-}
-
-pub struct RefIterVec<'a, T>
-where
-    T: Sized,
-{
-    vec: &'a Vec<T>,
-    idx: usize,
+    owns_data: bool,
 }
 
-impl<'a, T> Iterator for RefIterVec<'a, T>
-where
-    T: Sized,
-{
-    type Item = &'a T;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let res = self.vec.get(self.idx)?;
-        self.idx += 1;
-        Some(res)
+impl Buffer {
+    pub fn new(len: usize) -> Option<Self> {
+        Self::new_tagged(len, b"buffer")
     }
-}
-
-pub struct IterVec<T>
-where
-    T: Sized,
-{
-    vec: Vec<T>,
-    idx: usize,
-}
-
-impl<T> Iterator for IterVec<T>
-where
-    T: Sized,
-{
-    type Item = T;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.idx >= self.vec.len {
-            None
-        } else {
-            self.idx += 1;
-            Some(unsafe { self.vec.ptr.add(self.idx - 1).read_unaligned() })
-        }
+    /// Like [`Buffer::new`], `tag` names the subsystem in the `heap-poison` log
+    pub fn new_tagged(len: usize, tag: &'static [u8]) -> Option<Self> {
+        let ptr = mem_alloc(len, tag)?;
+        Some(Self {
+            ptr,
+            len,
+            owns_data: true,
+        })
     }
-}
-
-impl<T> IntoIterator for Vec<T>
-where
-    T: Sized,
-{
-    type Item = T;
-    type IntoIter = IterVec<T>;
 
-    fn into_iter(self) -> Self::IntoIter {
-        IterVec { vec: self, idx: 0 }
+    /// A buffer starting at a multiple of `align`, a power of two. Every buffer is at least 4KiB aligned.
+    pub fn new_aligned(len: usize, align: usize) -> Option<Self> {
+        Self::new_aligned_tagged(len, align, b"buffer")
     }
-}
-
-pub struct Buffer {
-    ptr: *mut u8,
-    len: usize,
-    owns_data: bool,
-}
 
-impl Buffer {
-    pub fn new(len: usize) -> Option<Self> {
-        let ptr = mem_alloc(len)?;
+    fn new_aligned_tagged(len: usize, align: usize, tag: &'static [u8]) -> Option<Self> {
+        let ptr = mem_alloc_aligned(len, align, tag)?;
         Some(Self {
             ptr,
             len,
@@ -845,6 +1171,20 @@ impl Buffer {
         })
     }
 
+    /// Allocates a buffer that will be handed to the kernel, zero-filled when `scrub` is set so no stale bootloader data leaks through
+    pub fn new_handoff(len: usize, scrub: bool) -> Option<Self> {
+        Self::new_handoff_aligned(len, 0x1000, scrub)
+    }
+
+    /// Like [`Buffer::new_handoff`], starting at a multiple of `align`
+    pub fn new_handoff_aligned(len: usize, align: usize, scrub: bool) -> Option<Self> {
+        let buffer = Self::new_aligned_tagged(len, align, b"handoff")?;
+        if scrub {
+            unsafe { self::scrub(buffer.ptr as usize, len) };
+        }
+        Some(buffer)
+    }
+
     pub const fn null() -> Self {
         Self {
             ptr: ptr::null_mut(),
@@ -963,11 +1303,18 @@ impl Drop for Buffer {
     }
 }
 
+impl Buffer {
+    /// Like [`Buffer::clone`], but returns None instead of panicking when the allocation fails
+    pub fn try_clone(&self) -> Option<Self> {
+        let mut other = Buffer::new(self.len)?;
+        self.copy_to(0, &mut other, 0, self.len);
+        Some(other)
+    }
+}
+
 impl Clone for Buffer {
     fn clone(&self) -> Self {
-        let mut other = Buffer::new(self.len).unwrap_or_else(|| kpanic());
-        self.copy_to(0, &mut other, 0, self.len);
-        other
+        self.try_clone().unwrap_or_else(|| kpanic())
     }
 }
 
@@ -0,0 +1,456 @@
+/// The heap is placed above the bootloader and the BIOS data
+pub const HEAP_MIN_ADDRESS: u64 = 0x10_0000;
+/// Stage2 runs without paging, the heap must end below 4GiB
+pub const HEAP_MAX_ADDRESS: u64 = 0x1_0000_0000;
+
+#[derive(Copy, Clone)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: MemoryRegionType,
+}
+
+/// Ordered from the least to the most restrictive
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(test, derive(Debug))]
+pub enum MemoryRegionType {
+    Usable,
+    /// The stage2 image, its stack and the BIOS bounce buffers
+    BootloaderReclaimable,
+    Reserved,
+}
+
+impl MemoryRegionType {
+    pub fn strictest(&self, other: &MemoryRegionType) -> MemoryRegionType {
+        (*self).max(*other)
+    }
+}
+
+/// Growable list the layout functions build their output in, stage2 implements it over its heap `Vec`. <br>
+/// Nothing in this module calls the BIOS, prints or touches a static, callers bring the memory map and the list storage. <br>
+pub trait RegionList: Sized {
+    fn with_capacity(capacity: usize) -> Self;
+    fn regions(&self) -> &[MemoryRegion];
+    fn push(&mut self, region: MemoryRegion);
+    /// `index` is at most the length
+    fn insert(&mut self, index: usize, region: MemoryRegion);
+    /// `index` is less than the length
+    fn remove(&mut self, index: usize);
+    fn sort_by_start(&mut self);
+}
+
+/// Splits the regions of `layout` where they overlap the ones before them, the overlapping part takes the strictest kind. <br>
+/// Returns the new list and whether any overlap was found, a single pass may leave overlaps when regions are nested. <br>
+pub fn overlapping_pass<L: RegionList>(layout: &[MemoryRegion]) -> (L, bool) {
+    let mut had_overlap = false;
+    let mut fixed_layout = L::with_capacity(layout.len());
+    for current in layout.iter().copied() {
+        let mut i = 0;
+        let mut split = false;
+        while i < fixed_layout.regions().len() {
+            let existing = fixed_layout.regions()[i];
+
+            if current.end <= existing.start || current.start >= existing.end {
+                i += 1;
+                continue;
+            }
+
+            had_overlap = true;
+            split = true;
+
+            // Overlap detected, replace the existing region with the parts of both
+            fixed_layout.remove(i);
+            let (first, second) = if existing.start <= current.start {
+                (existing, current)
+            } else {
+                (current, existing)
+            };
+            let (shorter, longer) = if existing.end <= current.end {
+                (existing, current)
+            } else {
+                (current, existing)
+            };
+
+            // Break into three parts: left, overlap, right
+            if first.start < second.start {
+                fixed_layout.insert(
+                    i,
+                    MemoryRegion {
+                        start: first.start,
+                        end: second.start,
+                        kind: first.kind,
+                    },
+                );
+                i += 1;
+            }
+
+            fixed_layout.insert(
+                i,
+                MemoryRegion {
+                    start: second.start,
+                    end: shorter.end,
+                    kind: current.kind.strictest(&existing.kind), // overlap = reserved wins
+                },
+            );
+            i += 1;
+
+            if shorter.end < longer.end {
+                fixed_layout.insert(
+                    i,
+                    MemoryRegion {
+                        start: shorter.end,
+                        end: longer.end,
+                        kind: longer.kind,
+                    },
+                );
+            }
+
+            break;
+        }
+
+        if !split {
+            fixed_layout.push(current);
+        }
+    }
+
+    (fixed_layout, had_overlap)
+}
+
+/// Sorts `layout` and makes it non overlapping, the strictest kind wins where regions overlap. <br>
+/// Empty regions are dropped and neighbours of the same kind merged. <br>
+pub fn normalize<L: RegionList>(mut layout: L) -> L {
+    layout.sort_by_start();
+
+    let ok_layout = loop {
+        let (new_layout, had_overlap) = overlapping_pass::<L>(layout.regions());
+        if !had_overlap {
+            break new_layout;
+        }
+        layout = new_layout;
+    };
+
+    let mut done_layout = L::with_capacity(ok_layout.regions().len());
+
+    let mut last_region: Option<MemoryRegion> = None;
+
+    for region in ok_layout.regions().iter().copied() {
+        if region.start >= region.end {
+            continue;
+        }
+        match last_region.as_mut() {
+            Some(last) if last.kind == region.kind && last.end == region.start => {
+                last.end = region.end;
+            }
+            Some(last) => {
+                done_layout.push(*last);
+                last_region = Some(region);
+            }
+            None => last_region = Some(region),
+        }
+    }
+
+    if let Some(last) = last_region {
+        done_layout.push(last);
+    }
+    done_layout
+}
+
+/// Bytes in the usable regions of `layout`
+pub fn usable_bytes(layout: &[MemoryRegion]) -> u64 {
+    layout
+        .iter()
+        .filter(|r| r.kind == MemoryRegionType::Usable)
+        .map(|r| r.end - r.start)
+        .sum()
+}
+
+/// `(start, end)` of the part of available memory at `base` the heap could use, None when it has nothing between 1MiB and 4GiB
+pub fn heap_portion(base: u64, len: u64) -> Option<(u64, u64)> {
+    let start = base.max(HEAP_MIN_ADDRESS);
+    let end = base.saturating_add(len).min(HEAP_MAX_ADDRESS);
+    (start < end).then_some((start, end))
+}
+
+/// Picks the memory map entry the heap goes in, the one with the largest [`heap_portion`]. <br>
+/// Entries are offered one at a time, in memory map order, the first one wins ties. <br>
+pub struct HeapRegionSelector {
+    /// Index of the best entry so far, in offering order
+    best: Option<usize>,
+    best_len: u64,
+    offered: usize,
+}
+
+impl HeapRegionSelector {
+    pub const fn new() -> Self {
+        Self {
+            best: None,
+            best_len: 0,
+            offered: 0,
+        }
+    }
+
+    /// Considers the next entry, given by its heap portion, returns whether it's the best one so far
+    pub fn offer(&mut self, portion: Option<(u64, u64)>) -> bool {
+        let index = self.offered;
+        self.offered += 1;
+        let len = portion.map_or(0, |(start, end)| end - start);
+        if len <= self.best_len {
+            return false;
+        }
+        self.best = Some(index);
+        self.best_len = len;
+        true
+    }
+
+    pub fn best(&self) -> Option<usize> {
+        self.best
+    }
+}
+
+impl Default for HeapRegionSelector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MemoryRegionType::*;
+
+    /// Host list over the std `Vec`, stage2 uses its heap `Vec` instead
+    impl RegionList for Vec<MemoryRegion> {
+        fn with_capacity(capacity: usize) -> Self {
+            Vec::with_capacity(capacity)
+        }
+
+        fn regions(&self) -> &[MemoryRegion] {
+            self
+        }
+
+        fn push(&mut self, region: MemoryRegion) {
+            Vec::push(self, region);
+        }
+
+        fn insert(&mut self, index: usize, region: MemoryRegion) {
+            Vec::insert(self, index, region);
+        }
+
+        fn remove(&mut self, index: usize) {
+            Vec::remove(self, index);
+        }
+
+        fn sort_by_start(&mut self) {
+            self.sort_by_key(|region| region.start);
+        }
+    }
+
+    fn layout(regions: &[(u64, u64, MemoryRegionType)]) -> Vec<MemoryRegion> {
+        regions
+            .iter()
+            .map(|&(start, end, kind)| MemoryRegion { start, end, kind })
+            .collect()
+    }
+
+    fn normalized(regions: &[(u64, u64, MemoryRegionType)]) -> Vec<(u64, u64, MemoryRegionType)> {
+        normalize(layout(regions))
+            .iter()
+            .map(|region| (region.start, region.end, region.kind))
+            .collect()
+    }
+
+    #[test]
+    fn reserved_splits_the_usable_region_it_overlaps() {
+        let result = normalized(&[(0x0, 0x10_0000, Usable), (0x9_F000, 0xA_0000, Reserved)]);
+        assert_eq!(
+            result,
+            [
+                (0x0, 0x9_F000, Usable),
+                (0x9_F000, 0xA_0000, Reserved),
+                (0xA_0000, 0x10_0000, Usable),
+            ]
+        );
+    }
+
+    #[test]
+    fn strictest_kind_wins_where_regions_overlap() {
+        let result = normalized(&[
+            (0x10_0000, 0x20_0000, Usable),
+            (0x18_0000, 0x28_0000, Reserved),
+            (0x1C_0000, 0x1D_0000, BootloaderReclaimable),
+            (0x8000, 0x1_0000, BootloaderReclaimable),
+            (0x0, 0x9_F000, Usable),
+        ]);
+        assert_eq!(
+            result,
+            [
+                (0x0, 0x8000, Usable),
+                (0x8000, 0x1_0000, BootloaderReclaimable),
+                (0x1_0000, 0x9_F000, Usable),
+                (0x10_0000, 0x18_0000, Usable),
+                (0x18_0000, 0x28_0000, Reserved),
+            ]
+        );
+    }
+
+    #[test]
+    fn reserved_crossing_4gib_splits_the_usable_region_above() {
+        let result = normalized(&[
+            (0x1_0000_0000, 0x2_0000_0000, Usable),
+            (0xC000_0000, 0x1_0010_0000, Reserved),
+        ]);
+        assert_eq!(
+            result,
+            [
+                (0xC000_0000, 0x1_0010_0000, Reserved),
+                (0x1_0010_0000, 0x2_0000_0000, Usable),
+            ]
+        );
+    }
+
+    #[test]
+    fn regions_above_4gib_keep_their_addresses() {
+        let result = normalized(&[
+            (0xFF_0000_0000, 0xFF_0010_0000, Usable),
+            (0x1_0000_0000, 0x2_0000_0000, Usable),
+            (0x0, 0x9_F000, Usable),
+        ]);
+        assert_eq!(
+            result,
+            [
+                (0x0, 0x9_F000, Usable),
+                (0x1_0000_0000, 0x2_0000_0000, Usable),
+                (0xFF_0000_0000, 0xFF_0010_0000, Usable),
+            ]
+        );
+        assert_eq!(
+            usable_bytes(&layout(&result)),
+            0x9_F000 + 0x1_0000_0000 + 0x10_0000
+        );
+    }
+
+    #[test]
+    fn adjacent_regions_of_the_same_kind_are_merged() {
+        let result = normalized(&[
+            (0x0, 0x8000, Usable),
+            (0x8000, 0x9_F000, Usable),
+            (0x9_F000, 0xA_0000, Reserved),
+            (0x10_0000, 0x20_0000, Usable),
+            (0x20_0000, 0x30_0000, Usable),
+            (0x30_0000, 0x40_0000, Usable),
+        ]);
+        assert_eq!(
+            result,
+            [
+                (0x0, 0x9_F000, Usable),
+                (0x9_F000, 0xA_0000, Reserved),
+                (0x10_0000, 0x40_0000, Usable),
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_length_regions_are_dropped() {
+        let result = normalized(&[
+            (0x0, 0x0, Reserved),
+            (0x0, 0x9_F000, Usable),
+            (0x5000, 0x5000, Reserved),
+            (0x9_F000, 0x9_F000, Usable),
+            (0x10_0000, 0x20_0000, Usable),
+        ]);
+        assert_eq!(
+            result,
+            [(0x0, 0x9_F000, Usable), (0x10_0000, 0x20_0000, Usable)]
+        );
+    }
+
+    #[test]
+    fn more_than_64_entries_are_sorted_and_kept() {
+        let mut regions = Vec::new();
+        for i in (0..100u64).rev() {
+            let kind = if i.is_multiple_of(2) {
+                Usable
+            } else {
+                Reserved
+            };
+            regions.push((i * 0x1000, (i + 1) * 0x1000, kind));
+        }
+        let result = normalized(&regions);
+        assert_eq!(result.len(), 100);
+        for (i, &(start, end, kind)) in result.iter().enumerate() {
+            let i = i as u64;
+            assert_eq!((start, end), (i * 0x1000, (i + 1) * 0x1000));
+            assert_eq!(
+                kind,
+                if i.is_multiple_of(2) {
+                    Usable
+                } else {
+                    Reserved
+                }
+            );
+        }
+    }
+
+    /// Offers the `(base, length)` entries in order, returns the index picked for the heap
+    fn select_heap(entries: &[(u64, u64)]) -> Option<usize> {
+        let mut selector = HeapRegionSelector::new();
+        for &(base, len) in entries {
+            selector.offer(heap_portion(base, len));
+        }
+        selector.best()
+    }
+
+    #[test]
+    fn heap_portion_skips_memory_below_1mib() {
+        assert_eq!(heap_portion(0x0, 0x9_F000), None);
+        assert_eq!(heap_portion(0x0, 0x10_0000), None);
+        assert_eq!(
+            heap_portion(0x8_0000, 0x10_0000),
+            Some((0x10_0000, 0x18_0000))
+        );
+    }
+
+    #[test]
+    fn heap_portion_stops_at_4gib() {
+        assert_eq!(
+            heap_portion(0xC000_0000, 0x8000_0000),
+            Some((0xC000_0000, 0x1_0000_0000))
+        );
+        assert_eq!(heap_portion(0x1_0000_0000, 0x1_0000_0000), None);
+        assert_eq!(heap_portion(u64::MAX - 0xFFF, 0x1000), None);
+    }
+
+    #[test]
+    fn heap_goes_in_the_largest_portion_below_4gib() {
+        // The entry crossing 4GiB only counts for its 1GiB below it
+        let entries = [
+            (0x0, 0x9_FC00),
+            (0x10_0000, 0x4000_1000),
+            (0x1_0000_0000, 0x4_0000_0000),
+            (0xC000_0000, 0x8000_0000),
+        ];
+        assert_eq!(select_heap(&entries), Some(1));
+        assert_eq!(select_heap(&entries[2..]), Some(1));
+    }
+
+    #[test]
+    fn heap_ties_go_to_the_first_entry() {
+        let entries = [
+            (0x10_0000, 0x10_0000),
+            (0x40_0000, 0x20_0000),
+            (0x80_0000, 0x20_0000),
+        ];
+        assert_eq!(select_heap(&entries), Some(1));
+    }
+
+    #[test]
+    fn no_heap_without_memory_between_1mib_and_4gib() {
+        assert_eq!(select_heap(&[]), None);
+        let entries = [
+            (0x0, 0x9_FC00),
+            (0x1_0000_0000, 0x1_0000_0000),
+            (0x20_0000, 0),
+        ];
+        assert_eq!(select_heap(&entries), None);
+    }
+}
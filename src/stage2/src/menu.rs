@@ -0,0 +1,193 @@
+use crate::{
+    bios::{bios_ticks, poll_keystroke, ticks_since, wait_us},
+    e9::write_string,
+    fs::Ext2FileSystem,
+    lang::{hex, render, Text},
+    obsiboot::ObsiBootConfig,
+    printf,
+    reload::{reload_config, LoadedConfig},
+    scrollback::{handle_scroll_key, scroll_to_live},
+    video::{Character, Color, Compositor, VgaDisplay, Video},
+};
+
+/// Scan codes of the navigation keys (INT 16h, AH=00h)
+const SCAN_UP: u8 = 0x48;
+const SCAN_DOWN: u8 = 0x50;
+const SCAN_HOME: u8 = 0x47;
+const SCAN_END: u8 = 0x4F;
+/// Keyboard polling period of the menu, in microseconds
+const POLL_PERIOD_US: u32 = 50_000;
+/// Width of the highlighted entry bar, longer names are cut
+const MENU_WIDTH: usize = 60;
+const FIRST_ENTRY_ROW: usize = 4;
+
+const NORMAL: u8 = Color::color(Color::Gray, Color::Black);
+const SELECTED: u8 = Color::color(Color::Black, Color::Gray);
+const TITLE: u8 = Color::color(Color::White, Color::Black);
+
+/// What ended the menu, with the entry highlighted at that point
+enum MenuAction {
+    Boot(usize),
+    Reload(usize),
+}
+
+/// BIOS timer ticks in `seconds`, at 18.2 ticks per second
+fn seconds_to_ticks(seconds: u32) -> u32 {
+    seconds.saturating_mul(182) / 10
+}
+
+fn draw(
+    compositor: &mut Compositor<VgaDisplay>,
+    config: &ObsiBootConfig,
+    selected: usize,
+    remaining_seconds: Option<u32>,
+) {
+    let (columns, rows) = compositor.size();
+    compositor.fill(Character {
+        character: b' ',
+        color: NORMAL,
+    });
+    let title = render(Text::MenuTitle, &[]);
+    compositor.write_at(columns.saturating_sub(title.len()) / 2, 1, &title, TITLE);
+
+    let left = columns.saturating_sub(MENU_WIDTH) / 2;
+    for (i, entry) in config.boot_entries().enumerate() {
+        let color = if i == selected { SELECTED } else { NORMAL };
+        let row = FIRST_ENTRY_ROW + i;
+        for x in 0..MENU_WIDTH {
+            compositor.put(
+                left + x,
+                row,
+                Character {
+                    character: b' ',
+                    color,
+                },
+            );
+        }
+        let name = &entry.name[..entry.name.len().min(MENU_WIDTH - 2)];
+        compositor.write_at(left + 1, row, name, color);
+    }
+
+    compositor.write_at(left, rows - 3, &render(Text::MenuHelp, &[]), NORMAL);
+    if let Some(seconds) = remaining_seconds {
+        compositor.write_at(
+            left,
+            rows - 2,
+            &render(Text::MenuTimeout, &[&hex(seconds)]),
+            NORMAL,
+        );
+    }
+    compositor.present();
+}
+
+/// Shows the entries until one is picked with Enter, or `timeout` seconds without a keypress pick `default`. <br>
+/// Any key stops the countdown, R leaves the menu to reload the config. Boots `default` when the screen grids can't be allocated. <br>
+fn run_menu(
+    bios_idt: usize,
+    config: &ObsiBootConfig,
+    count: usize,
+    default: usize,
+    timeout: u32,
+) -> MenuAction {
+    let Some(mut compositor) = Compositor::begin(VgaDisplay::new()) else {
+        printf!(b"Boot menu: not enough memory for the screen, booting the default entry\r\n");
+        return MenuAction::Boot(default);
+    };
+    let timeout_ticks = seconds_to_ticks(timeout);
+    let start = bios_ticks(bios_idt);
+    let mut selected = default;
+    let mut counting = timeout != 0;
+    let action = loop {
+        let remaining = if counting {
+            let elapsed = ticks_since(bios_idt, start);
+            if elapsed >= timeout_ticks {
+                printf!(b"Boot menu: timed out\r\n");
+                break MenuAction::Boot(selected);
+            }
+            Some(((timeout_ticks - elapsed) * 10).div_ceil(182))
+        } else {
+            None
+        };
+        draw(&mut compositor, config, selected, remaining);
+
+        let Some(key) = poll_keystroke(bios_idt) else {
+            wait_us(bios_idt, POLL_PERIOD_US);
+            continue;
+        };
+        counting = false;
+        if handle_scroll_key(key) {
+            continue;
+        }
+        if key.ascii == b'\r' {
+            break MenuAction::Boot(selected);
+        }
+        if key.ascii.eq_ignore_ascii_case(&b'r') {
+            break MenuAction::Reload(selected);
+        }
+        match key.scan_code {
+            SCAN_UP => selected = selected.checked_sub(1).unwrap_or(count - 1),
+            SCAN_DOWN => selected = (selected + 1) % count,
+            SCAN_HOME => selected = 0,
+            SCAN_END => selected = count - 1,
+            _ => {}
+        }
+    };
+
+    scroll_to_live();
+    compositor.fill(Character {
+        character: b' ',
+        color: NORMAL,
+    });
+    compositor.end();
+    unsafe { Video::get().clear() };
+    action
+}
+
+/// Picks the `entry=` block to boot, through the boot menu unless `timeout=0`. <br>
+/// A reload from the menu swaps `loaded` for the config on disk and shows the menu again, without the countdown. <br>
+/// Returns the index of the entry in `loaded.config`, None when the config has no entry, the default kernel is then booted. <br>
+pub fn select_boot_entry(
+    bios_idt: usize,
+    ext2: &mut Ext2FileSystem,
+    loaded: &mut LoadedConfig,
+) -> Option<usize> {
+    let mut count = loaded.config.boot_entries().count();
+    if count == 0 {
+        return None;
+    }
+    let mut default = loaded.config.default_entry.min(count - 1);
+    let mut timeout = loaded.config.menu_timeout;
+    let selected = if timeout == 0 {
+        printf!(b"Boot menu skipped (timeout=0)\r\n");
+        default
+    } else {
+        printf!(
+            b"Boot menu: 0x%x entries, default 0x%x, timeout 0x%x s\r\n",
+            count,
+            default,
+            timeout as usize
+        );
+        loop {
+            match run_menu(bios_idt, &loaded.config, count, default, timeout) {
+                MenuAction::Boot(selected) => break selected,
+                MenuAction::Reload(selected) => {
+                    reload_config(ext2, loaded);
+                    count = loaded.config.boot_entries().count();
+                    if count == 0 {
+                        printf!(b"Boot menu: the reloaded config has no entry\r\n");
+                        return None;
+                    }
+                    default = selected.min(count - 1);
+                    timeout = 0;
+                }
+            }
+        }
+    };
+    let entry = loaded.config.boot_entries().nth(selected)?;
+    printf!(b"Booting entry 0x%x: ", selected);
+    write_string(&entry.name);
+    printf!(b" (");
+    write_string(entry.kernel_path());
+    printf!(b")\r\n");
+    Some(selected)
+}
@@ -0,0 +1,510 @@
+use crate::{
+    elf::{load_elf, ElfError, ElfFileFlavour, SEGMENT_TYPE_LOAD},
+    fs::{Ext2Error, Ext2File},
+    kpanic,
+    mem::{Buffer, Vec},
+    paging::PhysicalSegment,
+    printf,
+    vesa::FramebufferInfo,
+    video::Video,
+};
+
+pub const HEADER_MAGIC: u32 = 0xE852_50D6;
+/// Value of `eax` when the kernel is entered
+pub const BOOTLOADER_MAGIC: u32 = 0x36D7_6289;
+/// The header must start in the first 32KiB of the image, on an 8 byte boundary
+const HEADER_SEARCH_LIMIT: usize = 32 * 1024;
+const ARCHITECTURE_I386: u32 = 0;
+
+const HEADER_TAG_END: u16 = 0;
+const HEADER_TAG_INFORMATION_REQUEST: u16 = 1;
+const HEADER_TAG_ADDRESS: u16 = 2;
+const HEADER_TAG_ENTRY_ADDRESS: u16 = 3;
+const HEADER_TAG_CONSOLE_FLAGS: u16 = 4;
+const HEADER_TAG_FRAMEBUFFER: u16 = 5;
+const HEADER_TAG_MODULE_ALIGN: u16 = 6;
+/// Header tag flag, the kernel boots without the tag being honored
+const HEADER_TAG_OPTIONAL: u16 = 1;
+
+const INFO_TAG_END: u32 = 0;
+const INFO_TAG_CMDLINE: u32 = 1;
+const INFO_TAG_BOOTLOADER_NAME: u32 = 2;
+const INFO_TAG_MODULE: u32 = 3;
+const INFO_TAG_BASIC_MEMINFO: u32 = 4;
+const INFO_TAG_MEMORY_MAP: u32 = 6;
+const INFO_TAG_FRAMEBUFFER: u32 = 8;
+/// Information tags a kernel may require in its information request
+const PROVIDED_INFO_TAGS: [u32; 6] = [
+    INFO_TAG_CMDLINE,
+    INFO_TAG_BOOTLOADER_NAME,
+    INFO_TAG_MODULE,
+    INFO_TAG_BASIC_MEMINFO,
+    INFO_TAG_MEMORY_MAP,
+    INFO_TAG_FRAMEBUFFER,
+];
+
+const MEMORY_AVAILABLE: u32 = 1;
+const MEMORY_RESERVED: u32 = 2;
+const FRAMEBUFFER_TYPE_RGB: u8 = 1;
+
+/// Where to load a kernel that isn't loaded from its ELF program headers
+#[derive(Clone, Copy)]
+pub struct AddressTag {
+    /// Physical address of the header magic
+    pub header_addr: u32,
+    pub load_addr: u32,
+    /// 0 to load up to the end of the file
+    pub load_end_addr: u32,
+    /// 0 when there is no bss
+    pub bss_end_addr: u32,
+}
+
+pub struct Multiboot2Header {
+    /// Offset of the header magic in the file
+    pub offset: usize,
+    pub address: Option<AddressTag>,
+    pub entry: Option<u32>,
+}
+
+pub enum Multiboot2Error {
+    NoHeader,
+    /// The header at this offset has a bad checksum
+    BadChecksum(usize),
+    UnsupportedArchitecture(u32),
+    /// The header tags run past the header
+    TruncatedHeader,
+    /// A header tag that isn't optional and isn't supported
+    UnsupportedHeaderTag(u16),
+    /// An information tag that isn't optional and isn't provided
+    UnsupportedInformationRequest(u32),
+    /// The address tag doesn't fit the file
+    BadAddressTag,
+    /// An address tag without an entry address tag
+    MissingEntryAddress,
+    /// The entry point of an ELF64 image is above 4GiB, out of reach of protected mode
+    EntryAbove4GiB(u64),
+    Ext2Error(Ext2Error),
+    ElfError(ElfError),
+}
+
+impl Multiboot2Error {
+    pub fn printf(&self) {
+        match self {
+            Multiboot2Error::NoHeader => printf!(
+                b"no Multiboot2 header in the first 0x%x bytes",
+                HEADER_SEARCH_LIMIT
+            ),
+            Multiboot2Error::BadChecksum(offset) => {
+                printf!(b"bad header checksum at offset 0x%x", *offset)
+            }
+            Multiboot2Error::UnsupportedArchitecture(arch) => {
+                printf!(b"unsupported architecture 0x%x", *arch)
+            }
+            Multiboot2Error::TruncatedHeader => printf!(b"header tags run past the header"),
+            Multiboot2Error::UnsupportedHeaderTag(tag) => {
+                printf!(b"unsupported required header tag 0x%x", *tag as u32)
+            }
+            Multiboot2Error::UnsupportedInformationRequest(tag) => {
+                printf!(b"unsupported required information tag 0x%x", *tag)
+            }
+            Multiboot2Error::BadAddressTag => printf!(b"the address tag doesn't fit the file"),
+            Multiboot2Error::MissingEntryAddress => {
+                printf!(b"address tag without an entry address tag")
+            }
+            Multiboot2Error::EntryAbove4GiB(entry) => printf!(
+                b"entry point 0x%x%x is above 4GiB",
+                (*entry >> 32) as u32,
+                *entry as u32
+            ),
+            Multiboot2Error::Ext2Error(_) => printf!(b"failed to read the kernel"),
+            Multiboot2Error::ElfError(_) => printf!(b"invalid ELF image"),
+        }
+    }
+
+    pub fn panic(&self) -> ! {
+        match self {
+            Multiboot2Error::Ext2Error(e) => e.panic(),
+            Multiboot2Error::ElfError(e) => e.panic(),
+            _ => {
+                printf!(b"Multiboot2: ");
+                self.printf();
+                printf!(b"\r\n");
+                unsafe {
+                    Video::get().write_string(b"Invalid Multiboot2 kernel\n");
+                }
+                kpanic();
+            }
+        }
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    let b = bytes.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    let b = bytes.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Finds and parses the Multiboot2 header of the kernel image
+pub fn find_header(file: &mut Ext2File) -> Result<Multiboot2Header, Multiboot2Error> {
+    let len = file.get_size().min(HEADER_SEARCH_LIMIT);
+    if len == 0 {
+        return Err(Multiboot2Error::NoHeader);
+    }
+    let mut buffer = Buffer::new_tagged(len, b"multiboot2")
+        .ok_or(Multiboot2Error::Ext2Error(Ext2Error::FailedMemAlloc(len)))?;
+    file.seek(0).map_err(Multiboot2Error::Ext2Error)?;
+    let read = file
+        .read(&mut buffer, len)
+        .map_err(Multiboot2Error::Ext2Error)?;
+    let bytes = &buffer[..read];
+
+    let offset = (0..read)
+        .step_by(8)
+        .find(|offset| u32_at(bytes, *offset) == Some(HEADER_MAGIC))
+        .ok_or(Multiboot2Error::NoHeader)?;
+    let architecture = u32_at(bytes, offset + 4).ok_or(Multiboot2Error::TruncatedHeader)?;
+    let header_length = u32_at(bytes, offset + 8).ok_or(Multiboot2Error::TruncatedHeader)?;
+    let checksum = u32_at(bytes, offset + 12).ok_or(Multiboot2Error::TruncatedHeader)?;
+    if HEADER_MAGIC
+        .wrapping_add(architecture)
+        .wrapping_add(header_length)
+        .wrapping_add(checksum)
+        != 0
+    {
+        return Err(Multiboot2Error::BadChecksum(offset));
+    }
+    if architecture != ARCHITECTURE_I386 {
+        return Err(Multiboot2Error::UnsupportedArchitecture(architecture));
+    }
+    printf!(
+        b"Multiboot2 header at offset 0x%x, 0x%x bytes\r\n",
+        offset,
+        header_length as usize
+    );
+
+    let header_end = offset + header_length as usize;
+    let tags = bytes
+        .get(..header_end)
+        .ok_or(Multiboot2Error::TruncatedHeader)?;
+    let mut header = Multiboot2Header {
+        offset,
+        address: None,
+        entry: None,
+    };
+    let mut tag = offset + 16;
+    loop {
+        let kind = u16_at(tags, tag).ok_or(Multiboot2Error::TruncatedHeader)?;
+        let flags = u16_at(tags, tag + 2).ok_or(Multiboot2Error::TruncatedHeader)?;
+        let size = u32_at(tags, tag + 4).ok_or(Multiboot2Error::TruncatedHeader)? as usize;
+        if size < 8 || tag + size > header_end {
+            return Err(Multiboot2Error::TruncatedHeader);
+        }
+        let field =
+            |i: usize| u32_at(tags, tag + 8 + i * 4).ok_or(Multiboot2Error::TruncatedHeader);
+        match kind {
+            HEADER_TAG_END => break,
+            HEADER_TAG_INFORMATION_REQUEST => {
+                for i in 0..(size - 8) / 4 {
+                    let requested = field(i)?;
+                    if flags & HEADER_TAG_OPTIONAL == 0 && !PROVIDED_INFO_TAGS.contains(&requested)
+                    {
+                        return Err(Multiboot2Error::UnsupportedInformationRequest(requested));
+                    }
+                }
+            }
+            HEADER_TAG_ADDRESS => {
+                header.address = Some(AddressTag {
+                    header_addr: field(0)?,
+                    load_addr: field(1)?,
+                    load_end_addr: field(2)?,
+                    bss_end_addr: field(3)?,
+                });
+            }
+            HEADER_TAG_ENTRY_ADDRESS => header.entry = Some(field(0)?),
+            // The console and the video mode are chosen by the config, modules are already page aligned
+            HEADER_TAG_CONSOLE_FLAGS | HEADER_TAG_FRAMEBUFFER | HEADER_TAG_MODULE_ALIGN => {}
+            _ if flags & HEADER_TAG_OPTIONAL != 0 => {
+                printf!(
+                    b"Multiboot2: ignoring optional header tag 0x%x\r\n",
+                    kind as u32
+                );
+            }
+            _ => return Err(Multiboot2Error::UnsupportedHeaderTag(kind)),
+        }
+        tag += size.next_multiple_of(8);
+    }
+    Ok(header)
+}
+
+/// The image of a Multiboot2 kernel
+pub enum Multiboot2Image<'f> {
+    /// Loaded as described by the address tag, the file doesn't have to be an ELF file
+    Flat(Ext2File<'f>),
+    /// Loaded at the physical addresses of its ELF program headers
+    Elf(ElfFileFlavour<'f>),
+}
+
+pub struct Multiboot2Kernel<'f> {
+    pub header: Multiboot2Header,
+    pub image: Multiboot2Image<'f>,
+}
+
+impl<'f> Multiboot2Kernel<'f> {
+    /// Scans `file` for its header. Without an address tag, the file must be a valid ELF32 or ELF64 file
+    pub fn open(mut file: Ext2File<'f>) -> Result<Self, Multiboot2Error> {
+        let header = find_header(&mut file)?;
+        let image = if header.address.is_some() {
+            Multiboot2Image::Flat(file)
+        } else {
+            let mut elf = load_elf(file).map_err(Multiboot2Error::ElfError)?;
+            match &mut elf {
+                ElfFileFlavour::Elf32(elf) => elf.validate_program_headers(),
+                ElfFileFlavour::Elf64(elf) => elf.validate_program_headers(),
+            }
+            .map_err(Multiboot2Error::ElfError)?;
+            Multiboot2Image::Elf(elf)
+        };
+        Ok(Self { header, image })
+    }
+
+    pub fn file_mut(&mut self) -> &mut Ext2File<'f> {
+        match &mut self.image {
+            Multiboot2Image::Flat(file) => file,
+            Multiboot2Image::Elf(ElfFileFlavour::Elf32(elf)) => elf.get_file_mut(),
+            Multiboot2Image::Elf(ElfFileFlavour::Elf64(elf)) => elf.get_file_mut(),
+        }
+    }
+
+    /// The physical ranges to load and the entry point. The entry address tag overrides the ELF entry point
+    pub fn segments(&mut self) -> Result<(Vec<PhysicalSegment>, u32), Multiboot2Error> {
+        let mut segments = Vec::new_tagged(4, b"multiboot2");
+        let elf_entry = match &mut self.image {
+            Multiboot2Image::Flat(file) => {
+                let Some(address) = self.header.address else {
+                    return Err(Multiboot2Error::BadAddressTag);
+                };
+                segments.push(flat_segment(&address, self.header.offset, file.get_size())?);
+                None
+            }
+            Multiboot2Image::Elf(ElfFileFlavour::Elf32(elf)) => {
+                let phs = elf
+                    .load_program_headers()
+                    .map_err(Multiboot2Error::ElfError)?;
+                for (index, ph) in phs.iter().enumerate() {
+                    if ph.segment_type == SEGMENT_TYPE_LOAD && ph.p_memsz != 0 {
+                        segments.push(PhysicalSegment {
+                            index,
+                            paddr: ph.p_paddr as u64,
+                            offset: ph.p_offset as usize,
+                            file_size: ph.p_filesz as usize,
+                            mem_size: ph.p_memsz as usize,
+                        });
+                    }
+                }
+                Some(elf.entry_point() as u64)
+            }
+            Multiboot2Image::Elf(ElfFileFlavour::Elf64(elf)) => {
+                let phs = elf
+                    .load_program_headers()
+                    .map_err(Multiboot2Error::ElfError)?;
+                for (index, ph) in phs.iter().enumerate() {
+                    if ph.segment_type == SEGMENT_TYPE_LOAD && ph.p_memsz != 0 {
+                        segments.push(PhysicalSegment {
+                            index,
+                            paddr: ph.p_paddr,
+                            offset: ph.p_offset as usize,
+                            file_size: ph.p_filesz as usize,
+                            mem_size: ph.p_memsz as usize,
+                        });
+                    }
+                }
+                Some(elf.entry_point())
+            }
+        };
+        let entry = match (self.header.entry, elf_entry) {
+            (Some(entry), _) => entry,
+            (None, Some(entry)) if entry <= u32::MAX as u64 => entry as u32,
+            (None, Some(entry)) => return Err(Multiboot2Error::EntryAbove4GiB(entry)),
+            (None, None) => return Err(Multiboot2Error::MissingEntryAddress),
+        };
+        Ok((segments, entry))
+    }
+}
+
+/// The single range described by an address tag
+fn flat_segment(
+    address: &AddressTag,
+    header_offset: usize,
+    file_size: usize,
+) -> Result<PhysicalSegment, Multiboot2Error> {
+    let header_delta = address
+        .header_addr
+        .checked_sub(address.load_addr)
+        .ok_or(Multiboot2Error::BadAddressTag)? as usize;
+    let offset = header_offset
+        .checked_sub(header_delta)
+        .ok_or(Multiboot2Error::BadAddressTag)?;
+    let loaded_size = if address.load_end_addr == 0 {
+        file_size - offset
+    } else {
+        address
+            .load_end_addr
+            .checked_sub(address.load_addr)
+            .ok_or(Multiboot2Error::BadAddressTag)? as usize
+    };
+    if offset + loaded_size > file_size {
+        return Err(Multiboot2Error::BadAddressTag);
+    }
+    let mem_size = if address.bss_end_addr == 0 {
+        loaded_size
+    } else {
+        address
+            .bss_end_addr
+            .checked_sub(address.load_addr)
+            .filter(|size| *size as usize >= loaded_size)
+            .ok_or(Multiboot2Error::BadAddressTag)? as usize
+    };
+    Ok(PhysicalSegment {
+        index: 0,
+        paddr: address.load_addr as u64,
+        offset,
+        file_size: loaded_size,
+        mem_size,
+    })
+}
+
+/// Builds the Multiboot2 boot information structure, tag by tag
+pub struct BootInformation {
+    bytes: Vec<u8>,
+}
+
+impl Default for BootInformation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BootInformation {
+    pub fn new() -> Self {
+        let mut info = Self {
+            bytes: Vec::new_tagged(256, b"multiboot2"),
+        };
+        // total_size, patched by `finish`, and reserved
+        info.put_u32(0);
+        info.put_u32(0);
+        info
+    }
+
+    fn put_bytes(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.bytes.push(*b);
+        }
+    }
+
+    fn put_u32(&mut self, value: u32) {
+        self.put_bytes(&value.to_le_bytes());
+    }
+
+    fn put_u64(&mut self, value: u64) {
+        self.put_bytes(&value.to_le_bytes());
+    }
+
+    fn patch_u32(&mut self, offset: usize, value: u32) {
+        for (i, b) in value.to_le_bytes().iter().enumerate() {
+            if let Some(byte) = self.bytes.get_mut(offset + i) {
+                *byte = *b;
+            }
+        }
+    }
+
+    /// Writes a tag header, `fill` writes the payload, then the size is patched and the tag padded to 8 bytes
+    fn tag(&mut self, kind: u32, fill: impl FnOnce(&mut Self)) {
+        let start = self.bytes.len();
+        self.put_u32(kind);
+        self.put_u32(0);
+        fill(self);
+        self.patch_u32(start + 4, (self.bytes.len() - start) as u32);
+        while !self.bytes.len().is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+    }
+
+    fn put_string(&mut self, string: &[u8]) {
+        self.put_bytes(string);
+        self.bytes.push(0);
+    }
+
+    pub fn cmdline(&mut self, cmdline: &[u8]) {
+        self.tag(INFO_TAG_CMDLINE, |info| info.put_string(cmdline));
+    }
+
+    pub fn bootloader_name(&mut self, name: &[u8]) {
+        self.tag(INFO_TAG_BOOTLOADER_NAME, |info| info.put_string(name));
+    }
+
+    pub fn module(&mut self, start: u32, end: u32, name: &[u8]) {
+        self.tag(INFO_TAG_MODULE, |info| {
+            info.put_u32(start);
+            info.put_u32(end);
+            info.put_string(name);
+        });
+    }
+
+    /// Usable KiB from 0 and from 1MiB
+    pub fn basic_meminfo(&mut self, lower_kib: u32, upper_kib: u32) {
+        self.tag(INFO_TAG_BASIC_MEMINFO, |info| {
+            info.put_u32(lower_kib);
+            info.put_u32(upper_kib);
+        });
+    }
+
+    /// One entry per `(start, end, usable)` region
+    pub fn memory_map(&mut self, regions: impl Iterator<Item = (u64, u64, bool)>) {
+        self.tag(INFO_TAG_MEMORY_MAP, |info| {
+            info.put_u32(24);
+            info.put_u32(0);
+            for (start, end, usable) in regions {
+                info.put_u64(start);
+                info.put_u64(end - start);
+                info.put_u32(if usable {
+                    MEMORY_AVAILABLE
+                } else {
+                    MEMORY_RESERVED
+                });
+                info.put_u32(0);
+            }
+        });
+    }
+
+    pub fn framebuffer(&mut self, framebuffer: &FramebufferInfo) {
+        self.tag(INFO_TAG_FRAMEBUFFER, |info| {
+            info.put_u64(framebuffer.addr);
+            info.put_u32(framebuffer.pitch);
+            info.put_u32(framebuffer.width);
+            info.put_u32(framebuffer.height);
+            info.put_bytes(&[framebuffer.bpp, FRAMEBUFFER_TYPE_RGB, 0, 0]);
+            for (position, size) in framebuffer.fields {
+                info.put_bytes(&[position, size]);
+            }
+        });
+    }
+
+    /// Size of the structure once [`BootInformation::finish`] added the end tag
+    pub fn finished_size(&self) -> usize {
+        self.bytes.len() + 8
+    }
+
+    /// Adds the end tag and copies the structure to `addr`
+    /// # Safety
+    /// The [`BootInformation::finished_size`] bytes at `addr` must be writable and not used by anything else, `addr` 8 byte aligned
+    pub unsafe fn finish(mut self, addr: u32) {
+        self.tag(INFO_TAG_END, |_| {});
+        let len = self.bytes.len();
+        self.patch_u32(0, len as u32);
+        core::ptr::copy_nonoverlapping(self.bytes.as_slice().as_ptr(), addr as *mut u8, len);
+    }
+}
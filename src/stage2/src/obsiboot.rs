@@ -1,8 +1,26 @@
-use crate::{e9::write_string, kpanic, printf};
+use crate::{
+    bench::DEFAULT_BENCH_BYTES,
+    diskhealth::{DEFAULT_HEALTH_PATH, DEFAULT_NOTICE_THRESHOLD},
+    e9::write_string,
+    fbcon::{parse_fb_font, FbFontConfig},
+    hash::{parse_sha256, SHA256_DIGEST_SIZE},
+    install::MultipleInstallsPolicy,
+    kpanic,
+    log::{with_level, LogLevel, DEFAULT_E9_LEVEL, DEFAULT_VGA_LEVEL},
+    log_warn,
+    mem::Buffer,
+    pause::{parse_pause_before_jump, PauseBeforeJump},
+    printf,
+    probe::{BootMode, ProbeThen},
+    scan::{parse_boot_partition, BootPartitionSelector},
+    video::TextMode,
+    warnings::{parse_warning_ids, warning, WarningId},
+};
 
 /// # ObsiBoot Kernel Parameters
 /// Contains information about the bootloader and the system
-/// Documentation for ObsiBoot struct version 1.
+/// Its physical address is passed in `rdi` to a 64-bit kernel, and to a 32-bit kernel both in `eax` and as the cdecl stack argument
+/// Documentation for ObsiBoot struct version 14.
 #[repr(C, packed)]
 pub struct ObsiBootKernelParameters {
     /// The size of this structure in bytes <br>
@@ -33,6 +51,10 @@ pub struct ObsiBootKernelParameters {
     /// A pointer to a sanitized memory layout given by the BIOS <br>
     /// Note: This is a physical address <br>
     /// Note: Any region that is marked as usable is fully usable by the kernel except for the one containing the address `usbale_kernel_memory_start`. See `usbale_kernel_memory_start` for more information. <br>
+    /// Note: The layout and this structure are in a region marked reserved, they stay valid until the kernel is done with them <br>
+    /// Note: An entry's `usable` is 0 for reserved, 1 for usable and 2 for bootloader reclaimable: the stage2 image, its stack and BIOS buffers, free once the kernel is done with the BIOS state <br>
+    /// Note: The page tables, the kernel and its stack are in regions marked reserved <br>
+    /// Note: Bootloader reclaimable entries were added in version 14, older versions only use 0 and 1 <br>
     pub ptr_to_memory_layout: u32,
     /// The number of entries in the memory layout <br>
     pub memory_layout_entry_count: u32,
@@ -51,6 +73,7 @@ pub struct ObsiBootKernelParameters {
     /// Note: Bootloaders may not set this value. See `page_tables_page_allocator_current_free_page` for more information. <br>
     pub page_tables_page_allocator_last_usable_page: u32,
     /// The base address of PML4 <br>
+    /// Note: 0 for a 32-bit kernel, which is entered in protected mode without paging, its segments loaded at their physical addresses <br>
     pub pml4_base_address: u32,
 
     /// The address of the first kernel usable memory. <br>
@@ -68,12 +91,250 @@ pub struct ObsiBootKernelParameters {
     /// Note: Each entry is 256 bytes <br>
     pub vbe_mode_info_block_entry_count: u32,
     /// The selected VESA mode <br>
+    /// Note: 0xFFFF when no VBE mode was set and the display was left in text mode <br>
     pub vbe_selected_mode: u32,
 
-    /// The initial stack pointer used to load the kernel
+    /// The initial stack pointer used to load the kernel <br>
+    /// Note: 16 byte aligned before the entry point is called, so `rsp + 8` is 16 byte aligned at the first kernel instruction (SysV) <br>
     pub kernel_stack_pointer: u64,
+
+    /// The total usable memory detected from the BIOS memory map, in bytes <br>
+    /// Note: Added in version 2 <br>
+    pub detected_usable_memory: u64,
+    /// The `mem_limit=` cap applied to the usable regions of the memory layout, in bytes, or 0 when no cap is applied <br>
+    /// Note: When non zero, the memory layout only reports this much usable memory, even though `detected_usable_memory` may be larger <br>
+    /// Note: Added in version 2 <br>
+    pub usable_memory_limit: u64,
+
+    /// The VBE mode requested by the config, as [mode number, width, height, bpp] <br>
+    /// Note: The mode number is 0xFFFF when the mode was requested by resolution, everything is 0 when no mode was requested <br>
+    /// Note: Added in version 2 <br>
+    pub vbe_requested_mode: [u16; 4],
+    /// How `vbe_selected_mode` was chosen, see the `VBE_SELECTED_*` constants <br>
+    /// Note: Added in version 2 <br>
+    pub vbe_selection: u32,
+
+    /// A pointer to the list of [`MemoryReservation`]s declared with `reserve=` <br>
+    /// Note: These ranges are already reported as not usable in the memory layout, the list tells the kernel why <br>
+    /// Note: Ranges outside the detected memory are listed too <br>
+    /// Note: This is a physical address <br>
+    /// Note: Added in version 3 <br>
+    pub reservations_ptr: u32,
+    /// The number of entries in the reservation list <br>
+    /// Note: Added in version 3 <br>
+    pub reservation_count: u32,
+    /// The size of one reservation entry in bytes <br>
+    /// Note: Added in version 3 <br>
+    pub reservation_entry_size: u32,
+
+    /// The physical address of the initial ramdisk, 0 when no `initrd=` is configured <br>
+    /// Note: When several `initrd=` files are configured, they are concatenated in config order, uncompressed cpio archives starting on a 4 byte boundary <br>
+    /// Note: Added in version 4 <br>
+    pub initrd_physical_addr: u64,
+    /// The size of the initial ramdisk in bytes, 0 when no `initrd=` is configured <br>
+    /// Note: Added in version 4 <br>
+    pub initrd_size: u64,
+
+    /// A pointer to the E820 entries exactly as the BIOS returned them, in the same order <br>
+    /// Note: The sanitized memory layout was built from this same snapshot, the BIOS is not queried again <br>
+    /// Note: Each entry is a `mem::SystemMemoryMap` (base, length, type), followed by the ACPI 3.0 extended attributes dword when `RAW_MEMORY_MAP_EXTENDED_ATTRIBUTES` is set in `raw_memory_map_flags` <br>
+    /// Note: This is a physical address, in the same bootloader memory as the sanitized layout <br>
+    /// Note: Added in version 5 <br>
+    pub raw_memory_map_ptr: u32,
+    /// The number of entries in the raw memory map <br>
+    /// Note: Added in version 5 <br>
+    pub raw_memory_map_entry_count: u32,
+    /// The size of one raw memory map entry in bytes <br>
+    /// Note: Added in version 5 <br>
+    pub raw_memory_map_entry_size: u32,
+    /// See the `RAW_MEMORY_MAP_*` constants <br>
+    /// Note: Added in version 5 <br>
+    pub raw_memory_map_flags: u32,
+
+    /// The lowest address of the kernel stack <br>
+    /// Note: This is a virtual address, the stack grows down from `kernel_stack_end` to here <br>
+    /// Note: For a 32-bit kernel, the stack is in bootloader memory and addresses are physical <br>
+    /// Note: Added in version 6 <br>
+    pub kernel_stack_start: u64,
+    /// The end (exclusive) of the kernel stack <br>
+    /// Note: This is a virtual address. `kernel_stack_pointer` is this minus the SysV alignment slack <br>
+    /// Note: Added in version 6 <br>
+    pub kernel_stack_end: u64,
+    /// The size of the unmapped guard gaps right below `kernel_stack_start` and right above `kernel_stack_end`, in bytes <br>
+    /// Note: Nothing else is mapped in these gaps, a stack overflow faults instead of corrupting the kernel <br>
+    /// Note: 0 for a 32-bit kernel, without paging there are no guard gaps <br>
+    /// Note: Added in version 6 <br>
+    pub kernel_stack_guard_size: u64,
+
+    /// Identifies the exact bootloader build, the same id is printed in the boot log and the probe report <br>
+    /// Note: Two builds of the same source tree have the same id <br>
+    /// Note: Added in version 7 <br>
+    pub bootloader_build_id: [u8; 16],
+
+    /// What the bootloader found out about the boot medium, see the `MEDIA_*` constants <br>
+    /// Note: Bits 8..16 hold the INT 13h AH=15h disk type as seen by the bootloader: 0 no disk, 1 removable, 2 removable with change line, 3 fixed, 4 unknown <br>
+    /// Note: Added in version 8 <br>
+    pub boot_media_flags: u32,
+
+    /// A pointer to the null terminated command line of the booted entry, from its `cmdline=` line <br>
+    /// Note: This is a physical address, 0 when the entry has no command line <br>
+    /// Note: Added in version 9 <br>
+    pub kernel_cmdline_ptr: u32,
+    /// The length of the command line in bytes, without the null terminator <br>
+    /// Note: Added in version 9 <br>
+    pub kernel_cmdline_len: u32,
+
+    /// The physical address of the linear framebuffer of `vbe_selected_mode`, 0 when the display was left in text mode <br>
+    /// Note: Mapped at this address and at `DIRECT_MAPPING_OFFSET` plus this address, write-through and cache disabled <br>
+    /// Note: Added in version 10 <br>
+    pub framebuffer_physical_addr: u64,
+    /// The size of the framebuffer in bytes, the pitch times the height of the mode <br>
+    /// Note: The mapping is rounded up to 2MiB pages, the bytes past this size are not part of the framebuffer <br>
+    /// Note: Added in version 10 <br>
+    pub framebuffer_size: u64,
+
+    /// A pointer to the list of `pci::PciDeviceInfo` found by the bootloader, in bus scan order <br>
+    /// Note: This is a physical address. BARs are the raw register values, they weren't sized <br>
+    /// Note: Added in version 11 <br>
+    pub pci_devices_ptr: u32,
+    /// The number of PCI functions in the list, 0 on machines without PCI <br>
+    /// Note: Added in version 11 <br>
+    pub pci_device_count: u32,
+    /// The size of one `pci::PciDeviceInfo` in bytes <br>
+    /// Note: Added in version 11 <br>
+    pub pci_device_entry_size: u32,
+
+    /// The physical address of the ACPI RSDP, 0 when the bootloader didn't find one <br>
+    /// Note: Found in the first KiB of the EBDA or in the 0xE0000-0xFFFFF BIOS area, its checksums were verified <br>
+    /// Note: Added in version 12 <br>
+    pub acpi_rsdp_addr: u32,
+    /// The RSDP revision, 0 for ACPI 1.0 and 2 or more when the XSDT address is valid. 0 without an RSDP <br>
+    /// Note: Added in version 12 <br>
+    pub acpi_rsdp_revision: u32,
+
+    /// How this boot was set up, see the `BOOT_FLAG_*` constants <br>
+    /// Note: Added in version 13 <br>
+    pub boot_flags: u32,
+}
+
+/// The raw memory map entries carry the ACPI 3.0 extended attributes dword
+pub const RAW_MEMORY_MAP_EXTENDED_ATTRIBUTES: u32 = 1;
+
+/// The boot medium is removable
+pub const MEDIA_REMOVABLE: u32 = 1 << 0;
+/// A write to the boot medium failed with the BIOS write-protected status
+pub const MEDIA_WRITE_PROTECTED: u32 = 1 << 1;
+/// The bootloader considered the boot medium read-only, after a failed write or with no medium present
+pub const MEDIA_READ_ONLY: u32 = 1 << 2;
+
+/// Safe mode was selected at the keyboard, the display was left in VGA text mode and `vbe_mode=` ignored
+pub const BOOT_FLAG_SAFE_MODE: u32 = 1 << 0;
+/// The page at virtual 0 is left unmapped in the identity and direct mappings, so null dereferences fault (`map_null_page=off`). <br>
+/// The IVT and the BIOS data area at 0x400 are in that page: kernels reading the BDA map it themselves or need `map_null_page=on`. <br>
+/// Clear with older bootloaders, which always mapped it. <br>
+pub const BOOT_FLAG_NULL_PAGE_UNMAPPED: u32 = 1 << 1;
+/// CR0.WP was set, ring 0 writes to read-only pages fault
+pub const BOOT_FLAG_WRITE_PROTECT: u32 = 1 << 2;
+/// CR4.PGE was set, entries with `PAGE_GLOBAL` survive CR3 reloads
+pub const BOOT_FLAG_GLOBAL_PAGES: u32 = 1 << 3;
+/// The PAT MSR holds `cpu_features::PAT_LAYOUT`, PAT and PWT set in an entry select write-combining. <br>
+/// Clear when the CPU has no PAT, which is left at its reset values otherwise. <br>
+pub const BOOT_FLAG_PAT: u32 = 1 << 4;
+
+pub const MAX_RESERVATIONS: usize = 16;
+pub const MAX_INITRDS: usize = 8;
+pub const RESERVATION_LABEL_LEN: usize = 16;
+pub const MAX_BOOT_ENTRIES: usize = 8;
+/// Kernel booted by an entry without `kernel=`, and when the config has no `entry=` block
+pub const DEFAULT_KERNEL_PATH: &[u8] = b"/kernel64.elf";
+/// Seconds the boot menu waits before booting the default entry, when `timeout=` isn't set
+pub const DEFAULT_MENU_TIMEOUT: u32 = 5;
+
+/// An `entry=<name>` block of the config, with the `kernel=`, `cmdline=` and `kernel_sha256=` lines following it
+pub struct BootEntry {
+    pub name: Buffer,
+    pub kernel: Option<Buffer>,
+    pub cmdline: Option<Buffer>,
+    /// Digest this entry's kernel file must hash to, not verified without one
+    pub kernel_sha256: Option<[u8; SHA256_DIGEST_SIZE]>,
 }
 
+impl BootEntry {
+    pub fn kernel_path(&self) -> &[u8] {
+        self.kernel.as_deref().unwrap_or(DEFAULT_KERNEL_PATH)
+    }
+
+    /// Copies the command line, null terminated, to memory kept for the kernel. Returns `(address, length)`, zeros without `cmdline=`
+    pub fn leak_cmdline(&self) -> (u32, u32) {
+        let Some(cmdline) = &self.cmdline else {
+            return (0, 0);
+        };
+        let Some(mut copy) = Buffer::new_tagged(cmdline.len() + 1, b"config") else {
+            kpanic();
+        };
+        copy[..cmdline.len()].copy_from_slice(cmdline);
+        copy[cmdline.len()] = 0;
+        let address = unsafe { copy.get_ptr() as u32 };
+        unsafe { copy.leak() };
+        (address, cmdline.len() as u32)
+    }
+}
+
+/// A physical range kept away from the kernel with `reserve=<start>-<end>[:label]`
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct MemoryReservation {
+    /// Page aligned, inclusive
+    pub start: u64,
+    /// Page aligned, exclusive
+    pub end: u64,
+    /// Zero padded, not null terminated when 16 bytes long
+    pub label: [u8; RESERVATION_LABEL_LEN],
+}
+
+impl MemoryReservation {
+    const fn empty() -> Self {
+        Self {
+            start: 0,
+            end: 0,
+            label: [0; RESERVATION_LABEL_LEN],
+        }
+    }
+
+    pub fn label(&self) -> &[u8] {
+        let len = self
+            .label
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(RESERVATION_LABEL_LEN);
+        &self.label[..len]
+    }
+
+    pub fn printf(&self) {
+        let (start, end) = (self.start, self.end);
+        printf!(
+            b"%x%x --> %x%x [",
+            (start >> 32) as u32,
+            start as u32,
+            (end >> 32) as u32,
+            end as u32
+        );
+        write_string(self.label());
+        printf!(b"]");
+    }
+}
+
+/// The requested mode was set, or none was requested and the mode at the native (EDID) resolution or else the best mode was set
+pub const VBE_SELECTED_REQUESTED: u32 = 0;
+/// The requested mode was unavailable, the closest one was set (`vbe_mode_fallback=closest`)
+pub const VBE_SELECTED_CLOSEST: u32 = 1;
+/// The requested mode was unavailable, the best one was set (`vbe_mode_fallback=best`)
+pub const VBE_SELECTED_BEST: u32 = 2;
+/// The requested mode was unavailable, the display was left in text mode (`vbe_mode_fallback=text`)
+pub const VBE_SELECTED_TEXT: u32 = 3;
+/// Safe mode, no VBE call was made and the display is in VGA text mode, the VBE info block and mode list are empty
+pub const VBE_SELECTED_SAFE_MODE: u32 = 4;
+
 impl ObsiBootKernelParameters {
     /// Computes the checksum, without modifying the structure. Does not set the checksum field.
     /// ### Uses a custom checksum algorithm:
@@ -136,6 +397,34 @@ impl ObsiBootKernelParameters {
             vbe_mode_info_block_entry_count: 0,
             vbe_selected_mode: 0,
             kernel_stack_pointer: 0,
+            detected_usable_memory: 0,
+            usable_memory_limit: 0,
+            vbe_requested_mode: [0; 4],
+            vbe_selection: 0,
+            reservations_ptr: 0,
+            reservation_count: 0,
+            reservation_entry_size: 0,
+            initrd_physical_addr: 0,
+            initrd_size: 0,
+            raw_memory_map_ptr: 0,
+            raw_memory_map_entry_count: 0,
+            raw_memory_map_entry_size: 0,
+            raw_memory_map_flags: 0,
+            kernel_stack_start: 0,
+            kernel_stack_end: 0,
+            kernel_stack_guard_size: 0,
+            bootloader_build_id: [0; 16],
+            boot_media_flags: 0,
+            kernel_cmdline_ptr: 0,
+            kernel_cmdline_len: 0,
+            framebuffer_physical_addr: 0,
+            framebuffer_size: 0,
+            pci_devices_ptr: 0,
+            pci_device_count: 0,
+            pci_device_entry_size: 0,
+            acpi_rsdp_addr: 0,
+            acpi_rsdp_revision: 0,
+            boot_flags: 0,
         }
     }
 }
@@ -145,13 +434,250 @@ pub enum ObsiBootConfigVbeMode {
     ModeInfo { width: u16, height: u16, bpp: u8 },
 }
 
+/// What to do when the requested VBE mode isn't available
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ObsiBootConfigVbeFallback {
+    /// Pick the available mode closest to the requested one (see `vesa::mode_distance`)
+    Closest,
+    /// Pick the largest direct color mode
+    Best,
+    /// Don't switch modes, stay in text mode
+    Text,
+    /// Abort the boot, listing the closest available modes
+    Fail,
+}
+
+/// How the kernel is entered, see `protocol=`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BootProtocol {
+    /// ELF64 in long mode or ELF32 in protected mode, with [`ObsiBootKernelParameters`]
+    ObsiBoot,
+    /// Multiboot2 header in the image, boot information structure in `ebx`, always in protected mode
+    Multiboot2,
+}
+
 pub struct ObsiBootConfig {
     pub vbe_mode: Option<ObsiBootConfigVbeMode>,
+    pub vbe_mode_fallback: ObsiBootConfigVbeFallback,
+    /// Whether to emit POST codes on port 0x80 (see `post::codes`)
+    pub post_codes: bool,
+    /// Caps the usable memory reported to the kernel and used by the bootloader, in bytes
+    pub mem_limit: Option<u64>,
+    /// What to do when more than one ObsidianBootloader installation is found
+    pub multiple_installs: MultipleInstallsPolicy,
+    /// Whether to boot the kernel or only inventory the machine
+    pub mode: BootMode,
+    /// Path of the pre-created file the probe report is written to
+    pub probe_report: Option<Buffer>,
+    /// What to do once the probe report is written
+    pub probe_then: ProbeThen,
+    /// Whether memory handed to the kernel is zero-filled at allocation
+    pub scrub_handoff_memory: bool,
+    /// Whether 64-bit kernel segments are read straight into frames of usable memory instead of heap buffers
+    pub stream_kernel: bool,
+    /// Whether the first 4KiB page is mapped at virtual 0 and at the direct mapping offset
+    pub map_null_page: bool,
+    /// Font of the framebuffer console, None to pick it from the mode height
+    pub fb_font: Option<FbFontConfig>,
+    /// Whether TLS, INTERP and relocated DYNAMIC kernel segments abort the boot instead of only warning
+    pub strict_elf: bool,
+    /// The `protocol=` the kernel is booted with
+    pub protocol: BootProtocol,
+    /// Whether BIOS call latencies are recorded (needs the `bios-latency` feature)
+    pub bios_latency: bool,
+    /// Whether to wait before the jump to the kernel, so the final screen can be read
+    pub pause_before_jump: PauseBeforeJump,
+    /// Ranges declared with `reserve=`, only the first `reservation_count` are valid
+    pub reservations: [MemoryReservation; MAX_RESERVATIONS],
+    pub reservation_count: usize,
+    /// `initrd=` paths in config order, concatenated into one image
+    pub initrds: [Option<Buffer>; MAX_INITRDS],
+    /// Bytes read per transfer size by `mode=bench`
+    pub bench_bytes: u64,
+    /// Whether any warning not in `strict_allow` aborts the boot (see `warnings`)
+    pub strict_boot: bool,
+    /// Mask of the warnings `strict_boot` accepts, from `strict_allow=<id>,<id>...`
+    pub strict_allow: u32,
+    /// Path of the `key=value` file translating the screen texts (see `lang::Text`)
+    pub lang_file: Option<Buffer>,
+    /// Repetitions of one disk error near one LBA that trigger the failing media notice, 0 disables it
+    pub disk_health_notice: u32,
+    /// Path of the pre-created file the disk errors are merged into across boots
+    pub disk_health_file: Option<Buffer>,
+    /// Partition to boot from instead of the one picked by the partition scan, the config itself is always read from the latter
+    pub boot_partition: Option<BootPartitionSelector>,
+    /// BIOS drive to boot from instead of the one the config was read from, from `boot_drive=`. `boot_partition=` then applies to it
+    pub boot_drive: Option<u8>,
+    /// VGA text geometry set once the config is read, from `text_mode=`
+    pub text_mode: TextMode,
+    /// `entry=` blocks in config order, the default kernel is booted without any
+    pub entries: [Option<BootEntry>; MAX_BOOT_ENTRIES],
+    /// Index of the entry booted when the menu times out or is skipped, from `default=`
+    pub default_entry: usize,
+    /// Seconds the boot menu waits before booting the default entry, 0 skips the menu
+    pub menu_timeout: u32,
+    /// Digest the default kernel must hash to, from a `kernel_sha256=` line before any `entry=` block. Not verified without one <br>
+    /// Only used when the config has no `entry=` block, each entry has its own. <br>
+    pub kernel_sha256: Option<[u8; SHA256_DIGEST_SIZE]>,
+    /// Most verbose messages written to e9, from `loglevel=<e9>[,<vga>]`
+    pub log_level: LogLevel,
+    /// Most verbose messages shown on screen, from the second value of `loglevel=`
+    pub vga_log_level: LogLevel,
+}
+
+/// Records a [`WarningId::InvalidConfigValue`] and logs `message` followed by the value at fault
+fn invalid_value(message: &[u8], value: &[u8]) {
+    warning(WarningId::InvalidConfigValue);
+    with_level(LogLevel::Warn, || {
+        write_string(message);
+        write_string(value);
+        printf!(b"\r\n");
+    });
+}
+
+fn parse_bool(value: &[u8]) -> Option<bool> {
+    match value {
+        b"1" | b"yes" | b"on" | b"true" => Some(true),
+        b"0" | b"no" | b"off" | b"false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses a `0x` prefixed hexadecimal address, or a size (see [`parse_size`])
+fn parse_address(value: &[u8]) -> Option<u64> {
+    let Some(digits) = value.strip_prefix(b"0x") else {
+        return parse_size(value);
+    };
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    let mut address: u64 = 0;
+    for c in digits {
+        let digit = match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            b'A'..=b'F' => c - b'A' + 10,
+            _ => return None,
+        };
+        address = (address << 4) | digit as u64;
+    }
+    Some(address)
+}
+
+/// Parses a size in bytes, with an optional `K`, `M` or `G` suffix (powers of 1024)
+fn parse_size(value: &[u8]) -> Option<u64> {
+    let (digits, multiplier) = match value.last() {
+        Some(b'K' | b'k') => (&value[..value.len() - 1], 1024),
+        Some(b'M' | b'm') => (&value[..value.len() - 1], 1024 * 1024),
+        Some(b'G' | b'g') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    u64::from_ascii(digits).ok()?.checked_mul(multiplier)
 }
 
 impl ObsiBootConfig {
     pub const fn empty() -> Self {
-        Self { vbe_mode: None }
+        Self {
+            vbe_mode: None,
+            vbe_mode_fallback: ObsiBootConfigVbeFallback::Best,
+            post_codes: true,
+            mem_limit: None,
+            multiple_installs: MultipleInstallsPolicy::Warn,
+            mode: BootMode::Kernel,
+            probe_report: None,
+            probe_then: ProbeThen::Halt,
+            scrub_handoff_memory: true,
+            stream_kernel: false,
+            map_null_page: false,
+            fb_font: None,
+            strict_elf: false,
+            protocol: BootProtocol::ObsiBoot,
+            bios_latency: false,
+            pause_before_jump: PauseBeforeJump::Off,
+            reservations: [MemoryReservation::empty(); MAX_RESERVATIONS],
+            reservation_count: 0,
+            initrds: [const { None }; MAX_INITRDS],
+            bench_bytes: DEFAULT_BENCH_BYTES,
+            strict_boot: false,
+            strict_allow: 0,
+            lang_file: None,
+            disk_health_notice: DEFAULT_NOTICE_THRESHOLD,
+            disk_health_file: None,
+            boot_partition: None,
+            boot_drive: None,
+            text_mode: TextMode::Mode80x25,
+            entries: [const { None }; MAX_BOOT_ENTRIES],
+            default_entry: 0,
+            menu_timeout: DEFAULT_MENU_TIMEOUT,
+            kernel_sha256: None,
+            log_level: DEFAULT_E9_LEVEL,
+            vga_log_level: DEFAULT_VGA_LEVEL,
+        }
+    }
+
+    /// `disk_health_file=`, or [`DEFAULT_HEALTH_PATH`]
+    pub fn disk_health_path(&self) -> &[u8] {
+        match &self.disk_health_file {
+            Some(path) => path,
+            None => DEFAULT_HEALTH_PATH,
+        }
+    }
+
+    pub fn initrd_paths(&self) -> impl Iterator<Item = &[u8]> + Clone {
+        self.initrds.iter().flatten().map(|path| &path[..])
+    }
+
+    pub fn boot_entries(&self) -> impl Iterator<Item = &BootEntry> {
+        self.entries.iter().flatten()
+    }
+
+    /// The entry the `kernel=` and `cmdline=` lines apply to, the last `entry=` seen
+    fn current_entry(&mut self) -> Option<&mut BootEntry> {
+        self.entries.iter_mut().flatten().last()
+    }
+
+    pub fn reservations(&self) -> &[MemoryReservation] {
+        &self.reservations[..self.reservation_count]
+    }
+
+    /// Parses `<start>-<end>[:label]` and records it, rejecting empty ranges and ranges overlapping a previous one
+    fn add_reservation(&mut self, value: &[u8]) -> Result<(), &'static [u8]> {
+        let (range, label) = match value.iter().position(|c| *c == b':') {
+            Some(i) => (&value[..i], &value[i + 1..]),
+            None => (value, &b""[..]),
+        };
+        let dash = range
+            .iter()
+            .position(|c| *c == b'-')
+            .ok_or(&b"expected <start>-<end>"[..])?;
+        let start = parse_address(&range[..dash]).ok_or(&b"invalid start"[..])?;
+        let end = parse_address(&range[dash + 1..]).ok_or(&b"invalid end"[..])?;
+        // Normalized to whole pages, the kernel can't use part of a page anyway
+        let start = start & !0xFFF;
+        let end = end.checked_add(0xFFF).ok_or(&b"invalid end"[..])? & !0xFFF;
+        if end <= start {
+            return Err(b"empty range");
+        }
+        if self
+            .reservations()
+            .iter()
+            .any(|r| start < r.end && r.start < end)
+        {
+            return Err(b"overlaps a previous reserve= range");
+        }
+        if self.reservation_count == MAX_RESERVATIONS {
+            return Err(b"too many reserve= ranges");
+        }
+        let mut reservation = MemoryReservation {
+            start,
+            end,
+            label: [0; RESERVATION_LABEL_LEN],
+        };
+        let len = label.len().min(RESERVATION_LABEL_LEN);
+        reservation.label[..len].copy_from_slice(&label[..len]);
+        self.reservations[self.reservation_count] = reservation;
+        self.reservation_count += 1;
+        Ok(())
     }
 
     pub fn parse(data: &[u8]) -> Self {
@@ -231,11 +757,537 @@ impl ObsiBootConfig {
                 continue;
             }
 
+            if is_key(data, i, b"vbe_mode_fallback=") {
+                i += 18;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match value {
+                    b"closest" => config.vbe_mode_fallback = ObsiBootConfigVbeFallback::Closest,
+                    b"best" => config.vbe_mode_fallback = ObsiBootConfigVbeFallback::Best,
+                    b"text" => config.vbe_mode_fallback = ObsiBootConfigVbeFallback::Text,
+                    b"fail" => config.vbe_mode_fallback = ObsiBootConfigVbeFallback::Fail,
+                    _ => {
+                        invalid_value(b"Invalid vbe_mode_fallback value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"kernel_sha256=") {
+                i += 14;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                let Some(digest) = parse_sha256(value) else {
+                    invalid_value(b"Invalid kernel_sha256 value: ", value);
+                    continue;
+                };
+                match config.current_entry() {
+                    Some(entry) => entry.kernel_sha256 = Some(digest),
+                    None => config.kernel_sha256 = Some(digest),
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"loglevel=") {
+                i += 9;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                let (e9, vga) = match value.iter().position(|c| *c == b',') {
+                    Some(comma) => (&value[..comma], Some(&value[comma + 1..])),
+                    None => (value, None),
+                };
+                match (
+                    LogLevel::from_name(e9),
+                    vga.map(LogLevel::from_name)
+                        .unwrap_or(Some(config.vga_log_level)),
+                ) {
+                    (Some(e9), Some(vga)) => {
+                        config.log_level = e9;
+                        config.vga_log_level = vga;
+                    }
+                    _ => {
+                        invalid_value(b"Invalid loglevel value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"protocol=") {
+                i += 9;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match value {
+                    b"obsiboot" => config.protocol = BootProtocol::ObsiBoot,
+                    b"multiboot2" => config.protocol = BootProtocol::Multiboot2,
+                    _ => {
+                        invalid_value(b"Invalid protocol value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"post_codes=") {
+                i += 11;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match parse_bool(value) {
+                    Some(enabled) => config.post_codes = enabled,
+                    None => {
+                        invalid_value(b"Invalid post_codes value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"scrub_handoff_memory=") {
+                i += 21;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match parse_bool(value) {
+                    Some(enabled) => config.scrub_handoff_memory = enabled,
+                    None => {
+                        invalid_value(b"Invalid scrub_handoff_memory value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"stream_kernel=") {
+                i += 14;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match parse_bool(value) {
+                    Some(enabled) => config.stream_kernel = enabled,
+                    None => {
+                        invalid_value(b"Invalid stream_kernel value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"map_null_page=") {
+                i += 14;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match parse_bool(value) {
+                    Some(enabled) => config.map_null_page = enabled,
+                    None => {
+                        invalid_value(b"Invalid map_null_page value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"bench_bytes=") {
+                i += 12;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match parse_size(value) {
+                    Some(bytes) if bytes != 0 => config.bench_bytes = bytes,
+                    _ => {
+                        invalid_value(b"Invalid bench_bytes value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"mem_limit=") {
+                i += 10;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match parse_size(value) {
+                    Some(limit) if limit != 0 => config.mem_limit = Some(limit),
+                    _ => {
+                        invalid_value(b"Invalid mem_limit value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"warn_on_multiple_installs=") {
+                i += 26;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match value {
+                    b"warn" => config.multiple_installs = MultipleInstallsPolicy::Warn,
+                    b"abort" => config.multiple_installs = MultipleInstallsPolicy::Abort,
+                    _ => {
+                        invalid_value(b"Invalid warn_on_multiple_installs value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"initrd=") {
+                i += 7;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                if value.is_empty() {
+                    warning(WarningId::InvalidConfigValue);
+                    log_warn!(b"Invalid initrd value: empty path\r\n");
+                    continue;
+                }
+                let Some(slot) = config.initrds.iter_mut().find(|p| p.is_none()) else {
+                    invalid_value(b"Too many initrd= lines, ignoring ", value);
+                    continue;
+                };
+                let Some(mut path) = Buffer::new_tagged(value.len(), b"config") else {
+                    kpanic();
+                };
+                path.copy_from_slice(value);
+                *slot = Some(path);
+                continue;
+            }
+
+            if is_key(data, i, b"entry=") {
+                i += 6;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                if value.is_empty() {
+                    warning(WarningId::InvalidConfigValue);
+                    log_warn!(b"Invalid entry value: empty name\r\n");
+                    continue;
+                }
+                let Some(slot) = config.entries.iter_mut().find(|e| e.is_none()) else {
+                    invalid_value(b"Too many entry= blocks, ignoring ", value);
+                    continue;
+                };
+                let Some(mut name) = Buffer::new_tagged(value.len(), b"config") else {
+                    kpanic();
+                };
+                name.copy_from_slice(value);
+                *slot = Some(BootEntry {
+                    name,
+                    kernel: None,
+                    cmdline: None,
+                    kernel_sha256: None,
+                });
+                continue;
+            }
+
+            if is_key(data, i, b"kernel=") || is_key(data, i, b"cmdline=") {
+                let kernel = is_key(data, i, b"kernel=");
+                i += if kernel { 7 } else { 8 };
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                let Some(entry) = config.current_entry() else {
+                    warning(WarningId::InvalidConfigValue);
+                    log_warn!(if kernel {
+                        b"kernel= outside of an entry= block, ignored\r\n"
+                    } else {
+                        b"cmdline= outside of an entry= block, ignored\r\n"
+                    });
+                    continue;
+                };
+                if kernel && value.is_empty() {
+                    warning(WarningId::InvalidConfigValue);
+                    log_warn!(b"Invalid kernel value: empty path\r\n");
+                    continue;
+                }
+                let Some(mut text) = Buffer::new_tagged(value.len().max(1), b"config") else {
+                    kpanic();
+                };
+                text[..value.len()].copy_from_slice(value);
+                if kernel {
+                    entry.kernel = Some(text);
+                } else {
+                    entry.cmdline = (!value.is_empty()).then_some(text);
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"default=") {
+                i += 8;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match usize::from_ascii(value) {
+                    Ok(index) => config.default_entry = index,
+                    Err(_) => {
+                        invalid_value(b"Invalid default value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"timeout=") {
+                i += 8;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match u32::from_ascii(value) {
+                    Ok(seconds) => config.menu_timeout = seconds,
+                    Err(_) => {
+                        invalid_value(b"Invalid timeout value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"reserve=") {
+                i += 8;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                if let Err(reason) = config.add_reservation(value) {
+                    warning(WarningId::InvalidConfigValue);
+                    with_level(LogLevel::Warn, || {
+                        printf!(b"Invalid reserve value: ");
+                        write_string(value);
+                        printf!(b" (");
+                        write_string(reason);
+                        printf!(b")\r\n");
+                    });
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"pause_before_jump=") {
+                i += 18;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match parse_pause_before_jump(value) {
+                    Some(pause) => config.pause_before_jump = pause,
+                    None => {
+                        invalid_value(b"Invalid pause_before_jump value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"bios_latency=") {
+                i += 13;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match parse_bool(value) {
+                    Some(enabled) => config.bios_latency = enabled,
+                    None => {
+                        invalid_value(b"Invalid bios_latency value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"strict_elf=") {
+                i += 11;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match parse_bool(value) {
+                    Some(strict) => config.strict_elf = strict,
+                    None => {
+                        invalid_value(b"Invalid strict_elf value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"strict_boot=") {
+                i += 12;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match parse_bool(value) {
+                    Some(strict) => config.strict_boot = strict,
+                    None => {
+                        invalid_value(b"Invalid strict_boot value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"strict_allow=") {
+                i += 13;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match parse_warning_ids(value) {
+                    Ok(mask) => config.strict_allow |= mask,
+                    Err(unknown) => {
+                        invalid_value(b"Invalid strict_allow value: unknown warning ", unknown);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"disk_health_notice=") {
+                i += 19;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match u32::from_ascii(value) {
+                    Ok(threshold) => config.disk_health_notice = threshold,
+                    Err(_) => {
+                        invalid_value(b"Invalid disk_health_notice value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"disk_health_file=") {
+                i += 17;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                let Some(mut path) = Buffer::new_tagged(value.len(), b"config") else {
+                    kpanic();
+                };
+                path.copy_from_slice(value);
+                config.disk_health_file = Some(path);
+                continue;
+            }
+
+            if is_key(data, i, b"boot_partition=") {
+                i += 15;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match parse_boot_partition(value) {
+                    Some(selector) => config.boot_partition = Some(selector),
+                    None => {
+                        invalid_value(b"Invalid boot_partition value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"boot_drive=") {
+                i += 11;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match parse_address(value).and_then(|drive| u8::try_from(drive).ok()) {
+                    Some(drive) => config.boot_drive = Some(drive),
+                    None => {
+                        invalid_value(b"Invalid boot_drive value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"text_mode=") {
+                i += 10;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match TextMode::parse(value) {
+                    Some(mode) => config.text_mode = mode,
+                    None => {
+                        invalid_value(b"Invalid text_mode value, keeping 80x25: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"fb_font=") {
+                i += 8;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match parse_fb_font(value) {
+                    (font, true) => config.fb_font = font,
+                    (_, false) => {
+                        invalid_value(b"Invalid fb_font value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"mode=") {
+                i += 5;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match value {
+                    b"kernel" => config.mode = BootMode::Kernel,
+                    b"probe" => config.mode = BootMode::Probe,
+                    b"bench" => config.mode = BootMode::Bench,
+                    _ => {
+                        invalid_value(b"Invalid mode value: ", value);
+                    }
+                }
+                continue;
+            }
+
+            if is_key(data, i, b"probe_report=") {
+                i += 13;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                let Some(mut path) = Buffer::new_tagged(value.len(), b"config") else {
+                    kpanic();
+                };
+                path.copy_from_slice(value);
+                config.probe_report = Some(path);
+                continue;
+            }
+
+            if is_key(data, i, b"lang_file=") {
+                i += 10;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                if value.is_empty() {
+                    warning(WarningId::InvalidConfigValue);
+                    log_warn!(b"Invalid lang_file value: empty path\r\n");
+                    continue;
+                }
+                let Some(mut path) = Buffer::new_tagged(value.len(), b"config") else {
+                    kpanic();
+                };
+                path.copy_from_slice(value);
+                config.lang_file = Some(path);
+                continue;
+            }
+
+            if is_key(data, i, b"probe_then=") {
+                i += 11;
+                let j = eol(data, i);
+                let value = data.get(i..j).unwrap_or(b"");
+                i = j;
+                match value {
+                    b"halt" => config.probe_then = ProbeThen::Halt,
+                    b"reboot" => config.probe_then = ProbeThen::Reboot,
+                    _ => {
+                        invalid_value(b"Invalid probe_then value: ", value);
+                    }
+                }
+                continue;
+            }
+
             printf!(b"Unknown config line: ");
             write_string(data.get(i..).unwrap_or(b"Error"));
             printf!(b"\r\n");
             kpanic();
         }
+        if config.default_entry != 0 && config.default_entry >= config.boot_entries().count() {
+            warning(WarningId::InvalidConfigValue);
+            log_warn!(
+                b"default=%x names no entry= block, using the first entry\r\n",
+                config.default_entry
+            );
+            config.default_entry = 0;
+        }
+        if config.kernel_sha256.is_some() && config.boot_entries().next().is_some() {
+            warning(WarningId::InvalidConfigValue);
+            log_warn!(b"kernel_sha256= before the first entry= block is ignored, set it in each entry\r\n");
+        }
         config
     }
 }
@@ -0,0 +1,455 @@
+// The page table editor only needs to report fatal errors: on e9, or by panicking in the unit tests
+#[cfg(not(test))]
+use crate::{addr::write_addr, e9::write_string, kpanic};
+#[cfg(test)]
+use tests::{kpanic, write_addr, write_string};
+
+pub const PAGE_SIZE: usize = 4096;
+pub const PAGE_SIZE_2MB: usize = 2 * 1024 * 1024;
+
+// Page Table Entry Flags
+pub const PAGE_PRESENT: u64 = 1 << 0;
+pub const PAGE_RW: u64 = 1 << 1;
+pub const PAGE_USER: u64 = 1 << 2;
+pub const PAGE_WRITE_THROUGH: u64 = 1 << 3;
+pub const PAGE_CACHE_DISABLE: u64 = 1 << 4;
+pub const PAGE_ACCESSED: u64 = 1 << 5;
+pub const PAGE_DIRTY: u64 = 1 << 6;
+pub const PAGE_HUGE: u64 = 1 << 7;
+pub const PAGE_GLOBAL: u64 = 1 << 8;
+pub const PAGE_NO_EXECUTE: u64 = 1 << 63;
+
+pub const KB4: usize = 4 * 1024;
+pub const MB2: usize = 2 * 1024 * 1024;
+
+// Helper to extract indices for 4-level paging
+fn split_virt_addr(addr: u64) -> (usize, usize, usize, usize) {
+    // Checked where addresses enter the loader, a non canonical one here would silently alias another PML4 slot
+    if !is_canonical(addr) {
+        write_string(b"Non canonical address ");
+        write_addr(addr);
+        write_string(b" reached the page mapper !\r\n");
+        kpanic();
+    }
+    let pml4 = ((addr >> 39) & 0x1FF) as usize;
+    let pdpt = ((addr >> 30) & 0x1FF) as usize;
+    let pd = ((addr >> 21) & 0x1FF) as usize;
+    let pt = ((addr >> 12) & 0x1FF) as usize;
+    (pml4, pdpt, pd, pt)
+}
+
+/// Whether bits 63:48 are copies of bit 47, as required by 4-level paging
+pub fn is_canonical(addr: u64) -> bool {
+    (((addr << 16) as i64) >> 16) as u64 == addr
+}
+
+/// First address of the higher half, where kernel-space placements must be
+pub const HIGHER_HALF_START: u64 = 0xFFFF_8000_0000_0000;
+
+/// Hands out the pages of the page tables arena, bottom up
+struct SimpleArenaAllocator {
+    end: usize,
+    current: usize,
+}
+
+impl SimpleArenaAllocator {
+    fn new(start: usize, end: usize) -> SimpleArenaAllocator {
+        SimpleArenaAllocator {
+            end,
+            current: start,
+        }
+    }
+
+    fn alloc(&mut self, size: usize) -> Option<usize> {
+        if self.current + size > self.end {
+            None
+        } else {
+            let ptr = self.current;
+            self.current += size;
+            Some(ptr)
+        }
+    }
+}
+
+// Align address down to nearest 4 KiB or 2 MiB
+pub(crate) fn align_down(addr: u64, align: u64) -> u64 {
+    addr & !(align - 1)
+}
+
+/// Physical address bits of a table entry
+const ENTRY_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+/// Physical address bits of a 2MiB page entry
+const ENTRY_ADDRESS_MASK_2MB: u64 = 0x000F_FFFF_FFE0_0000;
+/// Virtual memory covered by a PML4 entry and by a PDPT entry
+const PML4_ENTRY_SPAN: u64 = 1 << 39;
+const PDPT_ENTRY_SPAN: u64 = 1 << 30;
+
+/// First address of the `span` aligned block after the one holding `virt`, jumping over the canonical gap. <br>
+/// None past the end of the address space. <br>
+pub(crate) fn next_block(virt: u64, span: u64) -> Option<u64> {
+    let next = align_down(virt, span).checked_add(span)?;
+    Some(if is_canonical(next) {
+        next
+    } else {
+        next.max(HIGHER_HALF_START)
+    })
+}
+
+/// The PML4 being built and the arena its tables are allocated from. <br>
+/// Tables are only reached from their physical address through [`PageTableEditor::table`], no walk assumes how they are accessed. <br>
+pub struct PageTableEditor {
+    /// Physical address of the PML4
+    pml4: u64,
+    allocator: SimpleArenaAllocator,
+}
+
+impl PageTableEditor {
+    /// Allocates the PML4 from the arena `[arena_start, arena_end)`. <br>
+    /// # Safety
+    /// The arena must be page aligned memory nothing else uses, tables are written there until the jump <br>
+    pub unsafe fn new(arena_start: usize, arena_end: usize) -> PageTableEditor {
+        let mut editor = PageTableEditor {
+            pml4: 0,
+            allocator: SimpleArenaAllocator::new(arena_start, arena_end),
+        };
+        editor.pml4 = editor.alloc_table();
+        editor
+    }
+
+    pub fn pml4(&self) -> u64 {
+        self.pml4
+    }
+
+    /// `(start, end)` of the arena space no table uses yet
+    pub fn arena_free(&self) -> (usize, usize) {
+        (self.allocator.current, self.allocator.end)
+    }
+
+    /// Pointer to the table at physical address `phys`. <br>
+    /// Stage2 runs without paging, physical addresses are used as is. So do the unit tests, their arena is a host buffer. <br>
+    fn table(&self, phys: u64) -> *mut u64 {
+        phys as *mut u64
+    }
+
+    fn entry(&self, table: u64, index: usize) -> *mut u64 {
+        unsafe { self.table(table).add(index) }
+    }
+
+    /// A zeroed page for a new table, halts when the arena is full
+    fn alloc_table(&mut self) -> u64 {
+        let addr = self.allocator.alloc(PAGE_SIZE).unwrap_or_else(|| {
+            write_string(b"Failed to alloc a page table, the arena is full\r\n");
+            kpanic();
+        }) as u64;
+        unsafe {
+            core::ptr::write_bytes(self.table(addr) as *mut u8, 0, PAGE_SIZE);
+        }
+        addr
+    }
+
+    /// The table `entry` points to, allocated and linked when missing. <br>
+    /// A 2MiB page in a PD entry is split into a page table mapping the same range, 1GiB pages are never created. <br>
+    fn next_table(&mut self, entry: *mut u64) -> u64 {
+        unsafe {
+            if *entry & PAGE_PRESENT != 0 && *entry & PAGE_HUGE == 0 {
+                return *entry & ENTRY_ADDRESS_MASK;
+            }
+            let new = self.alloc_table();
+            if *entry & PAGE_PRESENT != 0 {
+                // A 2MiB page is there, split it so the rest of its range stays mapped
+                let base = *entry & ENTRY_ADDRESS_MASK_2MB;
+                let flags = *entry & (PAGE_NO_EXECUTE | 0xFFF) & !PAGE_HUGE;
+                for i in 0..512 {
+                    *self.entry(new, i) = (base + (i * PAGE_SIZE) as u64) | flags;
+                }
+            }
+            *entry = new | PAGE_PRESENT | PAGE_RW;
+            new
+        }
+    }
+
+    /// The PD entry covering `virt`, or the span of the first missing table on the way
+    fn find_pd_entry(&self, virt: u64) -> Result<*mut u64, u64> {
+        let (pml4_idx, pdpt_idx, pd_idx, _) = split_virt_addr(virt);
+        let pml4_entry = unsafe { *self.entry(self.pml4, pml4_idx) };
+        if pml4_entry & PAGE_PRESENT == 0 {
+            return Err(PML4_ENTRY_SPAN);
+        }
+        let pdpt_entry = unsafe { *self.entry(pml4_entry & ENTRY_ADDRESS_MASK, pdpt_idx) };
+        if pdpt_entry & PAGE_PRESENT == 0 {
+            return Err(PDPT_ENTRY_SPAN);
+        }
+        Ok(self.entry(pdpt_entry & ENTRY_ADDRESS_MASK, pd_idx))
+    }
+
+    pub fn map_4kb(&mut self, virt: u64, phys: u64, flags: u64) {
+        let (pml4_idx, pdpt_idx, pd_idx, pt_idx) = split_virt_addr(virt);
+        let pdpt = self.next_table(self.entry(self.pml4, pml4_idx));
+        let pd = self.next_table(self.entry(pdpt, pdpt_idx));
+        let pt = self.next_table(self.entry(pd, pd_idx));
+        unsafe {
+            *self.entry(pt, pt_idx) = align_down(phys, PAGE_SIZE as u64) | flags | PAGE_PRESENT;
+        }
+    }
+
+    pub fn map_2mb(&mut self, virt: u64, phys: u64, flags: u64) {
+        let (pml4_idx, pdpt_idx, pd_idx, _) = split_virt_addr(virt);
+        let pdpt = self.next_table(self.entry(self.pml4, pml4_idx));
+        let pd = self.next_table(self.entry(pdpt, pdpt_idx));
+        let pd_entry = self.entry(pd, pd_idx);
+        unsafe {
+            if *pd_entry & PAGE_PRESENT != 0 && *pd_entry & PAGE_HUGE == 0 {
+                // Replacing the page table would silently drop its 4KiB mappings
+                write_string(b"2MiB page at ");
+                write_addr(virt);
+                write_string(b" overlaps 4KiB mappings !\r\n");
+                kpanic();
+            }
+            *pd_entry = align_down(phys, PAGE_SIZE_2MB as u64) | flags | PAGE_PRESENT | PAGE_HUGE;
+        }
+    }
+
+    /// Unmaps every page of `[virt, virt + size)`, a 2MiB page partly in the range is split first. <br>
+    /// Emptied tables stay allocated. No TLB is flushed, the tables aren't live while stage2 edits them. <br>
+    pub fn unmap(&mut self, virt: u64, size: u64) {
+        let end = virt.saturating_add(size);
+        let mut virt = align_down(virt, KB4 as u64);
+        while virt < end {
+            let span = match self.find_pd_entry(virt) {
+                Err(span) => span,
+                Ok(pd_entry) => unsafe {
+                    if *pd_entry & PAGE_PRESENT == 0 {
+                        MB2 as u64
+                    } else if *pd_entry & PAGE_HUGE != 0
+                        && virt.is_multiple_of(MB2 as u64)
+                        && end - virt >= MB2 as u64
+                    {
+                        *pd_entry = 0;
+                        MB2 as u64
+                    } else {
+                        let pt = self.next_table(pd_entry);
+                        *self.entry(pt, split_virt_addr(virt).3) = 0;
+                        KB4 as u64
+                    }
+                },
+            };
+            let Some(next) = next_block(virt, span) else {
+                break;
+            };
+            virt = next;
+        }
+    }
+
+    /// Where `virt` is mapped, as `(physical address, entry flags, page size)`
+    pub fn query(&self, virt: u64) -> Option<(u64, u64, u64)> {
+        let pd_entry = unsafe { *self.find_pd_entry(virt).ok()? };
+        if pd_entry & PAGE_PRESENT == 0 {
+            return None;
+        }
+        if pd_entry & PAGE_HUGE != 0 {
+            return Some((
+                (pd_entry & ENTRY_ADDRESS_MASK_2MB) + (virt & (MB2 as u64 - 1)),
+                pd_entry & !ENTRY_ADDRESS_MASK_2MB,
+                MB2 as u64,
+            ));
+        }
+        let pt_idx = split_virt_addr(virt).3;
+        let pt_entry = unsafe { *self.entry(pd_entry & ENTRY_ADDRESS_MASK, pt_idx) };
+        if pt_entry & PAGE_PRESENT == 0 {
+            return None;
+        }
+        Some((
+            (pt_entry & ENTRY_ADDRESS_MASK) + (virt & (KB4 as u64 - 1)),
+            pt_entry & !ENTRY_ADDRESS_MASK,
+            KB4 as u64,
+        ))
+    }
+
+    /// Returns the physical address `virt` is mapped to, if it is mapped
+    pub fn translate(&self, virt: u64) -> Option<u64> {
+        self.query(virt).map(|(phys, _, _)| phys)
+    }
+
+    /// Checks that every page of `[virt_start, virt_end)` maps to the same offset of the physical range at `phys_start`. <br>
+    /// Returns the first address that doesn't, with the physical address it maps to if any. <br>
+    pub fn verify_mapping(
+        &self,
+        virt_start: u64,
+        virt_end: u64,
+        phys_start: u64,
+    ) -> Result<(), (u64, Option<u64>)> {
+        let mut virt = virt_start;
+        while virt < virt_end {
+            let Some((phys, _, page_size)) = self.query(virt) else {
+                return Err((virt, None));
+            };
+            if phys != phys_start + (virt - virt_start) {
+                return Err((virt, Some(phys)));
+            }
+            let Some(next) = next_block(virt, page_size) else {
+                break;
+            };
+            virt = next;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{self, Layout};
+
+    pub(super) fn kpanic() -> ! {
+        panic!("kpanic");
+    }
+
+    pub(super) fn write_string(_string: &[u8]) {}
+
+    pub(super) fn write_addr(_addr: u64) {}
+
+    /// Page aligned host memory standing in for the page tables arena
+    struct Arena {
+        start: *mut u8,
+        layout: Layout,
+    }
+
+    impl Arena {
+        fn new(pages: usize) -> Self {
+            let layout = Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap();
+            let start = unsafe { alloc::alloc(layout) };
+            assert!(!start.is_null());
+            Self { start, layout }
+        }
+
+        fn editor(&self) -> PageTableEditor {
+            let start = self.start as usize;
+            unsafe { PageTableEditor::new(start, start + self.layout.size()) }
+        }
+    }
+
+    impl Drop for Arena {
+        fn drop(&mut self) {
+            unsafe { alloc::dealloc(self.start, self.layout) };
+        }
+    }
+
+    const KERNEL_BASE: u64 = 0xFFFF_FFFF_8000_0000;
+    const PHYS: u64 = 0x4000_0000;
+    const FLAGS: u64 = PAGE_RW | PAGE_NO_EXECUTE;
+    /// Last 4KiB page below the canonical gap
+    const LOWER_HALF_LAST_PAGE: u64 = 0x0000_7FFF_FFFF_F000;
+
+    #[test]
+    fn query_reports_the_page_size() {
+        let arena = Arena::new(8);
+        let mut editor = arena.editor();
+        editor.map_2mb(KERNEL_BASE, PHYS, FLAGS);
+        editor.map_4kb(KERNEL_BASE + MB2 as u64, PHYS + 0x10_0000, FLAGS);
+
+        let (phys, flags, size) = editor.query(KERNEL_BASE + 0x1_2345).unwrap();
+        assert_eq!((phys, size), (PHYS + 0x1_2345, MB2 as u64));
+        assert_eq!(
+            flags & (FLAGS | PAGE_HUGE | PAGE_PRESENT),
+            FLAGS | PAGE_HUGE | PAGE_PRESENT
+        );
+
+        let (phys, flags, size) = editor.query(KERNEL_BASE + MB2 as u64 + 0x345).unwrap();
+        assert_eq!((phys, size), (PHYS + 0x10_0345, KB4 as u64));
+        assert_eq!(
+            flags & (FLAGS | PAGE_HUGE | PAGE_PRESENT),
+            FLAGS | PAGE_PRESENT
+        );
+
+        assert_eq!(editor.query(KERNEL_BASE + MB2 as u64 + KB4 as u64), None);
+        assert_eq!(editor.query(KERNEL_BASE - KB4 as u64), None);
+        assert_eq!(editor.query(0), None);
+    }
+
+    #[test]
+    fn unmap_splits_a_huge_page_partly_in_the_range() {
+        let arena = Arena::new(8);
+        let mut editor = arena.editor();
+        editor.map_2mb(KERNEL_BASE, PHYS, FLAGS);
+        editor.unmap(KERNEL_BASE + 0x3000, 0x2000);
+
+        assert_eq!(editor.query(KERNEL_BASE + 0x3000), None);
+        assert_eq!(editor.query(KERNEL_BASE + 0x4fff), None);
+        for offset in [0, 0x2fff, 0x5000, MB2 as u64 - 1] {
+            let (phys, flags, size) = editor.query(KERNEL_BASE + offset).unwrap();
+            assert_eq!((phys, size), (PHYS + offset, KB4 as u64));
+            assert_eq!(flags & (FLAGS | PAGE_HUGE), FLAGS);
+        }
+        assert_eq!(
+            editor.verify_mapping(KERNEL_BASE, KERNEL_BASE + 0x3000, PHYS),
+            Ok(())
+        );
+        assert_eq!(
+            editor.verify_mapping(KERNEL_BASE, KERNEL_BASE + MB2 as u64, PHYS),
+            Err((KERNEL_BASE + 0x3000, None))
+        );
+    }
+
+    #[test]
+    fn unmap_of_a_whole_huge_page_allocates_no_table() {
+        let arena = Arena::new(8);
+        let mut editor = arena.editor();
+        editor.map_2mb(KERNEL_BASE, PHYS, FLAGS);
+        editor.map_2mb(KERNEL_BASE + MB2 as u64, PHYS + MB2 as u64, FLAGS);
+        let free = editor.arena_free();
+        editor.unmap(KERNEL_BASE, MB2 as u64);
+
+        assert_eq!(editor.arena_free(), free);
+        assert_eq!(editor.query(KERNEL_BASE), None);
+        assert_eq!(editor.query(KERNEL_BASE + MB2 as u64 - 1), None);
+        let (phys, _, size) = editor.query(KERNEL_BASE + MB2 as u64).unwrap();
+        assert_eq!((phys, size), (PHYS + MB2 as u64, MB2 as u64));
+    }
+
+    #[test]
+    fn next_block_jumps_the_canonical_gap() {
+        assert_eq!(next_block(0x1234, KB4 as u64), Some(0x2000));
+        assert_eq!(next_block(0x20_0000, MB2 as u64), Some(0x40_0000));
+        assert_eq!(
+            next_block(LOWER_HALF_LAST_PAGE, KB4 as u64),
+            Some(HIGHER_HALF_START)
+        );
+        assert_eq!(
+            next_block(0x0000_7F80_0000_0000, PML4_ENTRY_SPAN),
+            Some(HIGHER_HALF_START)
+        );
+        assert_eq!(
+            next_block(HIGHER_HALF_START, PML4_ENTRY_SPAN),
+            Some(HIGHER_HALF_START + PML4_ENTRY_SPAN)
+        );
+        assert_eq!(next_block(0xFFFF_FFFF_FFFF_F000, KB4 as u64), None);
+    }
+
+    #[test]
+    fn unmap_walks_across_the_canonical_gap() {
+        let arena = Arena::new(16);
+        let mut editor = arena.editor();
+        editor.map_4kb(LOWER_HALF_LAST_PAGE, PHYS, FLAGS);
+        editor.map_4kb(HIGHER_HALF_START, PHYS + 0x1000, FLAGS);
+        editor.map_4kb(HIGHER_HALF_START + 0x1000, PHYS + 0x2000, FLAGS);
+
+        editor.unmap(
+            LOWER_HALF_LAST_PAGE,
+            HIGHER_HALF_START + 0x1000 - LOWER_HALF_LAST_PAGE,
+        );
+        assert_eq!(editor.query(LOWER_HALF_LAST_PAGE), None);
+        assert_eq!(editor.query(HIGHER_HALF_START), None);
+        assert_eq!(
+            editor.translate(HIGHER_HALF_START + 0x1000),
+            Some(PHYS + 0x2000)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "kpanic")]
+    fn non_canonical_addresses_are_refused() {
+        let arena = Arena::new(4);
+        let mut editor = arena.editor();
+        editor.map_4kb(0x0000_8000_0000_0000, PHYS, FLAGS);
+    }
+}
@@ -1,15 +1,42 @@
-use core::ptr::addr_of;
+use core::{arch::asm, cell::SyncUnsafeCell, cmp::Ordering, slice};
 
 use crate::{
-    e9::write_u32_decimal,
-    elf::{ElfError, ElfFile64, SEGMENT_TYPE_LOAD},
-    gdt::{init_gdtr, CODE64_SELECTOR, DATA64_SELECTOR},
+    addr::{register_kernel_segment, register_region, stage2_image, write_addr, write_addr_range},
+    bios::{bios_buffers, ebda_range},
+    cpu_features::enable_paging_features,
+    e9::{write_string, write_u32_decimal},
+    elf::{
+        check_segment_file_size, ElfError, ElfFile32, ElfFile64, ElfProgramHeader32,
+        ElfProgramHeader64, FLAG_EXECUTABLE, SEGMENT_TYPE_LOAD,
+    },
+    fs::Ext2File,
+    gdt::{init_gdtr, CODE32_SELECTOR, CODE64_SELECTOR, DATA32_SELECTOR, DATA64_SELECTOR},
+    install::{BOOTLOADER_VERSION, BUILD_ID},
     kpanic,
-    mem::{self, Buffer, Vec, RANGE_TYPE_AVAILABLE, SYSTEM_MEMORY_MAP, USED_MAP},
-    obsiboot::ObsiBootKernelParameters,
+    lang::{render, Text},
+    log::{self, LogLevel},
+    log_debug, log_warn,
+    media::boot_media,
+    mem::{self, Buffer, SystemMemory, SystemMemoryMap, Vec, RANGE_TYPE_AVAILABLE},
+    memlayout::{normalize, usable_bytes, MemoryRegion, MemoryRegionType, RegionList},
+    multiboot2::{BootInformation, Multiboot2Kernel},
+    obsiboot::{
+        MemoryReservation, ObsiBootKernelParameters, BOOT_FLAG_NULL_PAGE_UNMAPPED,
+        BOOT_FLAG_SAFE_MODE, MAX_RESERVATIONS, RAW_MEMORY_MAP_EXTENDED_ATTRIBUTES,
+    },
+    pagetable::{
+        align_down, is_canonical, PageTableEditor, HIGHER_HALF_START, KB4, MB2, PAGE_CACHE_DISABLE,
+        PAGE_RW, PAGE_SIZE, PAGE_WRITE_THROUGH,
+    },
+    pause::pause_before_jump,
+    pci::PciDeviceInfo,
+    post::{codes, post_code, post_code_progress},
     printf,
-    vesa::get_vbe_boot_info,
+    stream::{tee, BufferSink, MemorySink},
+    timing::{checkpoint, printf_boot_timing, ProgressBar},
     video::Video,
+    warnings::{enforce_strict_boot, warning, WarningId},
+    BootState,
 };
 
 extern "cdecl" {
@@ -21,110 +48,142 @@ extern "cdecl" {
         stack_pointer: u64,
         obsiboot_kernel_parameters: usize,
     ) -> !;
-}
 
-#[derive(Copy, Clone)]
-pub struct MemoryRegion {
-    start: u64,
-    end: u64,
-    kind: MemoryRegionType,
+    fn jump32(
+        data_selector: usize,
+        code_selector: usize,
+        entry: u32,
+        stack_pointer: u32,
+        obsiboot_kernel_parameters: usize,
+    ) -> !;
+
+    fn jump_multiboot2(
+        data_selector: usize,
+        code_selector: usize,
+        entry: u32,
+        boot_information: u32,
+    ) -> !;
 }
 
 #[repr(C, packed)]
 pub struct OsMemoryRegion {
     start: u64,
     end: u64,
+    /// One of [`REGION_RESERVED`], [`REGION_USABLE`] and [`REGION_RECLAIMABLE`]
     usable: u64,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
-pub enum MemoryRegionType {
-    Usable,
-    Reserved,
-}
+/// `OsMemoryRegion.usable` values
+pub const REGION_RESERVED: u64 = 0;
+pub const REGION_USABLE: u64 = 1;
+/// Used by the bootloader until the kernel is entered, usable once the kernel no longer needs anything from the bootloader
+pub const REGION_RECLAIMABLE: u64 = 2;
 
-impl MemoryRegionType {
-    fn strictest(&self, other: &MemoryRegionType) -> MemoryRegionType {
-        match (self, other) {
-            (MemoryRegionType::Usable, MemoryRegionType::Usable) => MemoryRegionType::Usable,
-            _ => MemoryRegionType::Reserved,
-        }
+impl RegionList for Vec<MemoryRegion> {
+    fn with_capacity(capacity: usize) -> Self {
+        Vec::new_tagged(capacity, b"paging")
     }
-}
 
-fn overlapping_pass(layout: Vec<MemoryRegion>) -> (Vec<MemoryRegion>, bool) {
-    let mut had_overlap = false;
-    let mut fixed_layout: Vec<MemoryRegion> = Vec::new(layout.len());
-    for region in layout.iter() {
-        let current = *region;
-        let mut i = 0;
-        while i < fixed_layout.len() {
-            let existing = fixed_layout.get(i).copied().unwrap_or_else(|| kpanic());
+    fn regions(&self) -> &[MemoryRegion] {
+        self.as_slice()
+    }
 
-            if current.end <= existing.start || current.start >= existing.end {
-                i += 1;
-                continue;
-            }
+    fn push(&mut self, region: MemoryRegion) {
+        Vec::push(self, region);
+    }
 
-            had_overlap = true;
-
-            // Overlap detected
-            let min_start = current.start.min(existing.start);
-            let max_end = current.end.max(existing.end);
-
-            // Break into three parts: left, overlap, right
-            if min_start < current.start {
-                fixed_layout.insert(
-                    i,
-                    MemoryRegion {
-                        start: min_start,
-                        end: current.start,
-                        kind: existing.kind,
-                    },
-                );
-                i += 1;
-            }
+    fn insert(&mut self, index: usize, region: MemoryRegion) {
+        Vec::insert(self, index, region);
+    }
 
-            let overlap_start = current.start.max(existing.start);
-            let overlap_end = current.end.min(existing.end);
-            fixed_layout.insert(
-                i,
-                MemoryRegion {
-                    start: overlap_start,
-                    end: overlap_end,
-                    kind: current.kind.strictest(&existing.kind), // overlap = reserved wins
-                },
-            );
-            i += 1;
-
-            if current.end < max_end {
-                fixed_layout.insert(
-                    i,
-                    MemoryRegion {
-                        start: current.end,
-                        end: max_end,
-                        kind: existing.kind,
-                    },
-                );
-            }
+    fn remove(&mut self, index: usize) {
+        Vec::remove(self, index);
+    }
 
-            break;
-        }
+    fn sort_by_start(&mut self) {
+        self.sort_by(|a, b| a.start.cmp(&b.start));
+    }
+}
 
-        if i == fixed_layout.len() {
-            fixed_layout.push(current);
+impl MemoryRegionType {
+    fn os_value(&self) -> u64 {
+        match self {
+            MemoryRegionType::Usable => REGION_USABLE,
+            MemoryRegionType::BootloaderReclaimable => REGION_RECLAIMABLE,
+            MemoryRegionType::Reserved => REGION_RESERVED,
         }
     }
+}
 
-    (fixed_layout, had_overlap)
+/// Stage1 points ESP here before calling into stage2, the stack grows down from it
+const STAGE2_STACK_TOP: u64 = 0x7C00;
+/// Kept below the current ESP for the calls made until the jump to the kernel
+const STAGE2_STACK_HEADROOM: u64 = 16 * 1024;
+/// End of the BIOS data area, the stack never goes below it
+const BDA_END: u64 = 0x500;
+
+/// `(start, end)` of the stack stage2 runs on
+fn stage2_stack() -> (u64, u64) {
+    let esp: u32;
+    unsafe { asm!("mov {}, esp", out(reg) esp) };
+    let bottom = align_down(
+        (esp as u64).saturating_sub(STAGE2_STACK_HEADROOM),
+        KB4 as u64,
+    );
+    (bottom.max(BDA_END), STAGE2_STACK_TOP)
 }
 
-fn parse_memory_layout() -> Vec<MemoryRegion> {
-    let mut layout: Vec<MemoryRegion> = unsafe {
-        #[allow(static_mut_refs)]
-        let mut v = Vec::new(SYSTEM_MEMORY_MAP.len());
-        #[allow(static_mut_refs)]
-        for map in SYSTEM_MEMORY_MAP.iter() {
+/// What the bootloader still needs until the jump, the kernel must not be told it's usable. <br>
+/// The BIOS memory map usually covers the EBDA but says nothing about the rest, which lies in conventional memory reported as available. <br>
+fn bootloader_regions() -> Vec<MemoryRegion> {
+    let mut regions = Vec::new_tagged(5, b"paging");
+    let (image_start, image_end) = stage2_image();
+    let (stack_start, stack_end) = stage2_stack();
+    let [buff, transfer_buff] = bios_buffers();
+    for (start, end) in [
+        (image_start, image_end),
+        (stack_start, stack_end),
+        buff,
+        transfer_buff,
+    ] {
+        regions.push(MemoryRegion {
+            start,
+            end,
+            kind: MemoryRegionType::BootloaderReclaimable,
+        });
+    }
+    if let Some((start, end)) = ebda_range() {
+        regions.push(MemoryRegion {
+            start,
+            end,
+            kind: MemoryRegionType::Reserved,
+        });
+    }
+    regions
+}
+
+/// Builds the sorted, non overlapping layout from the BIOS memory map, with the `reserve=` ranges and [`bootloader_regions`] carved out of the usable regions
+fn parse_memory_layout(
+    memory: &SystemMemory,
+    reservations: &[MemoryReservation],
+) -> Vec<MemoryRegion> {
+    let bootloader = bootloader_regions();
+    let layout: Vec<MemoryRegion> = {
+        let mut v = Vec::new_tagged(
+            memory.entries().len() + reservations.len() + bootloader.len(),
+            b"paging",
+        );
+        for region in bootloader.iter() {
+            v.push(*region);
+        }
+        for reservation in reservations {
+            v.push(MemoryRegion {
+                start: reservation.start,
+                end: reservation.end,
+                kind: MemoryRegionType::Reserved,
+            });
+        }
+        for map in memory.enabled_entries() {
             if map.is_null() {
                 continue;
             }
@@ -138,125 +197,444 @@ fn parse_memory_layout() -> Vec<MemoryRegion> {
                 },
             });
         }
-        // 64 elements is small enough to not bother implementing quicksort (sorry)
-        v.bubble_sort(|a, b| {
-            if a.start < b.start {
-                -1
-            } else if a.start > b.start {
-                1
-            } else {
-                0
-            }
-        });
         v
     };
+    normalize(layout)
+}
+
+/// Clips the usable regions of `layout` so that at most `limit` bytes are usable, keeping the lowest ones. <br>
+/// Truncated regions end on a 2MiB boundary when possible, on a 4KiB boundary otherwise. Reserved regions are never clipped. <br>
+fn clip_memory_layout(layout: Vec<MemoryRegion>, limit: u64) -> Vec<MemoryRegion> {
+    let mut clipped = Vec::new_tagged(layout.len().max(1), b"paging");
+    let mut remaining = limit;
+    for region in layout.iter() {
+        if region.kind != MemoryRegionType::Usable {
+            clipped.push(*region);
+            continue;
+        }
+        let len = region.end - region.start;
+        if len <= remaining {
+            remaining -= len;
+            clipped.push(*region);
+            continue;
+        }
+        let end = region.start + remaining;
+        let end = if align_down(end, MB2 as u64) > region.start {
+            align_down(end, MB2 as u64)
+        } else {
+            align_down(end, KB4 as u64)
+        };
+        remaining = 0;
+        if end > region.start {
+            clipped.push(MemoryRegion {
+                start: region.start,
+                end,
+                kind: region.kind,
+            });
+        }
+    }
+    clipped
+}
+
+/// Returns the end of the highest usable region once the layout is clipped to `limit` bytes of usable memory
+pub fn memory_limit_end(
+    memory: &SystemMemory,
+    reservations: &[MemoryReservation],
+    limit: u64,
+) -> u64 {
+    clip_memory_layout(parse_memory_layout(memory, reservations), limit)
+        .iter()
+        .filter(|r| r.kind == MemoryRegionType::Usable)
+        .map(|r| r.end)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Checks that the entry point lies in an executable LOAD segment and is mapped by `pml4`, then prints the code found there. <br>
+/// # Safety
+/// The tables of `editor` must be fully built, with the pages they map identity accessible <br>
+unsafe fn validate_entry_point(
+    entry: u64,
+    phs: &Vec<ElfProgramHeader64>,
+    editor: &PageTableEditor,
+) -> Result<(), ElfError> {
+    let Some((i, ph)) = phs.iter().enumerate().find(|(_, ph)| {
+        ph.segment_type == SEGMENT_TYPE_LOAD
+            && entry >= ph.p_vaddr
+            && entry - ph.p_vaddr < ph.p_memsz
+    }) else {
+        printf!(b"Entry point check failed: e_entry=");
+        write_addr(entry);
+        printf!(b", loaded segments:\r\n");
+        for ph in phs.iter().filter(|ph| ph.segment_type == SEGMENT_TYPE_LOAD) {
+            printf!(b"    ");
+            write_addr_range(ph.p_vaddr, ph.p_vaddr + ph.p_memsz);
+            printf!(b" flags=0x%x\r\n", ph.flags);
+        }
+        return Err(ElfError::EntryOutsideSegments(entry));
+    };
+
+    printf!(b"Entry point ");
+    write_addr(entry);
+    printf!(b" in segment %x ", i as u32);
+    write_addr_range(ph.p_vaddr, ph.p_vaddr + ph.p_memsz);
+    printf!(b" flags=0x%x\r\n", ph.flags);
+
+    if ph.flags & FLAG_EXECUTABLE == 0 {
+        printf!(b"Entry point check failed: segment is not executable\r\n");
+        return Err(ElfError::EntryNotExecutable(entry, i));
+    }
+
+    // The bytes at the entry may straddle a page boundary, check both pages
+    let (Some(phys), Some(_)) = (editor.translate(entry), editor.translate(entry + 15)) else {
+        printf!(b"Entry point check failed: entry is not mapped by the page tables\r\n");
+        return Err(ElfError::EntryNotMapped(entry));
+    };
 
-    let ok_layout = loop {
-        let (new_layout, had_overlap) = overlapping_pass(layout);
-        if !had_overlap {
-            break new_layout;
+    printf!(b"Code at entry:");
+    for j in 0..16u64 {
+        let phys = if (entry + j) % (KB4 as u64) < entry % (KB4 as u64) {
+            editor.translate(entry + j).unwrap_or(phys + j)
+        } else {
+            phys + j
+        };
+        printf!(b" %b", *(phys as *const u8));
+    }
+    printf!(b"\r\n");
+    Ok(())
+}
+
+/// A range to map, decided before any page table is allocated, or one the kernel loader mapped
+#[derive(Clone, Copy)]
+struct PlannedMapping {
+    virt: u64,
+    phys: u64,
+    len: u64,
+    page_size: u64,
+    /// Entry flags, `PAGE_PRESENT` is always added. Unused for the kernel ranges, `load_kernel` maps those
+    flags: u64,
+}
+
+/// Extra pages in the page tables arena, on top of the computed count
+const PAGE_TABLES_SLACK: usize = 4;
+
+/// Identity and direct mappings of the first MiB and of every usable region. <br>
+/// Without `map_null_page` the first MiB starts at 0x1000, so null dereferences fault, and so do reads of the IVT and the BIOS data area. <br>
+fn plan_layout_mappings(layout: &Vec<MemoryRegion>, map_null_page: bool) -> Vec<PlannedMapping> {
+    let mut plan = Vec::new_tagged(layout.len() * 6 + 2, b"paging");
+    let mut push_both = |phys: u64, end: u64, page_size: u64| {
+        if phys >= end {
+            return;
+        }
+        for virt in [phys, phys + DIRECT_MAPPING_OFFSET] {
+            plan.push(PlannedMapping {
+                virt,
+                phys,
+                len: end - phys,
+                page_size,
+                flags: PAGE_RW,
+            });
         }
-        layout = new_layout;
     };
 
-    let mut done_layout = Vec::new(16);
+    // 256 * 4KiB = 1MiB
+    let low_start = if map_null_page { 0 } else { KB4 as u64 };
+    push_both(low_start, 0x100000, KB4 as u64);
+
+    for region in layout.iter() {
+        if region.kind != MemoryRegionType::Usable || region.start < (1024 * 1024) {
+            continue;
+        }
+        let aligned_start = align_up(region.start, MB2 as u64);
+        let aligned_end = align_down(region.end, MB2 as u64);
+        let kb4_aligned_start = align_up(region.start, KB4 as u64);
+        let kb4_aligned_end = align_down(region.end, KB4 as u64);
+
+        if aligned_start < aligned_end {
+            push_both(aligned_start, aligned_end, MB2 as u64);
+            push_both(kb4_aligned_start, aligned_start, KB4 as u64);
+            push_both(aligned_end, kb4_aligned_end, KB4 as u64);
+        } else {
+            push_both(kb4_aligned_start, kb4_aligned_end, KB4 as u64);
+        }
+    }
+    plan
+}
+
+/// Cache attributes of the framebuffer pages. Write-combining needs the PAT, which some CPUs lack, see `cpu_features`
+const FRAMEBUFFER_FLAGS: u64 = PAGE_RW | PAGE_WRITE_THROUGH | PAGE_CACHE_DISABLE;
+
+/// Identity and direct mappings of the framebuffer, which usually sits in a reserved MMIO range the layout mappings skip. <br>
+/// Uses 2MiB pages, or 4KiB pages when the 2MiB aligned range would also cover a usable region. <br>
+fn plan_framebuffer_mappings(
+    framebuffer: Option<(u64, u64)>,
+    layout: &Vec<MemoryRegion>,
+) -> Vec<PlannedMapping> {
+    let mut plan = Vec::new_tagged(2, b"paging");
+    let Some((addr, size)) = framebuffer else {
+        return plan;
+    };
+    if size == 0 {
+        return plan;
+    }
+    let overlaps_usable = |start: u64, end: u64| {
+        start < 0x100000
+            || layout.iter().any(|region| {
+                region.kind == MemoryRegionType::Usable && start < region.end && region.start < end
+            })
+    };
+    let (mut start, mut end, mut page_size) = (
+        align_down(addr, MB2 as u64),
+        align_up(addr + size, MB2 as u64),
+        MB2 as u64,
+    );
+    if overlaps_usable(start, end) {
+        (start, end, page_size) = (
+            align_down(addr, KB4 as u64),
+            align_up(addr + size, KB4 as u64),
+            KB4 as u64,
+        );
+    }
+    if overlaps_usable(start, end) {
+        printf!(b"Framebuffer ");
+        write_addr_range(addr, addr + size);
+        printf!(b" overlaps usable memory, not mapping it separately\r\n");
+        return plan;
+    }
+    for virt in [start, start + DIRECT_MAPPING_OFFSET] {
+        plan.push(PlannedMapping {
+            virt,
+            phys: start,
+            len: end - start,
+            page_size,
+            flags: FRAMEBUFFER_FLAGS,
+        });
+    }
+    plan
+}
 
-    let mut last_region = None;
+/// End of the physical range covered by the identity and direct mappings, the framebuffer included
+fn mapped_physical_end(layout: &Vec<MemoryRegion>, framebuffer: Option<(u64, u64)>) -> u64 {
+    let layout_end = layout.iter().map(|r| r.end).max().unwrap_or(0);
+    let framebuffer_end = framebuffer.map_or(0, |(addr, size)| addr + size);
+    align_up(layout_end.max(framebuffer_end), MB2 as u64)
+}
 
-    for region in ok_layout.iter() {
-        match last_region {
-            None => {
-                last_region = Some(*region);
-            }
-            Some(mut last) => {
-                if last.kind == region.kind && last.end == region.start {
-                    last.end = region.end;
-                } else {
-                    done_layout.push(last);
-                    last_region = Some(*region);
+/// Computes how many page tables (PML4 included) are needed to map every planned range
+fn count_page_tables(plans: &[&Vec<PlannedMapping>]) -> usize {
+    fn record(keys: &mut Vec<u64>, key: u64) {
+        if keys.iter().all(|k| *k != key) {
+            keys.push(key);
+        }
+    }
+    let mut pdpts = Vec::new_tagged(4, b"paging");
+    let mut pds = Vec::new_tagged(16, b"paging");
+    let mut pts = Vec::new_tagged(64, b"paging");
+
+    for plan in plans {
+        for mapping in plan.iter() {
+            let end = mapping.virt + mapping.len;
+            let mut virt = mapping.virt;
+            // Tables only change on 2MiB boundaries
+            while virt < end {
+                record(&mut pdpts, virt >> 39);
+                record(&mut pds, virt >> 30);
+                if mapping.page_size == KB4 as u64 {
+                    record(&mut pts, virt >> 21);
                 }
+                virt = align_down(virt, MB2 as u64) + MB2 as u64;
             }
         }
     }
+    1 + pdpts.len() + pds.len() + pts.len()
+}
 
-    if let Some(last) = last_region {
-        done_layout.push(last);
+fn execute_plan(editor: &mut PageTableEditor, plan: &Vec<PlannedMapping>) {
+    for mapping in plan.iter() {
+        let end = mapping.virt + mapping.len;
+        log::with_level(LogLevel::Trace, || {
+            if mapping.page_size == KB4 as u64 {
+                printf!(b"Mapping (4KiB pages) ");
+            } else {
+                printf!(b"Mapping (2MiB pages) ");
+            }
+            write_addr_range(mapping.virt, end);
+            if mapping.phys != mapping.virt {
+                printf!(b" from ");
+                write_addr(mapping.phys);
+            }
+            printf!(b"\r\n");
+        });
+        let mut offset = 0;
+        while offset < mapping.len {
+            if mapping.page_size == KB4 as u64 {
+                editor.map_4kb(mapping.virt + offset, mapping.phys + offset, mapping.flags);
+            } else {
+                editor.map_2mb(mapping.virt + offset, mapping.phys + offset, mapping.flags);
+            }
+            offset += mapping.page_size;
+        }
     }
-    done_layout
 }
 
-struct SimpleArenaAllocator {
-    end: usize,
+/// Streamed kernel frames are taken above this, clear of the low memory the BIOS and the bootloader use
+const FRAMES_MIN_ADDRESS: u64 = 16 * 1024 * 1024;
+/// Stage2 runs without paging, it can only fill frames below 4GiB
+const FRAMES_MAX_ADDRESS: u64 = 0x1_0000_0000;
+/// Heap memory left free past the allocations made so far when frames are taken above the heap, for the buffers the kernel load still allocates
+const FRAMES_HEAP_HEADROOM: u64 = 8 * 1024 * 1024;
+/// Ranges frames are taken from, reserving what was used in one splits its layout entry in at most 3
+const MAX_FRAME_RANGES: usize = 8;
+
+/// Hands out physical frames for `stream_kernel=`, bottom up from the usable memory of the layout. <br>
+/// The heap is first limited to [`FRAMES_HEAP_HEADROOM`] past its allocations, the frames come from usable memory outside it. <br>
+/// The frames handed out are reserved in the layout by [`FrameAllocator::reserve_used`]. <br>
+struct FrameAllocator {
+    ranges: [(u64, u64); MAX_FRAME_RANGES],
+    count: usize,
+    /// Index in `ranges` frames are taken from
     current: usize,
+    /// Next free address in `ranges[current]`
+    next: u64,
 }
 
-impl SimpleArenaAllocator {
-    fn new(start: usize, end: usize) -> SimpleArenaAllocator {
-        printf!(
-            b"Page tables arena allocator from 0x%x to 0x%x\r\n",
-            start,
-            end
-        );
-        SimpleArenaAllocator {
-            end,
-            current: start,
+impl FrameAllocator {
+    fn new(layout: &Vec<MemoryRegion>) -> FrameAllocator {
+        let mut frames = FrameAllocator {
+            ranges: [(0, 0); MAX_FRAME_RANGES],
+            count: 0,
+            current: 0,
+            next: 0,
+        };
+        // The heap region usually spans all the memory above 1MiB, frames can only come from past the part it still needs
+        let heap_needed =
+            align_up(mem::get_last_header() as u64, KB4 as u64) + FRAMES_HEAP_HEADROOM;
+        if !mem::limit_heap(heap_needed) {
+            printf!(b"Kernel frames: the heap can't be limited, frames are only taken outside of it\r\n");
         }
+        let heap = mem::heap_region();
+        for region in layout.iter() {
+            if region.kind != MemoryRegionType::Usable {
+                continue;
+            }
+            let start = align_up(region.start.max(FRAMES_MIN_ADDRESS), KB4 as u64);
+            let end = align_down(region.end.min(FRAMES_MAX_ADDRESS), KB4 as u64);
+            match heap {
+                Some((heap_start, heap_end)) if heap_start < end && start < heap_end => {
+                    frames.add(start, align_down(heap_start, KB4 as u64));
+                    frames.add(align_up(heap_end, KB4 as u64), end);
+                }
+                _ => frames.add(start, end),
+            }
+        }
+        frames.next = frames.ranges[0].0;
+        log::with_level(LogLevel::Debug, || {
+            for (start, end) in frames.ranges[..frames.count].iter() {
+                printf!(b"Kernel frames: ");
+                write_addr_range(*start, *end);
+                printf!(b"\r\n");
+            }
+        });
+        frames
     }
 
-    fn alloc(&mut self, size: usize) -> Option<usize> {
-        if self.current + size > self.end {
-            None
-        } else {
-            let ptr = self.current;
-            self.current += size;
-            Some(ptr)
+    fn add(&mut self, start: u64, end: u64) {
+        if start < end && self.count < MAX_FRAME_RANGES {
+            self.ranges[self.count] = (start, end);
+            self.count += 1;
         }
     }
 
-    fn alloc_page(&mut self) -> *mut u64 {
-        let addr = self.alloc(PAGE_SIZE).unwrap_or_else(|| {
-            printf!(b"Failed to alloc page (size = 0x%x)\r\n", PAGE_SIZE);
-            kpanic();
-        });
-        unsafe {
-            core::ptr::write_bytes(addr as *mut u8, 0, PAGE_SIZE);
+    /// A 4KiB frame, from the next range once the current one is full
+    fn alloc_page(&mut self) -> Option<u64> {
+        while self.current < self.count {
+            if self.next + KB4 as u64 <= self.ranges[self.current].1 {
+                let frame = self.next;
+                self.next += KB4 as u64;
+                return Some(frame);
+            }
+            self.current += 1;
+            self.next = self.ranges.get(self.current).map_or(0, |range| range.0);
         }
-        addr as *mut u64
+        None
     }
-}
 
-static mut PML4: *mut u64 = core::ptr::null_mut();
+    /// A 2MiB aligned frame from the current range, None without moving on when it doesn't fit
+    fn alloc_huge_page(&mut self) -> Option<u64> {
+        let (_, end) = *self.ranges[..self.count].get(self.current)?;
+        let frame = align_up(self.next, MB2 as u64);
+        if frame + MB2 as u64 > end {
+            return None;
+        }
+        self.next = frame + MB2 as u64;
+        Some(frame)
+    }
 
-pub const PAGE_SIZE: usize = 4096;
-pub const PAGE_SIZE_2MB: usize = 2 * 1024 * 1024;
+    /// Marks the frames handed out reserved in `layout`, the gaps left by aligning 2MiB frames included
+    fn reserve_used(&self, layout: &mut Vec<MemoryRegion>) {
+        let mut regions = Vec::new_tagged(layout.len() + self.count, b"paging");
+        for region in layout.iter() {
+            regions.push(*region);
+        }
+        for (i, (start, end)) in self.ranges[..self.count].iter().enumerate() {
+            let used_end = match i.cmp(&self.current) {
+                Ordering::Less => *end,
+                Ordering::Equal => self.next,
+                Ordering::Greater => break,
+            };
+            if *start < used_end {
+                register_region(*start, used_end, b"kernel frames");
+                regions.push(MemoryRegion {
+                    start: *start,
+                    end: used_end,
+                    kind: MemoryRegionType::Reserved,
+                });
+            }
+        }
+        *layout = normalize(regions);
+    }
+}
 
-// Page Table Entry Flags
-pub const PAGE_PRESENT: u64 = 1 << 0;
-pub const PAGE_RW: u64 = 1 << 1;
-pub const PAGE_USER: u64 = 1 << 2;
-pub const PAGE_WRITE_THROUGH: u64 = 1 << 3;
-pub const PAGE_CACHE_DISABLE: u64 = 1 << 4;
-pub const PAGE_ACCESSED: u64 = 1 << 5;
-pub const PAGE_DIRTY: u64 = 1 << 6;
-pub const PAGE_HUGE: u64 = 1 << 7;
-pub const PAGE_GLOBAL: u64 = 1 << 8;
-pub const PAGE_NO_EXECUTE: u64 = 1 << 63;
+/// Where a virtual address handed to the loader came from, for error messages
+#[derive(Clone, Copy)]
+pub enum AddressSource {
+    /// The LOAD segment described by this program header
+    Segment(usize),
+    EntryPoint,
+}
 
-pub const KB4: usize = 4 * 1024;
-pub const MB2: usize = 2 * 1024 * 1024;
+impl AddressSource {
+    pub fn printf(&self) {
+        match self {
+            AddressSource::Segment(i) => printf!(b"program header %x", *i as u32),
+            AddressSource::EntryPoint => printf!(b"entry point"),
+        }
+    }
+}
 
-// Helper to extract indices for 4-level paging
-fn split_virt_addr(addr: u64) -> (usize, usize, usize, usize) {
-    let pml4 = ((addr >> 39) & 0x1FF) as usize;
-    let pdpt = ((addr >> 30) & 0x1FF) as usize;
-    let pd = ((addr >> 21) & 0x1FF) as usize;
-    let pt = ((addr >> 12) & 0x1FF) as usize;
-    (pml4, pdpt, pd, pt)
+/// Checks that the kernel-space range `start..start + len` is canonical, in the higher half, and doesn't wrap around
+pub fn check_kernel_range(source: AddressSource, start: u64, len: u64) -> Result<(), ElfError> {
+    if !is_canonical(start) {
+        return Err(ElfError::NonCanonicalAddress(source, start));
+    }
+    if start < HIGHER_HALF_START {
+        return Err(ElfError::LowerHalfAddress(source, start));
+    }
+    // The last byte must be canonical too, in the higher half every address past `start` is unless it wraps
+    if len != 0 && start.checked_add(len - 1).is_none() {
+        return Err(ElfError::CrossesCanonicalGap(source, start, len));
+    }
+    Ok(())
 }
 
-// Align address down to nearest 4 KiB or 2 MiB
-fn align_down(addr: u64, align: u64) -> u64 {
-    addr & !(align - 1)
+/// Checks every LOAD segment and the entry point with [`check_kernel_range`]
+fn check_kernel_addresses(phs: &Vec<ElfProgramHeader64>, entry: u64) -> Result<(), ElfError> {
+    for (i, ph) in phs.iter().enumerate() {
+        if ph.segment_type == SEGMENT_TYPE_LOAD {
+            check_kernel_range(AddressSource::Segment(i), ph.p_vaddr, ph.p_memsz)?;
+        }
+    }
+    check_kernel_range(AddressSource::EntryPoint, entry, 1)
 }
 
 // Align address up to nearest 4 KiB or 2 MiB
@@ -264,79 +642,426 @@ fn align_up(addr: u64, align: u64) -> u64 {
     (addr + align - 1) & !(align - 1)
 }
 
-unsafe fn map_page_4kb(virt: u64, phys: u64, flags: u64, allocator: &mut SimpleArenaAllocator) {
-    let (pml4_idx, pdpt_idx, pd_idx, pt_idx) = split_virt_addr(virt);
+/// Pages used to map the kernel segments, reported once they're loaded
+#[derive(Default)]
+struct KernelPageCounts {
+    huge: usize,
+    small: usize,
+}
 
-    let pml4_entry = &mut *PML4.add(pml4_idx);
-    let pdpt_ptr = if *pml4_entry & PAGE_PRESENT != 0 {
-        (*pml4_entry & 0x000F_FFFF_FFFF_F000) as *mut u64
-    } else {
-        let new = allocator.alloc_page();
-        *pml4_entry = new as u64 | PAGE_PRESENT | PAGE_RW;
-        new
-    };
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum VirtualRangeOwner {
+    Segment(usize),
+    KernelStack,
+    KernelStackGuard,
+    IdentityMapping,
+    DirectMapping,
+}
 
-    let pdpt_entry = &mut *pdpt_ptr.add(pdpt_idx);
-    let pd_ptr = if *pdpt_entry & PAGE_PRESENT != 0 {
-        (*pdpt_entry & 0x000F_FFFF_FFFF_F000) as *mut u64
-    } else {
-        let new = allocator.alloc_page();
-        *pdpt_entry = new as u64 | PAGE_PRESENT | PAGE_RW;
-        new
-    };
+impl VirtualRangeOwner {
+    fn printf(&self) {
+        match self {
+            VirtualRangeOwner::Segment(i) => printf!(b"segment %x", *i as u32),
+            VirtualRangeOwner::KernelStack => printf!(b"kernel stack"),
+            VirtualRangeOwner::KernelStackGuard => printf!(b"kernel stack guard"),
+            VirtualRangeOwner::IdentityMapping => printf!(b"identity mapping"),
+            VirtualRangeOwner::DirectMapping => printf!(b"direct mapping"),
+        }
+    }
+}
 
-    let pd_entry = &mut *pd_ptr.add(pd_idx);
-    let pt_ptr = if *pd_entry & PAGE_PRESENT != 0 && *pd_entry & PAGE_HUGE == 0 {
-        (*pd_entry & 0x000F_FFFF_FFFF_F000) as *mut u64
-    } else {
-        let new = allocator.alloc_page();
-        *pd_entry = new as u64 | PAGE_PRESENT | PAGE_RW;
-        new
-    };
+/// A virtual range the kernel address space needs, rounded to pages. <br>
+/// For segments, `bytes_start..bytes_end` is the exact range requested by the program header. <br>
+#[derive(Clone, Copy)]
+struct VirtualRange {
+    start: u64,
+    end: u64,
+    bytes_start: u64,
+    bytes_end: u64,
+    owner: VirtualRangeOwner,
+    flags: u32,
+}
+
+impl VirtualRange {
+    fn new(start: u64, end: u64, owner: VirtualRangeOwner) -> Self {
+        Self {
+            start,
+            end,
+            bytes_start: start,
+            bytes_end: end,
+            owner,
+            flags: 0,
+        }
+    }
+}
+
+/// Names the kernel segments, the kernel stack and the direct mapping for [`write_addr`], once [`check_virtual_ranges`] accepted them
+fn register_kernel_regions(
+    phs: &Vec<ElfProgramHeader64>,
+    layout: &Vec<MemoryRegion>,
+    framebuffer: Option<(u64, u64)>,
+) {
+    for (i, ph) in phs.iter().enumerate() {
+        if ph.segment_type == SEGMENT_TYPE_LOAD {
+            register_kernel_segment(i, ph.p_vaddr, ph.p_vaddr + ph.p_memsz);
+        }
+    }
+    register_region(
+        KERNEL_STACK_BASE - KERNEL_STACK_GUARD_SIZE,
+        KERNEL_STACK_BASE,
+        b"kernel stack guard",
+    );
+    register_region(
+        KERNEL_STACK_BASE,
+        KERNEL_STACK_BASE + KERNEL_STACK_SIZE,
+        b"kernel stack",
+    );
+    register_region(
+        KERNEL_STACK_BASE + KERNEL_STACK_SIZE,
+        KERNEL_STACK_BASE + KERNEL_STACK_SIZE + KERNEL_STACK_GUARD_SIZE,
+        b"kernel stack guard",
+    );
+    if let Some((addr, size)) = framebuffer {
+        register_region(addr, addr + size, b"framebuffer");
+    }
+    let physical_end = mapped_physical_end(layout, framebuffer);
+    register_region(
+        DIRECT_MAPPING_OFFSET,
+        DIRECT_MAPPING_OFFSET + physical_end,
+        b"direct map",
+    );
+}
+
+/// Checks that the kernel segments, the kernel stack and its guard, and the identity and direct mappings don't overlap. <br>
+/// Segments may share a page as long as their exact byte ranges don't overlap, the page then gets the union of their permissions. <br>
+/// Every conflict is reported before failing. <br>
+fn check_virtual_ranges(
+    phs: &Vec<ElfProgramHeader64>,
+    layout: &Vec<MemoryRegion>,
+    framebuffer: Option<(u64, u64)>,
+) -> Result<(), ElfError> {
+    let mut conflicts = 0;
+    let mut ranges: Vec<VirtualRange> = Vec::new_tagged(phs.len() + 4, b"paging");
+
+    for (i, ph) in phs.iter().enumerate() {
+        if ph.segment_type != SEGMENT_TYPE_LOAD || ph.p_memsz == 0 {
+            continue;
+        }
+        let end = match ph.p_vaddr.checked_add(ph.p_memsz) {
+            Some(end) if end <= u64::MAX - KB4 as u64 => end,
+            _ => {
+                printf!(b"Kernel ");
+                VirtualRangeOwner::Segment(i).printf();
+                printf!(b" wraps around the address space !\r\n");
+                conflicts += 1;
+                continue;
+            }
+        };
+        ranges.push(VirtualRange {
+            start: align_down(ph.p_vaddr, KB4 as u64),
+            end: align_up(end, KB4 as u64),
+            bytes_start: ph.p_vaddr,
+            bytes_end: end,
+            owner: VirtualRangeOwner::Segment(i),
+            flags: ph.flags,
+        });
+    }
+
+    ranges.push(VirtualRange::new(
+        KERNEL_STACK_BASE - KERNEL_STACK_GUARD_SIZE,
+        KERNEL_STACK_BASE,
+        VirtualRangeOwner::KernelStackGuard,
+    ));
+    ranges.push(VirtualRange::new(
+        KERNEL_STACK_BASE,
+        KERNEL_STACK_BASE + KERNEL_STACK_SIZE,
+        VirtualRangeOwner::KernelStack,
+    ));
+    ranges.push(VirtualRange::new(
+        KERNEL_STACK_BASE + KERNEL_STACK_SIZE,
+        KERNEL_STACK_BASE + KERNEL_STACK_SIZE + KERNEL_STACK_GUARD_SIZE,
+        VirtualRangeOwner::KernelStackGuard,
+    ));
+
+    let physical_end = mapped_physical_end(layout, framebuffer);
+    ranges.push(VirtualRange::new(
+        0,
+        physical_end,
+        VirtualRangeOwner::IdentityMapping,
+    ));
+    ranges.push(VirtualRange::new(
+        DIRECT_MAPPING_OFFSET,
+        DIRECT_MAPPING_OFFSET + physical_end,
+        VirtualRangeOwner::DirectMapping,
+    ));
+
+    ranges.bubble_sort(|a, b| {
+        if a.start < b.start {
+            -1
+        } else if a.start > b.start {
+            1
+        } else {
+            0
+        }
+    });
+
+    for i in 0..ranges.len() {
+        let a = *ranges.get(i).unwrap_or_else(|| kpanic());
+        for j in (i + 1)..ranges.len() {
+            let b = *ranges.get(j).unwrap_or_else(|| kpanic());
+            if b.start >= a.end {
+                break;
+            }
+            let overlap_start = a.start.max(b.start);
+            let overlap_end = a.end.min(b.end);
+
+            let both_segments = matches!(a.owner, VirtualRangeOwner::Segment(_))
+                && matches!(b.owner, VirtualRangeOwner::Segment(_));
+            if both_segments && (a.bytes_end <= b.bytes_start || b.bytes_end <= a.bytes_start) {
+                printf!(b"Note: ");
+                a.owner.printf();
+                printf!(b" and ");
+                b.owner.printf();
+                printf!(b" share pages ");
+                write_addr_range(overlap_start, overlap_end);
+                printf!(b", using permissions 0x%b\r\n", (a.flags | b.flags) as u8);
+                continue;
+            }
+
+            conflicts += 1;
+            printf!(b"Virtual range conflict between ");
+            a.owner.printf();
+            printf!(b" and ");
+            b.owner.printf();
+            printf!(b" on pages ");
+            write_addr_range(overlap_start, overlap_end);
+            printf!(b"\r\n");
+        }
+    }
 
-    let pt_entry = &mut *pt_ptr.add(pt_idx);
-    *pt_entry = align_down(phys, PAGE_SIZE as u64) | flags | PAGE_PRESENT;
+    if conflicts != 0 {
+        Err(ElfError::VirtualRangeConflicts(conflicts))
+    } else {
+        Ok(())
+    }
 }
 
-unsafe fn map_page_2mb(virt: u64, phys: u64, flags: u64, allocator: &mut SimpleArenaAllocator) {
-    let (pml4_idx, pdpt_idx, pd_idx, _) = split_virt_addr(virt);
+const KERNEL_STACK_SIZE: u64 = 2 * MB2 as u64;
+const KERNEL_STACK_BASE: u64 = 0xFFFF_9000_0000_0000;
+/// Unmapped virtual space kept on both sides of the kernel stack, one 2MiB slot so no huge page can cover it
+const KERNEL_STACK_GUARD_SIZE: u64 = MB2 as u64;
+/// Bytes the handoff pushes before the first kernel instruction runs (the return address of `call`)
+const HANDOFF_PUSHED_BYTES: u64 = 8;
+// The kernel entry sees `rsp - HANDOFF_PUSHED_BYTES`, SysV wants that plus 8 to be 16 byte aligned
+const _: () = assert!(HANDOFF_PUSHED_BYTES % 16 == 8);
+
+/// Returns the stack pointer to load before the handoff `call`, for a stack growing down from `usable_top`. <br>
+/// SysV requires `rsp + 8` to be 16 byte aligned at the entry instruction, after `call` pushed the return address. <br>
+fn handoff_stack_pointer(usable_top: u64) -> u64 {
+    align_down(usable_top, 16)
+}
 
-    let pml4_entry = &mut *PML4.add(pml4_idx);
-    let pdpt_ptr = if *pml4_entry & PAGE_PRESENT != 0 {
-        (*pml4_entry & 0x000F_FFFF_FFFF_F000) as *mut u64
+/// Address of the handoff copy of the E820 entries the memory layout was parsed from, handed to the kernel unmodified
+static KERNEL_RAW_MEMORY_MAP: SyncUnsafeCell<u32> = SyncUnsafeCell::new(0);
+
+/// `reserve=` ranges handed to the kernel, copied from the config right before the jump
+static KERNEL_RESERVATIONS: SyncUnsafeCell<[MemoryReservation; MAX_RESERVATIONS]> =
+    SyncUnsafeCell::new(unsafe { core::mem::zeroed() });
+
+/// Reads the LOAD segment `ph` into a heap buffer and maps it with 4KiB pages, returns the bytes read from the file
+fn load_segment_buffered(
+    file: &mut Ext2File,
+    ph: &ElfProgramHeader64,
+    editor: &mut PageTableEditor,
+    progress: &mut ProgressBar,
+    counts: &mut KernelPageCounts,
+    mapped: &mut Vec<PlannedMapping>,
+) -> Result<usize, ElfError> {
+    // The buffer starts at the page containing p_vaddr, so that the page offset of every byte is preserved
+    let page_offset = ph.p_vaddr % (KB4 as u64);
+    let virt_start = ph.p_vaddr - page_offset;
+    let buf_num_pages = ((page_offset + ph.p_memsz) as usize).div_ceil(KB4);
+    let buf_len = buf_num_pages * KB4;
+
+    // Large segments start `head` bytes into a 2MiB aligned block, at the 2MiB offset of virt_start, so their aligned runs can take 2MiB pages
+    let aligned = if buf_len >= MB2 {
+        let head = (virt_start % MB2 as u64) as usize;
+        Buffer::new_aligned(head + buf_len, MB2).map(|buf| (buf, head))
     } else {
-        let new = allocator.alloc_page();
-        *pml4_entry = new as u64 | PAGE_PRESENT | PAGE_RW;
-        new
+        None
+    };
+    let (mut buf, head) = match aligned {
+        Some(aligned) => aligned,
+        None => (
+            Buffer::new_aligned(buf_len, KB4).ok_or(ElfError::FailedMemAlloc(buf_len))?,
+            0,
+        ),
     };
+    unsafe { buf.get_ptr().add(head).write_bytes(0, buf_len) };
 
-    let pdpt_entry = &mut *pdpt_ptr.add(pdpt_idx);
-    let pd_ptr = if *pdpt_entry & PAGE_PRESENT != 0 {
-        (*pdpt_entry & 0x000F_FFFF_FFFF_F000) as *mut u64
+    let read = if ph.p_filesz == 0 {
+        0
     } else {
-        let new = allocator.alloc_page();
-        *pdpt_entry = new as u64 | PAGE_PRESENT | PAGE_RW;
-        new
+        file.seek(ph.p_offset as usize)
+            .map_err(ElfError::Ext2Error)?;
+        // Straight at the page offset, the buffer starts at the page containing p_vaddr
+        let mut sink = tee(
+            BufferSink::new(&mut buf, head + page_offset as usize),
+            progress,
+        );
+        file.stream_to(&mut sink, ph.p_filesz as usize)
+            .map_err(|e| ElfError::Ext2Error(e.into_ext2_error()))?
     };
 
-    let pd_entry = &mut *pd_ptr.add(pd_idx);
-    *pd_entry = align_down(phys, PAGE_SIZE_2MB as u64) | flags | PAGE_PRESENT | PAGE_HUGE;
+    let buf_ptr = unsafe { buf.get_ptr() as u64 } + head as u64;
+
+    log::with_level(LogLevel::Debug, || {
+        printf!(b"Mapping kernel vaddr=");
+        write_addr(virt_start);
+        printf!(b", paddr=");
+        write_addr(buf_ptr);
+        printf!(b", 4KiB npages=0x%x\r\n", buf_num_pages as u32);
+    });
+
+    let mut offset = 0;
+    while offset < buf_len as u64 {
+        let virt = virt_start + offset;
+        let phys = buf_ptr + offset;
+
+        // A whole 2MiB run is never shared with another segment, check_virtual_ranges only lets boundary pages be
+        if virt.is_multiple_of(MB2 as u64)
+            && phys.is_multiple_of(MB2 as u64)
+            && buf_len as u64 - offset >= MB2 as u64
+            && editor.translate(virt).is_none()
+        {
+            editor.map_2mb(virt, phys, PAGE_RW);
+            counts.huge += 1;
+            record_mapping(mapped, virt, phys, MB2 as u64);
+            offset += MB2 as u64;
+            continue;
+        }
+        match editor.translate(virt) {
+            Some(existing) => unsafe {
+                // Page shared with a previous segment (allowed by check_virtual_ranges), copy our bytes into it
+                let from = ph.p_vaddr.max(virt);
+                let to = (ph.p_vaddr + ph.p_memsz).min(virt + KB4 as u64);
+                core::ptr::copy_nonoverlapping(
+                    (phys + (from - virt)) as *const u8,
+                    (align_down(existing, KB4 as u64) + (from - virt)) as *mut u8,
+                    (to - from) as usize,
+                );
+            },
+            None => {
+                editor.map_4kb(virt, phys, PAGE_RW);
+                counts.small += 1;
+                record_mapping(mapped, virt, phys, KB4 as u64);
+            }
+        }
+        offset += KB4 as u64;
+    }
+
+    unsafe {
+        buf.leak();
+    }
+    Ok(read)
 }
 
-const KERNEL_STACK_SIZE: u64 = 2 * MB2 as u64;
+/// Reads the LOAD segment `ph` straight into frames from `frames`, each one mapped before it's filled. <br>
+/// Uses 2MiB pages where the segment covers a whole aligned 2MiB range, returns the bytes read from the file. <br>
+unsafe fn stream_segment(
+    file: &mut Ext2File,
+    ph: &ElfProgramHeader64,
+    editor: &mut PageTableEditor,
+    frames: &mut FrameAllocator,
+    progress: &mut ProgressBar,
+    counts: &mut KernelPageCounts,
+    mapped: &mut Vec<PlannedMapping>,
+) -> Result<usize, ElfError> {
+    let segment_end = ph.p_vaddr + ph.p_memsz;
+    let file_end = ph.p_vaddr + ph.p_filesz;
+    if ph.p_filesz != 0 {
+        file.seek(ph.p_offset as usize)
+            .map_err(ElfError::Ext2Error)?;
+    }
 
-static mut KERNEL_MEMORY_LAYOUT: [OsMemoryRegion; 32] = unsafe { core::mem::zeroed() };
+    let mut read = 0;
+    let mut virt = align_down(ph.p_vaddr, KB4 as u64);
+    while virt < segment_end {
+        let from = ph.p_vaddr.max(virt);
+        let (phys, size) = match editor.translate(virt) {
+            Some(existing) => {
+                // Page shared with a previous segment (allowed by check_virtual_ranges), only our bytes are zeroed
+                let phys = align_down(existing, KB4 as u64);
+                let to = segment_end.min(virt + KB4 as u64);
+                ((phys + (from - virt)) as *mut u8).write_bytes(0, (to - from) as usize);
+                (phys, KB4 as u64)
+            }
+            None => {
+                let huge = virt.is_multiple_of(MB2 as u64) && virt + MB2 as u64 <= segment_end;
+                match huge.then(|| frames.alloc_huge_page()).flatten() {
+                    Some(frame) => {
+                        (frame as *mut u8).write_bytes(0, MB2);
+                        editor.map_2mb(virt, frame, PAGE_RW);
+                        counts.huge += 1;
+                        record_mapping(mapped, virt, frame, MB2 as u64);
+                        (frame, MB2 as u64)
+                    }
+                    None => {
+                        let frame = frames.alloc_page().ok_or_else(|| {
+                            printf!(b"Out of usable memory for the kernel frames !\r\n");
+                            ElfError::FailedMemAlloc(KB4)
+                        })?;
+                        (frame as *mut u8).write_bytes(0, KB4);
+                        editor.map_4kb(virt, frame, PAGE_RW);
+                        counts.small += 1;
+                        record_mapping(mapped, virt, frame, KB4 as u64);
+                        (frame, KB4 as u64)
+                    }
+                }
+            }
+        };
+
+        let to = file_end.min(virt + size);
+        if from < to {
+            let len = (to - from) as usize;
+            let mut sink = tee(
+                MemorySink::new((phys + (from - virt)) as usize, len),
+                &mut *progress,
+            );
+            read += file
+                .stream_to(&mut sink, len)
+                .map_err(|e| ElfError::Ext2Error(e.into_ext2_error()))?;
+        }
+        virt += size;
+    }
+    Ok(read)
+}
 
+/// Loads the LOAD segments and maps them with `editor`, into heap buffers or, with `frames`, straight into frames of usable memory. <br>
+/// Then allocates and maps the kernel stack, returns its virtual `(start, end)`. Every range mapped is added to `mapped`. <br>
 fn load_kernel<'a>(
     kernel_file: &'a mut ElfFile64<'a>,
-    allocator: &mut SimpleArenaAllocator,
+    editor: &mut PageTableEditor,
+    mut frames: Option<&mut FrameAllocator>,
+    scrub: bool,
+    mapped: &mut Vec<PlannedMapping>,
 ) -> Result<(u64, u64), ElfError> {
     let phs = kernel_file.load_program_headers()?.clone();
     let file = kernel_file.get_file_mut();
 
+    checkpoint(b"kernel read");
+    let total = phs
+        .iter()
+        .filter(|ph| ph.segment_type == SEGMENT_TYPE_LOAD)
+        .map(|ph| ph.p_filesz as usize)
+        .sum();
+    let mut progress = ProgressBar::new(b"Loading kernel ", total);
+
     let mut max_addr = 0;
+    let mut counts = KernelPageCounts::default();
 
-    for ph in phs.iter() {
+    for (i, ph) in phs.iter().enumerate() {
+        post_code_progress(codes::SEGMENT_LOAD, i);
         if ph.p_vaddr + ph.p_memsz > max_addr {
             max_addr = ph.p_vaddr + ph.p_memsz;
         }
@@ -345,6 +1070,7 @@ fn load_kernel<'a>(
             continue;
         }
 
+        check_segment_file_size(i, ph.p_offset, ph.p_filesz, ph.p_memsz, file.get_size64())?;
         printf!(
             b"Loading segment: v_addr=0x%x%x, p_memsz=0x%x, p_filesz=0x%x\r\n",
             (ph.p_vaddr >> 32) as u32,
@@ -352,15 +1078,11 @@ fn load_kernel<'a>(
             ph.p_memsz as u32,
             ph.p_filesz as u32
         );
-        let mut buf = Buffer::new(ph.p_memsz as usize)
-            .ok_or(ElfError::FailedMemAlloc(ph.p_memsz as usize))?;
-        unsafe { buf.get_ptr().write_bytes(0, ph.p_memsz as usize) };
-
-        let read = {
-            file.seek(ph.p_offset as usize)
-                .map_err(ElfError::Ext2Error)?;
-            file.read(&mut buf, ph.p_filesz as usize)
-                .map_err(ElfError::Ext2Error)?
+        let read = match frames.as_deref_mut() {
+            Some(frames) => unsafe {
+                stream_segment(file, ph, editor, frames, &mut progress, &mut counts, mapped)?
+            },
+            None => load_segment_buffered(file, ph, editor, &mut progress, &mut counts, mapped)?,
         };
         printf!(
             b"Read 0x%x bytes of 0x%x bytes\r\n",
@@ -370,71 +1092,66 @@ fn load_kernel<'a>(
 
         if read != ph.p_filesz as usize {
             unsafe {
-                Video::get().write_string(b"Failed to boot: Could not read kernel !\n");
+                let video = Video::get();
+                video.write_string(&render(Text::KernelReadFailed, &[]));
+                video.write_char(b'\n');
             }
             kpanic();
         }
-
-        let buf_ptr = unsafe { buf.get_ptr() as u64 };
-        let buf_len = buf.len();
-        let buf_num_pages = buf_len.div_ceil(KB4);
-
-        printf!(
-            b"Mapping kernel (4KiB pages) vaddr=0x%x%x, paddr=0x%x%x, npages=0x%x\r\n",
-            (ph.p_vaddr >> 32) as u32,
-            ph.p_vaddr as u32,
-            (buf_ptr >> 32) as u32,
-            buf_ptr as u32,
-            buf_num_pages as u32
-        );
-
-        for i in 0..buf_num_pages {
-            let offset = (i as u64) * (KB4 as u64);
-            let virt = ph.p_vaddr + offset;
-            let phys = buf_ptr + offset;
-
-            unsafe {
-                map_page_4kb(virt, phys, PAGE_RW, allocator);
-            }
-        }
-
-        unsafe {
-            buf.leak();
-        }
     }
-
-    if max_addr > 0xFFFF_9000_0000_0000 {
-        printf!(
-            b"Kernel reserves memory until 0x%x%x > 0xFFFF900000000000 !\r\n",
-            (max_addr >> 32) as u32,
-            max_addr as u32
-        );
+    progress.finish();
+    printf!(
+        b"Kernel file: 0x%x sparse blocks read as zeros\r\n",
+        file.sparse_blocks()
+    );
+    printf!(
+        b"Kernel segments mapped with 0x%x 2MiB pages and 0x%x 4KiB pages\r\n",
+        counts.huge,
+        counts.small
+    );
+
+    let stack_guard_start = KERNEL_STACK_BASE - KERNEL_STACK_GUARD_SIZE;
+    if max_addr > stack_guard_start {
+        printf!(b"Kernel reserves memory until ");
+        write_addr(max_addr);
+        printf!(b" > ");
+        write_addr(stack_guard_start);
+        printf!(b" !\r\n");
         kpanic();
     }
 
-    let begin_stack = 0xFFFF_9000_0000_0000;
+    let begin_stack = KERNEL_STACK_BASE;
     let end_stack = begin_stack + KERNEL_STACK_SIZE;
 
-    let stack_buffer = Buffer::new(KERNEL_STACK_SIZE as usize)
+    // Mapped with 2MiB pages, which take the physical address rounded down to 2MiB
+    let stack_buffer = Buffer::new_handoff_aligned(KERNEL_STACK_SIZE as usize, MB2, scrub)
         .ok_or(ElfError::FailedMemAlloc(KERNEL_STACK_SIZE as usize))?;
 
     unsafe {
-        printf!(
-            b"Mapping kernel stack vaddr=0x%x%x, paddr=0x%x%x, npages=0x%x\r\n",
-            (begin_stack >> 32) as u32,
-            begin_stack as u32,
-            (stack_buffer.get_ptr() as u64 >> 32) as u32,
-            stack_buffer.get_ptr() as u32,
-            (end_stack - begin_stack).div_ceil(MB2 as u64) as u32
-        );
+        log::with_level(LogLevel::Debug, || {
+            printf!(b"Mapping kernel stack vaddr=");
+            write_addr(begin_stack);
+            printf!(b", paddr=");
+            write_addr(stack_buffer.get_ptr() as u64);
+            printf!(
+                b", npages=0x%x\r\n",
+                (end_stack - begin_stack).div_ceil(MB2 as u64) as u32
+            );
+        });
 
         for i in 0..(end_stack - begin_stack).div_ceil(MB2 as u64) {
             let offset = i * (MB2 as u64);
             let virt = begin_stack + offset;
             let phys = stack_buffer.get_ptr() as u64 + offset;
 
-            map_page_2mb(virt, phys, PAGE_RW, allocator);
+            editor.map_2mb(virt, phys, PAGE_RW);
         }
+        record_mapping(
+            mapped,
+            begin_stack,
+            stack_buffer.get_ptr() as u64,
+            end_stack - begin_stack,
+        );
 
         stack_buffer.leak();
     }
@@ -446,209 +1163,1062 @@ pub const DIRECT_MAPPING_OFFSET: u64 = 0xFFFF_A000_0000_0000;
 
 const BOOTLOADER_NAME: &[u8] =
     b"Obsidian Bootloader: https://github.com/AilPhaune/ObsidianBootloader/\0";
-static mut OBSIBOOT: ObsiBootKernelParameters = ObsiBootKernelParameters::empty();
+/// The parameters and the memory layout handed to the kernel, in a heap block the layout itself marks reserved. <br>
+/// Outside the stage2 image, which lies in low memory the kernel is free to reuse. <br>
+struct HandoffTables {
+    parameters: *mut ObsiBootKernelParameters,
+    layout: *mut OsMemoryRegion,
+    /// Entries `layout` has room for
+    layout_capacity: usize,
+}
 
-pub fn enable_paging_and_run_kernel<'a>(
-    kernel_file: &'a mut ElfFile64<'a>,
-    bios_idt: usize,
-    boot_drive: usize,
-) {
-    unsafe {
-        let entry64 = kernel_file.entry_point();
-        printf!(
-            b"Kernel entry point is 0x%x%x\r\n\n",
-            (entry64 >> 32) as u32,
-            entry64 as u32
-        );
-        if entry64 < 0xFFFF_8000_0000_0000 {
-            Video::get().write_string(b"Kernel entry point is < 0xFFFF800000000000 !\r\n");
+impl HandoffTables {
+    /// Allocates the tables and marks the heap up to their end reserved in `layout`, with room for `extra_regions` more entries. <br>
+    /// The heap below the tables is in use by the bootloader or handed to the kernel already, the usable memory after them starts at `usable_kernel_memory_start`. <br>
+    fn allocate(
+        layout: &mut Vec<MemoryRegion>,
+        extra_regions: usize,
+        scrub: bool,
+    ) -> HandoffTables {
+        // Carving the reserved range out of a usable region splits it in at most 3
+        let layout_capacity = layout.len() + 2 + extra_regions;
+        let size =
+            size_of::<ObsiBootKernelParameters>() + layout_capacity * size_of::<OsMemoryRegion>();
+        let Some(buffer) = Buffer::new_handoff(size, scrub) else {
+            printf!(b"Not enough memory for the kernel parameters !\r\n");
             kpanic();
+        };
+        let start = unsafe { buffer.leak().get_ptr() } as u64;
+        let end = align_up(start + size as u64, KB4 as u64);
+        register_region(start, end, b"kernel parameters");
+        reserve_heap(layout, end);
+
+        HandoffTables {
+            parameters: start as *mut ObsiBootKernelParameters,
+            layout: (start as usize + size_of::<ObsiBootKernelParameters>()) as *mut OsMemoryRegion,
+            layout_capacity,
         }
+    }
 
-        let layout = parse_memory_layout();
+    /// Halts unless the tables are in a region the kernel is told not to use
+    fn check_reserved(&self, layout: &Vec<MemoryRegion>) {
+        let start = self.parameters as u64;
+        let end = self.layout as u64 + (self.layout_capacity * size_of::<OsMemoryRegion>()) as u64;
+        check_not_usable(layout, start, end, b"kernel parameters");
+    }
+}
 
-        printf!(b"=== BEGIN MEMORY LAYOUT DUMP ===\r\n");
-        for region in layout.iter() {
-            printf!(
-                b"REGION: %x%x --> %x%x (usable:",
-                (region.start >> 32) as u32,
-                (region.start) as u32,
-                (region.end >> 32) as u32,
-                (region.end) as u32
-            );
-            if region.kind == MemoryRegionType::Usable {
-                printf!(b"yes)\r\n");
-            } else {
-                printf!(b"no)\r\n");
+/// Marks the heap up to `end` reserved in `layout`. <br>
+/// A later call with a higher `end` only grows the range, it never adds an entry to the layout. <br>
+fn reserve_heap(layout: &mut Vec<MemoryRegion>, end: u64) {
+    let mut regions = Vec::new_tagged(layout.len() + 1, b"paging");
+    for region in layout.iter() {
+        regions.push(*region);
+    }
+    regions.push(MemoryRegion {
+        start: align_down(mem::get_heap_start() as u64, KB4 as u64),
+        end,
+        kind: MemoryRegionType::Reserved,
+    });
+    *layout = normalize(regions);
+}
+
+/// Marks everything allocated so far reserved in `layout`, the page tables and the kernel buffers included. <br>
+/// Call it once nothing else is allocated for the kernel, before the layout is saved. <br>
+fn reserve_heap_in_use(layout: &mut Vec<MemoryRegion>) {
+    let end = align_up(mem::get_last_header() as u64, KB4 as u64);
+    log_debug!(b"Heap in use reserved up to 0x%x\r\n", end as u32);
+    reserve_heap(layout, end);
+}
+
+/// Halts unless `[start, end)` is covered by regions the kernel is told not to use, reserved or reclaimable
+fn check_not_usable(layout: &Vec<MemoryRegion>, start: u64, end: u64, name: &[u8]) {
+    let mut covered = start;
+    for region in layout.iter() {
+        if covered >= end || region.start > covered {
+            break;
+        }
+        if region.end <= covered {
+            continue;
+        }
+        if region.kind == MemoryRegionType::Usable {
+            break;
+        }
+        covered = region.end;
+    }
+    if covered < end {
+        printf!(b"Handoff check: ");
+        write_string(name);
+        printf!(b" at ");
+        write_addr_range(start, end);
+        printf!(b" is not in a reserved region !\r\n");
+        kpanic();
+    }
+}
+
+/// Halts unless the page tables and the physical pages of the kernel segments and stack are all outside usable memory
+fn check_kernel_memory(
+    layout: &Vec<MemoryRegion>,
+    editor: &PageTableEditor,
+    arena: (u64, u64),
+    phs: &Vec<ElfProgramHeader64>,
+) {
+    let pml4 = editor.pml4();
+    check_not_usable(layout, pml4, pml4 + PAGE_SIZE as u64, b"PML4");
+    check_not_usable(layout, arena.0, arena.1, b"page tables");
+    for ph in phs.iter() {
+        if ph.segment_type != SEGMENT_TYPE_LOAD {
+            continue;
+        }
+        let mut virt = align_down(ph.p_vaddr, KB4 as u64);
+        while virt < ph.p_vaddr + ph.p_memsz {
+            if let Some(phys) = editor.translate(virt) {
+                check_not_usable(layout, phys, phys + KB4 as u64, b"kernel segment");
+            }
+            virt += KB4 as u64;
+        }
+    }
+    let mut virt = KERNEL_STACK_BASE;
+    while virt < KERNEL_STACK_BASE + KERNEL_STACK_SIZE {
+        if let Some(phys) = editor.translate(virt) {
+            check_not_usable(layout, phys, phys + MB2 as u64, b"kernel stack");
+        }
+        virt += MB2 as u64;
+    }
+}
+
+/// Adds the mapping of `[virt, virt + len)` to `mapped`, merged into the last range when it continues it
+fn record_mapping(mapped: &mut Vec<PlannedMapping>, virt: u64, phys: u64, len: u64) {
+    let last = mapped.len().checked_sub(1);
+    if let Some(last) = last.and_then(|i| mapped.get_mut(i)) {
+        if last.virt + last.len == virt && last.phys + last.len == phys {
+            last.len += len;
+            return;
+        }
+    }
+    mapped.push(PlannedMapping {
+        virt,
+        phys,
+        len,
+        page_size: KB4 as u64,
+        flags: PAGE_RW,
+    });
+}
+
+/// Halts unless every range of `plans` is mapped where it should be, checked once the kernel is loaded
+fn verify_mappings(editor: &PageTableEditor, plans: &[&Vec<PlannedMapping>]) {
+    let mut pages = 0;
+    for plan in plans.iter() {
+        for mapping in plan.iter() {
+            let end = mapping.virt + mapping.len;
+            let Err((virt, found)) = editor.verify_mapping(mapping.virt, end, mapping.phys) else {
+                pages += mapping.len.div_ceil(KB4 as u64);
+                continue;
+            };
+            printf!(b"Mapping check: ");
+            write_addr(virt);
+            match found {
+                Some(phys) => {
+                    printf!(b" maps to ");
+                    write_addr(phys);
+                }
+                None => printf!(b" is not mapped"),
             }
+            printf!(b", expected ");
+            write_addr(mapping.phys + (virt - mapping.virt));
+            printf!(b" !\r\n");
+            kpanic();
         }
-        printf!(b"===  END MEMORY LAYOUT DUMP  ===\r\n\n");
+    }
+    log_debug!(b"Mapping check: 0x%x pages as expected\r\n", pages as u32);
+}
 
-        // 15MiB is allocated for page tables
-        #[allow(static_mut_refs)]
-        if USED_MAP >= SYSTEM_MEMORY_MAP.len() {
-            // unreachable, check already made when detecting memory layout from BIOS
+/// Halts if the page at virtual 0, or its direct mapping, ended up mapped. <br>
+/// Nothing after the CR3 load in `enable_paging_and_jump64` touches memory below 0x1000, the IVT is only used by BIOS calls. <br>
+fn check_null_page_unmapped(editor: &PageTableEditor) {
+    for virt in [0, DIRECT_MAPPING_OFFSET] {
+        if let Some(phys) = editor.translate(virt) {
+            printf!(b"Mapping check: null page ");
+            write_addr(virt);
+            printf!(b" maps to ");
+            write_addr(phys);
+            printf!(b" with map_null_page=off !\r\n");
             kpanic();
         }
-        let tables_base_addr = SYSTEM_MEMORY_MAP[USED_MAP].base_addr();
-        let tables_end_addr = tables_base_addr + 15 * 1024 * 1024;
-        if tables_base_addr > tables_end_addr || tables_end_addr > u32::MAX as u64 {
+    }
+    log_debug!(b"Null page left unmapped\r\n");
+}
+
+/// The memory layout handed to the kernel, with the `mem_limit=` cap applied. <br>
+/// Also returns the detected usable bytes and the applied cap (0 when none). <br>
+fn handoff_memory_layout(
+    state: &BootState,
+    reservations: &[MemoryReservation],
+) -> (Vec<MemoryRegion>, u64, u64) {
+    let layout = parse_memory_layout(&state.memory, reservations);
+    let detected_usable_memory = usable_bytes(layout.as_slice());
+    let (layout, usable_memory_limit) = match state.mem_limit {
+        Some(limit) if limit < detected_usable_memory => {
             printf!(
-                b"Invalid memory range for page tables: %x%x --> %x%x\r\n",
-                (tables_base_addr >> 32) as u32,
-                (tables_base_addr) as u32,
-                (tables_end_addr >> 32) as u32,
-                (tables_end_addr) as u32
+                b"mem_limit: detected 0x%x%x usable bytes, capped to 0x%x%x\r\n",
+                (detected_usable_memory >> 32) as u32,
+                detected_usable_memory as u32,
+                (limit >> 32) as u32,
+                limit as u32
             );
+            (clip_memory_layout(layout, limit), limit)
         }
-        let mut allocator =
-            SimpleArenaAllocator::new(tables_base_addr as usize, tables_end_addr as usize);
-
-        PML4 = allocator.alloc_page();
+        Some(limit) => {
+            log_warn!(
+                b"Warning: mem_limit 0x%x%x is not below the 0x%x%x detected usable bytes, ignored\r\n",
+                (limit >> 32) as u32,
+                limit as u32,
+                (detected_usable_memory >> 32) as u32,
+                detected_usable_memory as u32
+            );
+            warning(WarningId::MemLimitIgnored);
+            (layout, 0)
+        }
+        None => (layout, 0),
+    };
+    (layout, detected_usable_memory, usable_memory_limit)
+}
 
+/// Logs the layout and the `reserve=` ranges, then copies the reservations and the raw E820 map to their handoff tables
+unsafe fn dump_memory_layout(
+    state: &BootState,
+    layout: &Vec<MemoryRegion>,
+    reservations: &[MemoryReservation],
+) {
+    printf!(b"=== BEGIN MEMORY LAYOUT DUMP ===\r\n");
+    for region in layout.iter() {
         printf!(
-            b"Mapping (4KiB pages) 0x00000000 to 0x00100000\r\n",
-            PML4,
-            PML4
+            b"REGION: %x%x --> %x%x (usable:",
+            (region.start >> 32) as u32,
+            (region.start) as u32,
+            (region.end >> 32) as u32,
+            (region.end) as u32
         );
-        // 256 * 4KiB = 1MiB
-        for i in 0..256 {
-            let addr = (i * KB4) as u64;
-            map_page_4kb(addr, addr, PAGE_RW, &mut allocator);
-            map_page_4kb(addr + DIRECT_MAPPING_OFFSET, addr, PAGE_RW, &mut allocator);
+        match region.kind {
+            MemoryRegionType::Usable => printf!(b"yes)\r\n"),
+            MemoryRegionType::BootloaderReclaimable => printf!(b"after boot)\r\n"),
+            MemoryRegionType::Reserved => printf!(b"no)\r\n"),
+        }
+    }
+    for reservation in reservations {
+        printf!(b"RESERVE: ");
+        reservation.printf();
+        let detected = state.memory.enabled_entries().any(|map| {
+            reservation.start < map.base_addr() + map.len() && map.base_addr() < reservation.end
+        });
+        if detected {
+            printf!(b"\r\n");
+        } else {
+            printf!(b" (outside detected memory)\r\n");
         }
+    }
+    printf!(b"===  END MEMORY LAYOUT DUMP  ===\r\n\n");
+    let kernel_reservations = &mut *KERNEL_RESERVATIONS.get();
+    kernel_reservations[..reservations.len()].copy_from_slice(reservations);
+    // Same snapshot the layout was parsed from, so both tables always agree on what the BIOS said
+    let raw_memory_map = state.memory.entries();
+    let raw_size = size_of_val(raw_memory_map);
+    let Some(buffer) = Buffer::new_handoff(raw_size.max(1), state.scrub_handoff_memory) else {
+        printf!(b"Not enough memory for the raw memory map !\r\n");
+        kpanic();
+    };
+    let buffer = buffer.leak();
+    mem::mem_cpy(
+        buffer.get_ptr(),
+        raw_memory_map.as_ptr() as *const u8,
+        raw_size,
+    );
+    *KERNEL_RAW_MEMORY_MAP.get() = buffer.get_ptr() as u32;
+}
 
-        for region in layout.iter() {
-            if region.kind != MemoryRegionType::Usable || region.start < (1024 * 1024) {
-                continue;
+/// Copies `layout` to the handoff table, returns its entry count
+unsafe fn save_memory_layout(layout: &Vec<MemoryRegion>, tables: &HandoffTables) -> usize {
+    let num_memory_regions = layout.len();
+
+    if num_memory_regions > tables.layout_capacity {
+        printf!(b"Too many memory regions in layout !\r\n");
+        kpanic();
+    }
+    let kernel_memory_layout = slice::from_raw_parts_mut(tables.layout, num_memory_regions);
+    printf!(
+        b"\r\nMemory layout saved at 0x%x (",
+        kernel_memory_layout.as_ptr()
+    );
+    write_u32_decimal(num_memory_regions as u32);
+    printf!(b" entries)\r\n\n");
+    for (i, reg) in layout.iter().enumerate() {
+        match kernel_memory_layout.get_mut(i) {
+            None => {
+                printf!(b"Too many memory regions in layout !\r\n");
+                kpanic();
+            }
+            Some(region) => {
+                *region = OsMemoryRegion {
+                    start: reg.start,
+                    end: reg.end,
+                    usable: reg.kind.os_value(),
+                }
             }
+        }
+    }
+    num_memory_regions
+}
 
-            let aligned_start = align_up(region.start, MB2 as u64);
-            let aligned_end = align_down(region.end, MB2 as u64);
+/// The parameters that depend on how the kernel was loaded, the others come from the [`BootState`]
+struct KernelHandoff {
+    num_memory_regions: usize,
+    detected_usable_memory: u64,
+    usable_memory_limit: u64,
+    /// Page table arena current and last page, and the PML4. Zeros for a 32-bit kernel, which runs without paging.
+    page_tables: (u32, u32, u32),
+    /// Whether the page tables leave virtual 0 unmapped, false without paging
+    null_page_unmapped: bool,
+    kernel_stack_pointer: u64,
+    kernel_stack_start: u64,
+    kernel_stack_end: u64,
+    kernel_stack_guard_size: u64,
+    data: HandedOffData,
+}
 
-            printf!(
-                b"Mapping (2MiB pages) 0x%x to 0x%x\r\n",
-                aligned_start,
-                aligned_end
-            );
+/// Where the data allocated before the kernel was loaded is handed off
+struct HandedOffData {
+    initrd: (u64, u64),
+    cmdline: (u32, u32),
+    pci_devices_ptr: u32,
+    vbe_modes_info_ptr: u32,
+}
 
-            let mut addr = aligned_start;
-            while addr < aligned_end {
-                map_page_2mb(addr, addr, PAGE_RW, &mut allocator);
-                map_page_2mb(addr + DIRECT_MAPPING_OFFSET, addr, PAGE_RW, &mut allocator);
+impl HandedOffData {
+    /// Where `state` left it, for kernels that aren't loaded at physical addresses
+    fn of(state: &BootState) -> HandedOffData {
+        HandedOffData {
+            initrd: state.initrd,
+            cmdline: state.cmdline,
+            pci_devices_ptr: state.pci_devices.as_slice().as_ptr() as u32,
+            vbe_modes_info_ptr: state.vbe.boot_info().1,
+        }
+    }
+}
 
-                addr += MB2 as u64;
-            }
+/// Fills the parameters of `tables` and their checksum, after `dump_memory_layout` and `save_memory_layout` filled the tables they point to
+unsafe fn write_kernel_parameters(
+    state: &BootState,
+    reservation_count: usize,
+    handoff: &KernelHandoff,
+    tables: &HandoffTables,
+) {
+    let (vbe_info_block_ptr, _, vbe_mode_info_block_entry_count, vbe_selected_mode) =
+        state.vbe.boot_info();
+    let (vbe_requested_mode, vbe_selection) = state.vbe.selection_info();
+    let (page_tables_current, page_tables_end, pml4) = handoff.page_tables;
+    let (framebuffer_physical_addr, framebuffer_size) = state.vbe.framebuffer().unwrap_or((0, 0));
+    let mut boot_flags = 0;
+    if state.safe_mode {
+        boot_flags |= BOOT_FLAG_SAFE_MODE;
+    }
+    if handoff.null_page_unmapped {
+        boot_flags |= BOOT_FLAG_NULL_PAGE_UNMAPPED;
+    }
+    let obsiboot = &mut *tables.parameters;
+    *obsiboot = ObsiBootKernelParameters {
+        obsiboot_struct_size: size_of::<ObsiBootKernelParameters>() as u32,
+        obsiboot_struct_version: 14,
+        obsiboot_struct_checksum: [0; 8],
+        bootloader_name_ptr: BOOTLOADER_NAME.as_ptr() as u32,
+        bootloader_version: BOOTLOADER_VERSION,
+        bios_boot_drive: state.boot_drive as u32,
+        bios_idt_ptr: state.bios_idt as u32,
+        ptr_to_memory_layout: tables.layout as u32,
+        memory_layout_entry_count: handoff.num_memory_regions as u32,
+        memory_layout_entry_size: size_of::<OsMemoryRegion>() as u32,
+        page_tables_page_allocator_current_free_page: page_tables_current,
+        page_tables_page_allocator_last_usable_page: page_tables_end,
+        pml4_base_address: pml4,
+        usable_kernel_memory_start: mem::get_last_header(),
+        vbe_info_block_ptr,
+        vbe_modes_info_ptr: handoff.data.vbe_modes_info_ptr,
+        vbe_mode_info_block_entry_count,
+        vbe_selected_mode,
+        kernel_stack_pointer: handoff.kernel_stack_pointer,
+        detected_usable_memory: handoff.detected_usable_memory,
+        usable_memory_limit: handoff.usable_memory_limit,
+        vbe_requested_mode,
+        vbe_selection,
+        reservations_ptr: (*KERNEL_RESERVATIONS.get()).as_ptr() as u32,
+        reservation_count: reservation_count as u32,
+        reservation_entry_size: size_of::<MemoryReservation>() as u32,
+        initrd_physical_addr: handoff.data.initrd.0,
+        initrd_size: handoff.data.initrd.1,
+        raw_memory_map_ptr: *KERNEL_RAW_MEMORY_MAP.get(),
+        raw_memory_map_entry_count: state.memory.entries().len() as u32,
+        raw_memory_map_entry_size: size_of::<SystemMemoryMap>() as u32,
+        raw_memory_map_flags: RAW_MEMORY_MAP_EXTENDED_ATTRIBUTES,
+        kernel_stack_start: handoff.kernel_stack_start,
+        kernel_stack_end: handoff.kernel_stack_end,
+        kernel_stack_guard_size: handoff.kernel_stack_guard_size,
+        bootloader_build_id: BUILD_ID,
+        boot_media_flags: boot_media().flags(),
+        kernel_cmdline_ptr: handoff.data.cmdline.0,
+        kernel_cmdline_len: handoff.data.cmdline.1,
+        framebuffer_physical_addr,
+        framebuffer_size,
+        pci_devices_ptr: handoff.data.pci_devices_ptr,
+        pci_device_count: state.pci_devices.len() as u32,
+        pci_device_entry_size: size_of::<PciDeviceInfo>() as u32,
+        acpi_rsdp_addr: state.rsdp.map_or(0, |rsdp| rsdp.address),
+        acpi_rsdp_revision: state.rsdp.map_or(0, |rsdp| rsdp.revision as u32),
+        boot_flags,
+    };
+    if state.scrub_handoff_memory {
+        let (bytes, cycles) = mem::scrub_stats();
+        printf!(
+            b"Scrubbed 0x%x bytes of handoff memory in 0x%x%x TSC cycles\r\n",
+            bytes,
+            (cycles >> 32) as u32,
+            cycles as u32
+        );
+    }
+    let checksum = obsiboot.calculate_checksum();
+    obsiboot.obsiboot_struct_checksum = checksum;
+}
 
-            let kb4_aligned_start = align_up(region.start, KB4 as u64);
-            printf!(
-                b"> Sub-mapping (4KiB pages) 0x%x to 0x%x\r\n",
-                kb4_aligned_start,
-                aligned_start
-            );
-            let mut addr = kb4_aligned_start;
-            while addr < aligned_start {
-                map_page_4kb(addr, addr, PAGE_RW, &mut allocator);
-                map_page_4kb(addr + DIRECT_MAPPING_OFFSET, addr, PAGE_RW, &mut allocator);
-                addr += KB4 as u64;
+/// Sets `flags` in the handed off `boot_flags`, for steps that run after the parameters were written
+unsafe fn add_boot_flags(tables: &HandoffTables, flags: u32) {
+    let obsiboot = &mut *tables.parameters;
+    obsiboot.boot_flags |= flags;
+    let checksum = obsiboot.calculate_checksum();
+    obsiboot.obsiboot_struct_checksum = checksum;
+}
+
+pub fn enable_paging_and_run_kernel<'a>(kernel_file: &'a mut ElfFile64<'a>, state: &BootState) {
+    unsafe {
+        let entry64 = kernel_file.entry_point();
+        printf!(
+            b"Kernel entry point is 0x%x%x\r\n\n",
+            (entry64 >> 32) as u32,
+            entry64 as u32
+        );
+
+        post_code(codes::PAGING_BUILD);
+        checkpoint(b"page tables");
+        let reservations = &state.reservations[..state.reservation_count];
+        let (mut layout, detected_usable_memory, usable_memory_limit) =
+            handoff_memory_layout(state, reservations);
+        // Reserving the streamed kernel frames adds entries to the layout
+        let extra_regions = if state.stream_kernel {
+            2 * MAX_FRAME_RANGES
+        } else {
+            0
+        };
+        let tables =
+            HandoffTables::allocate(&mut layout, extra_regions, state.scrub_handoff_memory);
+        let phs = kernel_file
+            .load_program_headers()
+            .unwrap_or_else(|e| e.panic())
+            .clone();
+        check_kernel_addresses(&phs, entry64).unwrap_or_else(|e| e.panic());
+        let framebuffer = state.vbe.framebuffer();
+        check_virtual_ranges(&phs, &layout, framebuffer).unwrap_or_else(|e| e.panic());
+        register_kernel_regions(&phs, &layout, framebuffer);
+
+        dump_memory_layout(state, &layout, reservations);
+
+        let plan = plan_layout_mappings(&layout, state.map_null_page);
+        let framebuffer_plan = plan_framebuffer_mappings(framebuffer, &layout);
+        let mut kernel_plan = Vec::new_tagged(8, b"paging");
+        for ph in phs.iter() {
+            if ph.segment_type == SEGMENT_TYPE_LOAD && ph.p_memsz != 0 {
+                kernel_plan.push(PlannedMapping {
+                    virt: align_down(ph.p_vaddr, KB4 as u64),
+                    phys: 0,
+                    len: align_up(ph.p_vaddr + ph.p_memsz, KB4 as u64)
+                        - align_down(ph.p_vaddr, KB4 as u64),
+                    page_size: KB4 as u64,
+                    flags: 0,
+                });
             }
+        }
+        kernel_plan.push(PlannedMapping {
+            virt: KERNEL_STACK_BASE,
+            phys: 0,
+            len: KERNEL_STACK_SIZE,
+            page_size: MB2 as u64,
+            flags: 0,
+        });
 
-            let kb4_aligned_end = align_down(region.end, KB4 as u64);
+        // Page tables are only allocated once every mapping is known, so the arena is exactly sized
+        let table_pages =
+            count_page_tables(&[&plan, &framebuffer_plan, &kernel_plan]) + PAGE_TABLES_SLACK;
+        let arena = Buffer::new_aligned(table_pages * PAGE_SIZE, PAGE_SIZE).unwrap_or_else(|| {
             printf!(
-                b"> Sub-mapping (4KiB pages) 0x%x to 0x%x\r\n",
-                aligned_end,
-                kb4_aligned_end
+                b"Failed to allocate 0x%x pages for page tables !\r\n",
+                table_pages
             );
-            let mut addr = aligned_end;
-            while addr < kb4_aligned_end {
-                map_page_4kb(addr, addr, PAGE_RW, &mut allocator);
-                map_page_4kb(addr + DIRECT_MAPPING_OFFSET, addr, PAGE_RW, &mut allocator);
-                addr += KB4 as u64;
-            }
+            kpanic();
+        });
+        let arena_start = arena.get_ptr() as usize;
+        if state.scrub_handoff_memory {
+            // Pages past the allocator's current page are handed to the kernel as free page-table pages
+            mem::scrub(arena_start, table_pages * PAGE_SIZE);
+        }
+        arena.leak();
+        register_region(
+            arena_start as u64,
+            (arena_start + table_pages * PAGE_SIZE) as u64,
+            b"page tables",
+        );
+        printf!(
+            b"Page tables arena allocator from 0x%x to 0x%x\r\n",
+            arena_start,
+            arena_start + table_pages * PAGE_SIZE
+        );
+        let mut editor = PageTableEditor::new(arena_start, arena_start + table_pages * PAGE_SIZE);
+        let pml4 = editor.pml4();
+
+        execute_plan(&mut editor, &plan);
+        execute_plan(&mut editor, &framebuffer_plan);
+
+        let mut frames = state.stream_kernel.then(|| FrameAllocator::new(&layout));
+        let mut kernel_mappings = Vec::new_tagged(16, b"paging");
+        let (stack_start, stack_end) = load_kernel(
+            kernel_file,
+            &mut editor,
+            frames.as_mut(),
+            state.scrub_handoff_memory,
+            &mut kernel_mappings,
+        )
+        .unwrap_or_else(|e| e.panic());
+        if let Some(frames) = &frames {
+            frames.reserve_used(&mut layout);
+        }
+        reserve_heap_in_use(&mut layout);
+        let num_memory_regions = save_memory_layout(&layout, &tables);
+        let stack_pointer = handoff_stack_pointer(stack_end);
+        validate_entry_point(entry64, &phs, &editor).unwrap_or_else(|e| e.panic());
+
+        let (arena_free_start, arena_end) = editor.arena_free();
+        printf!(
+            b"Page tables: used 0x%x pages of 0x%x estimated\r\n",
+            (arena_free_start - arena_start) / PAGE_SIZE,
+            table_pages
+        );
+
+        printf!(b"\r\nPaging tables built at ");
+        write_addr(pml4);
+        printf!(b"\r\n");
+
+        write_kernel_parameters(
+            state,
+            reservations.len(),
+            &KernelHandoff {
+                num_memory_regions,
+                detected_usable_memory,
+                usable_memory_limit,
+                page_tables: (arena_free_start as u32, arena_end as u32, pml4 as u32),
+                null_page_unmapped: !state.map_null_page,
+                kernel_stack_pointer: stack_pointer,
+                kernel_stack_start: stack_start,
+                kernel_stack_end: stack_end,
+                kernel_stack_guard_size: KERNEL_STACK_GUARD_SIZE,
+                data: HandedOffData::of(state),
+            },
+            &tables,
+        );
+
+        printf!(b"Entry point ");
+        write_addr(entry64);
+        printf!(b", parameters at ");
+        write_addr(tables.parameters as u64);
+        printf!(b", stack top ");
+        write_addr(stack_pointer);
+        printf!(b"\r\n");
+        tables.check_reserved(&layout);
+        check_kernel_memory(
+            &layout,
+            &editor,
+            (
+                arena_start as u64,
+                (arena_start + table_pages * PAGE_SIZE) as u64,
+            ),
+            &phs,
+        );
+        verify_mappings(&editor, &[&plan, &framebuffer_plan, &kernel_mappings]);
+        if !state.map_null_page {
+            check_null_page_unmapped(&editor);
+        }
+        // Everything the kernel gets is final here, and BIOS calls still work until the long mode switch
+        enforce_strict_boot(state.strict_boot, state.strict_allow);
+        // Before the pause, the time spent waiting for a key isn't part of the boot
+        checkpoint(b"jump");
+        printf_boot_timing();
+        pause_before_jump(state.bios_idt, state.pause_before_jump);
+
+        init_gdtr();
+        // Last step before the jump, the BIOS calls are all done and the CPU state is the kernel's
+        add_boot_flags(&tables, enable_paging_features());
+        printf!(b"\r\nJumping to kernel.\r\n\n\n");
+        post_code(codes::JUMP);
+        enable_paging_and_jump64(
+            pml4 as usize,
+            DATA64_SELECTOR,
+            CODE64_SELECTOR,
+            entry64,
+            stack_pointer,
+            tables.parameters as usize,
+        );
+    }
+}
+
+/// Stack handed to a 32-bit kernel, allocated from the heap
+const KERNEL32_STACK_SIZE: usize = 64 * 1024;
+/// 32-bit kernels are loaded at their physical addresses, never below 1MiB where the bootloader and the BIOS data live
+const KERNEL32_MIN_ADDRESS: u64 = 0x10_0000;
+
+/// A range of the kernel file copied to a physical address, the rest of `mem_size` zeroed
+pub struct PhysicalSegment {
+    /// Program header index, for error messages
+    pub index: usize,
+    pub paddr: u64,
+    pub offset: usize,
+    pub file_size: usize,
+    pub mem_size: usize,
+}
+
+/// Reads the LOAD segments of a 32-bit kernel into the heap, see [`stage_physical_segments`]
+fn stage_kernel32<'a>(
+    kernel_file: &'a mut ElfFile32<'a>,
+    phs: &Vec<ElfProgramHeader32>,
+    layout: &Vec<MemoryRegion>,
+) -> Result<StagedSegments, ElfError> {
+    let file_size = kernel_file.get_file().get_size64();
+    let mut segments = Vec::new_tagged(phs.len().max(1), b"paging");
+    for (index, ph) in phs.iter().enumerate() {
+        if ph.segment_type == SEGMENT_TYPE_LOAD && ph.p_memsz != 0 {
+            check_segment_file_size(
+                index,
+                ph.p_offset as u64,
+                ph.p_filesz as u64,
+                ph.p_memsz as u64,
+                file_size,
+            )?;
+            segments.push(PhysicalSegment {
+                index,
+                paddr: ph.p_paddr as u64,
+                offset: ph.p_offset as usize,
+                file_size: ph.p_filesz as usize,
+                mem_size: ph.p_memsz as usize,
+            });
+        }
+    }
+    stage_physical_segments(kernel_file.get_file_mut(), &segments, layout)
+}
+
+/// A segment read into the heap, waiting to be copied to `paddr`
+struct StagedSegment {
+    paddr: u64,
+    mem_size: usize,
+    data: Buffer,
+}
+
+/// Segments of a kernel loaded at physical addresses, read into heap buffers first. <br>
+/// The heap usually starts at 1MiB, right where such kernels are linked, so the bootloader keeps using that memory until [`StagedSegments::copy`] runs just before the jump. <br>
+struct StagedSegments {
+    segments: Vec<StagedSegment>,
+}
+
+impl StagedSegments {
+    /// `(start, end)` of every segment at its physical address
+    fn ranges(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.segments
+            .iter()
+            .map(|segment| (segment.paddr, segment.paddr + segment.mem_size as u64))
+    }
+
+    /// Marks the segments reserved in `layout`
+    fn reserve(&self, layout: &mut Vec<MemoryRegion>) {
+        let mut regions = Vec::new_tagged(layout.len() + self.segments.len(), b"paging");
+        for region in layout.iter() {
+            regions.push(*region);
+        }
+        for (start, end) in self.ranges() {
+            regions.push(MemoryRegion {
+                start: align_down(start, KB4 as u64),
+                end: align_up(end, KB4 as u64),
+                kind: MemoryRegionType::Reserved,
+            });
         }
+        *layout = normalize(regions);
+    }
 
-        let num_memory_regions = layout.len();
+    /// Halts unless every segment is in a region the kernel is told not to use
+    fn check_reserved(&self, layout: &Vec<MemoryRegion>) {
+        for (start, end) in self.ranges() {
+            check_not_usable(layout, start, end, b"kernel segment");
+        }
+    }
 
-        #[allow(static_mut_refs)]
-        if num_memory_regions > KERNEL_MEMORY_LAYOUT.len() {
-            printf!(b"Too many memory regions in layout !\r\n");
+    /// Copies the `len` bytes at `addr` to a new handoff buffer when they overlap a segment, which [`StagedSegments::copy`] would overwrite. <br>
+    /// Returns the address the data is handed off at. <br>
+    unsafe fn relocate(&self, addr: u64, len: usize, scrub: bool) -> u64 {
+        let end = addr + len as u64;
+        if len == 0
+            || !self
+                .ranges()
+                .any(|(start, seg_end)| addr < seg_end && start < end)
+        {
+            return addr;
+        }
+        let Some(buffer) = Buffer::new_handoff(len, scrub) else {
+            printf!(b"Not enough memory to move handoff data out of the kernel segments !\r\n");
             kpanic();
+        };
+        let buffer = buffer.leak();
+        mem::mem_cpy(buffer.get_ptr(), addr as *const u8, len);
+        log_debug!(
+            b"Moved 0x%x bytes at 0x%x out of the kernel segments, to 0x%x\r\n",
+            len,
+            addr as usize,
+            buffer.get_ptr() as usize
+        );
+        buffer.get_ptr() as u64
+    }
+
+    /// Moves the data `state` hands off out of the segments, it was allocated before they were fenced off the heap
+    unsafe fn relocate_handed_off(&self, state: &BootState) -> HandedOffData {
+        let data = HandedOffData::of(state);
+        let scrub = state.scrub_handoff_memory;
+        let (_, _, vbe_mode_count, _) = state.vbe.boot_info();
+        let (initrd_addr, initrd_size) = data.initrd;
+        let (cmdline_ptr, cmdline_len) = data.cmdline;
+        // The cmdline is null terminated
+        let cmdline_size = if cmdline_ptr == 0 {
+            0
+        } else {
+            cmdline_len as usize + 1
+        };
+        HandedOffData {
+            initrd: (
+                self.relocate(initrd_addr, initrd_size as usize, scrub),
+                initrd_size,
+            ),
+            cmdline: (
+                self.relocate(cmdline_ptr as u64, cmdline_size, scrub) as u32,
+                cmdline_len,
+            ),
+            pci_devices_ptr: self.relocate(
+                data.pci_devices_ptr as u64,
+                size_of_val(state.pci_devices.as_slice()),
+                scrub,
+            ) as u32,
+            vbe_modes_info_ptr: self.relocate(
+                data.vbe_modes_info_ptr as u64,
+                // 256 bytes per mode info structure
+                vbe_mode_count as usize * 256,
+                scrub,
+            ) as u32,
+        }
+    }
+
+    /// Copies every segment to its physical address and zeroes its bss. <br>
+    /// This overwrites heap memory, nothing but the jump may run after it. <br>
+    unsafe fn copy(&self) {
+        for segment in self.segments.iter() {
+            let dst = segment.paddr as *mut u8;
+            let file_size = segment.data.len();
+            core::ptr::copy_nonoverlapping(segment.data.get_ptr(), dst, file_size);
+            dst.add(file_size)
+                .write_bytes(0, segment.mem_size - file_size);
         }
+    }
+}
+
+/// Reads `segments` of `file` into the heap, to be copied to their physical addresses by [`StagedSegments::copy`]. <br>
+/// Every segment must be in a usable region of `layout`, above [`KERNEL32_MIN_ADDRESS`]. <br>
+/// The free heap memory under the segments is fenced off first, so the buffers and everything allocated for the handoff after them stay clear of the segments. <br>
+fn stage_physical_segments(
+    file: &mut Ext2File,
+    segments: &Vec<PhysicalSegment>,
+    layout: &Vec<MemoryRegion>,
+) -> Result<StagedSegments, ElfError> {
+    for segment in segments.iter() {
+        let start = segment.paddr;
+        let end = start + segment.mem_size as u64;
+        let usable = layout
+            .iter()
+            .any(|r| r.kind == MemoryRegionType::Usable && r.start <= start && end <= r.end);
+        if start < KERNEL32_MIN_ADDRESS || !usable {
+            return Err(ElfError::SegmentNotInUsableMemory(segment.index, start));
+        }
+    }
+    for segment in segments.iter() {
+        let fenced = mem::fence_heap(segment.paddr, segment.paddr + segment.mem_size as u64);
+        log_debug!(
+            b"Segment %x: 0x%x heap bytes fenced off\r\n",
+            segment.index,
+            fenced
+        );
+    }
+
+    checkpoint(b"kernel read");
+    let total = segments.iter().map(|segment| segment.file_size).sum();
+    let mut progress = ProgressBar::new(b"Loading kernel ", total);
+    let mut staged = Vec::new_tagged(segments.len().max(1), b"paging");
+    for segment in segments.iter() {
+        post_code_progress(codes::SEGMENT_LOAD, segment.index);
         printf!(
-            b"\r\nMemory layout saved at 0x%x (",
-            addr_of!(KERNEL_MEMORY_LAYOUT)
+            b"Loading segment: p_paddr=0x%x, p_memsz=0x%x, p_filesz=0x%x\r\n",
+            segment.paddr as usize,
+            segment.mem_size,
+            segment.file_size
         );
-        write_u32_decimal(num_memory_regions as u32);
-        printf!(b" entries)\r\n\n");
-        for (i, reg) in layout.iter().enumerate() {
-            #[allow(static_mut_refs)]
-            match KERNEL_MEMORY_LAYOUT.get_mut(i) {
-                None => {
-                    printf!(b"Too many memory regions in layout !\r\n");
-                    kpanic();
-                }
-                Some(region) => {
-                    *region = OsMemoryRegion {
-                        start: reg.start,
-                        end: reg.end,
-                        usable: if reg.kind == MemoryRegionType::Usable {
-                            1
-                        } else {
-                            0
-                        },
-                    }
+        let data = Buffer::new_tagged(segment.file_size, b"kernel")
+            .ok_or(ElfError::FailedMemAlloc(segment.file_size))?;
+        if segment.file_size != 0 {
+            file.seek(segment.offset).map_err(ElfError::Ext2Error)?;
+            let mut sink = tee(
+                unsafe { MemorySink::new(data.get_ptr() as usize, segment.file_size) },
+                &mut progress,
+            );
+            let read = file
+                .stream_to(&mut sink, segment.file_size)
+                .map_err(|e| ElfError::Ext2Error(e.into_ext2_error()))?;
+            if read != segment.file_size {
+                printf!(
+                    b"Read 0x%x bytes of 0x%x bytes\r\n",
+                    read,
+                    segment.file_size
+                );
+                unsafe {
+                    let video = Video::get();
+                    video.write_string(&render(Text::KernelReadFailed, &[]));
+                    video.write_char(b'\n');
                 }
+                kpanic();
             }
         }
+        staged.push(StagedSegment {
+            paddr: segment.paddr,
+            mem_size: segment.mem_size,
+            data,
+        });
+    }
+    progress.finish();
+    printf!(
+        b"Kernel file: 0x%x sparse blocks read as zeros\r\n",
+        file.sparse_blocks()
+    );
+    Ok(StagedSegments { segments: staged })
+}
 
-        let (_, stack_end) = load_kernel(kernel_file, &mut allocator).unwrap_or_else(|e| e.panic());
+/// Boots a 32-bit kernel in protected mode, without paging and without requiring long mode. <br>
+/// The kernel gets the same [`ObsiBootKernelParameters`], with the page table fields zeroed, its pointer as the cdecl argument and in `eax`. <br>
+pub fn run_kernel32<'a>(kernel_file: &'a mut ElfFile32<'a>, state: &BootState) {
+    unsafe {
+        let entry = kernel_file.entry_point();
+        printf!(
+            b"Kernel entry point is 0x%x (32-bit, no paging)\r\n\n",
+            entry
+        );
+
+        let reservations = &state.reservations[..state.reservation_count];
+        let (mut layout, detected_usable_memory, usable_memory_limit) =
+            handoff_memory_layout(state, reservations);
+
+        let phs = kernel_file
+            .load_program_headers()
+            .unwrap_or_else(|e| e.panic())
+            .clone();
+        let entry_in_segment = phs.iter().any(|ph| {
+            ph.segment_type == SEGMENT_TYPE_LOAD
+                && ph.p_paddr <= entry
+                && (entry - ph.p_paddr) < ph.p_memsz
+        });
+        if !entry_in_segment {
+            ElfError::EntryOutsideSegments(entry as u64).panic();
+        }
+        // Before anything is allocated for the handoff, the segments are checked against the layout without the heap reserved
+        let staged = stage_kernel32(kernel_file, &phs, &layout).unwrap_or_else(|e| e.panic());
+        // Each segment reserved inside a usable region splits it in at most 3
+        let tables = HandoffTables::allocate(
+            &mut layout,
+            2 * staged.segments.len(),
+            state.scrub_handoff_memory,
+        );
+        dump_memory_layout(state, &layout, reservations);
+
+        let stack = Buffer::new_handoff(KERNEL32_STACK_SIZE, state.scrub_handoff_memory)
+            .unwrap_or_else(|| {
+                printf!(b"Failed to allocate the kernel stack !\r\n");
+                kpanic();
+            });
+        let stack_start = stack.get_ptr() as u64;
+        let stack_end = stack_start + KERNEL32_STACK_SIZE as u64;
+        stack.leak();
+        // The i386 SysV ABI wants esp 16 byte aligned at the `call`, which follows the push of the argument
+        let stack_pointer = align_down(stack_end, 16) - 12;
+
+        let data = staged.relocate_handed_off(state);
+        reserve_heap_in_use(&mut layout);
+        staged.reserve(&mut layout);
+        let num_memory_regions = save_memory_layout(&layout, &tables);
+        write_kernel_parameters(
+            state,
+            reservations.len(),
+            &KernelHandoff {
+                num_memory_regions,
+                detected_usable_memory,
+                usable_memory_limit,
+                page_tables: (0, 0, 0),
+                null_page_unmapped: false,
+                kernel_stack_pointer: stack_pointer,
+                kernel_stack_start: stack_start,
+                kernel_stack_end: stack_end,
+                kernel_stack_guard_size: 0,
+                data,
+            },
+            &tables,
+        );
 
         printf!(
-            b"\r\nPaging tables built at 0x%x%x\r\n",
-            (PML4 as u64 >> 32) as u32,
-            PML4 as u32
+            b"Entry point 0x%x, parameters at 0x%x, stack top 0x%x\r\n",
+            entry,
+            tables.parameters as u32,
+            stack_pointer as u32
         );
+        tables.check_reserved(&layout);
+        check_not_usable(&layout, stack_start, stack_end, b"kernel stack");
+        staged.check_reserved(&layout);
+        enforce_strict_boot(state.strict_boot, state.strict_allow);
+        // Before the pause, the time spent waiting for a key isn't part of the boot
+        checkpoint(b"jump");
+        printf_boot_timing();
+        pause_before_jump(state.bios_idt, state.pause_before_jump);
 
-        let (
-            vbe_info_block_ptr,
-            vbe_modes_info_ptr,
-            vbe_mode_info_block_entry_count,
-            vbe_selected_mode,
-        ) = get_vbe_boot_info();
-        OBSIBOOT = ObsiBootKernelParameters {
-            obsiboot_struct_size: size_of::<ObsiBootKernelParameters>() as u32,
-            obsiboot_struct_version: 1,
-            obsiboot_struct_checksum: [0; 8],
-            bootloader_name_ptr: BOOTLOADER_NAME.as_ptr() as u32,
-            bootloader_version: [1, 0, 0, 0],
-            bios_boot_drive: boot_drive as u32,
-            bios_idt_ptr: bios_idt as u32,
-            ptr_to_memory_layout: addr_of!(KERNEL_MEMORY_LAYOUT) as u32,
-            memory_layout_entry_count: num_memory_regions as u32,
-            memory_layout_entry_size: size_of::<OsMemoryRegion>() as u32,
-            page_tables_page_allocator_current_free_page: allocator.current as u32,
-            page_tables_page_allocator_last_usable_page: allocator.end as u32,
-            pml4_base_address: PML4 as u32,
-            usable_kernel_memory_start: mem::get_last_header(),
-            vbe_info_block_ptr,
-            vbe_modes_info_ptr,
-            vbe_mode_info_block_entry_count,
-            vbe_selected_mode,
-            kernel_stack_pointer: stack_end,
+        init_gdtr();
+        printf!(b"\r\nJumping to 32-bit kernel.\r\n\n\n");
+        post_code(codes::JUMP);
+        staged.copy();
+        jump32(
+            DATA32_SELECTOR,
+            CODE32_SELECTOR,
+            entry,
+            stack_pointer as u32,
+            tables.parameters as usize,
+        );
+    }
+}
+
+/// Conventional memory ends below the EBDA, the VGA memory and the option ROMs
+const LOW_MEMORY_END: u64 = 0xA_0000;
+
+/// Page aligned start of the highest `len` bytes of usable memory below [`LOW_MEMORY_END`], for data kernels expect below 1MiB
+fn low_memory_area(layout: &Vec<MemoryRegion>, len: usize) -> Option<u64> {
+    let size = align_up(len as u64, KB4 as u64);
+    layout
+        .iter()
+        .filter(|r| r.kind == MemoryRegionType::Usable && r.start < LOW_MEMORY_END)
+        .filter_map(|r| {
+            let start = align_down(r.end.min(LOW_MEMORY_END), KB4 as u64).checked_sub(size)?;
+            (start >= r.start).then_some(start)
+        })
+        .max()
+}
+
+/// Boots a kernel with the Multiboot2 protocol: protected mode without paging, `eax` holding [`BOOTLOADER_MAGIC`] and `ebx` the boot information structure. <br>
+/// The boot information is placed in usable memory below 1MiB, the initrd is moved out of the kernel segments when it overlaps them. <br>
+/// The memory map tag is built from the same layout as the ObsiBoot one, memory used by the boot information, the initrd module and the heap is reported as available. <br>
+pub fn run_multiboot2(mut kernel: Multiboot2Kernel, state: &BootState) {
+    unsafe {
+        let reservations = &state.reservations[..state.reservation_count];
+        let (layout, _, _) = handoff_memory_layout(state, reservations);
+        dump_memory_layout(state, &layout, reservations);
+
+        let (segments, entry) = kernel.segments().unwrap_or_else(|e| e.panic());
+        let entry_in_segment = segments.iter().any(|segment| {
+            segment.paddr <= entry as u64
+                && (entry as u64 - segment.paddr) < segment.mem_size as u64
+        });
+        if !entry_in_segment {
+            ElfError::EntryOutsideSegments(entry as u64).panic();
+        }
+        let staged = stage_physical_segments(kernel.file_mut(), &segments, &layout)
+            .unwrap_or_else(|e| e.panic());
+
+        let mut info = BootInformation::new();
+        let (cmdline_ptr, cmdline_len) = state.cmdline;
+        if cmdline_ptr != 0 {
+            info.cmdline(slice::from_raw_parts(
+                cmdline_ptr as *const u8,
+                cmdline_len as usize,
+            ));
+        } else {
+            info.cmdline(b"");
+        }
+        info.bootloader_name(&BOOTLOADER_NAME[..BOOTLOADER_NAME.len() - 1]);
+        let (initrd_addr, initrd_size) = state.initrd;
+        let initrd_addr = staged.relocate(
+            initrd_addr,
+            initrd_size as usize,
+            state.scrub_handoff_memory,
+        );
+        if initrd_size != 0 {
+            info.module(
+                initrd_addr as u32,
+                (initrd_addr + initrd_size) as u32,
+                b"initrd",
+            );
+        }
+        let usable_from = |addr: u64| {
+            layout
+                .iter()
+                .find(|r| r.kind == MemoryRegionType::Usable && r.start <= addr && addr < r.end)
+                .map_or(0, |r| r.end - addr)
         };
-        #[allow(static_mut_refs)]
-        let checksum = OBSIBOOT.calculate_checksum();
-        OBSIBOOT.obsiboot_struct_checksum = checksum;
+        info.basic_meminfo(
+            (usable_from(0).min(0xA0000) / 1024) as u32,
+            (usable_from(0x10_0000) / 1024).min(u32::MAX as u64) as u32,
+        );
+        info.memory_map(
+            layout
+                .iter()
+                .map(|r| (r.start, r.end, r.kind == MemoryRegionType::Usable)),
+        );
+        if let Some(framebuffer) = state.vbe.framebuffer_info() {
+            info.framebuffer(&framebuffer);
+        }
+        let info_size = info.finished_size();
+        let Some(info_ptr) = low_memory_area(&layout, info_size) else {
+            printf!(b"No usable memory below 1MiB for the Multiboot2 boot information !\r\n");
+            kpanic();
+        };
+        register_region(
+            info_ptr,
+            info_ptr + info_size as u64,
+            b"multiboot2 information",
+        );
+        let info_ptr = info_ptr as u32;
+        info.finish(info_ptr);
 
-        init_gdtr();
-        printf!(b"\r\nJumping to kernel.\r\n\n\n");
-        enable_paging_and_jump64(
-            PML4 as usize,
-            DATA64_SELECTOR,
-            CODE64_SELECTOR,
-            entry64,
-            stack_end,
-            addr_of!(OBSIBOOT) as usize,
+        printf!(
+            b"Multiboot2 entry point 0x%x, boot information at 0x%x\r\n",
+            entry,
+            info_ptr
         );
+        enforce_strict_boot(state.strict_boot, state.strict_allow);
+        // Before the pause, the time spent waiting for a key isn't part of the boot
+        checkpoint(b"jump");
+        printf_boot_timing();
+        pause_before_jump(state.bios_idt, state.pause_before_jump);
+
+        init_gdtr();
+        printf!(b"\r\nJumping to Multiboot2 kernel.\r\n\n\n");
+        post_code(codes::JUMP);
+        staged.copy();
+        jump_multiboot2(DATA32_SELECTOR, CODE32_SELECTOR, entry, info_ptr);
     }
 }
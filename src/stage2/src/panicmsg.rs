@@ -0,0 +1,128 @@
+use core::{arch::asm, cell::SyncUnsafeCell, fmt::Write, panic::PanicInfo};
+
+/// Room for the location and the message of one panic, longer reports are cut and end with [`TRUNCATION_MARKER`]
+pub const PANIC_MESSAGE_SIZE: usize = 512;
+pub const TRUNCATION_MARKER: &[u8] = b" [truncated]";
+
+/// A panic report being built, filled front to back and never grown
+pub struct PanicMessage {
+    bytes: [u8; PANIC_MESSAGE_SIZE],
+    len: usize,
+    truncated: bool,
+}
+
+/// Lives outside the heap: the panic path runs exactly when the heap may be exhausted or corrupt
+static PANIC_MESSAGE: SyncUnsafeCell<PanicMessage> = SyncUnsafeCell::new(PanicMessage::new());
+
+impl PanicMessage {
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; PANIC_MESSAGE_SIZE],
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    /// Appends as much of `data` as fits, keeping room for the truncation marker
+    pub fn push(&mut self, data: &[u8]) {
+        if self.truncated {
+            return;
+        }
+        let room = PANIC_MESSAGE_SIZE - TRUNCATION_MARKER.len() - self.len;
+        let count = data.len().min(room);
+        self.bytes[self.len..self.len + count].copy_from_slice(&data[..count]);
+        self.len += count;
+        if count < data.len() {
+            self.bytes[self.len..self.len + TRUNCATION_MARKER.len()]
+                .copy_from_slice(TRUNCATION_MARKER);
+            self.len += TRUNCATION_MARKER.len();
+            self.truncated = true;
+        }
+    }
+
+    pub fn push_decimal(&mut self, mut value: u32) {
+        let mut digits = [0u8; 10];
+        let mut i = digits.len();
+        loop {
+            i -= 1;
+            digits[i] = b'0' + (value % 10) as u8;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        self.push(&digits[i..]);
+    }
+
+    /// Pushes `value` as 8 hex digits
+    pub fn push_hex(&mut self, value: u32) {
+        let mut digits = [0u8; 8];
+        for (i, digit) in digits.iter_mut().enumerate() {
+            *digit = b"0123456789ABCDEF"[(value >> (28 - i * 4)) as usize & 0xF];
+        }
+        self.push(&digits);
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+impl Default for PanicMessage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for PanicMessage {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Registers read as the panic handler is entered
+#[derive(Clone, Copy)]
+pub struct PanicRegisters {
+    pub eflags: u32,
+    pub esp: u32,
+}
+
+impl PanicRegisters {
+    /// Inlined so that ESP is the one of the panic handler frame
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let eflags: u32;
+        let esp: u32;
+        unsafe {
+            asm!("pushfd", "pop {}", out(reg) eflags);
+            asm!("mov {}, esp", out(reg) esp, options(nomem, nostack, preserves_flags));
+        }
+        Self { eflags, esp }
+    }
+}
+
+/// Formats `info` as `file:line:column: message`, then the `registers` line, into the static panic buffer. <br>
+/// Never allocates, only call it once per boot from the panic handler (the buffer is reused). <br>
+pub fn format_panic(info: &PanicInfo, registers: &PanicRegisters) -> &'static [u8] {
+    let message = unsafe { &mut *PANIC_MESSAGE.get() };
+    *message = PanicMessage::new();
+    if let Some(location) = info.location() {
+        message.push(location.file().as_bytes());
+        message.push(b":");
+        message.push_decimal(location.line());
+        message.push(b":");
+        message.push_decimal(location.column());
+        message.push(b": ");
+    }
+    let _ = write!(message, "{}", info.message());
+    message.push(b"\r\nEFLAGS=0x");
+    message.push_hex(registers.eflags);
+    message.push(b" ESP=0x");
+    message.push_hex(registers.esp);
+    message.as_bytes()
+}
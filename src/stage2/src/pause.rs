@@ -0,0 +1,133 @@
+use core::arch::asm;
+
+use crate::{
+    bios::{poll_keystroke, wait_us},
+    e9::write_u32_decimal,
+    fbcon,
+    io::outb,
+    lang::{hex, render, Text},
+    printf,
+    video::{CellDisplay, Character, Color, VgaDisplay},
+};
+
+/// What to do right before jumping to the kernel, see `pause_before_jump=`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PauseBeforeJump {
+    Off,
+    /// Wait for a keypress
+    Key,
+    /// Wait this many seconds, any key skips the countdown
+    Seconds(u32),
+}
+
+/// Parses `off`, `key` or `seconds:<n>`
+pub fn parse_pause_before_jump(value: &[u8]) -> Option<PauseBeforeJump> {
+    match value {
+        b"off" => Some(PauseBeforeJump::Off),
+        b"key" => Some(PauseBeforeJump::Key),
+        _ => {
+            let seconds = value.strip_prefix(b"seconds:")?;
+            Some(PauseBeforeJump::Seconds(u32::from_ascii(seconds).ok()?))
+        }
+    }
+}
+
+/// Keyboard polling period of the countdown, in microseconds
+const POLL_PERIOD_US: u32 = 100_000;
+
+/// Writes `parts` over the last row of `display` and blanks the rest of it, the writing position of the streaming output stays where it was
+fn draw_last_row(display: &mut impl CellDisplay, parts: &[&[u8]]) {
+    let (columns, rows) = display.size();
+    let text = parts.iter().flat_map(|part| part.iter()).copied();
+    for (x, c) in text
+        .chain(core::iter::repeat(b' '))
+        .take(columns)
+        .enumerate()
+    {
+        display.write_run(
+            x,
+            rows - 1,
+            &[Character {
+                character: c,
+                color: Color::color(Color::White, Color::Black),
+            }],
+        );
+    }
+}
+
+/// Rewrites the last row of the framebuffer console once the display left text mode, of the VGA text screen before
+fn draw_status(parts: &[&[u8]]) {
+    if fbcon::with_active_console(|console| draw_last_row(console, parts)).is_none() {
+        draw_last_row(&mut VgaDisplay::new(), parts);
+    }
+}
+
+/// Rewrites the last text row, so the countdown never scrolls the final report away
+fn status_line(seconds: Option<u32>) {
+    let prompt = render(Text::PauseBeforeJump, &[]);
+    match seconds {
+        Some(seconds) => draw_status(&[&prompt, &render(Text::PauseSeconds, &[&hex(seconds)])]),
+        None => draw_status(&[&prompt]),
+    }
+}
+
+/// Resets the machine through the keyboard controller, then the reset control register, then a triple fault. <br>
+/// Halts if all of them fail, the boot never goes on to the kernel. <br>
+fn reboot(bios_idt: usize) -> ! {
+    unsafe {
+        // Pulse the reset line through the keyboard controller
+        outb(0x64, 0xFE);
+        wait_us(bios_idt, 100_000);
+        // Reset control register: request a system reset, then a hard reset
+        outb(0xCF9, 0x02);
+        outb(0xCF9, 0x06);
+        wait_us(bios_idt, 100_000);
+        // With an empty IDT, the breakpoint can't be delivered and the CPU shuts down
+        let idtr = [0u16; 3];
+        asm!(
+            "lidt [{}]",
+            "int3",
+            "2:",
+            "cli",
+            "hlt",
+            "jmp 2b",
+            in(reg) idtr.as_ptr(),
+            options(noreturn)
+        );
+    }
+}
+
+/// Pauses according to `pause`, after everything the kernel gets was prepared. <br>
+/// `r` reboots instead of jumping, any other key resumes the boot. <br>
+pub fn pause_before_jump(bios_idt: usize, pause: PauseBeforeJump) {
+    let mut remaining_us = match pause {
+        PauseBeforeJump::Off => return,
+        PauseBeforeJump::Key => None,
+        PauseBeforeJump::Seconds(seconds) => Some(seconds.saturating_mul(1_000_000)),
+    };
+    printf!(b"Pausing before the jump to the kernel");
+    if let Some(us) = remaining_us {
+        printf!(b" for ");
+        write_u32_decimal(us / 1_000_000);
+        printf!(b" seconds");
+    }
+    printf!(b"\r\n");
+
+    loop {
+        status_line(remaining_us.map(|us| us.div_ceil(1_000_000)));
+        if let Some(key) = poll_keystroke(bios_idt) {
+            if key.ascii == b'r' || key.ascii == b'R' {
+                printf!(b"Rebooting on user request\r\n");
+                reboot(bios_idt);
+            }
+            break;
+        }
+        match remaining_us {
+            Some(0) => break,
+            Some(us) => remaining_us = Some(us.saturating_sub(POLL_PERIOD_US)),
+            None => {}
+        }
+        wait_us(bios_idt, POLL_PERIOD_US);
+    }
+    draw_status(&[]);
+}
@@ -0,0 +1,199 @@
+use crate::{
+    e9::write_hex_u16,
+    io::{inl, outl},
+    mem::Vec,
+    printf,
+};
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+const DEVICES_PER_BUS: u8 = 32;
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+const HEADER_TYPE_MULTIFUNCTION: u8 = 0x80;
+const HEADER_TYPE_GENERAL: u8 = 0x00;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_TO_PCI: u8 = 0x04;
+/// Nested bridges followed, deeper buses aren't scanned
+const MAX_BRIDGE_DEPTH: usize = 16;
+
+/// One PCI function, as handed to the kernel
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct PciDeviceInfo {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    /// Without the multifunction bit
+    pub header_type: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    /// Raw BAR registers, unsized. Bridges only have the first 2, the others are 0
+    pub bars: [u32; 6],
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub reserved: u16,
+}
+
+fn config_read(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let address = CONFIG_ENABLE
+        | (bus as u32) << 16
+        | (device as u32) << 11
+        | (function as u32) << 8
+        | (offset & 0xFC) as u32;
+    unsafe {
+        outl(CONFIG_ADDRESS, address);
+        inl(CONFIG_DATA)
+    }
+}
+
+/// Configuration mechanism #1 is there when the address register keeps what was written to it. <br>
+/// Without a PCI host bridge the port floats, reads return all ones. <br>
+fn mechanism1_present() -> bool {
+    unsafe {
+        let saved = inl(CONFIG_ADDRESS);
+        outl(CONFIG_ADDRESS, CONFIG_ENABLE);
+        let present = inl(CONFIG_ADDRESS) == CONFIG_ENABLE;
+        outl(CONFIG_ADDRESS, saved);
+        present
+    }
+}
+
+/// Short name of the common classes, for the boot log
+fn class_name(class: u8, subclass: u8) -> &'static [u8] {
+    match (class, subclass) {
+        (0x01, 0x01) => b"IDE controller",
+        (0x01, 0x06) => b"SATA controller",
+        (0x01, 0x08) => b"NVMe controller",
+        (0x01, _) => b"storage controller",
+        (0x02, _) => b"network controller",
+        (0x03, _) => b"display controller",
+        (0x04, _) => b"multimedia controller",
+        (0x06, 0x00) => b"host bridge",
+        (0x06, 0x01) => b"ISA bridge",
+        (0x06, 0x04) => b"PCI-to-PCI bridge",
+        (0x06, _) => b"bridge",
+        (0x0C, 0x03) => b"USB controller",
+        (0x0C, _) => b"serial bus controller",
+        _ => b"other",
+    }
+}
+
+struct Scan {
+    devices: Vec<PciDeviceInfo>,
+    scanned: [bool; 256],
+}
+
+impl Scan {
+    fn scan_bus(&mut self, bus: u8, depth: usize) {
+        if self.scanned[bus as usize] || depth > MAX_BRIDGE_DEPTH {
+            return;
+        }
+        self.scanned[bus as usize] = true;
+        for device in 0..DEVICES_PER_BUS {
+            self.scan_device(bus, device, depth);
+        }
+    }
+
+    fn scan_device(&mut self, bus: u8, device: u8, depth: usize) {
+        if config_read(bus, device, 0, 0x00) as u16 == 0xFFFF {
+            return;
+        }
+        let multifunction =
+            (config_read(bus, device, 0, 0x0C) >> 16) as u8 & HEADER_TYPE_MULTIFUNCTION != 0;
+        let functions = if multifunction {
+            FUNCTIONS_PER_DEVICE
+        } else {
+            1
+        };
+        for function in 0..functions {
+            self.scan_function(bus, device, function, depth);
+        }
+    }
+
+    fn scan_function(&mut self, bus: u8, device: u8, function: u8, depth: usize) {
+        let ids = config_read(bus, device, function, 0x00);
+        if ids as u16 == 0xFFFF {
+            return;
+        }
+        let class = config_read(bus, device, function, 0x08);
+        let header_type = (config_read(bus, device, function, 0x0C) >> 16) as u8;
+        let bar_count = match header_type & !HEADER_TYPE_MULTIFUNCTION {
+            HEADER_TYPE_GENERAL => 6,
+            HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+        let mut bars = [0; 6];
+        for (i, bar) in bars.iter_mut().enumerate().take(bar_count) {
+            *bar = config_read(bus, device, function, 0x10 + i as u8 * 4);
+        }
+        let interrupt = config_read(bus, device, function, 0x3C);
+        let info = PciDeviceInfo {
+            bus,
+            device,
+            function,
+            header_type: header_type & !HEADER_TYPE_MULTIFUNCTION,
+            vendor_id: ids as u16,
+            device_id: (ids >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            bars,
+            interrupt_line: interrupt as u8,
+            interrupt_pin: (interrupt >> 8) as u8,
+            reserved: 0,
+        };
+        printf_device(&info);
+        self.devices.push(info);
+
+        if info.class == CLASS_BRIDGE && info.subclass == SUBCLASS_PCI_TO_PCI {
+            let secondary_bus = (config_read(bus, device, function, 0x18) >> 8) as u8;
+            self.scan_bus(secondary_bus, depth + 1);
+        }
+    }
+}
+
+/// `bb:dd.f vvvv:dddd class cc.ss.pp name`
+fn printf_device(info: &PciDeviceInfo) {
+    printf!(b"PCI %b:%b.%b ", info.bus, info.device, info.function);
+    write_hex_u16(info.vendor_id);
+    printf!(b":");
+    write_hex_u16(info.device_id);
+    printf!(b" class %b.%b.%b ", info.class, info.subclass, info.prog_if);
+    printf!(class_name(info.class, info.subclass));
+    printf!(b"\r\n");
+}
+
+/// Enumerates the PCI functions through configuration mechanism #1, following PCI-to-PCI bridges. <br>
+/// A multifunction host bridge at 00:00.0 means one host controller per function, each owning the bus of the same number. <br>
+/// Returns an empty list on machines without PCI. <br>
+pub fn enumerate_pci() -> Vec<PciDeviceInfo> {
+    let mut scan = Scan {
+        devices: Vec::new(16),
+        scanned: [false; 256],
+    };
+    if !mechanism1_present() {
+        printf!(b"PCI: configuration mechanism #1 not present, no devices\r\n");
+        return scan.devices;
+    }
+    let multiple_host_controllers =
+        (config_read(0, 0, 0, 0x0C) >> 16) as u8 & HEADER_TYPE_MULTIFUNCTION != 0;
+    if multiple_host_controllers {
+        for function in 0..FUNCTIONS_PER_DEVICE {
+            if config_read(0, 0, function, 0x00) as u16 != 0xFFFF {
+                scan.scan_bus(function, 0);
+            }
+        }
+    } else {
+        scan.scan_bus(0, 0);
+    }
+    printf!(b"PCI: 0x%x functions\r\n", scan.devices.len());
+    scan.devices
+}
@@ -0,0 +1,61 @@
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use crate::io::{outb, UNUSED_PORT};
+
+/// # POST code map
+/// Codes written to port 0x80 to localize hangs on machines without any other output. <br>
+/// Note: These values are published and must stay stable across releases. New codes may be added, existing ones must never be renumbered. <br>
+pub mod codes {
+    /// Written by `iowait` before any marker was emitted
+    pub const IDLE: u8 = 0x01;
+    /// Enumerating PCI devices
+    pub const PCI_SCAN: u8 = 0x10;
+    /// Memory detection started
+    pub const MEMORY_DETECT: u8 = 0x20;
+    /// E820 entry N, emitted as `E820_ENTRY + (N % 15)` (0x21..=0x2F)
+    pub const E820_ENTRY: u8 = 0x21;
+    /// Reading the GUID partition table
+    pub const GPT_READ: u8 = 0x30;
+    /// Mounting the boot filesystem
+    pub const MOUNT: u8 = 0x40;
+    /// Reading and parsing the config file
+    pub const CONFIG: u8 = 0x50;
+    /// Reading the kernel ELF headers
+    pub const KERNEL_HEADERS: u8 = 0x60;
+    /// Loading kernel segment N, emitted as `SEGMENT_LOAD + (N % 15)` (0x61..=0x6F)
+    pub const SEGMENT_LOAD: u8 = 0x61;
+    /// Building the page tables
+    pub const PAGING_BUILD: u8 = 0x70;
+    /// About to jump to the kernel
+    pub const JUMP: u8 = 0x7F;
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static LAST_CODE: AtomicU8 = AtomicU8::new(codes::IDLE);
+
+/// Writes a POST code to port 0x80, unless emission was disabled
+pub fn post_code(code: u8) {
+    LAST_CODE.store(code, Ordering::Relaxed);
+    if ENABLED.load(Ordering::Relaxed) {
+        unsafe { outb(UNUSED_PORT, code) };
+    }
+}
+
+/// Writes `base + (n % 15)`, used as a heartbeat by loops that own a 16-code range
+pub fn post_code_progress(base: u8, n: usize) {
+    post_code(base + (n % 15) as u8);
+}
+
+/// Returns the last emitted POST code, or [`codes::IDLE`] if none was emitted yet
+pub fn last_post_code() -> u8 {
+    LAST_CODE.load(Ordering::Relaxed)
+}
+
+/// Enables or disables writes to port 0x80, for boards where it is repurposed
+pub fn set_post_codes_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn post_codes_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,471 @@
+use core::arch::{asm, x86::__cpuid};
+
+use crate::{
+    bench,
+    bios::{preferred_transfer_sectors, short_read_count, DiskParams},
+    cpu_extensions::ExtensionsStatus,
+    diskhealth::{merged_record, LBA_BUCKET_SECTORS},
+    fs::{Ext2FileSystem, Ext2FileType},
+    install::{BOOTLOADER_VERSION, BUILD_ID_HEX},
+    io::outb,
+    iolat, kpanic,
+    media::{boot_media, writes_allowed},
+    mem::{Buffer, SystemMemory, Vec},
+    obsiboot::ObsiBootConfig,
+    printf,
+    video::Video,
+    warnings::{enforce_strict_boot, recorded_warnings, strict_violations, warning_count},
+};
+
+pub enum BootMode {
+    /// Load and run `/kernel64.elf`
+    Kernel,
+    /// Inventory the machine and write the report to the boot partition, without loading any kernel
+    Probe,
+    /// Benchmark the boot disk, then write the probe report with the results
+    Bench,
+}
+
+pub enum ProbeThen {
+    Halt,
+    Reboot,
+}
+
+const DEFAULT_REPORT_PATH: &[u8] = b"/obsiboot-report.txt";
+
+/// Everything the probe report is built from, gathered by the regular boot phases
+pub struct ProbeInputs<'a> {
+    pub boot_drive: u8,
+    pub disk_params: &'a DiskParams,
+    pub memory: &'a SystemMemory,
+    pub extensions: &'a ExtensionsStatus,
+}
+
+#[derive(Clone, Copy)]
+struct ReportSection {
+    name: &'static [u8],
+    start: usize,
+    end: usize,
+    optional: bool,
+}
+
+/// Text report made of `[section]` headers followed by `key=value` lines
+struct ProbeReport {
+    data: Vec<u8>,
+    sections: Vec<ReportSection>,
+}
+
+impl ProbeReport {
+    fn new() -> Self {
+        Self {
+            data: Vec::new_tagged(1024, b"probe"),
+            sections: Vec::new_tagged(8, b"probe"),
+        }
+    }
+
+    fn put(&mut self, text: &[u8]) {
+        for c in text {
+            self.data.push(*c);
+        }
+    }
+
+    fn put_hex(&mut self, value: u64) {
+        self.put(b"0x");
+        let digits = (64 - value.leading_zeros()).div_ceil(4).max(1);
+        for i in (0..digits).rev() {
+            self.data
+                .push(b"0123456789ABCDEF"[((value >> (i * 4)) & 0xF) as usize]);
+        }
+    }
+
+    fn put_decimal(&mut self, value: u64) {
+        let mut digits = [0u8; 20];
+        let mut n = value;
+        let mut i = digits.len();
+        loop {
+            i -= 1;
+            digits[i] = b'0' + (n % 10) as u8;
+            n /= 10;
+            if n == 0 {
+                break;
+            }
+        }
+        self.put(&digits[i..]);
+    }
+
+    fn key_hex(&mut self, key: &[u8], value: u64) {
+        self.put(key);
+        self.put(b"=");
+        self.put_hex(value);
+        self.put(b"\n");
+    }
+
+    fn key_decimal(&mut self, key: &[u8], value: u64) {
+        self.put(key);
+        self.put(b"=");
+        self.put_decimal(value);
+        self.put(b"\n");
+    }
+
+    fn section(&mut self, name: &'static [u8], optional: bool, body: impl FnOnce(&mut Self)) {
+        let start = self.data.len();
+        self.put(b"[");
+        self.put(name);
+        self.put(b"]\n");
+        body(self);
+        self.sections.push(ReportSection {
+            name,
+            start,
+            end: self.data.len(),
+            optional,
+        });
+    }
+
+    /// Renders the report into exactly `max_len` bytes, dropping optional sections from the end until it fits. <br>
+    /// Dropped sections are listed in a trailing `[dropped]` section, the rest is padded with newlines so no older report shows through. <br>
+    fn render(&self, max_len: usize) -> Result<Buffer, RenderError> {
+        // Bit i set = section i dropped
+        let mut dropped: u32 = 0;
+        let dropped_len = |dropped: u32| {
+            let mut len = 0;
+            for (i, section) in self.sections.iter().enumerate() {
+                if dropped & (1 << i) != 0 {
+                    len += section.name.len() + 1;
+                }
+            }
+            if len == 0 {
+                0
+            } else {
+                len + b"[dropped]\n".len()
+            }
+        };
+        let total_len = |dropped: u32| {
+            let mut len = 0;
+            for (i, section) in self.sections.iter().enumerate() {
+                if dropped & (1 << i) == 0 {
+                    len += section.end - section.start;
+                }
+            }
+            len + dropped_len(dropped)
+        };
+
+        let mut i = self.sections.len();
+        while total_len(dropped) > max_len {
+            if i == 0 {
+                return Err(RenderError::TooSmall);
+            }
+            i -= 1;
+            if self.sections.get(i).is_some_and(|section| section.optional) {
+                dropped |= 1 << i;
+            }
+        }
+
+        let mut out = Buffer::new_tagged(max_len.max(1), b"probe")
+            .ok_or(RenderError::FailedMemAlloc(max_len))?;
+        let mut at = 0;
+        let mut copy = |out: &mut Buffer, bytes: &mut dyn Iterator<Item = u8>| {
+            for c in bytes {
+                if let Some(b) = out.get_mut(at) {
+                    *b = c;
+                }
+                at += 1;
+            }
+        };
+        for (i, section) in self.sections.iter().enumerate() {
+            if dropped & (1 << i) == 0 {
+                copy(
+                    &mut out,
+                    &mut (section.start..section.end).filter_map(|j| self.data.get(j).copied()),
+                );
+            }
+        }
+        if dropped != 0 {
+            copy(&mut out, &mut b"[dropped]\n".iter().copied());
+            for (i, section) in self.sections.iter().enumerate() {
+                if dropped & (1 << i) != 0 {
+                    copy(&mut out, &mut section.name.iter().copied());
+                    copy(&mut out, &mut b"\n".iter().copied());
+                }
+            }
+        }
+        let padding = max_len - total_len(dropped);
+        copy(&mut out, &mut core::iter::repeat_n(b'\n', padding));
+        Ok(out)
+    }
+}
+
+enum RenderError {
+    /// The file can't hold the required sections
+    TooSmall,
+    FailedMemAlloc(usize),
+}
+
+fn build_report(inputs: &ProbeInputs, config: &ObsiBootConfig) -> ProbeReport {
+    let mut report = ProbeReport::new();
+
+    report.section(b"obsiboot", false, |r| {
+        r.put(b"version=");
+        for (i, part) in BOOTLOADER_VERSION.iter().enumerate() {
+            if i != 0 {
+                r.put(b".");
+            }
+            r.put_decimal(*part as u64);
+        }
+        r.put(b"\n");
+        r.put(b"build_id=");
+        r.put(BUILD_ID_HEX.as_bytes());
+        r.put(b"\n");
+        r.key_hex(b"boot_drive", inputs.boot_drive as u64);
+    });
+
+    report.section(b"memory", false, |r| {
+        let mut usable = 0;
+        for map in inputs.memory.entries() {
+            r.put(b"e820=");
+            r.put_hex(map.base_addr());
+            r.put(b" ");
+            r.put_hex(map.len());
+            r.put(b" ");
+            r.put_decimal(map.range_type() as u64);
+            if !map.is_enabled() {
+                r.put(b" ignored");
+            }
+            r.put(b"\n");
+            if map.is_enabled() && map.range_type() == crate::mem::RANGE_TYPE_AVAILABLE {
+                usable += map.len();
+            }
+        }
+        r.key_hex(b"usable_bytes", usable);
+    });
+
+    if !config.reservations().is_empty() {
+        report.section(b"reservations", false, |r| {
+            for reservation in config.reservations() {
+                r.put(b"reserve=");
+                r.put_hex(reservation.start);
+                r.put(b" ");
+                r.put_hex(reservation.end);
+                r.put(b" ");
+                r.put(reservation.label());
+                r.put(b"\n");
+            }
+        });
+    }
+
+    report.section(b"disk", false, |r| {
+        let params = inputs.disk_params;
+        r.key_hex(b"info", params.info as u64);
+        r.key_decimal(b"sectors", params.sectors);
+        r.key_decimal(b"bytes_per_sector", params.bytes_per_sector as u64);
+        r.key_decimal(b"cylinders", params.cylinders as u64);
+        r.key_decimal(b"heads", params.heads as u64);
+        r.key_decimal(b"sectors_per_track", params.sectors_per_track as u64);
+        r.key_decimal(b"short_reads", short_read_count() as u64);
+        let media = boot_media();
+        r.put(b"media_type=");
+        r.put(media.media_type.name());
+        r.put(b"\n");
+        r.key_decimal(b"removable", media.removable as u64);
+        r.key_decimal(b"writable", media.writable() as u64);
+    });
+
+    if iolat::enabled() {
+        report.section(b"bios_latency", true, |r| {
+            for (interrupt, histogram) in iolat::histograms() {
+                if histogram.calls == 0 {
+                    continue;
+                }
+                match interrupt {
+                    Some(interrupt) => {
+                        r.put(b"int");
+                        r.put_hex(interrupt as u64);
+                    }
+                    None => r.put(b"other"),
+                }
+                r.put(b"=");
+                for (i, count) in histogram.buckets.iter().enumerate() {
+                    if i != 0 {
+                        r.put(b",");
+                    }
+                    r.put_decimal(*count as u64);
+                }
+                r.put(b" max_us=");
+                r.put_decimal(iolat::cycles_to_us(histogram.max_cycles));
+                r.put(b"\n");
+            }
+            if let Some(worst) = iolat::worst_call() {
+                r.key_hex(b"worst_int", worst.interrupt as u64);
+                r.key_decimal(b"worst_us", iolat::cycles_to_us(worst.cycles));
+                for (name, value) in [
+                    b"worst_eax",
+                    b"worst_ebx",
+                    b"worst_ecx",
+                    b"worst_edx",
+                    b"worst_esi",
+                    b"worst_edi",
+                ]
+                .iter()
+                .zip(worst.registers)
+                {
+                    r.key_hex(*name, value as u64);
+                }
+            }
+        });
+    }
+
+    if bench::results().next().is_some() {
+        report.section(b"disk_bench", false, |r| {
+            for result in bench::results() {
+                r.put(b"sectors_per_call=");
+                r.put_decimal(result.sectors_per_call as u64);
+                r.put(b" bytes=");
+                r.put_decimal(result.bytes);
+                r.put(b" us=");
+                r.put_decimal(result.us);
+                r.put(b" kib_per_s=");
+                r.put_decimal(result.kib_per_second());
+                r.put(b"\n");
+            }
+            r.key_decimal(
+                b"preferred_sectors_per_call",
+                preferred_transfer_sectors() as u64,
+            );
+        });
+    }
+
+    report.section(b"disk_health", true, |r| {
+        let record = merged_record();
+        r.key_decimal(b"boots", record.boots as u64);
+        r.key_decimal(b"bucket_sectors", LBA_BUCKET_SECTORS);
+        for entry in record.entries() {
+            r.put(b"error=");
+            r.put_decimal(entry.bucket as u64);
+            r.put(b" ");
+            r.put_hex(entry.code as u64);
+            r.put(b" ");
+            r.put_decimal(entry.count as u64);
+            r.put(b"\n");
+        }
+    });
+
+    report.section(b"warnings", false, |r| {
+        let violations = strict_violations(config.strict_allow);
+        r.key_decimal(b"count", warning_count() as u64);
+        r.key_decimal(b"strict_boot", config.strict_boot as u64);
+        r.put(b"status=");
+        r.put(if config.strict_boot && violations != 0 {
+            b"strict_failed"
+        } else {
+            b"ok"
+        });
+        r.put(b"\n");
+        for id in recorded_warnings() {
+            r.put(b"warning=");
+            r.put(id.name());
+            r.put(b"\n");
+        }
+    });
+
+    report.section(b"cpu", true, |r| {
+        let (leaf0, leaf1) = (__cpuid(0), __cpuid(1));
+        r.put(b"vendor=");
+        for reg in [leaf0.ebx, leaf0.edx, leaf0.ecx] {
+            r.put(&reg.to_le_bytes());
+        }
+        r.put(b"\n");
+        r.key_hex(b"max_leaf", leaf0.eax as u64);
+        r.key_hex(b"signature", leaf1.eax as u64);
+        r.key_hex(b"features_ecx", leaf1.ecx as u64);
+        r.key_hex(b"features_edx", leaf1.edx as u64);
+        r.key_decimal(b"hypervisor", ((leaf1.ecx >> 31) & 1) as u64);
+        r.key_decimal(b"fpu", inputs.extensions.fpu as u64);
+        r.key_decimal(b"sse", inputs.extensions.sse as u64);
+    });
+
+    report
+}
+
+fn probe_failed(message: &[u8]) -> ! {
+    printf!(b"Probe failed: ");
+    crate::e9::write_string(message);
+    printf!(b"\r\n");
+    unsafe {
+        let video = Video::get();
+        video.write_string(b"Probe failed: ");
+        video.write_string(message);
+        video.write_char(b'\n');
+    }
+    kpanic();
+}
+
+/// Writes the hardware report to the configured file and halts or reboots, never loads a kernel. <br>
+/// The report file must already exist on the boot partition and be large enough: it is overwritten in place, never grown. <br>
+/// Optional sections are dropped (and listed under `[dropped]`) when the file is too small for the full report. <br>
+pub fn run_probe_mode(
+    ext2: &mut Ext2FileSystem,
+    config: &ObsiBootConfig,
+    inputs: &ProbeInputs,
+) -> ! {
+    printf!(b"Probe mode: collecting hardware report\r\n");
+    let report = build_report(inputs, config);
+
+    let path: &[u8] = match &config.probe_report {
+        Some(path) => path,
+        None => DEFAULT_REPORT_PATH,
+    };
+    let inode = match ext2.find_inode(path).unwrap_or_else(|e| e.panic()) {
+        Some(inode) => inode,
+        None => probe_failed(b"report file missing, pre-create it on the boot partition"),
+    };
+    let Ext2FileType::File(mut file) = ext2.open(inode).unwrap_or_else(|e| e.panic()) else {
+        probe_failed(b"report path is not a regular file");
+    };
+
+    let rendered = match report.render(file.get_size()) {
+        Ok(rendered) => rendered,
+        Err(RenderError::TooSmall) => {
+            probe_failed(b"report file too small for the required sections")
+        }
+        Err(RenderError::FailedMemAlloc(size)) => {
+            printf!(b"Probe report: failed to allocate 0x%x bytes\r\n", size);
+            probe_failed(b"not enough memory to render the report")
+        }
+    };
+    if writes_allowed(b"Probe report") {
+        let written = file.write_in_place(&rendered).unwrap_or_else(|e| e.panic());
+
+        printf!(
+            b"Probe report: wrote 0x%x bytes (file size 0x%x)\r\n",
+            written,
+            file.get_size()
+        );
+        unsafe {
+            let video = Video::get();
+            video.write_string(b"Probe report written: 0x");
+            video.write_hex_u32(written as u32);
+            video.write_string(b" bytes, ");
+            video.write_hex_u32(inputs.memory.entries().len() as u32);
+            video.write_string(b" E820 entries\n");
+        }
+    } else {
+        unsafe {
+            let video = Video::get();
+            video.write_string(
+                b"Probe report not written: the boot medium is read-only, see the log\n",
+            );
+        }
+    }
+    // After the report is written, so it records the failure status
+    enforce_strict_boot(config.strict_boot, config.strict_allow);
+
+    match config.probe_then {
+        ProbeThen::Reboot => unsafe {
+            // Pulse the reset line through the keyboard controller
+            outb(0x64, 0xFE);
+        },
+        ProbeThen::Halt => {}
+    }
+    loop {
+        unsafe { asm!("cli", "hlt") };
+    }
+}
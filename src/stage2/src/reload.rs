@@ -0,0 +1,199 @@
+use crate::{
+    e9::write_string,
+    fs::{Ext2Error, Ext2FileSystem, Ext2FileType},
+    kpanic,
+    log::configure_logging,
+    log_warn,
+    mem::Buffer,
+    obsiboot::ObsiBootConfig,
+    printf,
+    warnings::{warning, WarningId},
+};
+
+/// Config files looked up in this order, the first one found is used. `/obsiboot.conf` is the name used by older installs.
+pub const CONFIG_PATHS: [&[u8]; 3] = [b"/boot/obsiboot.cfg", b"/obsiboot.cfg", b"/obsiboot.conf"];
+/// Path embedded at build time from the `OBSIBOOT_CONFIG` environment variable, looked up before [`CONFIG_PATHS`]
+pub const CONFIG_OVERRIDE: Option<&str> = option_env!("OBSIBOOT_CONFIG");
+/// Larger config files are refused
+pub const MAX_CONFIG_SIZE: usize = 64 * 1024;
+
+/// Keys whose effect can't be undone once the boot flow applied them
+const APPLIED_AT_BOOT_KEYS: [&[u8]; 14] = [
+    b"vbe_mode",
+    b"vbe_mode_fallback",
+    b"fb_font",
+    b"mem_limit",
+    b"reserve",
+    b"bios_latency",
+    b"warn_on_multiple_installs",
+    b"mode",
+    b"boot_partition",
+    b"boot_drive",
+    b"text_mode",
+    b"lang_file",
+    b"default",
+    b"timeout",
+];
+
+/// The active config, with the text it was parsed from so a reload can be compared against it
+pub struct LoadedConfig {
+    pub config: ObsiBootConfig,
+    pub source: Option<Buffer>,
+    pub mtime: Option<u32>,
+    /// The file the config was read from, None when the built-in defaults are used
+    pub path: Option<&'static [u8]>,
+}
+
+/// Reads the whole config file at `path`. Returns None when nothing is there, and panics with the path when the file can't be read.
+fn read_config_file(ext2: &mut Ext2FileSystem, path: &'static [u8]) -> Option<LoadedConfig> {
+    let failed = |reason: &[u8]| -> ! {
+        printf!(b"Failed to read the config file ");
+        write_string(path);
+        printf!(b": ");
+        write_string(reason);
+        printf!(b"\r\n");
+        kpanic();
+    };
+    let mut file = match ext2.open_path(path) {
+        Ok(Ext2FileType::File(file)) => file,
+        Ok(_) => {
+            write_string(path);
+            printf!(b" is not a file, ignored\r\n");
+            return None;
+        }
+        Err(Ext2Error::FileNotFound(_)) => return None,
+        Err(e) => {
+            printf!(b"Failed to open the config file ");
+            write_string(path);
+            printf!(b"\r\n");
+            e.panic();
+        }
+    };
+    let size = file.get_size64();
+    if size > MAX_CONFIG_SIZE as u64 {
+        failed(b"larger than 64KiB");
+    }
+    let size = size as usize;
+    let Some(mut contents) = Buffer::new(size.max(1)) else {
+        failed(b"out of memory");
+    };
+    let read = file.read(&mut contents, size).unwrap_or_else(|e| {
+        printf!(b"Failed to read the config file ");
+        write_string(path);
+        printf!(b"\r\n");
+        e.panic();
+    });
+    if read != size {
+        failed(b"file ended before its inode size");
+    }
+    printf!(b"Found obsiboot config at ");
+    write_string(path);
+    printf!(b", 0x%x bytes\r\n", size);
+    Some(LoadedConfig {
+        config: ObsiBootConfig::parse(&contents[..size]),
+        source: Some(contents),
+        mtime: Some(file.get_mtime()),
+        path: Some(path),
+    })
+}
+
+/// Reads and parses the first config file found, see [`CONFIG_OVERRIDE`] and [`CONFIG_PATHS`]. <br>
+/// Without any, the built-in defaults are used and a warning is raised. <br>
+pub fn read_config(ext2: &mut Ext2FileSystem) -> LoadedConfig {
+    let override_path = CONFIG_OVERRIDE.map(str::as_bytes);
+    for path in override_path.iter().chain(CONFIG_PATHS.iter()) {
+        if let Some(loaded) = read_config_file(ext2, path) {
+            return loaded;
+        }
+        if Some(*path) == override_path {
+            printf!(b"Config override ");
+            write_string(path);
+            printf!(b" not found, trying the default paths\r\n");
+        }
+    }
+    warning(WarningId::ConfigMissing);
+    log_warn!(b"No config file found, using the built-in defaults\r\n");
+    LoadedConfig {
+        config: ObsiBootConfig::empty(),
+        source: None,
+        mtime: None,
+        path: None,
+    }
+}
+
+/// Iterates the `key=value` lines of a config, comments and lines without `=` are skipped. CRLF line ends are accepted.
+fn config_lines(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    data.split(|c| *c == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.starts_with(b"#"))
+        .filter_map(|line| {
+            let equals = line.iter().position(|c| *c == b'=')?;
+            Some((&line[..equals], &line[equals + 1..]))
+        })
+}
+
+fn printf_line(prefix: &[u8], key: &[u8], value: &[u8]) {
+    write_string(prefix);
+    write_string(key);
+    printf!(b"=");
+    write_string(value);
+}
+
+fn printf_applied_note(key: &[u8]) {
+    if APPLIED_AT_BOOT_KEYS.contains(&key) {
+        printf!(b" (requires reboot to apply)");
+    }
+    printf!(b"\r\n");
+}
+
+/// Logs the keys added, removed and changed between two config texts. <br>
+/// A key set once on both sides is reported as changed (old -> new), repeatable keys like `initrd=` are compared line by line. <br>
+/// Returns the number of differences. <br>
+pub fn printf_config_diff(old: &[u8], new: &[u8]) -> usize {
+    let mut differences = 0;
+    let count = |data: &[u8], key: &[u8]| config_lines(data).filter(|(k, _)| *k == key).count();
+
+    for (key, value) in config_lines(old) {
+        if count(old, key) == 1 && count(new, key) == 1 {
+            let Some((_, new_value)) = config_lines(new).find(|(k, _)| *k == key) else {
+                continue;
+            };
+            if new_value != value {
+                differences += 1;
+                printf_line(b"    ~ ", key, value);
+                printf!(b" -> ");
+                write_string(new_value);
+                printf_applied_note(key);
+            }
+        } else if !config_lines(new).any(|line| line == (key, value)) {
+            differences += 1;
+            printf_line(b"    - ", key, value);
+            printf_applied_note(key);
+        }
+    }
+    for (key, value) in config_lines(new) {
+        let changed = count(old, key) == 1 && count(new, key) == 1;
+        if !changed && !config_lines(old).any(|line| line == (key, value)) {
+            differences += 1;
+            printf_line(b"    + ", key, value);
+            printf_applied_note(key);
+        }
+    }
+    differences
+}
+
+/// Re-reads the config from disk through [`read_config`], logs what changed and replaces `active` with it. <br>
+/// Keys in `APPLIED_AT_BOOT_KEYS` are swapped too, but are reported as only taking effect on the next boot. <br>
+pub fn reload_config(ext2: &mut Ext2FileSystem, active: &mut LoadedConfig) {
+    let reloaded = read_config(ext2);
+    let old: &[u8] = active.source.as_deref().unwrap_or(b"");
+    let new: &[u8] = reloaded.source.as_deref().unwrap_or(b"");
+    printf!(b"Reloaded ");
+    write_string(reloaded.path.unwrap_or(b"the built-in defaults"));
+    printf!(b", changes:\r\n");
+    if printf_config_diff(old, new) == 0 {
+        printf!(b"    (none)\r\n");
+    }
+    configure_logging(reloaded.config.log_level, reloaded.config.vga_log_level);
+    *active = reloaded;
+}
@@ -0,0 +1,27 @@
+use crate::{
+    bios::{keyboard_flags, peek_keystroke, poll_keystroke, KEYBOARD_FLAGS_SHIFT},
+    e9::set_vga_mirror,
+    printf,
+    video::{Color, Video},
+};
+
+/// Keys that select safe mode when pressed before stage2 starts, besides holding Shift
+const SAFE_MODE_KEYS: [u8; 2] = *b"sS";
+
+/// Enters safe mode when Shift is held or `s` was pressed, checked once right after the screen is cleared. <br>
+/// Safe mode keeps the display in VGA text mode whatever `vbe_mode=` says, and mirrors the e9 log on screen. <br>
+/// Any other pending key is left for the boot menu. <br>
+pub fn check_safe_mode_key(bios_idt: usize) -> bool {
+    let shift = keyboard_flags(bios_idt) & KEYBOARD_FLAGS_SHIFT != 0;
+    let key = peek_keystroke(bios_idt).is_some_and(|key| SAFE_MODE_KEYS.contains(&key.ascii));
+    if key {
+        poll_keystroke(bios_idt);
+    }
+    if !shift && !key {
+        return false;
+    }
+    Video::println(b"SAFE MODE", Color::Black, Color::Yellow);
+    printf!(b"Safe mode: graphics mode switching disabled, logging to the screen\r\n");
+    set_vga_mirror(true);
+    true
+}
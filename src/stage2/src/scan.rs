@@ -0,0 +1,382 @@
+use core::ops::RangeInclusive;
+
+use crate::{
+    bios::{bios_ticks, sectors_read, ticks_since, DiskParams, ExtendedDisk},
+    e9::write_guid,
+    e9::write_string,
+    fs::{superblock_string, Ext2FileSystem, Ext2Probe},
+    gpt::{parse_guid, GUIDPartitionTable, PARTITION_GUID_TYPE_LINUX_FS},
+    iso9660::{find_iso_file, is_optical, BOOT_IMAGE_PATH},
+    kpanic,
+    mem::{Buffer, Vec},
+    printf,
+};
+
+/// Partition named by `boot_partition=`
+pub enum BootPartitionSelector {
+    /// Position in the partition listing printed at boot
+    Index(usize),
+    /// Unique partition GUID, in on-disk byte order
+    Guid([u8; 16]),
+    /// GPT partition name
+    Name(Buffer),
+}
+
+/// Parses a decimal partition index, a unique partition GUID, or else takes the value as a partition name
+pub fn parse_boot_partition(value: &[u8]) -> Option<BootPartitionSelector> {
+    if value.is_empty() {
+        return None;
+    }
+    if let Ok(index) = usize::from_ascii(value) {
+        return Some(BootPartitionSelector::Index(index));
+    }
+    if let Some(guid) = parse_guid(value) {
+        return Some(BootPartitionSelector::Guid(guid));
+    }
+    let Some(mut name) = Buffer::new(value.len()) else {
+        kpanic();
+    };
+    name.copy_from_slice(value);
+    Some(BootPartitionSelector::Name(name))
+}
+
+/// Time the full mounts of one scan may take, in BIOS timer ticks (18.2 per second). <br>
+/// Once spent, the remaining candidates are not mounted unless none was mountable yet. <br>
+pub const MOUNT_SCAN_BUDGET_TICKS: u32 = 36;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CandidateStatus {
+    /// The superblock probe failed, not an ext2 filesystem
+    NotExt2,
+    MountFailed,
+    /// Mounted, but the kernel isn't there
+    NoKernel,
+    /// Mounted without the kernel, used because no candidate has it
+    Fallback,
+    Selected,
+    /// Skipped once a candidate was selected or the time budget was spent
+    NotExamined,
+}
+
+struct Candidate {
+    partition: usize,
+    probe: Option<Ext2Probe>,
+    status: CandidateStatus,
+    /// Sectors read by the probe and the full mount
+    sectors: usize,
+}
+
+/// Higher is mounted first: a partition labelled "boot" or last mounted at /boot likely holds the kernel at its root
+fn likelihood(probe: &Ext2Probe) -> u32 {
+    let mut score = 0;
+    if superblock_string(&probe.volume_name).eq_ignore_ascii_case(b"boot") {
+        score += 2;
+    }
+    match superblock_string(&probe.last_mount_path) {
+        b"/boot" => score += 2,
+        b"/" => score += 1,
+        _ => {}
+    }
+    score
+}
+
+fn printf_candidates(candidates: &Vec<Candidate>) {
+    printf!(b"Partition scan:\r\n");
+    for candidate in candidates.iter() {
+        printf!(b"    partition 0x%x: ", candidate.partition);
+        if let Some(probe) = &candidate.probe {
+            printf!(b"label \"");
+            write_string(superblock_string(&probe.volume_name));
+            printf!(b"\", last mounted at \"");
+            write_string(superblock_string(&probe.last_mount_path));
+            printf!(b"\", ");
+        }
+        write_string(match candidate.status {
+            CandidateStatus::NotExt2 => b"not ext2",
+            CandidateStatus::MountFailed => b"mount failed",
+            CandidateStatus::NoKernel => b"no kernel",
+            CandidateStatus::Fallback => b"selected (no candidate has the kernel)",
+            CandidateStatus::Selected => b"selected",
+            CandidateStatus::NotExamined => b"not examined further",
+        });
+        printf!(b", 0x%x sectors read\r\n", candidate.sectors);
+    }
+}
+
+/// Picks the Linux filesystem partition to boot from, in two passes. <br>
+/// The probe pass reads only the superblock of every Linux partition and orders them by likelihood (see `likelihood`). <br>
+/// The mount pass then fully mounts them in that order until one holds `kernel_path`, within [`MOUNT_SCAN_BUDGET_TICKS`]. <br>
+/// When no partition holds the kernel the first mountable one is returned, so the missing kernel is reported by the caller. <br>
+pub fn scan_boot_partitions(
+    bios_idt: usize,
+    disk: &ExtendedDisk,
+    gpt: &GUIDPartitionTable,
+    kernel_path: &[u8],
+) -> Option<(usize, Ext2FileSystem)> {
+    let mut candidates: Vec<Candidate> = Vec::new(gpt.get_partitions().len().max(1));
+    let mut probe_disk = disk.clone();
+    for (i, partition) in gpt.get_partitions().iter().enumerate() {
+        if partition.type_guid != PARTITION_GUID_TYPE_LINUX_FS {
+            continue;
+        }
+        let before = sectors_read();
+        let probe = Ext2FileSystem::probe(&mut probe_disk, &partition.as_disk_range()).ok();
+        candidates.push(Candidate {
+            partition: i,
+            status: match probe {
+                Some(_) => CandidateStatus::NotExamined,
+                None => CandidateStatus::NotExt2,
+            },
+            probe,
+            sectors: sectors_read() - before,
+        });
+    }
+    // Stable: equally likely candidates keep the partition table order
+    candidates.bubble_sort(|a, b| {
+        let score = |c: &Candidate| c.probe.as_ref().map_or(0, likelihood) as isize;
+        score(b) - score(a)
+    });
+
+    let start = bios_ticks(bios_idt);
+    let mut fallback: Option<(usize, Ext2FileSystem)> = None;
+    let mut selected: Option<(usize, Ext2FileSystem)> = None;
+    for index in 0..candidates.len() {
+        let Some(candidate) = candidates.get_mut(index) else {
+            break;
+        };
+        if candidate.status == CandidateStatus::NotExt2 {
+            continue;
+        }
+        let elapsed = ticks_since(bios_idt, start);
+        if selected.is_some() || (fallback.is_some() && elapsed > MOUNT_SCAN_BUDGET_TICKS) {
+            continue;
+        }
+
+        let before = sectors_read();
+        let Some(partition) = gpt.get_partitions().get(candidate.partition) else {
+            continue;
+        };
+        match Ext2FileSystem::mount_ro(disk.clone(), partition.as_disk_range()) {
+            Ok(mut ext2) => {
+                let has_kernel = matches!(ext2.find_inode(kernel_path), Ok(Some(_)));
+                candidate.sectors += sectors_read() - before;
+                if has_kernel {
+                    candidate.status = CandidateStatus::Selected;
+                    selected = Some((candidate.partition, ext2));
+                } else {
+                    candidate.status = CandidateStatus::NoKernel;
+                    if fallback.is_none() {
+                        fallback = Some((candidate.partition, ext2));
+                    }
+                }
+            }
+            Err(e) => {
+                candidate.sectors += sectors_read() - before;
+                candidate.status = CandidateStatus::MountFailed;
+                printf!(
+                    b"Failed to mount partition 0x%x as ext2: ",
+                    candidate.partition
+                );
+                e.printf();
+            }
+        }
+    }
+
+    if let (None, Some((partition, _))) = (&selected, &fallback) {
+        for index in 0..candidates.len() {
+            if let Some(candidate) = candidates.get_mut(index) {
+                if candidate.partition == *partition {
+                    candidate.status = CandidateStatus::Fallback;
+                }
+            }
+        }
+    }
+    printf_candidates(&candidates);
+    selected.or(fallback)
+}
+
+/// BIOS hard disk numbers probed when the boot drive has nothing to boot
+const PROBED_DRIVES: RangeInclusive<u8> = 0x80..=0x8F;
+
+/// Drive and partition the kernel is booted from
+pub struct BootVolume {
+    /// BIOS drive number, handed to the kernel as `bios_boot_drive`
+    pub drive: u8,
+    pub disk: ExtendedDisk,
+    pub disk_params: DiskParams,
+    /// None when booting from an ext2 image on ISO9660 media, see [`open_iso_volume`]
+    pub gpt: Option<GUIDPartitionTable>,
+    /// Index in `gpt`, 0 without one
+    pub partition: usize,
+    pub ext2: Ext2FileSystem,
+}
+
+/// Mounts the ext2 image [`BOOT_IMAGE_PATH`] of the ISO9660 filesystem on `disk`, for CD/DVD and El Torito boots. <br>
+/// Returns None, after logging why, when there is no such file or it doesn't mount. <br>
+pub fn open_iso_volume(
+    drive: u8,
+    disk: &mut ExtendedDisk,
+    disk_params: DiskParams,
+) -> Option<BootVolume> {
+    let range = match find_iso_file(disk, BOOT_IMAGE_PATH) {
+        Ok(range) => range,
+        Err(e) => {
+            printf!(b"Drive 0x%b: ISO9660 boot image: ", drive);
+            e.printf();
+            printf!(b"\r\n");
+            return None;
+        }
+    };
+    match Ext2FileSystem::mount_ro(disk.clone(), range) {
+        Ok(ext2) => {
+            printf!(b"Drive 0x%b: mounted the ISO9660 boot image\r\n", drive);
+            Some(BootVolume {
+                drive,
+                disk: disk.clone(),
+                disk_params,
+                gpt: None,
+                partition: 0,
+                ext2,
+            })
+        }
+        Err(e) => {
+            printf!(b"Drive 0x%b: ISO9660 boot image failed to mount: ", drive);
+            e.printf();
+            printf!(b"\r\n");
+            None
+        }
+    }
+}
+
+/// Reads the GPT of BIOS drive `drive` and picks one of its partitions with [`scan_boot_partitions`]. <br>
+/// Optical drives without a GPT are tried with [`open_iso_volume`]. <br>
+/// Returns None, after logging why, when the drive is absent, any disk call fails, or it has no GPT or no mountable partition. <br>
+pub fn open_boot_volume(bios_idt: usize, drive: u8, kernel_path: &[u8]) -> Option<BootVolume> {
+    let mut disk = ExtendedDisk::new(drive, bios_idt);
+    if !disk.check_present() {
+        printf!(b"Drive 0x%b: not present\r\n", drive);
+        return None;
+    }
+    let disk_params = match disk.get_params() {
+        Ok(params) => params,
+        Err(e) => {
+            printf!(b"Drive 0x%b: ", drive);
+            e.printf();
+            printf!(b", skipped\r\n");
+            return None;
+        }
+    };
+    let gpt = match GUIDPartitionTable::read(&mut disk) {
+        Ok(gpt) => gpt,
+        Err(_) if is_optical(&disk_params) => {
+            return open_iso_volume(drive, &mut disk, disk_params);
+        }
+        Err(e) => {
+            printf!(b"Drive 0x%b: ", drive);
+            e.printf();
+            printf!(b", skipped\r\n");
+            return None;
+        }
+    };
+    if gpt.used_backup() {
+        printf!(
+            b"Drive 0x%b: primary GPT damaged, using the backup\r\n",
+            drive
+        );
+    }
+    let Some((partition, ext2)) = scan_boot_partitions(bios_idt, &disk, &gpt, kernel_path) else {
+        printf!(b"Drive 0x%b: no ext2 linux partition, skipped\r\n", drive);
+        return None;
+    };
+    printf!(
+        b"Drive 0x%b: mounted partition 0x%b\r\n",
+        drive,
+        partition as u8
+    );
+    Some(BootVolume {
+        drive,
+        disk,
+        disk_params,
+        gpt: Some(gpt),
+        partition,
+        ext2,
+    })
+}
+
+/// Tries the BIOS hard disks 0x80 to 0x8F other than `boot_drive` in order, the first with a mountable ext2 partition is used
+pub fn probe_other_drives(
+    bios_idt: usize,
+    boot_drive: u8,
+    kernel_path: &[u8],
+) -> Option<BootVolume> {
+    printf!(b"Looking for an ext2 partition on the other BIOS drives\r\n");
+    PROBED_DRIVES
+        .filter(|drive| *drive != boot_drive)
+        .find_map(|drive| open_boot_volume(bios_idt, drive, kernel_path))
+}
+
+fn printf_selector(selector: &BootPartitionSelector) {
+    match selector {
+        BootPartitionSelector::Index(index) => printf!(b"0x%x", *index),
+        BootPartitionSelector::Guid(guid) => write_guid(*guid),
+        BootPartitionSelector::Name(name) => {
+            printf!(b"\"");
+            write_string(name);
+            printf!(b"\"");
+        }
+    }
+}
+
+/// Mounts the partition requested with `boot_partition=`, when it isn't `current` already. <br>
+/// Returns None, after logging why, when the partition doesn't exist or doesn't mount, the caller then keeps `current`. <br>
+pub fn mount_selected_partition(
+    disk: &ExtendedDisk,
+    gpt: &GUIDPartitionTable,
+    selector: &BootPartitionSelector,
+    current: usize,
+) -> Option<(usize, Ext2FileSystem)> {
+    let partitions = gpt.get_partitions();
+    let found = match selector {
+        BootPartitionSelector::Index(index) => partitions.get(*index).map(|_| *index),
+        BootPartitionSelector::Guid(guid) => partitions
+            .iter()
+            .position(|partition| partition.unique_guid == *guid),
+        BootPartitionSelector::Name(name) => gpt.find_partition_by_name(name).and_then(|found| {
+            partitions
+                .iter()
+                .position(|partition| core::ptr::eq(partition, found))
+        }),
+    };
+    let Some(index) = found else {
+        printf!(b"boot_partition=");
+        printf_selector(selector);
+        printf!(b": no such partition, keeping partition 0x%x\r\n", current);
+        return None;
+    };
+    if index == current {
+        printf!(b"boot_partition=");
+        printf_selector(selector);
+        printf!(b": partition 0x%x, already mounted\r\n", index);
+        return None;
+    }
+    let partition = partitions.get(index)?;
+    match Ext2FileSystem::mount_ro(disk.clone(), partition.as_disk_range()) {
+        Ok(ext2) => {
+            printf!(b"boot_partition=");
+            printf_selector(selector);
+            printf!(b": mounted partition 0x%x\r\n", index);
+            Some((index, ext2))
+        }
+        Err(e) => {
+            printf!(b"boot_partition=");
+            printf_selector(selector);
+            printf!(
+                b": partition 0x%x failed to mount, keeping partition 0x%x: ",
+                index,
+                current
+            );
+            e.printf();
+            None
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use core::{cell::SyncUnsafeCell, slice};
+
+use crate::{
+    bios::{poll_keystroke, wait_us, Keystroke},
+    mem::Buffer,
+    printf,
+    video::{Character, Color, Video},
+};
+
+/// Lines kept, the ones on screen included
+pub const SCROLLBACK_LINES: usize = 512;
+/// Cells stored per line, the widest text mode
+pub const MAX_COLUMNS: usize = 90;
+
+const SCAN_PAGE_UP: u8 = 0x49;
+const SCAN_PAGE_DOWN: u8 = 0x51;
+/// Keyboard polling period once panicked, in microseconds
+const POLL_PERIOD_US: u32 = 50_000;
+const INDICATOR: &[u8] = b" SCROLLBACK - PgDn to return ";
+
+struct Scrollback {
+    /// `SCROLLBACK_LINES * MAX_COLUMNS` cells, None until [`init_scrollback`] ran
+    lines: Option<Buffer>,
+    bios_idt: usize,
+    /// Line number, since boot, of screen row 0 on the live screen
+    top: usize,
+    /// How many lines the view is scrolled back, 0 shows the live screen
+    view: usize,
+}
+
+/// Only touched by the single stage2 thread, never from an interrupt handler
+unsafe impl Sync for Scrollback {}
+
+static SCROLLBACK: SyncUnsafeCell<Scrollback> = SyncUnsafeCell::new(Scrollback {
+    lines: None,
+    bios_idt: 0,
+    top: 0,
+    view: 0,
+});
+
+fn scrollback() -> &'static mut Scrollback {
+    unsafe { &mut *SCROLLBACK.get() }
+}
+
+impl Scrollback {
+    /// Cells of line `number`, None before [`init_scrollback`]
+    fn line(&mut self, number: usize) -> Option<&mut [Character]> {
+        let buffer = self.lines.as_mut()?;
+        let start = number % SCROLLBACK_LINES * MAX_COLUMNS;
+        let cells = unsafe {
+            slice::from_raw_parts_mut(
+                buffer.get_ptr() as *mut Character,
+                SCROLLBACK_LINES * MAX_COLUMNS,
+            )
+        };
+        Some(&mut cells[start..start + MAX_COLUMNS])
+    }
+
+    /// Lines above the live screen still in the ring
+    fn history(&self, rows: usize) -> usize {
+        self.top.min(SCROLLBACK_LINES - rows)
+    }
+}
+
+/// Allocates the ring and copies the current screen into it, everything written to [`Video`] afterwards is kept. <br>
+/// Page Up and Page Down are only read by the boot menu and the panic screen, through INT 16h on `bios_idt`. <br>
+pub fn init_scrollback(bios_idt: usize) {
+    let Some(buffer) = Buffer::new_tagged(
+        SCROLLBACK_LINES * MAX_COLUMNS * size_of::<Character>(),
+        b"scrollback",
+    ) else {
+        printf!(b"Warning: not enough memory for the scrollback buffer\r\n");
+        return;
+    };
+    let scrollback = scrollback();
+    scrollback.lines = Some(buffer);
+    scrollback.bios_idt = bios_idt;
+    copy_screen();
+}
+
+/// Copies what the VGA shows into the live lines, for writes that bypassed [`Video`]
+fn copy_screen() {
+    let video = unsafe { Video::get() };
+    let (columns, rows) = video.size();
+    let scrollback = scrollback();
+    for y in 0..rows {
+        let Some(line) = scrollback.line(scrollback.top + y) else {
+            return;
+        };
+        for (x, cell) in line.iter_mut().enumerate() {
+            *cell = if x < columns {
+                video.read_vga(x, y)
+            } else {
+                Character {
+                    character: 0,
+                    color: 0,
+                }
+            };
+        }
+    }
+}
+
+/// Whether writes to the screen should reach video memory, false while scrolled back
+pub fn is_live() -> bool {
+    scrollback().view == 0
+}
+
+/// Keeps the cell written at `(x, y)` of the live screen
+pub fn record(x: usize, y: usize, cell: Character) {
+    let scrollback = scrollback();
+    let top = scrollback.top;
+    if let Some(line) = scrollback.line(top + y) {
+        if let Some(slot) = line.get_mut(x) {
+            *slot = cell;
+        }
+    }
+}
+
+/// The cell at `(x, y)` of the live screen, None before [`init_scrollback`]
+pub fn live_cell(x: usize, y: usize) -> Option<Character> {
+    let scrollback = scrollback();
+    let top = scrollback.top;
+    scrollback.line(top + y)?.get(x).copied()
+}
+
+/// The live screen of `rows` rows scrolled up by `amount`, the new bottom lines are filled with `blank`. <br>
+/// A scrolled back view stays on the same lines. <br>
+pub fn record_scroll(amount: usize, rows: usize, blank: Character) {
+    let scrollback = scrollback();
+    if scrollback.lines.is_none() {
+        return;
+    }
+    scrollback.top += amount;
+    let top = scrollback.top;
+    for number in top + rows - amount.min(rows)..top + rows {
+        if let Some(line) = scrollback.line(number) {
+            line.fill(blank);
+        }
+    }
+    if scrollback.view != 0 {
+        scrollback.view = (scrollback.view + amount).min(scrollback.history(rows));
+    }
+}
+
+/// Redraws the screen from the ring at the current view
+fn render() {
+    let video = unsafe { Video::get() };
+    let (columns, rows) = video.size();
+    let scrollback = scrollback();
+    let first = scrollback.top - scrollback.view;
+    let view = scrollback.view;
+    for y in 0..rows {
+        if let Some(line) = scrollback.line(first + y) {
+            video.write_vga_row(y, &line[..columns.min(MAX_COLUMNS)]);
+        }
+    }
+    if view != 0 {
+        let color = Color::color(Color::Black, Color::Yellow);
+        let cells = INDICATOR.iter().map(|c| Character {
+            character: *c,
+            color,
+        });
+        for (i, cell) in cells.enumerate() {
+            video.write_vga_cell(columns.saturating_sub(INDICATOR.len()) + i, 0, cell);
+        }
+    }
+}
+
+/// Scrolls the view by `lines`, back into the history when negative, then redraws the screen
+pub fn scroll_view(lines: isize) {
+    let (_, rows) = unsafe { Video::get().size() };
+    let scrollback = scrollback();
+    if scrollback.lines.is_none() {
+        return;
+    }
+    let view = scrollback.view.saturating_add_signed(-lines);
+    scrollback.view = view.min(scrollback.history(rows));
+    render();
+}
+
+/// Returns to the live screen, if scrolled back
+pub fn scroll_to_live() {
+    if !is_live() {
+        scroll_view(isize::MAX);
+    }
+}
+
+/// Scrolls a page for Page Up and Page Down, returns false for any other key
+pub fn handle_scroll_key(key: Keystroke) -> bool {
+    let (_, rows) = unsafe { Video::get().size() };
+    let page = rows as isize - 1;
+    match key.scan_code {
+        SCAN_PAGE_UP => scroll_view(-page),
+        SCAN_PAGE_DOWN => scroll_view(page),
+        _ => return false,
+    }
+    true
+}
+
+/// The end of the panic path: lets the boot log be read with Page Up and Page Down. <br>
+/// Halts without reading the keyboard when the ring wasn't allocated, the panic came before BIOS calls were set up. <br>
+pub fn panic_scrollback() -> ! {
+    let bios_idt = scrollback().bios_idt;
+    if scrollback().lines.is_none() || bios_idt == 0 {
+        #[allow(clippy::empty_loop)]
+        loop {}
+    }
+    // The panic message was written straight to video memory, see `report_panic`
+    copy_screen();
+    loop {
+        match poll_keystroke(bios_idt) {
+            Some(key) => {
+                handle_scroll_key(key);
+            }
+            None => wait_us(bios_idt, POLL_PERIOD_US),
+        }
+    }
+}
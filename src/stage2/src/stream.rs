@@ -0,0 +1,212 @@
+use crate::{
+    crc32::crc32_update,
+    fs::Ext2Error,
+    hash::{Sha256, SHA256_DIGEST_SIZE},
+    kpanic,
+    mem::Buffer,
+    printf,
+    video::Video,
+};
+
+pub enum StreamError {
+    Ext2Error(Ext2Error),
+    /// A sink needed more room than it has: capacity and requested end offset
+    SinkFull(usize, usize),
+}
+
+impl StreamError {
+    /// The filesystem error that stopped the stream. A full sink is a bug in the caller, it panics
+    pub fn into_ext2_error(self) -> Ext2Error {
+        match self {
+            StreamError::Ext2Error(e) => e,
+            e => e.panic(),
+        }
+    }
+
+    pub fn panic(&self) -> ! {
+        match self {
+            StreamError::Ext2Error(e) => e.panic(),
+            StreamError::SinkFull(capacity, end) => {
+                printf!(
+                    b"Stream sink overflow: 0x%x bytes needed, 0x%x available\r\n",
+                    *end,
+                    *capacity
+                );
+                unsafe {
+                    let video = Video::get();
+                    video.write_string(b"Stream sink overflow: 0x");
+                    video.write_hex_u32(*end as u32);
+                    video.write_string(b" > 0x");
+                    video.write_hex_u32(*capacity as u32);
+                    video.write_char(b'\n');
+                }
+                kpanic();
+            }
+        }
+    }
+}
+
+/// Consumer of a byte stream fed in chunks, see [`crate::fs::Ext2File::stream_to`] for the read loop driving it. <br>
+/// `update` must not allocate, it runs once per filesystem block. An error stops the stream. <br>
+pub trait StreamSink {
+    type Output;
+
+    fn update(&mut self, chunk: &[u8]) -> Result<(), StreamError>;
+
+    fn finalize(self) -> Self::Output;
+}
+
+/// CRC-32 of the stream, see [`crate::crc32`]
+#[derive(Default)]
+pub struct Crc32Sink {
+    crc: u32,
+}
+
+impl StreamSink for Crc32Sink {
+    type Output = u32;
+
+    fn update(&mut self, chunk: &[u8]) -> Result<(), StreamError> {
+        self.crc = crc32_update(self.crc, chunk);
+        Ok(())
+    }
+
+    fn finalize(self) -> u32 {
+        self.crc
+    }
+}
+
+/// SHA-256 of the stream, see [`crate::hash`]
+#[derive(Default)]
+pub struct Sha256Sink {
+    hasher: Sha256,
+}
+
+impl StreamSink for Sha256Sink {
+    type Output = [u8; SHA256_DIGEST_SIZE];
+
+    fn update(&mut self, chunk: &[u8]) -> Result<(), StreamError> {
+        self.hasher.update(chunk);
+        Ok(())
+    }
+
+    fn finalize(self) -> [u8; SHA256_DIGEST_SIZE] {
+        self.hasher.finalize()
+    }
+}
+
+/// Counts the streamed bytes
+#[derive(Default)]
+pub struct CountingSink {
+    bytes: usize,
+}
+
+impl StreamSink for CountingSink {
+    type Output = usize;
+
+    fn update(&mut self, chunk: &[u8]) -> Result<(), StreamError> {
+        self.bytes += chunk.len();
+        Ok(())
+    }
+
+    fn finalize(self) -> usize {
+        self.bytes
+    }
+}
+
+/// Copies the stream into `buffer` from `offset` on, fails instead of writing past its end
+pub struct BufferSink<'b> {
+    buffer: &'b mut Buffer,
+    offset: usize,
+}
+
+impl<'b> BufferSink<'b> {
+    pub fn new(buffer: &'b mut Buffer, offset: usize) -> Self {
+        Self { buffer, offset }
+    }
+}
+
+impl StreamSink for BufferSink<'_> {
+    /// Offset after the last copied byte
+    type Output = usize;
+
+    fn update(&mut self, chunk: &[u8]) -> Result<(), StreamError> {
+        let end = self.offset + chunk.len();
+        let Some(destination) = self.buffer[..].get_mut(self.offset..end) else {
+            return Err(StreamError::SinkFull(self.buffer.len(), end));
+        };
+        destination.copy_from_slice(chunk);
+        self.offset = end;
+        Ok(())
+    }
+
+    fn finalize(self) -> usize {
+        self.offset
+    }
+}
+
+/// Feeds every chunk to both sinks, `first` before `second`
+pub struct Tee<A: StreamSink, B: StreamSink> {
+    first: A,
+    second: B,
+}
+
+pub fn tee<A: StreamSink, B: StreamSink>(first: A, second: B) -> Tee<A, B> {
+    Tee { first, second }
+}
+
+impl<A: StreamSink, B: StreamSink> StreamSink for Tee<A, B> {
+    type Output = (A::Output, B::Output);
+
+    fn update(&mut self, chunk: &[u8]) -> Result<(), StreamError> {
+        self.first.update(chunk)?;
+        self.second.update(chunk)
+    }
+
+    fn finalize(self) -> Self::Output {
+        (self.first.finalize(), self.second.finalize())
+    }
+}
+
+/// Copies the stream to raw memory, for data placed at a fixed physical address outside the heap
+pub struct MemorySink {
+    address: usize,
+    len: usize,
+    offset: usize,
+}
+
+impl MemorySink {
+    /// # Safety
+    /// The `len` bytes at `address` must be writable and not used by anything else
+    pub unsafe fn new(address: usize, len: usize) -> Self {
+        Self {
+            address,
+            len,
+            offset: 0,
+        }
+    }
+}
+
+impl StreamSink for MemorySink {
+    /// Bytes copied
+    type Output = usize;
+
+    fn update(&mut self, chunk: &[u8]) -> Result<(), StreamError> {
+        let end = self.offset + chunk.len();
+        if end > self.len {
+            return Err(StreamError::SinkFull(self.len, end));
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                chunk.as_ptr(),
+                (self.address + self.offset) as *mut u8,
+                chunk.len(),
+            )
+        };
+        self.offset = end;
+        Ok(())
+    }
+
+    fn finalize(self) -> usize {
+        self.offset
+    }
+}
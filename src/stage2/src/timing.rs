@@ -0,0 +1,193 @@
+use core::cell::SyncUnsafeCell;
+
+use crate::{
+    bios::{bios_ticks, TICKS_PER_DAY},
+    e9::{write_string, write_u32_decimal},
+    fbcon, printf,
+    stream::{StreamError, StreamSink},
+    video::{CellDisplay, Character, Color, VgaDisplay, Video},
+};
+
+/// Checkpoints kept per boot, later ones are dropped
+const MAX_CHECKPOINTS: usize = 16;
+/// Stage names are padded to this width in the timing table
+const NAME_WIDTH: usize = 20;
+/// Cells of the progress bar, the percentage is written after it
+const BAR_WIDTH: usize = 50;
+const BAR_FILLED: u8 = 0xDB;
+const BAR_EMPTY: u8 = 0xB0;
+
+struct Timeline {
+    /// 0 until [`init_timing`] ran, no checkpoint is recorded before
+    bios_idt: usize,
+    checkpoints: [(&'static [u8], u32); MAX_CHECKPOINTS],
+    len: usize,
+}
+
+/// Single threaded, only touched through the functions below
+static TIMELINE: SyncUnsafeCell<Timeline> = SyncUnsafeCell::new(Timeline {
+    bios_idt: 0,
+    checkpoints: [(&[], 0); MAX_CHECKPOINTS],
+    len: 0,
+});
+
+fn timeline() -> &'static mut Timeline {
+    unsafe { &mut *TIMELINE.get() }
+}
+
+/// Starts the boot timeline, checkpoints read the BIOS tick counter through `bios_idt`
+pub fn init_timing(bios_idt: usize) {
+    timeline().bios_idt = bios_idt;
+    checkpoint(b"stage2 entry");
+}
+
+/// Marks the start of the boot stage `name`, it lasts until the next checkpoint. <br>
+/// Uses INT 1Ah, call it only while BIOS calls work. <br>
+pub fn checkpoint(name: &'static [u8]) {
+    let timeline = timeline();
+    if timeline.bios_idt == 0 || timeline.len == MAX_CHECKPOINTS {
+        return;
+    }
+    timeline.checkpoints[timeline.len] = (name, bios_ticks(timeline.bios_idt));
+    timeline.len += 1;
+}
+
+/// Ticks from `start` to `end`, the counter wraps to 0 at midnight
+fn ticks_between(start: u32, end: u32) -> u32 {
+    (end + TICKS_PER_DAY - start) % TICKS_PER_DAY
+}
+
+fn ticks_to_ms(ticks: u32) -> u32 {
+    (ticks as u64 * 10_000 / 182) as u32
+}
+
+fn printf_row(name: &[u8], ticks: u32) {
+    printf!(b"  ");
+    write_string(name);
+    for _ in name.len()..NAME_WIDTH {
+        printf!(b" ");
+    }
+    write_u32_decimal(ticks_to_ms(ticks));
+    printf!(b" ms (");
+    write_u32_decimal(ticks);
+    printf!(b" ticks)\r\n");
+}
+
+/// Prints the duration of every stage and the total, at the 55ms resolution of the BIOS timer
+pub fn printf_boot_timing() {
+    let timeline = timeline();
+    if timeline.len < 2 {
+        return;
+    }
+    let checkpoints = &timeline.checkpoints[..timeline.len];
+    printf!(b"Boot timing:\r\n");
+    for pair in checkpoints.windows(2) {
+        printf_row(pair[0].0, ticks_between(pair[0].1, pair[1].1));
+    }
+    let total = checkpoints
+        .windows(2)
+        .map(|pair| ticks_between(pair[0].1, pair[1].1))
+        .sum();
+    printf_row(b"total", total);
+}
+
+/// Bar on its own screen line, filled as bytes are reported. Drawn on the framebuffer console once the display left text mode. <br>
+/// Feed it to [`crate::fs::Ext2File::stream_to`] next to the real sink with [`crate::stream::tee`]. <br>
+pub struct ProgressBar {
+    total: usize,
+    done: usize,
+    filled: usize,
+    /// Screen position of the first cell
+    column: usize,
+    row: usize,
+}
+
+impl ProgressBar {
+    /// Writes `label` and an empty bar, on the current line
+    pub fn new(label: &[u8], total: usize) -> Self {
+        let (column, row) = fbcon::with_active_console(|console| {
+            console.write_string(label);
+            console.position()
+        })
+        .unwrap_or_else(|| {
+            let video = unsafe { Video::get() };
+            video.write_string(label);
+            let (column, row) = video.current_writing_position();
+            (column as usize, row as usize)
+        });
+        let bar = Self {
+            total,
+            done: 0,
+            filled: 0,
+            column,
+            row,
+        };
+        bar.draw();
+        bar
+    }
+
+    /// The bar followed by the percentage, e.g. " 042%"
+    fn cells(&self) -> [Character; BAR_WIDTH + 5] {
+        let mut cells = [Character {
+            character: b' ',
+            color: Color::color(Color::White, Color::Black),
+        }; BAR_WIDTH + 5];
+        for (i, cell) in cells[..BAR_WIDTH].iter_mut().enumerate() {
+            cell.character = if i < self.filled {
+                BAR_FILLED
+            } else {
+                BAR_EMPTY
+            };
+            cell.color = Color::color(Color::LightGreen, Color::Black);
+        }
+        let percent = self.filled * 100 / BAR_WIDTH;
+        for (cell, digit) in
+            cells[BAR_WIDTH + 1..]
+                .iter_mut()
+                .zip([percent / 100, percent / 10 % 10, percent % 10])
+        {
+            cell.character = b'0' + digit as u8;
+        }
+        cells[BAR_WIDTH + 4].character = b'%';
+        cells
+    }
+
+    /// Rewrites the bar in place, the writing position stays where it is
+    fn draw(&self) {
+        let cells = self.cells();
+        let drawn =
+            fbcon::with_active_console(|console| console.write_run(self.column, self.row, &cells));
+        if drawn.is_none() {
+            VgaDisplay::new().write_run(self.column, self.row, &cells);
+        }
+    }
+
+    pub fn advance(&mut self, bytes: usize) {
+        self.done = (self.done + bytes).min(self.total);
+        let filled = (self.done * BAR_WIDTH)
+            .checked_div(self.total)
+            .unwrap_or(BAR_WIDTH);
+        if filled > self.filled {
+            self.filled = filled;
+            self.draw();
+        }
+    }
+
+    /// Fills the bar and moves to the next line
+    pub fn finish(mut self) {
+        self.filled = BAR_WIDTH;
+        self.draw();
+        fbcon::write_screen_char(b'\n');
+    }
+}
+
+impl StreamSink for &mut ProgressBar {
+    type Output = ();
+
+    fn update(&mut self, chunk: &[u8]) -> Result<(), StreamError> {
+        self.advance(chunk.len());
+        Ok(())
+    }
+
+    fn finalize(self) {}
+}
@@ -1,13 +1,22 @@
 use core::ptr::addr_of;
 
 use crate::{
-    bios::{unsafe_call_bios_interrupt, BiosInterruptResult},
-    e9::write_char,
+    bios::{unsafe_call_bios_interrupt, BiosCallGuard, BiosInterruptResult},
+    e9::{write_char, write_string, write_u32_decimal},
+    edid::read_native_resolution,
+    fbcon::{self, FbFontConfig, FramebufferConsole},
     kpanic,
-    mem::{memset, Buffer},
-    obsiboot::{ObsiBootConfig, ObsiBootConfigVbeMode},
+    lang::{render, Text},
+    log::{self, LogLevel},
+    log_debug, log_warn,
+    mem::{memset, Buffer, Vec},
+    obsiboot::{
+        ObsiBootConfig, ObsiBootConfigVbeFallback, ObsiBootConfigVbeMode, VBE_SELECTED_BEST,
+        VBE_SELECTED_CLOSEST, VBE_SELECTED_REQUESTED, VBE_SELECTED_SAFE_MODE, VBE_SELECTED_TEXT,
+    },
     printf, ptr_to_seg_off, seg_off_to_ptr,
     video::Video,
+    warnings::{warning, WarningId},
 };
 
 #[repr(C, packed)]
@@ -61,32 +70,410 @@ pub struct VesaModeInfoStructure {
 
 #[repr(align(512))]
 struct VesaContainer([u8; 512]);
-#[repr(align(256))]
-struct VesaContainerSmall([u8; 256]);
 
+/// Mode info buffer followed by a canary, to detect video BIOSes writing past the 256 bytes they own
+#[repr(C, align(256))]
+struct VesaModeInfoContainer {
+    info: [u8; 256],
+    canary: [u32; 4],
+}
+
+const MODE_INFO_CANARY: u32 = 0x0B5D_CA7E;
+
+#[derive(Clone, Copy)]
 struct BestMode {
     mode: u16,
     width: usize,
     height: usize,
     bpp: u8,
     framebuffer: u32,
+    /// Bytes per scan line
+    pitch: usize,
+    /// (position, size) of the red, green and blue fields of a pixel
+    fields: [(u8, u8); 3],
+}
+
+/// The selected mode, as described by boot protocols that carry a framebuffer description
+pub struct FramebufferInfo {
+    pub addr: u64,
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+    /// (position, size) of the red, green and blue fields of a pixel
+    pub fields: [(u8, u8); 3],
 }
 
 static mut VESA_INFO: VesaContainer = VesaContainer([0; 512]);
-static mut VESA_MODE_INFO: VesaContainerSmall = VesaContainerSmall([0; 256]);
-
-static mut MODES_BUFFER: Buffer = Buffer::null();
-static mut BESTMODE: BestMode = BestMode {
-    mode: 0,
-    width: 0,
-    height: 0,
-    bpp: 0,
-    framebuffer: 0,
+static mut VESA_MODE_INFO: VesaModeInfoContainer = VesaModeInfoContainer {
+    info: [0; 256],
+    canary: [MODE_INFO_CANARY; 4],
 };
 
+/// The enumerated VBE modes and the one that was set, handed to the kernel
+pub struct VbeBootInfo {
+    modes: Buffer,
+    /// None when the display was left in text mode
+    selected: Option<BestMode>,
+    requested: [u16; 4],
+    selection: u32,
+}
+
+impl VbeBootInfo {
+    /// Returns `(vbe_info_block_ptr, vbe_modes_info_ptr, vbe_mode_count, vbe_selected_mode)`
+    pub fn boot_info(&self) -> (u32, u32, u32, u32) {
+        unsafe {
+            let vbe_info_block_ptr = addr_of!(VESA_INFO.0) as u32;
+            let vbe_modes_info_ptr = self.modes.get_ptr() as u32;
+            let vbe_mode_count = self.modes.len() as u32 / 256;
+            let vbe_selected_mode = self.selected.map(|m| m.mode as u32).unwrap_or(0xFFFF);
+
+            (
+                vbe_info_block_ptr,
+                vbe_modes_info_ptr,
+                vbe_mode_count,
+                vbe_selected_mode,
+            )
+        }
+    }
+}
+
+impl VbeBootInfo {
+    /// Returns a text console on the selected mode, None when the display was left in text mode
+    pub fn console(&self, font: Option<FbFontConfig>) -> Option<FramebufferConsole> {
+        let mode = self.selected?;
+        FramebufferConsole::new(
+            mode.framebuffer as usize,
+            mode.width,
+            mode.height,
+            mode.pitch,
+            mode.bpp,
+            font,
+        )
+    }
+
+    /// Returns `(physical address, size)` of the linear framebuffer, None when the display was left in text mode
+    pub fn framebuffer(&self) -> Option<(u64, u64)> {
+        let mode = self.selected?;
+        Some((mode.framebuffer as u64, (mode.pitch * mode.height) as u64))
+    }
+
+    /// Returns the selected mode, None when the display was left in text mode
+    pub fn framebuffer_info(&self) -> Option<FramebufferInfo> {
+        let mode = self.selected?;
+        Some(FramebufferInfo {
+            addr: mode.framebuffer as u64,
+            pitch: mode.pitch as u32,
+            width: mode.width as u32,
+            height: mode.height as u32,
+            bpp: mode.bpp,
+            fields: mode.fields,
+        })
+    }
+
+    /// Returns `(vbe_requested_mode, vbe_selection)`, see `ObsiBootKernelParameters`
+    pub fn selection_info(&self) -> ([u16; 4], u32) {
+        (self.requested, self.selection)
+    }
+}
+
 const MESSAGE: &[u8] = b"Failed to switch to graphics mode !\r\n";
 
-pub fn switch_to_graphics(bios_idt: usize, config: &ObsiBootConfig) {
+/// # Safety
+/// `modes_buffer` must hold at least `index + 1` mode info structures
+unsafe fn get_mode_info(modes_buffer: &Buffer, index: usize) -> &VesaModeInfoStructure {
+    &*(modes_buffer.get_ptr() as *const VesaModeInfoStructure).add(index)
+}
+
+/// Distance between a mode and the requested resolution and bpp, lower is closer. <br>
+/// Resolution dominates: each pixel of width or height difference weighs 32, each bit of bpp difference weighs 1. <br>
+pub fn mode_distance(width: u16, height: u16, bpp: u8, requested: (u16, u16, u8)) -> u32 {
+    let (req_width, req_height, req_bpp) = requested;
+    (width.abs_diff(req_width) as u32 + height.abs_diff(req_height) as u32) * 32
+        + bpp.abs_diff(req_bpp) as u32
+}
+
+/// Why a mode can't be handed to the kernel, None for direct color modes with a linear framebuffer. <br>
+/// The boot parameters only describe direct color framebuffers: indexed modes would need a palette, banked ones window switching. <br>
+fn unsupported_reason(mode_info: &VesaModeInfoStructure) -> Option<&'static [u8]> {
+    if (mode_info.attributes & 0x80) != 0x80 {
+        return Some(b"no linear framebuffer, banked access only");
+    }
+    match mode_info.memory_model {
+        0x06 => None,
+        0x00 => Some(b"text mode"),
+        0x01 => Some(b"CGA graphics"),
+        0x02 => Some(b"Hercules graphics"),
+        0x03 => Some(b"planar"),
+        0x04 => Some(b"packed pixel, indexed color needs a palette the kernel isn't told about"),
+        0x05 => Some(b"non-chain 4, 256 color"),
+        0x07 => Some(b"YUV"),
+        _ => Some(b"unknown memory model"),
+    }
+}
+
+/// How the mode to set was picked
+#[derive(Clone, Copy)]
+enum ModeSelection {
+    Set(BestMode, u32),
+    Text,
+}
+
+/// Enumerated modes, with the invalid ones and the ones in `skip_list` filtered out
+struct ModeCandidates<'a> {
+    modes_buffer: &'a Buffer,
+    modes: &'a Vec<u16>,
+    valid: &'a Vec<bool>,
+    skip_list: &'a Vec<u16>,
+}
+
+impl ModeCandidates<'_> {
+    /// # Safety
+    /// `modes_buffer` must hold one mode info structure per entry of `modes`
+    unsafe fn get(&self, i: usize) -> Option<(u16, &VesaModeInfoStructure)> {
+        let mode = *self.modes.get(i)?;
+        if self.skip_list.iter().any(|m| *m == mode) {
+            return None;
+        }
+        Some((mode, get_mode_info(self.modes_buffer, i)))
+    }
+
+    /// Like `get`, but also requires a valid direct color mode with a linear framebuffer
+    unsafe fn get_graphic(&self, i: usize) -> Option<(u16, &VesaModeInfoStructure)> {
+        let (mode, mode_info) = self.get(i)?;
+        if self.valid.get(i) != Some(&true) || unsupported_reason(mode_info).is_some() {
+            return None;
+        }
+        Some((mode, mode_info))
+    }
+
+    fn as_best(mode: u16, mode_info: &VesaModeInfoStructure) -> BestMode {
+        BestMode {
+            mode,
+            width: mode_info.width as usize,
+            height: mode_info.height as usize,
+            bpp: mode_info.bpp,
+            framebuffer: mode_info.framebuffer,
+            pitch: mode_info.pitch as usize,
+            fields: [
+                (mode_info.red_position, mode_info.red_mask),
+                (mode_info.green_position, mode_info.green_mask),
+                (mode_info.blue_position, mode_info.blue_mask),
+            ],
+        }
+    }
+
+    unsafe fn requested(&self, config: &ObsiBootConfig) -> Option<BestMode> {
+        for i in 0..self.modes.len() {
+            let Some((mode, mode_info)) = self.get(i) else {
+                continue;
+            };
+            let matches = match config.vbe_mode {
+                Some(ObsiBootConfigVbeMode::ModeNumber(m)) => mode == m,
+                Some(ObsiBootConfigVbeMode::ModeInfo { width, height, bpp }) => {
+                    mode_info.width == width && mode_info.height == height && mode_info.bpp == bpp
+                }
+                None => false,
+            };
+            if !matches {
+                continue;
+            }
+            // Rejected as if unavailable, so vbe_mode_fallback= decides what happens instead
+            if let Some(reason) = unsupported_reason(mode_info) {
+                printf!(
+                    b"Configured mode %x is unsupported: memory_model=0x%x, attributes=0x%x (",
+                    mode as u32,
+                    mode_info.memory_model as u32,
+                    mode_info.attributes as u32
+                );
+                write_string(reason);
+                printf!(b")\r\n");
+                let video = Video::get();
+                video.write_string(&render(Text::VbeModeUnsupported, &[]));
+                video.write_char(b'\n');
+                continue;
+            }
+            printf!(b"Selecting configured mode %x\r\n", mode as u32);
+            return Some(Self::as_best(mode, mode_info));
+        }
+        None
+    }
+
+    /// The largest direct color mode with a linear framebuffer
+    unsafe fn best(&self) -> Option<BestMode> {
+        let mut bestmode: Option<BestMode> = None;
+        for i in 0..self.modes.len() {
+            let Some((mode, mode_info)) = self.get_graphic(i) else {
+                continue;
+            };
+
+            let pixelcount = (mode_info.width as usize) * (mode_info.height as usize);
+            let (best_pixels, best_bpp) = bestmode
+                .map(|b| (b.width * b.height, b.bpp))
+                .unwrap_or((0, 0));
+
+            if (pixelcount > best_pixels) && mode_info.bpp >= 24
+                || (pixelcount == best_pixels && mode_info.bpp > best_bpp)
+            {
+                bestmode = Some(Self::as_best(mode, mode_info));
+            }
+        }
+        bestmode
+    }
+
+    /// The deepest direct color mode with exactly the given resolution
+    unsafe fn native(&self, (width, height): (u16, u16)) -> Option<BestMode> {
+        let mut native: Option<BestMode> = None;
+        for i in 0..self.modes.len() {
+            let Some((mode, mode_info)) = self.get_graphic(i) else {
+                continue;
+            };
+            if mode_info.width != width || mode_info.height != height {
+                continue;
+            }
+            if native.is_none_or(|n| mode_info.bpp > n.bpp) {
+                native = Some(Self::as_best(mode, mode_info));
+            }
+        }
+        native
+    }
+
+    /// The mode minimizing [`mode_distance`], among the ones not larger than the requested resolution if there are any
+    unsafe fn closest(&self, requested: (u16, u16, u8)) -> Option<BestMode> {
+        let (req_width, req_height, _) = requested;
+        let fits = |mode_info: &VesaModeInfoStructure| {
+            mode_info.width <= req_width && mode_info.height <= req_height
+        };
+        let any_fits =
+            (0..self.modes.len()).any(|i| self.get_graphic(i).is_some_and(|(_, info)| fits(info)));
+
+        let mut closest: Option<(u32, BestMode)> = None;
+        for i in 0..self.modes.len() {
+            let Some((mode, mode_info)) = self.get_graphic(i) else {
+                continue;
+            };
+            if any_fits && !fits(mode_info) {
+                continue;
+            }
+            let distance =
+                mode_distance(mode_info.width, mode_info.height, mode_info.bpp, requested);
+            if closest.is_none_or(|(d, _)| distance < d) {
+                closest = Some((distance, Self::as_best(mode, mode_info)));
+            }
+        }
+        closest.map(|(_, mode)| mode)
+    }
+
+    /// Lists the `count` graphic modes closest to the requested one
+    unsafe fn print_closest(&self, requested: (u16, u16, u8), count: usize) {
+        let mut printed: Vec<u16> = Vec::new_tagged(count.max(1), b"vesa");
+        for _ in 0..count {
+            let mut closest: Option<(u32, u16, &VesaModeInfoStructure)> = None;
+            for i in 0..self.modes.len() {
+                let Some((mode, mode_info)) = self.get_graphic(i) else {
+                    continue;
+                };
+                if printed.iter().any(|m| *m == mode) {
+                    continue;
+                }
+                let distance =
+                    mode_distance(mode_info.width, mode_info.height, mode_info.bpp, requested);
+                if closest.is_none_or(|(d, _, _)| distance < d) {
+                    closest = Some((distance, mode, mode_info));
+                }
+            }
+            let Some((_, mode, mode_info)) = closest else {
+                break;
+            };
+            printf!(b"    mode %x: vbe_mode=", mode as u32);
+            write_u32_decimal(mode_info.width as u32);
+            printf!(b"x");
+            write_u32_decimal(mode_info.height as u32);
+            printf!(b":");
+            write_u32_decimal(mode_info.bpp as u32);
+            printf!(b"\r\n");
+            printed.push(mode);
+        }
+    }
+}
+
+/// The requested mode as `(width, height, bpp)`, or None when it can't be known (unavailable mode number)
+fn requested_resolution(config: &ObsiBootConfig) -> Option<(u16, u16, u8)> {
+    match config.vbe_mode {
+        Some(ObsiBootConfigVbeMode::ModeInfo { width, height, bpp }) => Some((width, height, bpp)),
+        _ => None,
+    }
+}
+
+/// Picks the mode to switch to among the candidates. <br>
+/// The mode requested by the config wins if present, otherwise `vbe_mode_fallback` decides. <br>
+/// Without a requested mode, a mode at the `native` resolution of the display is picked, or else the largest direct color mode with a linear framebuffer. <br>
+unsafe fn select_mode(
+    config: &ObsiBootConfig,
+    candidates: &ModeCandidates,
+    native: Option<(u16, u16)>,
+) -> Option<ModeSelection> {
+    if let Some(mode) = candidates.requested(config) {
+        return Some(ModeSelection::Set(mode, VBE_SELECTED_REQUESTED));
+    }
+    if config.vbe_mode.is_none() {
+        if let Some(mode) = native.and_then(|native| candidates.native(native)) {
+            return Some(ModeSelection::Set(mode, VBE_SELECTED_REQUESTED));
+        }
+        if native.is_some() {
+            printf!(b"No VBE mode at the native resolution, using the largest one\r\n");
+        }
+        return candidates
+            .best()
+            .map(|mode| ModeSelection::Set(mode, VBE_SELECTED_REQUESTED));
+    }
+
+    match config.vbe_mode_fallback {
+        ObsiBootConfigVbeFallback::Best => candidates
+            .best()
+            .map(|mode| ModeSelection::Set(mode, VBE_SELECTED_BEST)),
+        ObsiBootConfigVbeFallback::Closest => match requested_resolution(config) {
+            Some(requested) => candidates
+                .closest(requested)
+                .map(|mode| ModeSelection::Set(mode, VBE_SELECTED_CLOSEST)),
+            None => {
+                printf!(b"Requested mode number is unavailable, its resolution is unknown: using the best mode\r\n");
+                candidates
+                    .best()
+                    .map(|mode| ModeSelection::Set(mode, VBE_SELECTED_BEST))
+            }
+        },
+        ObsiBootConfigVbeFallback::Text => Some(ModeSelection::Text),
+        ObsiBootConfigVbeFallback::Fail => {
+            let video = Video::get();
+            video.write_string(&render(Text::VbeModeUnavailable, &[]));
+            video.write_char(b'\n');
+            printf!(b"Requested VBE mode unavailable (vbe_mode_fallback=fail)");
+            if let Some(requested) = requested_resolution(config) {
+                printf!(b", closest available modes:\r\n");
+                candidates.print_closest(requested, 5);
+            } else {
+                printf!(b"\r\n");
+            }
+            kpanic();
+        }
+    }
+}
+
+/// Boot info for safe mode, where no VBE call is made and the display stays in VGA text mode
+pub fn safe_mode_info() -> VbeBootInfo {
+    let Some(modes) = Buffer::new_tagged(0, b"vesa") else {
+        kpanic();
+    };
+    VbeBootInfo {
+        modes,
+        selected: None,
+        requested: [0; 4],
+        selection: VBE_SELECTED_SAFE_MODE,
+    }
+}
+
+pub fn switch_to_graphics(bios_idt: usize, config: &ObsiBootConfig) -> VbeBootInfo {
     unsafe {
         let info = &*(addr_of!(VESA_INFO.0) as *const VbeInfoBlock);
         let (seg, off) = ptr_to_seg_off(addr_of!(VESA_INFO.0) as usize);
@@ -140,16 +527,8 @@ pub fn switch_to_graphics(bios_idt: usize, config: &ObsiBootConfig) {
         // Video modes
         let mut ptr = seg_off_to_ptr(info.video_mode_ptr[1], info.video_mode_ptr[0]) as *const u16;
 
-        let mut bestmode: BestMode = BestMode {
-            mode: 0,
-            width: 0,
-            height: 0,
-            bpp: 0,
-            framebuffer: 0,
-        };
-
-        let mode_info = &*(addr_of!(VESA_MODE_INFO.0) as *const VesaModeInfoStructure);
-        let (seg, off) = ptr_to_seg_off(addr_of!(VESA_MODE_INFO.0) as usize);
+        let mode_info = &*(addr_of!(VESA_MODE_INFO.info) as *const VesaModeInfoStructure);
+        let (seg, off) = ptr_to_seg_off(addr_of!(VESA_MODE_INFO.info) as usize);
         printf!(b"Mode info ptr=%x:%x\r\n", seg, off);
 
         let mode_count = {
@@ -159,14 +538,33 @@ pub fn switch_to_graphics(bios_idt: usize, config: &ObsiBootConfig) {
             }
             i
         };
-        MODES_BUFFER = Buffer::new(mode_count * 256).unwrap_or_else(|| {
-            printf!(
-                b"Failed to allocate 0x%x bytes of memory for VESA modes buffer\r\n",
+        let requested = match config.vbe_mode {
+            None => [0; 4],
+            Some(ObsiBootConfigVbeMode::ModeNumber(mode)) => [mode, 0, 0, 0],
+            Some(ObsiBootConfigVbeMode::ModeInfo { width, height, bpp }) => {
+                [0xFFFF, width, height, bpp as u16]
+            }
+        };
+
+        // Text mode keeps working without the mode list, no reason to stop the boot
+        let Some(modes_buffer) = Buffer::new_tagged(mode_count * 256, b"vesa") else {
+            log_warn!(
+                b"Warning: failed to allocate 0x%x bytes for the VESA modes buffer, staying in text mode\r\n",
                 mode_count * 256
             );
-            Video::get().write_string(MESSAGE);
-            kpanic();
-        });
+            warning(WarningId::VesaFallback);
+            return VbeBootInfo {
+                modes: Buffer::new_tagged(0, b"vesa").unwrap_or_else(|| kpanic()),
+                selected: None,
+                requested,
+                selection: VBE_SELECTED_TEXT,
+            };
+        };
+
+        // Modes that corrupted state or failed to be set during this boot
+        let mut skip_list: Vec<u16> = Vec::default();
+        let mut modes: Vec<u16> = Vec::new_tagged(mode_count.max(1), b"vesa");
+        let mut valid: Vec<bool> = Vec::new_tagged(mode_count.max(1), b"vesa");
 
         let mut i = 0;
         loop {
@@ -175,6 +573,8 @@ pub fn switch_to_graphics(bios_idt: usize, config: &ObsiBootConfig) {
                 break;
             }
 
+            VESA_MODE_INFO.canary = [MODE_INFO_CANARY; 4];
+            let guard = BiosCallGuard::new();
             let res = unsafe_call_bios_interrupt(
                 bios_idt,
                 0x10,
@@ -191,68 +591,28 @@ pub fn switch_to_graphics(bios_idt: usize, config: &ObsiBootConfig) {
             ) as *const BiosInterruptResult;
             ptr = ptr.add(1);
 
-            #[allow(static_mut_refs)]
-            let mode_ptr = MODES_BUFFER.get_ptr() as *mut VesaModeInfoStructure;
-            *mode_ptr.add(i) = mode_info.clone();
-            i += 1;
-
-            match config.vbe_mode {
-                Some(ObsiBootConfigVbeMode::ModeNumber(m)) => {
-                    printf!(b"m=%x, mode=%x\r\n", m, mode);
-                    if bestmode.mode == m {
-                        // Mode already selected
-                        printf!(b"ALREADY SELECTED\r\n");
-                        continue;
-                    }
-                    if mode == m {
-                        printf!(b"SELECTING\r\n");
-                        bestmode.mode = mode;
-                        bestmode.width = mode_info.width as usize;
-                        bestmode.height = mode_info.height as usize;
-                        bestmode.bpp = mode_info.bpp;
-                        bestmode.framebuffer = mode_info.framebuffer;
-                        continue;
-                    }
-                }
-                Some(ObsiBootConfigVbeMode::ModeInfo { width, height, bpp }) => {
-                    if bestmode.width == width as usize
-                        && bestmode.height == height as usize
-                        && bestmode.bpp == bpp
-                    {
-                        // Mode already selected
-                        continue;
-                    }
-                    if mode_info.width == width
-                        && mode_info.height == height
-                        && mode_info.bpp == bpp
-                    {
-                        bestmode.mode = mode;
-                        bestmode.width = mode_info.width as usize;
-                        bestmode.height = mode_info.height as usize;
-                        bestmode.bpp = mode_info.bpp;
-                        bestmode.framebuffer = mode_info.framebuffer;
-                        continue;
-                    }
-                }
-                None => {}
+            let mode_ptr = modes_buffer.get_ptr() as *mut VesaModeInfoStructure;
+            let corrupted = !guard.verify(&*res) || VESA_MODE_INFO.canary != [MODE_INFO_CANARY; 4];
+            if corrupted {
+                printf!(
+                    b"VESA mode %x corrupted state during 4F01h, skipping it\r\n",
+                    mode as u32
+                );
+                skip_list.push(mode);
+                (mode_ptr.add(i) as *mut u8).write_bytes(0, 256);
+            } else {
+                *mode_ptr.add(i) = mode_info.clone();
             }
+            i += 1;
+            modes.push(mode);
+            valid.push(!corrupted && ((*res).eax & 0xFFFF) == 0x4F);
 
-            if ((*res).eax & 0xFFFF) != 0x4F {
+            if corrupted || ((*res).eax & 0xFFFF) != 0x4F {
                 // Error/unsupported mode
                 continue;
             }
 
-            if (mode_info.attributes & 0x80) != 0x80 {
-                // Mode doesn't have linear framebuffer
-                continue;
-            }
-
-            if mode_info.memory_model != 0x06 {
-                // Mode doesn't have direct color memory model
-                continue;
-            }
-
-            printf!(
+            log_debug!(
                 b"\r\nVESA Mode %x: width=0x%x, height=0x%x, bpp=0x%b, window_a=0x%x, window_b=0x%x, granularity=0x%x, window_size=0x%x, attributes=0x%x, segment_a=0x%x, segment_b=0x%x, win_func_ptr=0x%x, pitch=0x%x, w_char=0x%b, y_char=0x%b, planes=0x%b, bpp=0x%b, banks=0x%b, memory_model=0x%b, bank_size=0x%b, image_pages=0x%b, reserved0=0x%b, red_mask=0x%b, red_position=0x%b, green_mask=0x%b, green_position=0x%b, blue_mask=0x%b, blue_position=0x%b, reserved_mask=0x%b, reserved_position=0x%b, direct_color_attributes=0x%b\r\n",
                 mode as u32,
                 mode_info.width as u32,
@@ -286,74 +646,127 @@ pub fn switch_to_graphics(bios_idt: usize, config: &ObsiBootConfig) {
                 mode_info.reserved_position as u32,
                 mode_info.direct_color_attributes as u32
             );
-
-            let pixelcount = (mode_info.width as usize) * (mode_info.height as usize);
-            let best_pixels = bestmode.width * bestmode.height;
-
-            if (pixelcount > best_pixels) && mode_info.bpp >= 24
-                || (pixelcount == best_pixels && mode_info.bpp > bestmode.bpp)
-            {
-                bestmode.mode = mode;
-                bestmode.width = mode_info.width as usize;
-                bestmode.height = mode_info.height as usize;
-                bestmode.bpp = mode_info.bpp;
-                bestmode.framebuffer = mode_info.framebuffer;
+            if let Some(reason) = unsupported_reason(mode_info) {
+                log::with_level(LogLevel::Debug, || {
+                    printf!(b"    not offered: ");
+                    write_string(reason);
+                    printf!(b"\r\n");
+                });
             }
         }
 
-        printf!(
-            b"Best VBE mode: framebuffer=%x, mode=%x, width=%x, height=%x, bpp=%x\r\n",
-            bestmode.framebuffer,
-            bestmode.mode as u32,
-            bestmode.width as u32,
-            bestmode.height as u32,
-            bestmode.bpp as u32
-        );
+        // Only consulted without vbe_mode=, a configured mode always wins over the panel's
+        let native = if config.vbe_mode.is_none() {
+            read_native_resolution(bios_idt)
+                .map_err(|e| {
+                    printf!(b"EDID unavailable: ");
+                    e.printf();
+                    printf!(b", using the largest VBE mode\r\n");
+                })
+                .ok()
+        } else {
+            None
+        };
 
-        let res = unsafe_call_bios_interrupt(
-            bios_idt,
-            0x10,
-            0x4f02,
-            bestmode.mode as usize,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-        ) as *const BiosInterruptResult;
+        let selection = loop {
+            let candidates = ModeCandidates {
+                modes_buffer: &modes_buffer,
+                modes: &modes,
+                valid: &valid,
+                skip_list: &skip_list,
+            };
+            let Some(selection) = select_mode(config, &candidates, native) else {
+                Video::get().write_string(MESSAGE);
+                printf!(b"No usable VBE mode left\r\n");
+                kpanic();
+            };
+            let ModeSelection::Set(bestmode, _) = selection else {
+                printf!(b"Requested VBE mode unavailable (vbe_mode_fallback=text), staying in text mode\r\n");
+                break selection;
+            };
 
-        if ((*res).eax & 0xFFFF) != 0x4F {
-            Video::get().write_string(MESSAGE);
-            printf!(b"Failed to set graphics mode: eax=%x\r\n", (*res).eax);
-            kpanic();
-        }
+            printf!(
+                b"Best VBE mode: framebuffer=%x, mode=%x, width=%x, height=%x, bpp=%x\r\n",
+                bestmode.framebuffer,
+                bestmode.mode as u32,
+                bestmode.width as u32,
+                bestmode.height as u32,
+                bestmode.bpp as u32
+            );
 
-        memset(
-            bestmode.framebuffer as usize,
-            0,
-            bestmode.width * bestmode.height * (bestmode.bpp as usize / 8),
+            let guard = BiosCallGuard::new();
+            let res = unsafe_call_bios_interrupt(
+                bios_idt,
+                0x10,
+                0x4f02,
+                bestmode.mode as usize,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ) as *const BiosInterruptResult;
+
+            if guard.verify(&*res) && ((*res).eax & 0xFFFF) == 0x4F {
+                break selection;
+            }
+            printf!(
+                b"Failed to set graphics mode %x: eax=%x, falling back to the next best mode\r\n",
+                bestmode.mode as u32,
+                (*res).eax as u32
+            );
+            skip_list.push(bestmode.mode);
+        };
+
+        printf!(
+            b"VBE mode request: mode=%x, width=%x, height=%x, bpp=%x, fallback policy=",
+            requested[0] as u32,
+            requested[1] as u32,
+            requested[2] as u32,
+            requested[3] as u32
         );
+        match config.vbe_mode_fallback {
+            ObsiBootConfigVbeFallback::Closest => printf!(b"closest"),
+            ObsiBootConfigVbeFallback::Best => printf!(b"best"),
+            ObsiBootConfigVbeFallback::Text => printf!(b"text"),
+            ObsiBootConfigVbeFallback::Fail => printf!(b"fail"),
+        }
 
-        BESTMODE = bestmode;
-    }
-}
+        let (selected, selection) = match selection {
+            ModeSelection::Set(bestmode, how) => {
+                printf!(
+                    b", selected mode=%x (selection %x)\r\n",
+                    bestmode.mode as u32,
+                    how
+                );
+                fbcon::leave_text_mode();
+                memset(
+                    bestmode.framebuffer as usize,
+                    0,
+                    bestmode.width * bestmode.height * (bestmode.bpp as usize / 8),
+                );
+                (Some(bestmode), how)
+            }
+            ModeSelection::Text => {
+                printf!(b", staying in text mode\r\n");
+                (None, VBE_SELECTED_TEXT)
+            }
+        };
+        if config.vbe_mode.is_some() && selection != VBE_SELECTED_REQUESTED {
+            log_warn!(
+                b"Warning: the requested VBE mode is unavailable, fell back to another one\r\n"
+            );
+            warning(WarningId::VesaFallback);
+        }
 
-#[allow(static_mut_refs)]
-pub fn get_vbe_boot_info() -> (u32, u32, u32, u32) {
-    unsafe {
-        let vbe_info_block_ptr = VESA_INFO.0.as_ptr() as u32;
-        let vbe_modes_info_ptr = MODES_BUFFER.get_ptr() as u32;
-        let vbe_mode_count = MODES_BUFFER.len() as u32 / 256;
-        let vbe_selected_mode = BESTMODE.mode as u32;
-
-        (
-            vbe_info_block_ptr,
-            vbe_modes_info_ptr,
-            vbe_mode_count,
-            vbe_selected_mode,
-        )
+        VbeBootInfo {
+            modes: modes_buffer,
+            selected,
+            requested,
+            selection,
+        }
     }
 }
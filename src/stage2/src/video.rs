@@ -1,6 +1,11 @@
-use core::cell::SyncUnsafeCell;
+use core::{cell::SyncUnsafeCell, slice};
 
-use crate::io::{inb, outb};
+use crate::{
+    bios::unsafe_call_bios_interrupt,
+    io::{inb, outb},
+    mem::Buffer,
+    scrollback,
+};
 
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
@@ -44,11 +49,72 @@ impl Color {
     }
 }
 
-pub const VGA_WIDTH: usize = 80;
-pub const VGA_HEIGHT: usize = 25;
 pub const VGA_START_ADDRESS: usize = 0xB8000;
-pub const VGA_SIZE: usize = VGA_WIDTH * VGA_HEIGHT;
-pub const VGA_END_ADDRESS: usize = VGA_START_ADDRESS + size_of::<Character>() * VGA_SIZE;
+
+const VGA_MISC_WRITE: u16 = 0x3C2;
+pub const VGA_SEQ_INDEX: u16 = 0x3C4;
+pub const VGA_GC_INDEX: u16 = 0x3CE;
+pub const VGA_CRTC_INDEX: u16 = 0x3D4;
+const VGA_INPUT_STATUS: u16 = 0x3DA;
+const VGA_AC_INDEX: u16 = 0x3C0;
+
+/// # Safety
+/// Reads a VGA register, the card must be in a mode where it is meaningful
+pub unsafe fn vga_read(index_port: u16, index: u8) -> u8 {
+    outb(index_port, index);
+    inb(index_port + 1)
+}
+
+/// # Safety
+/// Writes a VGA register, a wrong value can leave the display unreadable
+pub unsafe fn vga_write(index_port: u16, index: u8, value: u8) {
+    outb(index_port, index);
+    outb(index_port + 1, value);
+}
+
+/// BDA word holding the number of text columns
+const BDA_COLUMNS: usize = 0x44A;
+/// BDA byte holding the number of text rows minus one
+const BDA_LAST_ROW: usize = 0x484;
+
+/// CRTC registers 0x00-0x18 of the 90x60 mode: 8 pixel wide characters in the 720 pixels of 80 9-pixel ones, 60 rows of the 8x8 font in 480 lines
+const CRTC_90X60: [u8; 25] = [
+    0x6B, 0x59, 0x5A, 0x82, 0x60, 0x8D, 0x0B, 0x3E, 0x00, 0x47, 0x06, 0x07, 0x00, 0x00, 0x00, 0x00,
+    0xEA, 0x0C, 0xDF, 0x2D, 0x08, 0xE8, 0x05, 0xA3, 0xFF,
+];
+
+/// VGA text geometries, from `text_mode=`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TextMode {
+    /// BIOS mode 3 with the 8x16 font, what the BIOS boots in
+    Mode80x25,
+    /// Mode 3 with the 8x8 ROM font
+    Mode80x50,
+    /// The 80x50 mode with 8 pixel wide characters and 480 lines
+    Mode90x60,
+}
+
+impl TextMode {
+    /// Returns `(columns, rows)`
+    pub const fn size(self) -> (u16, u16) {
+        match self {
+            TextMode::Mode80x25 => (80, 25),
+            TextMode::Mode80x50 => (80, 50),
+            TextMode::Mode90x60 => (90, 60),
+        }
+    }
+
+    /// Parses `80x25`, `80x50` or `90x60`
+    pub fn parse(value: &[u8]) -> Option<TextMode> {
+        match value {
+            b"80x25" => Some(TextMode::Mode80x25),
+            b"80x50" => Some(TextMode::Mode80x50),
+            b"90x60" => Some(TextMode::Mode90x60),
+            _ => None,
+        }
+    }
+}
+
 pub struct Cursor {}
 
 impl Cursor {
@@ -69,8 +135,8 @@ impl Cursor {
         }
     }
 
-    pub fn update_cursor(x: usize, y: usize) {
-        let pos = y * VGA_WIDTH + x;
+    /// `pos` is the cell index, `row * columns + column`
+    pub fn update_cursor(pos: usize) {
         unsafe {
             outb(0x3D4, 0x0F);
             outb(0x3D5, (pos & 0xFF) as u8);
@@ -112,11 +178,13 @@ pub struct Video {
     current_x: u16,
     current_y: u16,
     current_color: u8,
+    columns: u16,
+    rows: u16,
 }
 
 impl Video {
     /// # Safety
-    /// This function is safe to call as long as the video memory is mapped at 0xB8000 and the VGA size is 80x25
+    /// This function is safe to call as long as the video memory is mapped at 0xB8000 and the VGA is in the text mode last set with [`Video::set_text_mode`], 80x25 by default
     pub unsafe fn get() -> &'static mut Video {
         &mut *VIDEO.get()
     }
@@ -147,11 +215,87 @@ impl Video {
             current_x: 0,
             current_y: 0,
             current_color: Color::color(Color::White, Color::Black),
+            columns: 80,
+            rows: 25,
+        }
+    }
+
+    /// Returns `(columns, rows)` of the current text mode
+    pub fn size(&self) -> (usize, usize) {
+        (self.columns as usize, self.rows as usize)
+    }
+
+    fn cells(&self) -> usize {
+        self.columns as usize * self.rows as usize
+    }
+
+    fn blank(&self) -> Character {
+        Character {
+            character: 0,
+            color: self.current_color,
+        }
+    }
+
+    /// Writes the cell at index `pos` of the screen, kept in the scrollback and only shown when not scrolled back
+    fn put(&mut self, pos: usize, cell: Character) {
+        let columns = self.columns as usize;
+        scrollback::record(pos % columns, pos / columns, cell);
+        if scrollback::is_live() {
+            unsafe { *video_memory![pos] = cell };
+        }
+    }
+
+    /// Like [`Video::write_char`] at `(x, y)`, for full-screen UIs. Doesn't move the writing position
+    pub fn put_cell(&mut self, x: usize, y: usize, cell: Character) {
+        if x < self.columns as usize && y < self.rows as usize {
+            self.put(y * self.columns as usize + x, cell);
+        }
+    }
+
+    /// The cell at `(x, y)` of the live screen, even while the view is scrolled back
+    pub fn cell(&self, x: usize, y: usize) -> Character {
+        scrollback::live_cell(x, y).unwrap_or_else(|| self.read_vga(x, y))
+    }
+
+    /// What video memory shows at `(x, y)`
+    pub fn read_vga(&self, x: usize, y: usize) -> Character {
+        unsafe { *video_memory![y * self.columns as usize + x] }
+    }
+
+    /// Writes straight to video memory, bypassing the scrollback
+    pub fn write_vga_cell(&self, x: usize, y: usize, cell: Character) {
+        if x < self.columns as usize && y < self.rows as usize {
+            unsafe { *video_memory![y * self.columns as usize + x] = cell };
+        }
+    }
+
+    /// Writes row `y` straight to video memory, bypassing the scrollback
+    pub fn write_vga_row(&self, y: usize, cells: &[Character]) {
+        for (x, cell) in cells.iter().enumerate() {
+            self.write_vga_cell(x, y, *cell);
         }
     }
 
+    /// Switches the VGA to `mode` through INT 10h and clears the screen. <br>
+    /// The BIOS sets 80x25 and 80x50, 90x60 is 80x50 with its timings reprogrammed. <br>
+    pub fn set_text_mode(&mut self, bios_idt: usize, mode: TextMode) {
+        unsafe {
+            // AX=0003h, 80x25 color text
+            unsafe_call_bios_interrupt(bios_idt, 0x10, 0x0003, 0, 0, 0, 0, 0, 0, 0, 0, 0);
+            if mode != TextMode::Mode80x25 {
+                // AX=1112h BL=0, load the 8x8 ROM font into block 0, the BIOS recomputes the rows
+                unsafe_call_bios_interrupt(bios_idt, 0x10, 0x1112, 0, 0, 0, 0, 0, 0, 0, 0, 0);
+            }
+            if mode == TextMode::Mode90x60 {
+                program_90x60();
+            }
+        }
+        (self.columns, self.rows) = mode.size();
+        self.clear();
+    }
+
     pub fn update_cursor(&mut self) {
-        Cursor::update_cursor(self.current_x as usize, self.current_y as usize);
+        Cursor::update_cursor(self.current_position() as usize);
     }
 
     pub fn current_writing_position(&mut self) -> (u16, u16) {
@@ -166,14 +310,14 @@ impl Video {
 
     /// Doesn't update the cursor
     pub fn set_writing_column(&mut self, x: i16) {
-        let x = x % (VGA_WIDTH as i16);
-        self.current_x = (((VGA_WIDTH as i16) + x) as u16) % (VGA_WIDTH as u16);
+        let x = x % (self.columns as i16);
+        self.current_x = (((self.columns as i16) + x) as u16) % self.columns;
     }
 
     /// Doesn't update the cursor
     pub fn set_writing_row(&mut self, y: i16) {
-        let y = y % (VGA_HEIGHT as i16);
-        self.current_y = (((VGA_HEIGHT as i16) + y) as u16) % (VGA_HEIGHT as u16);
+        let y = y % (self.rows as i16);
+        self.current_y = (((self.rows as i16) + y) as u16) % self.rows;
     }
 
     /// Doesn't update the cursor
@@ -184,16 +328,19 @@ impl Video {
     /// Doesn't update the cursor
     pub fn line_feed(&mut self) {
         self.current_y += 1;
-        if self.current_y as usize == VGA_HEIGHT {
+        if self.current_y == self.rows {
             self.scroll(1);
         }
     }
 
+    /// The cleared screen is kept in the scrollback, as if scrolled away
     pub fn clear(&mut self) {
-        unsafe {
-            for i in 0..(VGA_WIDTH * VGA_HEIGHT) {
-                video_memory![i].character = 0;
-                video_memory![i].color = self.current_color;
+        scrollback::record_scroll(self.rows as usize, self.rows as usize, self.blank());
+        if scrollback::is_live() {
+            unsafe {
+                for i in 0..self.cells() {
+                    *video_memory![i] = self.blank();
+                }
             }
         }
         self.current_x = 0;
@@ -210,9 +357,14 @@ impl Video {
         if amount == 0 {
             return;
         }
-        if amount >= (VGA_HEIGHT as u16) {
+        scrollback::record_scroll(amount as usize, self.rows as usize, self.blank());
+        if !scrollback::is_live() {
+            self.current_y = self.current_y.saturating_sub(amount);
+            return;
+        }
+        if amount >= self.rows {
             unsafe {
-                for i in 0..(VGA_WIDTH * VGA_HEIGHT) {
+                for i in 0..self.cells() {
                     video_memory![i].character = 0;
                     video_memory![i].color = self.current_color;
                 }
@@ -220,13 +372,13 @@ impl Video {
             self.current_y = 0;
             return;
         }
-        let remaining_lines = (VGA_HEIGHT as u16) - amount;
-        let remaining_chars = remaining_lines * (VGA_WIDTH as u16);
+        let cells = self.cells();
+        let remaining_chars = (self.rows - amount) as usize * self.columns as usize;
         unsafe {
-            for i in 0..(remaining_chars as usize) {
-                *video_memory![i] = *video_memory![VGA_SIZE - (remaining_chars as usize) + i];
+            for i in 0..remaining_chars {
+                *video_memory![i] = *video_memory![cells - remaining_chars + i];
             }
-            for i in (remaining_chars as usize)..VGA_SIZE {
+            for i in remaining_chars..cells {
                 video_memory![i].character = 0;
                 video_memory![i].color = self.current_color;
             }
@@ -235,31 +387,34 @@ impl Video {
     }
 
     pub fn current_position(&self) -> u16 {
-        self.current_y * (VGA_WIDTH as u16) + self.current_x
+        self.current_y * self.columns + self.current_x
     }
 
     fn write_char0(&mut self, character: u8) {
         if character == b'\r' {
             self.current_x = 0;
         } else if character == b'\n' {
-            if self.current_y == (VGA_HEIGHT - 1) as u16 {
+            if self.current_y == self.rows - 1 {
                 self.scroll(1);
             }
             self.current_y += 1;
             self.current_x = 0;
         } else {
-            if self.current_x == VGA_WIDTH as u16 {
+            if self.current_x == self.columns {
                 self.current_x = 0;
-                if self.current_y == (VGA_HEIGHT - 1) as u16 {
+                if self.current_y == self.rows - 1 {
                     self.scroll(1);
                 }
                 self.current_y += 1;
             }
-            unsafe {
-                let pos = self.current_position() as usize;
-                video_memory![pos].character = character;
-                video_memory![pos].color = self.current_color;
-            }
+            let pos = self.current_position() as usize;
+            self.put(
+                pos,
+                Character {
+                    character,
+                    color: self.current_color,
+                },
+            );
             self.current_x += 1;
         }
     }
@@ -282,11 +437,12 @@ impl Video {
     }
 
     pub fn write_centered(&mut self, string: &[u8]) {
-        if string.len() > VGA_WIDTH {
+        let columns = self.columns as usize;
+        if string.len() > columns {
             self.write_string(string);
             return;
         }
-        self.current_x = ((VGA_WIDTH - string.len()) >> 1) as u16;
+        self.current_x = ((columns - string.len()) >> 1) as u16;
         for c in string.iter() {
             self.write_char0(*c);
         }
@@ -294,11 +450,9 @@ impl Video {
     }
 
     pub fn clear_line(&mut self, line: u16) {
-        unsafe {
-            for i in 0..VGA_WIDTH {
-                video_memory![i + line as usize * VGA_WIDTH].character = 0;
-                video_memory![i + line as usize * VGA_WIDTH].color = self.current_color;
-            }
+        let columns = self.columns as usize;
+        for i in 0..columns {
+            self.put(i + line as usize * columns, self.blank());
         }
     }
 
@@ -355,3 +509,287 @@ impl Video {
         self.current_color = color;
     }
 }
+
+/// Programs the 90x60 timings over the 80x50 mode set by the BIOS
+unsafe fn program_90x60() {
+    // Negative sync polarities select 480 lines
+    outb(VGA_MISC_WRITE, 0xE7);
+    // 8 pixel wide characters, under a synchronous reset of the sequencer
+    vga_write(VGA_SEQ_INDEX, 0x00, 0x01);
+    vga_write(VGA_SEQ_INDEX, 0x01, 0x01);
+    vga_write(VGA_SEQ_INDEX, 0x00, 0x03);
+    // CRTC registers 0-7 are write protected until bit 7 of register 0x11 is cleared
+    vga_write(VGA_CRTC_INDEX, 0x11, vga_read(VGA_CRTC_INDEX, 0x11) & 0x7F);
+    for (index, value) in CRTC_90X60.iter().enumerate() {
+        vga_write(VGA_CRTC_INDEX, index as u8, *value);
+    }
+    // No horizontal panning with 8 pixel characters, reading the status register resets the attribute flip-flop
+    inb(VGA_INPUT_STATUS);
+    outb(VGA_AC_INDEX, 0x20 | 0x13);
+    outb(VGA_AC_INDEX, 0x00);
+    // The BIOS and the kernel read the geometry from the BDA
+    (BDA_COLUMNS as *mut u16).write_volatile(90);
+    (BDA_LAST_ROW as *mut u8).write_volatile(59);
+}
+
+/// Minimal VGA writer for the panic path. <br>
+/// Only uses local state and never touches the cursor or color of [`Video`], which may be half-updated when a panic interrupts it. It only reads the text geometry. <br>
+pub struct PanicWriter {
+    position: usize,
+    color: u8,
+    columns: usize,
+    cells: usize,
+}
+
+impl PanicWriter {
+    /// Starts at the beginning of the row after the hardware cursor, or at the last row if the cursor is out of bounds
+    pub fn new(color: u8) -> Self {
+        let (columns, rows) = unsafe { Video::get().size() };
+        let cursor = Cursor::get_cursor_position() as usize;
+        let row = if cursor < columns * rows {
+            (cursor / columns + 1).min(rows - 1)
+        } else {
+            rows - 1
+        };
+        Self {
+            position: row * columns,
+            color,
+            columns,
+            cells: columns * rows,
+        }
+    }
+
+    /// Starts at the given row, clamped to the screen
+    pub fn at_row(row: usize, color: u8) -> Self {
+        let (columns, rows) = unsafe { Video::get().size() };
+        Self {
+            position: row.min(rows - 1) * columns,
+            color,
+            columns,
+            cells: columns * rows,
+        }
+    }
+
+    pub fn write_char(&mut self, character: u8) {
+        match character {
+            b'\r' => self.position -= self.position % self.columns,
+            b'\n' => self.position += self.columns - self.position % self.columns,
+            _ => {
+                if self.position < self.cells {
+                    unsafe {
+                        *video_memory![self.position] = Character {
+                            character,
+                            color: self.color,
+                        };
+                    }
+                }
+                self.position += 1;
+            }
+        }
+        // Wrap to the top instead of scrolling, scrolling would read back memory that may be mid-update
+        if self.position >= self.cells {
+            self.position = 0;
+        }
+    }
+
+    pub fn write_string(&mut self, string: &[u8]) {
+        for c in string.iter() {
+            self.write_char(*c);
+        }
+    }
+}
+
+/// A character grid the [`Compositor`] can present to
+pub trait CellDisplay {
+    /// Returns `(columns, rows)`
+    fn size(&self) -> (usize, usize);
+    /// Writes `cells` starting at column `x` of row `y`, never past the end of the row
+    fn write_run(&mut self, x: usize, y: usize, cells: &[Character]);
+    /// Returns what is currently displayed, None when the backend can't be read back
+    fn read_cell(&self, x: usize, y: usize) -> Option<Character>;
+    /// Called when the compositor takes over the display
+    fn enter(&mut self) {}
+    /// Called when the compositor gives the display back to streaming output
+    fn leave(&mut self) {}
+}
+
+/// The VGA text screen, streaming output resumes where it was when the compositor took over
+pub struct VgaDisplay {
+    saved_position: (u16, u16),
+}
+
+impl VgaDisplay {
+    pub const fn new() -> Self {
+        Self {
+            saved_position: (0, 0),
+        }
+    }
+}
+
+impl Default for VgaDisplay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CellDisplay for VgaDisplay {
+    fn size(&self) -> (usize, usize) {
+        unsafe { Video::get().size() }
+    }
+
+    fn write_run(&mut self, x: usize, y: usize, cells: &[Character]) {
+        let video = unsafe { Video::get() };
+        for (i, cell) in cells.iter().enumerate() {
+            video.put_cell(x + i, y, *cell);
+        }
+    }
+
+    fn read_cell(&self, x: usize, y: usize) -> Option<Character> {
+        Some(unsafe { Video::get().cell(x, y) })
+    }
+
+    fn enter(&mut self) {
+        self.saved_position = unsafe { Video::get().current_writing_position() };
+    }
+
+    fn leave(&mut self) {
+        unsafe {
+            let video = Video::get();
+            let (x, y) = self.saved_position;
+            video.set_writing_position(x as i16, y as i16);
+            video.update_cursor();
+        }
+    }
+}
+
+fn same_cell(a: &Character, b: &Character) -> bool {
+    a.character == b.character && a.color == b.color
+}
+
+/// Off-screen character grid for full-screen UIs. <br>
+/// UI code draws into the shadow grid, [`Compositor::present`] then only rewrites the cells that changed since the previous present, in contiguous runs. <br>
+/// Streaming output (`Video::write_string`, logs) must not be used between [`Compositor::begin`] and [`Compositor::end`]. <br>
+pub struct Compositor<D: CellDisplay> {
+    display: D,
+    columns: usize,
+    rows: usize,
+    shadow: Buffer,
+    presented: Buffer,
+}
+
+impl<D: CellDisplay> Compositor<D> {
+    /// Takes over `display`, snapshotting its content into the shadow grid. Returns None if the grids can't be allocated
+    pub fn begin(display: D) -> Option<Self> {
+        let (columns, rows) = display.size();
+        let len = (columns * rows * size_of::<Character>()).max(1);
+        let mut compositor = Self {
+            columns,
+            rows,
+            shadow: Buffer::new_tagged(len, b"video")?,
+            presented: Buffer::new_tagged(len, b"video")?,
+            display,
+        };
+        compositor.display.enter();
+        for y in 0..rows {
+            for x in 0..columns {
+                // A backend that can't be read back is assumed blank, the first present redraws what differs from that
+                let cell = compositor.display.read_cell(x, y).unwrap_or(Character {
+                    character: 0,
+                    color: 0,
+                });
+                compositor.shadow_mut()[y * columns + x] = cell;
+                compositor.presented_mut()[y * columns + x] = cell;
+            }
+        }
+        Some(compositor)
+    }
+
+    fn shadow_mut(&mut self) -> &mut [Character] {
+        unsafe {
+            slice::from_raw_parts_mut(
+                self.shadow.get_ptr() as *mut Character,
+                self.columns * self.rows,
+            )
+        }
+    }
+
+    fn presented_mut(&mut self) -> &mut [Character] {
+        unsafe {
+            slice::from_raw_parts_mut(
+                self.presented.get_ptr() as *mut Character,
+                self.columns * self.rows,
+            )
+        }
+    }
+
+    /// Returns `(columns, rows)`
+    pub fn size(&self) -> (usize, usize) {
+        (self.columns, self.rows)
+    }
+
+    pub fn put(&mut self, x: usize, y: usize, cell: Character) {
+        if x < self.columns && y < self.rows {
+            let columns = self.columns;
+            self.shadow_mut()[y * columns + x] = cell;
+        }
+    }
+
+    /// Writes `text` from (x, y), clipped at the end of the row
+    pub fn write_at(&mut self, x: usize, y: usize, text: &[u8], color: u8) {
+        for (i, c) in text.iter().enumerate() {
+            self.put(
+                x + i,
+                y,
+                Character {
+                    character: *c,
+                    color,
+                },
+            );
+        }
+    }
+
+    pub fn fill(&mut self, cell: Character) {
+        for c in self.shadow_mut() {
+            *c = cell;
+        }
+    }
+
+    /// Writes the cells that changed since the last present, returns how many were written
+    pub fn present(&mut self) -> usize {
+        let len = self.columns * self.rows;
+        // Both grids are owned by self and never resized, the slices don't outlive this call
+        let (shadow, presented) = unsafe {
+            (
+                slice::from_raw_parts(self.shadow.get_ptr() as *const Character, len),
+                slice::from_raw_parts_mut(self.presented.get_ptr() as *mut Character, len),
+            )
+        };
+        let mut written = 0;
+        for y in 0..self.rows {
+            let row = y * self.columns;
+            let mut x = 0;
+            while x < self.columns {
+                if same_cell(&shadow[row + x], &presented[row + x]) {
+                    x += 1;
+                    continue;
+                }
+                let mut end = x + 1;
+                while end < self.columns && !same_cell(&shadow[row + end], &presented[row + end]) {
+                    end += 1;
+                }
+                self.display.write_run(x, y, &shadow[row + x..row + end]);
+                presented[row + x..row + end].copy_from_slice(&shadow[row + x..row + end]);
+                written += end - x;
+                x = end;
+            }
+        }
+        written
+    }
+
+    /// Presents the pending changes and gives the display back to streaming output
+    pub fn end(mut self) -> D {
+        self.present();
+        self.display.leave();
+        self.display
+    }
+}
@@ -0,0 +1,201 @@
+use core::cell::SyncUnsafeCell;
+
+use crate::{
+    e9::write_string,
+    kpanic,
+    lang::{render, Text},
+    printf,
+    video::Video,
+};
+
+/// Stable identifiers of the warning sites, named by `strict_allow=` and the probe report
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum WarningId {
+    InvalidConfigValue,
+    MultipleInstalls,
+    JournalReplay,
+    ShortRead,
+    MemLimitHeap,
+    MemLimitIgnored,
+    ReserveLowMemory,
+    ReserveHeapOverlap,
+    ElfIgnoredSegment,
+    VesaFallback,
+    BenchHypervisor,
+    GptBackup,
+    GptEntryCount,
+    ConfigMissing,
+    AcpiMissing,
+}
+
+pub const WARNING_IDS: [WarningId; 15] = [
+    WarningId::InvalidConfigValue,
+    WarningId::MultipleInstalls,
+    WarningId::JournalReplay,
+    WarningId::ShortRead,
+    WarningId::MemLimitHeap,
+    WarningId::MemLimitIgnored,
+    WarningId::ReserveLowMemory,
+    WarningId::ReserveHeapOverlap,
+    WarningId::ElfIgnoredSegment,
+    WarningId::VesaFallback,
+    WarningId::BenchHypervisor,
+    WarningId::GptBackup,
+    WarningId::GptEntryCount,
+    WarningId::ConfigMissing,
+    WarningId::AcpiMissing,
+];
+
+impl WarningId {
+    /// The identifier accepted by `strict_allow=`, never changes once released
+    pub fn name(self) -> &'static [u8] {
+        match self {
+            WarningId::InvalidConfigValue => b"config-value",
+            WarningId::MultipleInstalls => b"multiple-installs",
+            WarningId::JournalReplay => b"journal-replay",
+            WarningId::ShortRead => b"short-read",
+            WarningId::MemLimitHeap => b"mem-limit-heap",
+            WarningId::MemLimitIgnored => b"mem-limit-ignored",
+            WarningId::ReserveLowMemory => b"reserve-low-memory",
+            WarningId::ReserveHeapOverlap => b"reserve-heap-overlap",
+            WarningId::ElfIgnoredSegment => b"elf-ignored-segment",
+            WarningId::VesaFallback => b"vesa-fallback",
+            WarningId::BenchHypervisor => b"bench-hypervisor",
+            WarningId::GptBackup => b"gpt-backup",
+            WarningId::GptEntryCount => b"gpt-entry-count",
+            WarningId::ConfigMissing => b"config-missing",
+            WarningId::AcpiMissing => b"acpi-missing",
+        }
+    }
+
+    pub fn description(self) -> &'static [u8] {
+        match self {
+            WarningId::InvalidConfigValue => b"invalid config value ignored",
+            WarningId::MultipleInstalls => b"multiple bootloader installations found",
+            WarningId::JournalReplay => b"filesystem journal needs a replay",
+            WarningId::ShortRead => b"BIOS read transferred fewer sectors than requested",
+            WarningId::MemLimitHeap => b"heap already extends past mem_limit",
+            WarningId::MemLimitIgnored => b"mem_limit not below the usable memory, ignored",
+            WarningId::ReserveLowMemory => b"reserve= range covers the low 1MiB",
+            WarningId::ReserveHeapOverlap => b"reserve= range overlaps the bootloader heap",
+            WarningId::ElfIgnoredSegment => b"kernel ELF segment ignored by the loader",
+            WarningId::VesaFallback => b"requested VBE mode unavailable, fell back",
+            WarningId::BenchHypervisor => b"disk benchmark running under a hypervisor",
+            WarningId::GptBackup => b"primary GPT corrupted, booted from the backup",
+            WarningId::GptEntryCount => b"GPT declares more than 128 partition entries",
+            WarningId::ConfigMissing => b"no config file found, built-in defaults used",
+            WarningId::AcpiMissing => b"no ACPI RSDP found",
+        }
+    }
+
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        WARNING_IDS.into_iter().find(|id| id.name() == name)
+    }
+
+    fn bit(self) -> u32 {
+        1 << (self as u32)
+    }
+}
+
+/// Most warnings kept in order for the strict boot report, later ones are only counted
+pub const MAX_RECORDED_WARNINGS: usize = 32;
+
+struct WarningLog {
+    counts: [u32; WARNING_IDS.len()],
+    recorded: [Option<WarningId>; MAX_RECORDED_WARNINGS],
+    recorded_len: usize,
+}
+
+/// Single threaded, only touched through [`warning`] and the readers below
+static WARNINGS: SyncUnsafeCell<WarningLog> = SyncUnsafeCell::new(WarningLog {
+    counts: [0; WARNING_IDS.len()],
+    recorded: [None; MAX_RECORDED_WARNINGS],
+    recorded_len: 0,
+});
+
+/// Records a warning for `strict_boot=`, call it next to the message it stands for. <br>
+/// Recording doesn't depend on where (or whether) the message is printed. <br>
+pub fn warning(id: WarningId) {
+    let log = unsafe { &mut *WARNINGS.get() };
+    log.counts[id as usize] = log.counts[id as usize].saturating_add(1);
+    if log.recorded_len < MAX_RECORDED_WARNINGS {
+        log.recorded[log.recorded_len] = Some(id);
+        log.recorded_len += 1;
+    }
+}
+
+/// Total number of warnings recorded since boot
+pub fn warning_count() -> usize {
+    let log = unsafe { &*WARNINGS.get() };
+    log.counts.iter().map(|count| *count as usize).sum()
+}
+
+/// The first [`MAX_RECORDED_WARNINGS`] warnings, in the order they happened
+pub fn recorded_warnings() -> impl Iterator<Item = WarningId> {
+    let log = unsafe { &*WARNINGS.get() };
+    log.recorded.into_iter().take(log.recorded_len).flatten()
+}
+
+/// Warnings not accepted by the `strict_allow=` mask `allow`
+pub fn strict_violations(allow: u32) -> usize {
+    let log = unsafe { &*WARNINGS.get() };
+    WARNING_IDS
+        .iter()
+        .filter(|id| allow & id.bit() == 0)
+        .map(|id| log.counts[*id as usize] as usize)
+        .sum()
+}
+
+/// Parses a comma separated list of warning identifiers into a `strict_allow=` mask, returns the first unknown one on failure
+pub fn parse_warning_ids(value: &[u8]) -> Result<u32, &[u8]> {
+    let mut mask = 0;
+    for name in value.split(|c| *c == b',').filter(|name| !name.is_empty()) {
+        mask |= WarningId::from_name(name).ok_or(name)?.bit();
+    }
+    Ok(mask)
+}
+
+/// With `strict_boot=1`, aborts the boot listing every recorded warning when one isn't accepted by the `allow` mask. <br>
+/// Call it at the last point before the boot is considered successful (the jump, or the end of a probe run). <br>
+pub fn enforce_strict_boot(strict: bool, allow: u32) {
+    let violations = strict_violations(allow);
+    if !strict || violations == 0 {
+        return;
+    }
+    printf!(
+        b"strict_boot=1: 0x%x warnings not allowed by strict_allow=, aborting\r\n",
+        violations
+    );
+    unsafe {
+        let video = Video::get();
+        video.write_string(&render(Text::StrictBootFailed, &[]));
+        video.write_char(b'\n');
+    }
+    for id in recorded_warnings() {
+        let allowed = allow & id.bit() != 0;
+        printf!(b"    ");
+        write_string(id.name());
+        printf!(b": ");
+        write_string(id.description());
+        if allowed {
+            printf!(b" (allowed)");
+        }
+        printf!(b"\r\n");
+        unsafe {
+            let video = Video::get();
+            video.write_string(b"  ");
+            video.write_string(id.name());
+            if allowed {
+                video.write_string(b" (allowed)");
+            }
+            video.write_char(b'\n');
+        }
+    }
+    if warning_count() > MAX_RECORDED_WARNINGS {
+        printf!(
+            b"    ... and 0x%x more\r\n",
+            warning_count() - MAX_RECORDED_WARNINGS
+        );
+    }
+    kpanic();
+}